@@ -0,0 +1,22 @@
+// Web Push 的 service worker，页面里通过 navigator.serviceWorker.register('/assets/sw.js') 注册
+// 服务端发送的推送不带内容，只是提醒有新的变化，收到后显示一条通知，点击时打开 /todos
+self.addEventListener('push', (event) => {
+  event.waitUntil(
+    self.registration.showNotification('New activity', {
+      body: 'Something has changed, tap to see the latest updates.',
+      // 相同 tag 的通知会替换之前的，不会堆积很多条
+      tag: 'push',
+      renotify: true,
+    })
+  );
+});
+
+self.addEventListener('notificationclick', (event) => {
+  event.notification.close();
+  event.waitUntil(
+    self.clients.matchAll({ type: 'window', includeUncontrolled: true }).then((windows) => {
+      const existing = windows.find((client) => new URL(client.url).pathname === '/todos');
+      return existing ? existing.focus() : self.clients.openWindow('/todos');
+    })
+  );
+});
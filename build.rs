@@ -0,0 +1,43 @@
+use std::process::Command;
+
+/*
+ * 编译时记录当前的 git commit，程序里通过 env!("GIT_SHA") 读取，见 dashboard
+ * 在没有 .git 目录的地方编译（比如 Docker 里只复制了源码）时，可以通过 GIT_SHA 环境变量传进来，都没有时是 unknown
+ */
+fn git_sha() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    let sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short=12", "HEAD"])
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_SHA={}", sha);
+}
+
+/**
+ * 用 prost 把 proto/ 下的 .proto 文件生成 Rust 代码，放在 OUT_DIR 里，见 protobuf
+ * .proto 由 protox 解析（纯 Rust 实现），编译机器上不需要装 protoc
+ */
+fn protobuf() {
+    println!("cargo:rerun-if-changed=proto");
+    let descriptors = protox::compile(["demo.proto"], ["proto"]).expect("invalid .proto file");
+    prost_build::Config::new()
+        .compile_fds(descriptors)
+        .expect("failed to generate protobuf code");
+}
+
+fn main() {
+    git_sha();
+    protobuf();
+}
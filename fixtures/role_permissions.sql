@@ -0,0 +1,3 @@
+-- support 角色可以查看审计日志
+INSERT INTO role_permissions (role, permission, scope) VALUES ('support', 'audit:read', 'any')
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,25 @@
+-- 离线同步
+-- seq：每次插入和修改都从同一个序列取一个新值，客户端记住同步到的 seq，下次只拉取比它大的变化
+-- updated_at：最后一次修改的时间，离线客户端提交修改时按这个时间判断谁是后写的（last-write-wins）
+-- client_id：客户端离线创建记录时生成的 id，重复提交同一条创建时不会插入两次
+CREATE SEQUENCE todos_seq;
+ALTER TABLE todos
+    ADD COLUMN seq BIGINT NOT NULL DEFAULT nextval('todos_seq'),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN client_id TEXT;
+CREATE INDEX todos_seq_idx ON todos (tenant_id, seq);
+CREATE UNIQUE INDEX todos_client_id_idx ON todos (tenant_id, client_id) WHERE client_id IS NOT NULL;
+
+-- 修改时分配新的 seq；语句里没有指定 updated_at 时设置为当前时间
+CREATE OR REPLACE FUNCTION todos_touch() RETURNS trigger AS $$
+BEGIN
+    NEW.seq := nextval('todos_seq');
+    IF NEW.updated_at IS NOT DISTINCT FROM OLD.updated_at THEN
+        NEW.updated_at := now();
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER todos_touch BEFORE UPDATE ON todos
+FOR EACH ROW EXECUTE FUNCTION todos_touch();
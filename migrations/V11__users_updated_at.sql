@@ -0,0 +1,17 @@
+-- 用户最后一次修改的时间，列表接口的 ?modified_since= 按它只返回变化过的记录
+-- 软删除也是一次 UPDATE，所以删除和恢复同样会更新 updated_at
+ALTER TABLE users ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- 只在返回给客户端的字段变化时更新，登录失败计数之类的内部字段变化不算修改
+CREATE OR REPLACE FUNCTION users_touch() RETURNS trigger AS $$
+BEGIN
+    IF (NEW.username, NEW.email, NEW.role, NEW.deleted_at, NEW.version)
+        IS DISTINCT FROM (OLD.username, OLD.email, OLD.role, OLD.deleted_at, OLD.version) THEN
+        NEW.updated_at := now();
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_touch BEFORE UPDATE ON users
+FOR EACH ROW EXECUTE FUNCTION users_touch();
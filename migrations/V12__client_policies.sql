@@ -0,0 +1,19 @@
+-- 按 User-Agent 区分的客户端策略，修改之后最多 CLIENT_POLICY_RELOAD_SECS 秒生效，不需要重启
+-- user_agent_patterns：User-Agent 里包含其中任意一个（不区分大小写）就属于这一类，按 priority 从小到大匹配
+-- rate_limit：每个 IP 在 RATE_LIMIT_WINDOW_SECS 内的请求数，为空时使用 RATE_LIMIT_REQUESTS
+-- cache_max_age：GET 请求成功、并且接口自己没有设置 Cache-Control 时加上 Cache-Control: max-age，为空时不加
+-- denied_paths：不允许访问的路径前缀，返回 403
+CREATE TABLE client_policies (
+    class TEXT PRIMARY KEY,
+    priority INT NOT NULL DEFAULT 100,
+    user_agent_patterns TEXT[] NOT NULL DEFAULT '{}',
+    rate_limit INT CHECK (rate_limit > 0),
+    cache_max_age INT CHECK (cache_max_age >= 0),
+    denied_paths TEXT[] NOT NULL DEFAULT '{}'
+);
+
+-- 爬虫的 User-Agent 里通常也有 Mozilla，所以要排在 browser 前面
+INSERT INTO client_policies (class, priority, user_agent_patterns, rate_limit, cache_max_age, denied_paths) VALUES
+    ('bot', 10, '{bot,crawler,spider,slurp}', 60, 300, '{/api/sync/,/api/push/,/api/todos/import,/auth/,/admin/,/oauth/}'),
+    ('sdk', 20, '{okhttp,python-requests,axios,go-http-client,curl}', NULL, NULL, '{}'),
+    ('browser', 30, '{mozilla/}', NULL, NULL, '{}');
@@ -0,0 +1,13 @@
+-- 重定向和改写规则，修改之后最多 REDIRECT_RULES_RELOAD_SECS 秒生效，不需要重启，见 rules 模块
+-- pattern：匹配的路径，:name 匹配一段，结尾的 * 匹配剩下的部分，比如 /blog/:slug、/old/*
+-- target：目标地址，可以引用 :name 和 :splat（* 匹配到的部分），也可以是 https:// 开头的外部地址
+-- status：301/302/303/307/308 返回重定向，200 表示在服务内部改写路径，客户端看不到变化
+-- 按 priority 从小到大匹配，第一条匹配的规则生效
+CREATE TABLE redirect_rules (
+    pattern TEXT PRIMARY KEY CHECK (pattern LIKE '/%'),
+    target TEXT NOT NULL CHECK (target <> ''),
+    status INT NOT NULL DEFAULT 301 CHECK (status IN (200, 301, 302, 303, 307, 308)),
+    priority INT NOT NULL DEFAULT 100,
+    -- 只能改写到本站的路径
+    CHECK (status <> 200 OR target LIKE '/%')
+);
@@ -0,0 +1,33 @@
+-- 给老版本客户端做兼容的转换规则，跟 client_policies 一起每隔 CLIENT_POLICY_RELOAD_SECS 秒重新加载，见 compat 模块
+-- client_class：对哪一类客户端生效，对应 client_policies.class
+-- route：匹配到的路由，比如 /todos/:id，为空时对所有路由生效
+-- direction：request 转换请求（在 handler 之前），response 转换响应（在 handler 之后）
+-- target：body 转换 JSON 请求体/响应体里的字段，header 转换请求头/响应头
+-- action 和 value：
+--   rename     把 field 改名为 value（JSON 字符串），比如请求里的 name 改成 title
+--   default    field 不存在时补上 value
+--   stringify  数字改成字符串，只用于 body
+--   unix_time  RFC 3339 格式的时间改成 Unix 时间戳（秒），只用于 body
+-- field 用 . 分隔嵌套的字段，比如 owner.id，路过的数组会对每个元素都做转换
+-- 同一个请求的规则按 priority 从小到大依次执行
+CREATE TABLE compat_rules (
+    id BIGSERIAL PRIMARY KEY,
+    client_class TEXT NOT NULL REFERENCES client_policies (class) ON UPDATE CASCADE ON DELETE CASCADE,
+    route TEXT CHECK (route LIKE '/%'),
+    direction TEXT NOT NULL CHECK (direction IN ('request', 'response')),
+    target TEXT NOT NULL DEFAULT 'body' CHECK (target IN ('body', 'header')),
+    action TEXT NOT NULL CHECK (action IN ('rename', 'default', 'stringify', 'unix_time')),
+    field TEXT NOT NULL CHECK (field <> ''),
+    value JSONB,
+    priority INT NOT NULL DEFAULT 100,
+    CHECK (action <> 'rename' OR jsonb_typeof(value) = 'string'),
+    CHECK (action <> 'default' OR value IS NOT NULL),
+    CHECK (target = 'body' OR action IN ('rename', 'default'))
+);
+
+-- 例子：老版本的 Android 客户端（User-Agent 里带 legacy-android）创建用户时提交的是 login，读取时要求 id 是字符串
+-- INSERT INTO client_policies (class, priority, user_agent_patterns) VALUES ('legacy-android', 5, '{legacy-android}');
+-- INSERT INTO compat_rules (client_class, route, direction, action, field, value) VALUES
+--     ('legacy-android', '/api/users', 'request', 'rename', 'login', '"username"'),
+--     ('legacy-android', '/api/users', 'response', 'stringify', 'items.id', NULL),
+--     ('legacy-android', '/api/users/:id', 'response', 'stringify', 'id', NULL);
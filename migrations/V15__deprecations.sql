@@ -0,0 +1,25 @@
+-- 准备下线的接口，修改之后最多 DEPRECATION_RELOAD_SECS 秒生效，见 deprecation 模块
+-- route：匹配到的路由，比如 /api/users/:id
+-- announced_at：宣布废弃的时间，放在 Deprecation 响应头里
+-- sunset_at：下线的时间，放在 Sunset 响应头里，过了这个时间接口返回 410；为空表示还没定
+-- replacement：替代的接口，放在 Link: <...>; rel="successor-version" 里
+-- link：迁移说明的地址，放在 Link: <...>; rel="deprecation" 里
+CREATE TABLE deprecations (
+    route TEXT PRIMARY KEY CHECK (route LIKE '/%'),
+    announced_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sunset_at TIMESTAMPTZ CHECK (sunset_at > announced_at),
+    replacement TEXT,
+    link TEXT
+);
+
+-- 每个用户调用废弃接口的次数，用来找出下线前需要通知的人
+-- notified_at：发送下线提醒邮件的时间，每个用户每个接口只提醒一次
+CREATE TABLE deprecation_usage (
+    route TEXT NOT NULL REFERENCES deprecations (route) ON UPDATE CASCADE ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    requests BIGINT NOT NULL,
+    first_seen_at TIMESTAMPTZ NOT NULL,
+    last_seen_at TIMESTAMPTZ NOT NULL,
+    notified_at TIMESTAMPTZ,
+    PRIMARY KEY (route, user_id)
+);
@@ -0,0 +1,19 @@
+-- 后台任务的结果单独保存，过期之后由后台任务删除，任务本身（状态、错误信息）继续保留，见 jobs 模块
+-- result_type：结果的类型名，比如 audit_log.report，客户端据此解析 result
+-- expires_at：创建时加上 JOB_RESULT_TTL_SECS
+CREATE TABLE job_results (
+    job_id UUID PRIMARY KEY REFERENCES jobs (id) ON DELETE CASCADE,
+    result_type TEXT NOT NULL,
+    result JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX job_results_expires_at ON job_results (expires_at);
+
+-- 已有的结果搬过来，保留 7 天
+INSERT INTO job_results (job_id, result_type, result, created_at, expires_at)
+    SELECT id, kind, result, coalesce(finished_at, created_at), coalesce(finished_at, created_at) + interval '7 days'
+    FROM jobs WHERE result IS NOT NULL;
+
+ALTER TABLE jobs DROP COLUMN result;
@@ -0,0 +1,39 @@
+-- 这些表有变化时在 cache_invalidation 频道上发出失效消息，每个实例收到后丢掉内存里对应的缓存，见 invalidation 模块
+-- payload 为 {"cache": "policies", "key": "admin", "sent_at": "..."}
+-- 第一个参数是缓存的名字；有第二个参数时用这一列的值作为 key（行级触发器），否则用表名（语句级触发器）
+CREATE OR REPLACE FUNCTION notify_cache_invalidation() RETURNS trigger AS $$
+DECLARE
+    key TEXT := TG_TABLE_NAME;
+BEGIN
+    IF TG_NARGS > 1 THEN
+        IF TG_OP = 'DELETE' THEN
+            key := to_jsonb(OLD) ->> TG_ARGV[1];
+        ELSE
+            key := to_jsonb(NEW) ->> TG_ARGV[1];
+        END IF;
+    END IF;
+    PERFORM pg_notify(
+        'cache_invalidation',
+        json_build_object('cache', TG_ARGV[0], 'key', key, 'sent_at', clock_timestamp())::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- 规则表一般是整批修改的，每条语句发一次，收到后重新加载整张表
+CREATE TRIGGER client_policies_invalidate AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON client_policies
+FOR EACH STATEMENT EXECUTE FUNCTION notify_cache_invalidation('rules');
+CREATE TRIGGER compat_rules_invalidate AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON compat_rules
+FOR EACH STATEMENT EXECUTE FUNCTION notify_cache_invalidation('rules');
+CREATE TRIGGER redirect_rules_invalidate AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON redirect_rules
+FOR EACH STATEMENT EXECUTE FUNCTION notify_cache_invalidation('rules');
+CREATE TRIGGER deprecations_invalidate AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON deprecations
+FOR EACH STATEMENT EXECUTE FUNCTION notify_cache_invalidation('rules');
+
+-- 按角色缓存的权限，只丢掉改动的角色
+CREATE TRIGGER role_permissions_invalidate AFTER INSERT OR UPDATE OR DELETE ON role_permissions
+FOR EACH ROW EXECUTE FUNCTION notify_cache_invalidation('policies', 'role');
+
+-- 吊销记录只会新增，过期之后的清理不影响缓存
+CREATE TRIGGER revoked_tokens_invalidate AFTER INSERT ON revoked_tokens
+FOR EACH ROW EXECUTE FUNCTION notify_cache_invalidation('revocation', 'jti');
@@ -0,0 +1,18 @@
+-- 已经归档到对象存储的审计日志，每一行对应一个 gzip 压缩的 CSV 文件，见 archive 模块
+-- 对应的行已经从 audit_log 里删除，查历史记录时按时间范围找到 object_key，再去对象存储下载
+CREATE TABLE audit_archives (
+    id BIGSERIAL PRIMARY KEY,
+    bucket TEXT NOT NULL,
+    object_key TEXT NOT NULL UNIQUE,
+    first_id BIGINT NOT NULL,
+    last_id BIGINT NOT NULL,
+    row_count BIGINT NOT NULL,
+    bytes BIGINT NOT NULL,
+    -- 上传的文件的 SHA-256，下载之后可以据此校验
+    sha256 TEXT NOT NULL,
+    first_at TIMESTAMPTZ NOT NULL,
+    last_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX audit_archives_first_at ON audit_archives (first_at);
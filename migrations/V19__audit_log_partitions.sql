@@ -0,0 +1,53 @@
+-- audit_log 改成按 created_at 按月分区的表，分区名形如 audit_log_2024_01，见 db::partitions
+-- 按时间范围查询时只扫描相关的分区，过期的分区整个卸下（DETACH）之后再删除，不用逐行 DELETE
+-- 主键必须包含分区键，改成 (id, created_at)；id 仍然来自原来的序列，不会重复
+ALTER TABLE audit_log RENAME TO audit_log_unpartitioned;
+ALTER INDEX audit_log_pkey RENAME TO audit_log_unpartitioned_pkey;
+ALTER INDEX audit_log_action_idx RENAME TO audit_log_unpartitioned_action_idx;
+
+CREATE TABLE audit_log (
+    id BIGINT NOT NULL DEFAULT nextval('audit_log_id_seq'),
+    actor_id BIGINT,
+    actor TEXT NOT NULL,
+    ip TEXT,
+    route TEXT NOT NULL,
+    action TEXT NOT NULL,
+    payload JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    impersonator_id BIGINT,
+    PRIMARY KEY (id, created_at)
+) PARTITION BY RANGE (created_at);
+
+CREATE INDEX audit_log_action_idx ON audit_log (action);
+CREATE INDEX audit_log_created_at_idx ON audit_log (created_at);
+
+-- 序列改为属于新表，删除旧表时不会被一起删掉
+ALTER SEQUENCE audit_log_id_seq OWNED BY audit_log.id;
+
+-- 不属于任何分区的记录（比如调度任务还没来得及创建下个月的分区）写到默认分区，创建分区时再搬过去
+CREATE TABLE audit_log_default PARTITION OF audit_log DEFAULT;
+
+-- 已有数据所在的月份和当前月份建好分区，之后的月份由调度任务提前创建
+DO $$
+DECLARE
+    month TIMESTAMP;
+BEGIN
+    FOR month IN
+        SELECT date_trunc('month', created_at AT TIME ZONE 'UTC') FROM audit_log_unpartitioned
+        UNION
+        SELECT date_trunc('month', now() AT TIME ZONE 'UTC')
+    LOOP
+        EXECUTE format(
+            'CREATE TABLE %I PARTITION OF audit_log FOR VALUES FROM (%L) TO (%L)',
+            'audit_log_' || to_char(month, 'YYYY_MM'),
+            month AT TIME ZONE 'UTC',
+            (month + INTERVAL '1 month') AT TIME ZONE 'UTC'
+        );
+    END LOOP;
+END $$;
+
+INSERT INTO audit_log (id, actor_id, actor, ip, route, action, payload, created_at, impersonator_id)
+SELECT id, actor_id, actor, ip, route, action, payload, created_at, impersonator_id
+FROM audit_log_unpartitioned;
+
+DROP TABLE audit_log_unpartitioned;
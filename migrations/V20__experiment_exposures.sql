@@ -0,0 +1,11 @@
+-- A/B 实验的曝光记录，见 experiments 模块
+-- 每个实验对象（登录用户 user:1 或匿名会话 session:<uuid>）一行，记录看到的是哪个版本、看到过几次
+CREATE TABLE experiment_exposures (
+    experiment TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    variant TEXT NOT NULL,
+    exposures BIGINT NOT NULL DEFAULT 1,
+    first_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (experiment, subject)
+);
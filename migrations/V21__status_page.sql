@@ -0,0 +1,38 @@
+-- 公开状态页（/status）使用的表，见 status 模块
+
+-- 故障：由管理员通过 /admin/incidents 创建和更新
+-- status：investigating、identified、monitoring、resolved
+-- impact：minor、major、critical，影响 components 里列出的组件在状态页上的显示
+CREATE TABLE incidents (
+    id BIGSERIAL PRIMARY KEY,
+    title TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'investigating',
+    impact TEXT NOT NULL,
+    components TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    resolved_at TIMESTAMPTZ
+);
+
+CREATE INDEX incidents_created_at ON incidents (created_at);
+
+-- 故障的处理进展，按时间顺序显示在状态页上
+CREATE TABLE incident_updates (
+    id BIGSERIAL PRIMARY KEY,
+    incident_id BIGINT NOT NULL REFERENCES incidents (id) ON DELETE CASCADE,
+    status TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX incident_updates_incident_id ON incident_updates (incident_id);
+
+-- 各组件每天的检查次数，用来画状态页上的可用率条
+CREATE TABLE status_samples (
+    component TEXT NOT NULL,
+    day DATE NOT NULL,
+    checks BIGINT NOT NULL DEFAULT 0,
+    degraded BIGINT NOT NULL DEFAULT 0,
+    outages BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (component, day)
+);
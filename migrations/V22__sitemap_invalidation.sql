@@ -0,0 +1,6 @@
+-- sitemap.xml 里的链接和修改时间来自这两张表，有变化时让每个实例丢掉缓存的 sitemap，见 sitemap 模块
+-- 语句级触发器，批量导入待办事项时也只发一次
+CREATE TRIGGER todos_sitemap_invalidate AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON todos
+FOR EACH STATEMENT EXECUTE FUNCTION notify_cache_invalidation('sitemap');
+CREATE TRIGGER incidents_sitemap_invalidate AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON incidents
+FOR EACH STATEMENT EXECUTE FUNCTION notify_cache_invalidation('sitemap');
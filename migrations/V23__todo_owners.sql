@@ -0,0 +1,7 @@
+-- 待办事项的创建者，todo:edit 权限是 own / org 范围时按它判断能否修改，见 permissions
+-- 之前创建的待办事项没有创建者，只有 any 范围的角色能修改
+ALTER TABLE todos ADD COLUMN owner_id BIGINT REFERENCES users (id);
+
+-- 普通用户可以新建待办事项，只能修改和删除自己创建的，管理员的 * 权限不受影响
+INSERT INTO role_permissions (role, permission, scope) VALUES ('user', 'todo:edit', 'own')
+ON CONFLICT DO NOTHING;
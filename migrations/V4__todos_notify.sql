@@ -0,0 +1,14 @@
+-- todos 表有变化时通过 NOTIFY 通知监听了 todos 频道的连接，payload 为 {"op": "INSERT", "id": 1}
+CREATE OR REPLACE FUNCTION notify_todos() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'todos',
+        json_build_object('op', TG_OP, 'id', COALESCE(NEW.id, OLD.id))::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todos_notify ON todos;
+CREATE TRIGGER todos_notify AFTER INSERT OR UPDATE OR DELETE ON todos
+FOR EACH ROW EXECUTE FUNCTION notify_todos();
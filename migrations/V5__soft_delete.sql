@@ -0,0 +1,3 @@
+-- 软删除：删除时只记录删除时间，之后可以通过管理接口恢复
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE todos ADD COLUMN deleted_at TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+-- 乐观锁：每次修改时版本号加一，更新时带上读取时的版本号，不一致说明中间被别人修改过
+ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE todos ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,5 @@
+-- 全文检索：生成列由数据库在每次插入和修改时自动重新计算，GIN 索引也随之更新，应用代码不需要维护
+-- 使用 simple 配置，只按空白和标点分词、转成小写，不做词干提取，中英文标题都可以按词匹配
+ALTER TABLE todos
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('simple', title)) STORED;
+CREATE INDEX todos_search_idx ON todos USING GIN (search);
@@ -0,0 +1,18 @@
+-- 多租户：todos 按 tenant_id 隔离，已有的数据都属于默认租户
+ALTER TABLE todos ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+CREATE INDEX todos_tenant_idx ON todos (tenant_id, id);
+
+-- 通知里带上租户，监听方可以只处理自己租户的变化
+CREATE OR REPLACE FUNCTION notify_todos() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'todos',
+        json_build_object(
+            'op', TG_OP,
+            'id', COALESCE(NEW.id, OLD.id),
+            'tenant', COALESCE(NEW.tenant_id, OLD.tenant_id)
+        )::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
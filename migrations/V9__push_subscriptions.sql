@@ -0,0 +1,10 @@
+-- 浏览器的 Web Push 订阅，endpoint 是推送服务分配的地址，同一个浏览器重复订阅时得到的是同一个 endpoint
+-- p256dh 和 auth 是加密推送内容用的密钥，现在发送的推送不带内容，先保存下来
+CREATE TABLE push_subscriptions (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    endpoint TEXT NOT NULL UNIQUE,
+    p256dh TEXT NOT NULL,
+    auth TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,131 @@
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use tower_http::classify::ServerErrorsFailureClass;
+use tracing::Span;
+
+use crate::telemetry;
+
+/*
+ * 访问日志：每个请求结束时打印一行，target 是 access_log，字段有
+ * method、path、route（匹配到的路由，比如 /api/todos/:id，没有匹配到时是 -）、status、latency_ms、
+ * size（响应体的字节数，压缩之后的流式响应长度未知，记为 -）和 client_ip。
+ * 5xx 用 ERROR 级别，其他用 INFO；不需要时可以通过 RUST_LOG=info,access_log=warn 只保留出错的请求。
+ * TraceLayer 的 on_response 只能看到响应，所以 annotate 先把请求的信息放进响应的 extensions 里，
+ * 它要放在 TraceLayer 的里面、所有其他中间件的外面。
+ */
+
+/**
+ * on_response 需要的请求信息
+ */
+#[derive(Clone)]
+struct AccessInfo {
+    method: String,
+    path: String,
+    route: Option<String>,
+    client_ip: Option<String>,
+}
+
+pub async fn annotate(req: Request, next: Next) -> Response {
+    let info = AccessInfo {
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        route: req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string()),
+        client_ip: req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string()),
+    };
+    let mut res = next.run(req).await;
+    res.extensions_mut().insert(info);
+    res
+}
+
+/**
+ * 响应体的字节数：优先用 Content-Length，其次是已知的 body 长度
+ */
+fn response_size(res: &Response) -> Option<u64> {
+    res.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .or_else(|| res.body().size_hint().exact())
+}
+
+/**
+ * TraceLayer 的 on_response：打印访问日志，开启 OTLP 导出时在 span 上记录状态码
+ */
+pub fn on_response(res: &Response, latency: Duration, span: &Span) {
+    telemetry::record_response(res, span);
+
+    let dash = || "-".to_string();
+    let info = res.extensions().get::<AccessInfo>();
+    let method = info.map(|info| info.method.clone()).unwrap_or_else(dash);
+    let path = info.map(|info| info.path.clone()).unwrap_or_else(dash);
+    let route = info
+        .and_then(|info| info.route.clone())
+        .unwrap_or_else(dash);
+    let client_ip = info
+        .and_then(|info| info.client_ip.clone())
+        .unwrap_or_else(dash);
+    let size = response_size(res)
+        .map(|size| size.to_string())
+        .unwrap_or_else(dash);
+    let status = res.status().as_u16();
+    let latency_ms = latency.as_micros() as f64 / 1000.0;
+
+    if res.status().is_server_error() {
+        tracing::error!(
+            target: "access_log",
+            method = %method,
+            path = %path,
+            route = %route,
+            status,
+            latency_ms,
+            size = %size,
+            client_ip = %client_ip,
+            "{} {} {}",
+            method,
+            path,
+            status
+        );
+    } else {
+        tracing::info!(
+            target: "access_log",
+            method = %method,
+            path = %path,
+            route = %route,
+            status,
+            latency_ms,
+            size = %size,
+            client_ip = %client_ip,
+            "{} {} {}",
+            method,
+            path,
+            status
+        );
+    }
+}
+
+/**
+ * TraceLayer 的 on_failure：5xx 已经在访问日志里了，这里只记录响应体传输中途出错的情况
+ */
+pub fn on_failure(failure: ServerErrorsFailureClass, latency: Duration, _span: &Span) {
+    if let ServerErrorsFailureClass::Error(err) = failure {
+        tracing::error!(
+            target: "access_log",
+            latency_ms = latency.as_micros() as f64 / 1000.0,
+            error = %err,
+            "response body failed"
+        );
+    }
+}
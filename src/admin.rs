@@ -0,0 +1,774 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use askama::Template;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::{request::Parts, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_postgres::types::ToSql;
+
+use crate::{
+    audit::Audit,
+    auth::{verify_credentials, AuthUser, SESSION_TOKEN_KEY},
+    db::ConnectionPool,
+    error::internal_error,
+    impersonate,
+    pagination::{Paginated, Pagination},
+    permissions::{Authorize, Permission, TableManage},
+    session::Session,
+    AppState,
+};
+
+/*
+ * 根据表结构自动生成的后台管理页面，类似一个极简版的 Django admin
+ * 启动时从 pg_catalog 中读取 ADMIN_TABLES 里每张表的字段（名称、类型、是否可空、是否有默认值、主键），
+ * 按字段类型生成对应的表单控件，并在服务端按类型校验提交的值。
+ * 页面通过 /admin/login 登录，登录后 access token 保存在会话里，需要 table:manage 权限。
+ * 列表页按主键倒序分页显示（?page=2&per_page=50，见 pagination）。
+ */
+
+/**
+ * 字段类型，决定表单控件、读取和写入时的类型转换
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    Integer,
+    Decimal,
+    Boolean,
+    Timestamp,
+    Date,
+    Json,
+    Text,
+}
+
+impl FieldKind {
+    fn from_type_name(name: &str) -> Self {
+        match name {
+            "int2" | "int4" | "int8" => FieldKind::Integer,
+            "numeric" | "float4" | "float8" => FieldKind::Decimal,
+            "bool" => FieldKind::Boolean,
+            "timestamptz" => FieldKind::Timestamp,
+            "date" => FieldKind::Date,
+            "json" | "jsonb" => FieldKind::Json,
+            _ => FieldKind::Text,
+        }
+    }
+
+    fn input(self) -> &'static str {
+        match self {
+            FieldKind::Integer | FieldKind::Decimal => "number",
+            FieldKind::Boolean => "checkbox",
+            FieldKind::Timestamp => "datetime-local",
+            FieldKind::Date => "date",
+            FieldKind::Json => "textarea",
+            FieldKind::Text => "text",
+        }
+    }
+
+    fn step(self) -> &'static str {
+        match self {
+            FieldKind::Decimal => "any",
+            _ => "1",
+        }
+    }
+
+    /**
+     * 校验表单提交的值，返回错误信息
+     */
+    fn validate(self, value: &str) -> Result<(), String> {
+        let valid = match self {
+            FieldKind::Integer => value.parse::<i64>().is_ok(),
+            FieldKind::Decimal => value.parse::<f64>().is_ok_and(f64::is_finite),
+            FieldKind::Timestamp => ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"]
+                .iter()
+                .any(|format| NaiveDateTime::parse_from_str(value, format).is_ok()),
+            FieldKind::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            FieldKind::Json => serde_json::from_str::<serde_json::Value>(value).is_ok(),
+            FieldKind::Boolean | FieldKind::Text => true,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("not a valid {}", self.description()))
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            FieldKind::Integer => "integer",
+            FieldKind::Decimal => "number",
+            FieldKind::Boolean => "boolean",
+            FieldKind::Timestamp => "date and time",
+            FieldKind::Date => "date",
+            FieldKind::Json => "JSON document",
+            FieldKind::Text => "text",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Column {
+    name: String,
+    // format_type 的结果，如 bigint、character varying(50)，写入时用来做类型转换
+    sql_type: String,
+    kind: FieldKind,
+    nullable: bool,
+    has_default: bool,
+}
+
+impl Column {
+    fn ident(&self) -> String {
+        quote_ident(&self.name)
+    }
+
+    /**
+     * 读取时统一转换成文本，时间转换成 datetime-local 控件需要的格式（UTC）
+     */
+    fn select_expr(&self) -> String {
+        match self.kind {
+            FieldKind::Timestamp => format!(
+                "to_char({} AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS')",
+                self.ident()
+            ),
+            _ => format!("{}::text", self.ident()),
+        }
+    }
+
+    /**
+     * 写入时以文本传参，再在 SQL 中转换成字段的实际类型
+     */
+    fn bind_expr(&self, index: usize) -> String {
+        match self.kind {
+            FieldKind::Timestamp => format!("(${}::text || '+00')::timestamptz", index),
+            _ => format!("${}::text::{}", index, self.sql_type),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TableSchema {
+    name: String,
+    primary_key: Column,
+    columns: Vec<Column>,
+}
+
+impl TableSchema {
+    fn ident(&self) -> String {
+        quote_ident(&self.name)
+    }
+
+    fn select_list(&self) -> String {
+        self.columns
+            .iter()
+            .map(Column::select_expr)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/**
+ * 启动时读取的表结构，按表名索引
+ */
+#[derive(Clone, Default)]
+pub struct AdminTables(Arc<HashMap<String, TableSchema>>);
+
+impl AdminTables {
+    /**
+     * 读取白名单中每张表的结构，表不存在或者没有单列主键时跳过并打印告警
+     */
+    pub async fn load(
+        pool: &ConnectionPool,
+        tables: &[String],
+    ) -> Result<Self, (StatusCode, String)> {
+        let conn = pool.get().await.map_err(internal_error)?;
+        let mut schemas = HashMap::new();
+        for table in tables {
+            let rows = conn
+                .query(
+                    "SELECT a.attname, format_type(a.atttypid, a.atttypmod), t.typname, a.attnotnull,
+                         a.atthasdef OR a.attidentity <> '', COALESCE(a.attnum = ANY(i.indkey), false)
+                     FROM pg_attribute a
+                     JOIN pg_type t ON t.oid = a.atttypid
+                     LEFT JOIN pg_index i ON i.indrelid = a.attrelid AND i.indisprimary
+                     WHERE a.attrelid = to_regclass($1) AND a.attnum > 0 AND NOT a.attisdropped
+                     ORDER BY a.attnum",
+                    &[table],
+                )
+                .await
+                .map_err(internal_error)?;
+            if rows.is_empty() {
+                tracing::warn!("admin table {} does not exist, skipped", table);
+                continue;
+            }
+
+            let mut columns = Vec::new();
+            let mut primary_keys = Vec::new();
+            for row in rows {
+                let type_name: String = row.get(2);
+                let column = Column {
+                    name: row.get(0),
+                    sql_type: row.get(1),
+                    kind: FieldKind::from_type_name(&type_name),
+                    nullable: !row.get::<_, bool>(3),
+                    has_default: row.get(4),
+                };
+                if row.get::<_, bool>(5) {
+                    primary_keys.push(column.clone());
+                }
+                columns.push(column);
+            }
+            if primary_keys.len() != 1 {
+                tracing::warn!(
+                    "admin table {} has no single-column primary key, skipped",
+                    table
+                );
+                continue;
+            }
+            let primary_key = primary_keys.remove(0);
+            schemas.insert(
+                table.clone(),
+                TableSchema {
+                    name: table.clone(),
+                    primary_key,
+                    columns,
+                },
+            );
+        }
+        Ok(AdminTables(Arc::new(schemas)))
+    }
+
+    fn get(&self, table: &str) -> Result<&TableSchema, (StatusCode, String)> {
+        self.0
+            .get(table)
+            .ok_or((StatusCode::NOT_FOUND, "table not found".to_string()))
+    }
+}
+
+/**
+ * 管理页面的鉴权提取器
+ * 和 Authorize<P> 一样，只是未登录时重定向到登录页，而不是返回 401
+ */
+pub struct AdminPage<P = TableManage> {
+    pub user: AuthUser,
+    _permission: PhantomData<fn() -> P>,
+}
+
+#[async_trait]
+impl<P> FromRequestParts<AppState> for AdminPage<P>
+where
+    P: Permission,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        match Authorize::<P>::from_request_parts(parts, state).await {
+            Ok(auth) => Ok(AdminPage {
+                user: auth.user,
+                _permission: PhantomData,
+            }),
+            Err((StatusCode::UNAUTHORIZED, _)) => Err(Redirect::to("/admin/login").into_response()),
+            Err(err) => Err(err.into_response()),
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/login", get(login_page).post(login))
+        .route("/admin/logout", post(logout))
+        .route("/admin/tables", get(index))
+        .route("/admin/tables/:table", get(list).post(create))
+        .route("/admin/tables/:table/new", get(new))
+        .route("/admin/tables/:table/:id", get(edit).post(update))
+}
+
+#[derive(Template)]
+#[template(path = "admin/login.html")]
+struct LoginTemplate {
+    message: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/index.html")]
+struct IndexTemplate {
+    username: String,
+    tables: Vec<String>,
+    // 数据库结构和迁移不一致的地方，见 db::schema
+    drift: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/list.html")]
+struct ListTemplate {
+    table: String,
+    primary_key: usize,
+    columns: Vec<String>,
+    page: Paginated<Vec<String>>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/form.html")]
+struct FormTemplate {
+    table: String,
+    // 编辑时为主键的值，新建时为 None
+    id: Option<String>,
+    fields: Vec<Field>,
+    message: Option<String>,
+}
+
+/**
+ * 表单中的一个字段
+ */
+struct Field {
+    name: String,
+    sql_type: String,
+    input: &'static str,
+    step: &'static str,
+    value: String,
+    checked: bool,
+    required: bool,
+    readonly: bool,
+    error: Option<String>,
+}
+
+fn render(template: impl Template) -> Result<Response, (StatusCode, String)> {
+    Ok(Html(template.render().map_err(internal_error)?).into_response())
+}
+
+async fn login_page() -> Result<Response, (StatusCode, String)> {
+    render(LoginTemplate { message: None })
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+async fn login(
+    State(state): State<AppState>,
+    session: Session,
+    audit: Audit,
+    Form(input): Form<LoginForm>,
+) -> Result<Response, (StatusCode, String)> {
+    let (user_id, verified) =
+        verify_credentials(&state, audit.ip(), &input.username, &input.password).await?;
+    let Some(user_id) = user_id.filter(|_| verified) else {
+        audit
+            .record(
+                &state.pool,
+                user_id,
+                &input.username,
+                "auth.login_failed",
+                json!({ "username": input.username, "flow": "admin" }),
+            )
+            .await;
+        return render(LoginTemplate {
+            message: Some("Invalid username or password.".to_string()),
+        });
+    };
+
+    let token = state
+        .jwt
+        .issue(user_id, &input.username)
+        .map_err(internal_error)?;
+    session.insert(SESSION_TOKEN_KEY, token);
+    audit
+        .record(
+            &state.pool,
+            Some(user_id),
+            &input.username,
+            "auth.login",
+            json!({ "flow": "admin" }),
+        )
+        .await;
+    Ok(Redirect::to("/admin/tables").into_response())
+}
+
+/**
+ * 退出登录，吊销会话中的 token
+ */
+async fn logout(
+    State(state): State<AppState>,
+    session: Session,
+) -> Result<Redirect, (StatusCode, String)> {
+    // 模拟登录期间退出时，模拟的 token 和管理员原来的 token 都要吊销
+    let tokens = [
+        session.get::<String>(SESSION_TOKEN_KEY),
+        impersonate::clear(&session),
+    ];
+    for claims in tokens
+        .iter()
+        .flatten()
+        .filter_map(|token| state.jwt.decode(token))
+    {
+        state.revocations.revoke(&state.pool, &claims).await?;
+    }
+    session.remove(SESSION_TOKEN_KEY);
+    Ok(Redirect::to("/admin/login"))
+}
+
+async fn index(
+    AdminPage { user, .. }: AdminPage,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let mut tables: Vec<String> = state.admin_tables.0.keys().cloned().collect();
+    tables.sort();
+    render(IndexTemplate {
+        username: user.username,
+        tables,
+        drift: state.schema_drift.report().problems().cloned().collect(),
+    })
+}
+
+async fn list(
+    _page: AdminPage,
+    State(state): State<AppState>,
+    Path(table): Path<String>,
+    pagination: Pagination,
+) -> Result<Response, (StatusCode, String)> {
+    let schema = state.admin_tables.get(&table)?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let rows = conn
+        .query(
+            &format!(
+                "SELECT {} FROM {} ORDER BY {} DESC LIMIT $1 OFFSET $2",
+                schema.select_list(),
+                schema.ident(),
+                schema.primary_key.ident()
+            ),
+            &[&pagination.limit(), &pagination.offset()],
+        )
+        .await
+        .map_err(internal_error)?;
+    let total: i64 = conn
+        .query_one(&format!("SELECT count(*) FROM {}", schema.ident()), &[])
+        .await
+        .map_err(internal_error)?
+        .get(0);
+
+    render(ListTemplate {
+        table: schema.name.clone(),
+        primary_key: schema
+            .columns
+            .iter()
+            .position(|column| column.name == schema.primary_key.name)
+            .unwrap_or_default(),
+        columns: schema.columns.iter().map(|c| c.name.clone()).collect(),
+        page: Paginated::new(
+            rows.iter()
+                .map(|row| {
+                    (0..row.len())
+                        .map(|i| row.get::<_, Option<String>>(i).unwrap_or_default())
+                        .collect()
+                })
+                .collect(),
+            total,
+            pagination,
+        ),
+    })
+}
+
+/**
+ * 根据表结构和当前值生成表单字段
+ */
+fn fields(
+    schema: &TableSchema,
+    values: &HashMap<String, String>,
+    errors: &FieldErrors,
+    editing: bool,
+) -> Vec<Field> {
+    schema
+        .columns
+        .iter()
+        // 新建时，有默认值的主键（自增 id）由数据库生成，不需要出现在表单里
+        .filter(|column| editing || column.name != schema.primary_key.name || !column.has_default)
+        .map(|column| {
+            let value = values.get(&column.name).cloned().unwrap_or_default();
+            Field {
+                name: column.name.clone(),
+                sql_type: column.sql_type.clone(),
+                input: column.kind.input(),
+                step: column.kind.step(),
+                checked: value == "true",
+                value,
+                required: column.kind != FieldKind::Boolean
+                    && !column.nullable
+                    && (editing || !column.has_default),
+                readonly: editing && column.name == schema.primary_key.name,
+                error: errors.get(&column.name).cloned(),
+            }
+        })
+        .collect()
+}
+
+async fn new(
+    _page: AdminPage,
+    State(state): State<AppState>,
+    Path(table): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let schema = state.admin_tables.get(&table)?;
+    render(FormTemplate {
+        table: schema.name.clone(),
+        id: None,
+        fields: fields(schema, &HashMap::new(), &HashMap::new(), false),
+        message: None,
+    })
+}
+
+async fn edit(
+    _page: AdminPage,
+    State(state): State<AppState>,
+    Path((table, id)): Path<(String, String)>,
+) -> Result<Response, (StatusCode, String)> {
+    let schema = state.admin_tables.get(&table)?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let row = conn
+        .query_opt(
+            &format!(
+                "SELECT {} FROM {} WHERE {} = {}",
+                schema.select_list(),
+                schema.ident(),
+                schema.primary_key.ident(),
+                schema.primary_key.bind_expr(1)
+            ),
+            &[&id],
+        )
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, "row not found".to_string()))?;
+
+    let values = schema
+        .columns
+        .iter()
+        .enumerate()
+        .filter_map(|(i, column)| Some((column.name.clone(), row.get::<_, Option<String>>(i)?)))
+        .collect();
+    render(FormTemplate {
+        table: schema.name.clone(),
+        id: Some(id),
+        fields: fields(schema, &values, &HashMap::new(), true),
+        message: None,
+    })
+}
+
+// 字段名 -> 错误信息
+type FieldErrors = HashMap<String, String>;
+
+/**
+ * 校验表单并转换成要写入的字段
+ * 返回 (字段, 值) 列表，值为 None 时写入 NULL；新建时留空且有默认值的字段不写入，交给数据库生成
+ */
+fn parse_form<'a>(
+    schema: &'a TableSchema,
+    form: &HashMap<String, String>,
+    editing: bool,
+) -> Result<Vec<(&'a Column, Option<String>)>, FieldErrors> {
+    let mut values = Vec::new();
+    let mut errors = HashMap::new();
+    for column in &schema.columns {
+        if column.name == schema.primary_key.name && (editing || column.has_default) {
+            continue;
+        }
+        // 没有勾选的 checkbox 不会被提交
+        if column.kind == FieldKind::Boolean {
+            values.push((column, Some(form.contains_key(&column.name).to_string())));
+            continue;
+        }
+        let value = form
+            .get(&column.name)
+            .map(|value| value.trim())
+            .unwrap_or_default();
+        if value.is_empty() {
+            if !editing && column.has_default {
+                continue;
+            }
+            if column.nullable {
+                values.push((column, None));
+            } else {
+                errors.insert(column.name.clone(), "is required".to_string());
+            }
+            continue;
+        }
+        match column.kind.validate(value) {
+            Ok(()) => values.push((column, Some(value.to_string()))),
+            Err(error) => {
+                errors.insert(column.name.clone(), error);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(errors)
+    }
+}
+
+/**
+ * 校验失败或者数据库报错（比如违反唯一约束）时，带着用户填写的内容重新渲染表单
+ */
+fn invalid_form(
+    schema: &TableSchema,
+    id: Option<String>,
+    form: &HashMap<String, String>,
+    errors: &FieldErrors,
+    message: Option<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let mut values = form.clone();
+    for column in &schema.columns {
+        if column.kind == FieldKind::Boolean {
+            values.insert(
+                column.name.clone(),
+                form.contains_key(&column.name).to_string(),
+            );
+        }
+    }
+    if let Some(id) = &id {
+        values.insert(schema.primary_key.name.clone(), id.clone());
+    }
+    let page = render(FormTemplate {
+        table: schema.name.clone(),
+        fields: fields(schema, &values, errors, id.is_some()),
+        id,
+        message,
+    })?;
+    Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response())
+}
+
+fn params<'a>(values: &'a [(&Column, Option<String>)]) -> Vec<&'a (dyn ToSql + Sync)> {
+    values
+        .iter()
+        .map(|(_, value)| value as &(dyn ToSql + Sync))
+        .collect()
+}
+
+async fn create(
+    AdminPage { user, .. }: AdminPage,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path(table): Path<String>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Response, (StatusCode, String)> {
+    let schema = state.admin_tables.get(&table)?;
+    let values = match parse_form(schema, &form, false) {
+        Ok(values) => values,
+        Err(errors) => return invalid_form(schema, None, &form, &errors, None),
+    };
+
+    let sql = if values.is_empty() {
+        format!(
+            "INSERT INTO {} DEFAULT VALUES RETURNING {}::text",
+            schema.ident(),
+            schema.primary_key.ident()
+        )
+    } else {
+        format!(
+            "INSERT INTO {} ({}) VALUES ({}) RETURNING {}::text",
+            schema.ident(),
+            values
+                .iter()
+                .map(|(column, _)| column.ident())
+                .collect::<Vec<_>>()
+                .join(", "),
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, (column, _))| column.bind_expr(i + 1))
+                .collect::<Vec<_>>()
+                .join(", "),
+            schema.primary_key.ident()
+        )
+    };
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let id: String = match conn.query_one(&sql, &params(&values)).await {
+        Ok(row) => row.get(0),
+        Err(err) if err.as_db_error().is_some() => {
+            let message = err.as_db_error().map(|err| err.message().to_string());
+            return invalid_form(schema, None, &form, &HashMap::new(), message);
+        }
+        Err(err) => return Err(internal_error(err)),
+    };
+    drop(conn);
+
+    audit
+        .record(
+            &state.pool,
+            Some(user.id),
+            &user.username,
+            "admin.table.create",
+            json!({ "table": table, "id": id }),
+        )
+        .await;
+    Ok(Redirect::to(&format!("/admin/tables/{}", table)).into_response())
+}
+
+async fn update(
+    AdminPage { user, .. }: AdminPage,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path((table, id)): Path<(String, String)>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Response, (StatusCode, String)> {
+    let schema = state.admin_tables.get(&table)?;
+    let values = match parse_form(schema, &form, true) {
+        Ok(values) => values,
+        Err(errors) => return invalid_form(schema, Some(id), &form, &errors, None),
+    };
+    if values.is_empty() {
+        return Ok(Redirect::to(&format!("/admin/tables/{}", table)).into_response());
+    }
+
+    let sql = format!(
+        "UPDATE {} SET {} WHERE {} = {}",
+        schema.ident(),
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, (column, _))| format!("{} = {}", column.ident(), column.bind_expr(i + 1)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        schema.primary_key.ident(),
+        schema.primary_key.bind_expr(values.len() + 1)
+    );
+    let mut params = params(&values);
+    params.push(&id);
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let updated = match conn.execute(&sql, &params).await {
+        Ok(updated) => updated,
+        Err(err) if err.as_db_error().is_some() => {
+            let message = err.as_db_error().map(|err| err.message().to_string());
+            return invalid_form(schema, Some(id), &form, &HashMap::new(), message);
+        }
+        Err(err) => return Err(internal_error(err)),
+    };
+    drop(conn);
+    if updated == 0 {
+        return Err((StatusCode::NOT_FOUND, "row not found".to_string()));
+    }
+
+    audit
+        .record(
+            &state.pool,
+            Some(user.id),
+            &user.username,
+            "admin.table.update",
+            json!({ "table": table, "id": id }),
+        )
+        .await;
+    Ok(Redirect::to(&format!("/admin/tables/{}", table)).into_response())
+}
@@ -0,0 +1,222 @@
+use std::io::Write;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use serde::Deserialize;
+
+use crate::{
+    config::ArchiveConfig,
+    db::{
+        repo::{self, AuditArchive, AuditEntry},
+        ConnectionPool,
+    },
+    error::internal_error,
+    pagination::{Paginated, Pagination},
+    permissions::{AuditRead, Authorize},
+    storage::{self, ObjectStore},
+    AppState,
+};
+
+/*
+ * 审计日志归档：Postgres 里只保留最近的审计日志，更早的转存到对象存储（见 storage），满足合规要求的同时不让表无限增长
+ * 每隔 AUDIT_ARCHIVE_INTERVAL_SECS 秒检查一次，把早于 AUDIT_ARCHIVE_AFTER_DAYS 天的日志按 id 顺序分批处理：
+ * 1. 每批最多 AUDIT_ARCHIVE_BATCH_SIZE 条，写成 gzip 压缩的 CSV，
+ *    对象名形如 {AUDIT_ARCHIVE_PREFIX}2024/01/31/audit_log-100-200.csv.gz（第一条记录的日期和 id 范围）
+ * 2. 上传之后重新下载一遍，和本地的 SHA-256 对比，一致才继续
+ * 3. 在同一个事务里记录到 audit_archives，并从 audit_log 里删除这批记录
+ * 中途失败时这一批留在数据库里，下次重新上传同名的对象覆盖掉，不会丢数据，也不会留下重复的归档记录。
+ * 多个实例同时运行时用 advisory lock 保证只有一个在归档。没有配置对象存储（S3_BUCKET）时不归档。
+ * GET /admin/audit_log/archives?since=&page=&per_page= 分页列出归档文件（见 pagination），需要 audit:read 权限。
+ */
+
+// 归档任务的 advisory lock
+const LOCK_ID: i64 = 0x6175_6469_745f_6172;
+// 每次最多处理多少批，剩下的留到下一次
+const MAX_BATCHES: usize = 100;
+const CSV_HEADER: &str = "id,created_at,actor_id,actor,impersonator_id,ip,route,action,payload\n";
+
+/**
+ * CSV 字段：包含逗号、引号或者换行时加上双引号，里面的双引号写两遍
+ */
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            entry.created_at.to_rfc3339(),
+            entry.actor_id.map(|id| id.to_string()).unwrap_or_default(),
+            entry.actor.clone(),
+            entry
+                .impersonator_id
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            entry.ip.clone().unwrap_or_default(),
+            entry.route.clone(),
+            entry.action.clone(),
+            entry.payload.to_string(),
+        ];
+        let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&line.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/**
+ * 归档一批，没有需要归档的记录时返回 None
+ */
+async fn archive_batch(
+    pool: &ConnectionPool,
+    store: &ObjectStore,
+    config: &ArchiveConfig,
+    before: DateTime<Utc>,
+) -> Result<Option<AuditArchive>, (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    let entries = repo::list_audit_log_older_than(&*conn, before, config.batch_size)
+        .await
+        .map_err(internal_error)?;
+    drop(conn);
+    let (Some(first), Some(last)) = (entries.first(), entries.last()) else {
+        return Ok(None);
+    };
+
+    let body = gzip(to_csv(&entries).as_bytes()).map_err(internal_error)?;
+    let sha256 = storage::sha256_hex(&body);
+    let archive = AuditArchive {
+        bucket: store.bucket().to_string(),
+        object_key: format!(
+            "{}{}/audit_log-{}-{}.csv.gz",
+            config.prefix,
+            first.created_at.format("%Y/%m/%d"),
+            first.id,
+            last.id
+        ),
+        first_id: first.id,
+        last_id: last.id,
+        row_count: entries.len() as i64,
+        bytes: body.len() as i64,
+        sha256,
+        first_at: first.created_at,
+        last_at: last.created_at,
+    };
+
+    let upload_error = |err: String| (StatusCode::BAD_GATEWAY, err);
+    store
+        .put(&archive.object_key, body, "application/gzip")
+        .await
+        .map_err(upload_error)?;
+    let uploaded = store.get(&archive.object_key).await.map_err(upload_error)?;
+    if storage::sha256_hex(&uploaded) != archive.sha256 {
+        return Err((
+            StatusCode::BAD_GATEWAY,
+            format!("{}: checksum mismatch after upload", archive.object_key),
+        ));
+    }
+
+    let ids: Vec<i64> = entries.iter().map(|entry| entry.id).collect();
+    let mut conn = pool.get().await.map_err(internal_error)?;
+    let tx = conn.transaction().await.map_err(internal_error)?;
+    repo::upsert_audit_archive(&tx, &archive)
+        .await
+        .map_err(internal_error)?;
+    repo::delete_audit_log(&tx, &ids)
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok(Some(archive))
+}
+
+/**
+ * 归档所有过期的审计日志，返回归档的条数；其他实例正在归档时直接返回 0
+ */
+pub async fn run(
+    pool: &ConnectionPool,
+    store: &ObjectStore,
+    config: &ArchiveConfig,
+) -> Result<i64, (StatusCode, String)> {
+    // session 级别的锁，持有这个连接直到归档结束，进程退出时连接断开，锁也随之释放
+    let lock = pool.get().await.map_err(internal_error)?;
+    let locked: bool = lock
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&LOCK_ID])
+        .await
+        .map_err(internal_error)?
+        .get(0);
+    if !locked {
+        return Ok(0);
+    }
+
+    let before = Utc::now() - config.after;
+    let mut archived = 0;
+    let mut outcome = Ok(());
+    for _ in 0..MAX_BATCHES {
+        match archive_batch(pool, store, config, before).await {
+            Ok(Some(archive)) => {
+                tracing::info!(
+                    "archived {} audit log entries to {}",
+                    archive.row_count,
+                    archive.object_key
+                );
+                archived += archive.row_count;
+                if archive.row_count < config.batch_size {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                outcome = Err(err);
+                break;
+            }
+        }
+    }
+
+    let _ = lock
+        .execute("SELECT pg_advisory_unlock($1)", &[&LOCK_ID])
+        .await;
+    outcome.map(|()| archived)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/audit_log/archives", get(list))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    since: Option<DateTime<Utc>>,
+}
+
+async fn list(
+    _auth: Authorize<AuditRead>,
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+    pagination: Pagination,
+) -> Result<Json<Paginated<AuditArchive>>, (StatusCode, String)> {
+    let conn = state.read().get().await.map_err(internal_error)?;
+    let archives =
+        repo::list_audit_archives(&*conn, query.since, pagination.limit(), pagination.offset())
+            .await
+            .map_err(internal_error)?;
+    let total = repo::count_audit_archives(&*conn, query.since)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(Paginated::new(archives, total, pagination)))
+}
@@ -0,0 +1,128 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, request::Parts, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use tower::{Service, ServiceExt};
+use tower_http::services::ServeDir;
+
+use crate::config::AssetsConfig;
+
+/*
+ * 多目录叠加的静态文件服务
+ * 按顺序在多个目录里查找请求的文件，找到第一个就返回，前面的目录覆盖后面目录里的同名文件：
+ * 1. 租户目录 {ASSET_TENANTS_DIR}/{Host}/（配置了 ASSET_TENANTS_DIR 并且目录存在时）
+ * 2. ASSET_ROOTS 里的目录，比如 "themes/dark,assets"，主题目录里只需要放要替换的文件
+ * 这样给某个租户换 logo、样式时，只放几个文件就可以，不需要复制整个 assets 目录。
+ * 每个目录都由 ServeDir 读取，Range、If-Modified-Since、预压缩文件等行为和单个 ServeDir 一样。
+ */
+
+#[derive(Clone)]
+pub struct Overlay {
+    roots: Arc<Vec<PathBuf>>,
+    tenants_dir: Option<Arc<PathBuf>>,
+    // 所有目录里都找不到时返回的页面（状态码仍然是 404），同样按目录顺序查找
+    not_found: Option<&'static str>,
+}
+
+impl Overlay {
+    pub fn new(config: &AssetsConfig) -> Self {
+        Overlay {
+            roots: Arc::new(config.roots.iter().map(PathBuf::from).collect()),
+            tenants_dir: config.tenants_dir.as_ref().map(|dir| Arc::new(dir.into())),
+            not_found: None,
+        }
+    }
+
+    pub fn not_found_page(mut self, path: &'static str) -> Self {
+        self.not_found = Some(path);
+        self
+    }
+
+    /**
+     * 本次请求要查找的目录，租户目录排在最前面
+     * Host 只允许字母、数字、- 和 .，避免通过 Host 请求头访问到其它目录
+     */
+    fn roots_for(&self, headers: &HeaderMap) -> Vec<PathBuf> {
+        let tenant = self.tenants_dir.as_ref().and_then(|dir| {
+            let host = headers.get(header::HOST)?.to_str().ok()?;
+            let host = host.split(':').next()?.to_ascii_lowercase();
+            let valid = !host.is_empty()
+                && !host.starts_with('.')
+                && !host.contains("..")
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+            let root = dir.join(host);
+            (valid && root.is_dir()).then_some(root)
+        });
+        tenant
+            .into_iter()
+            .chain(self.roots.iter().cloned())
+            .collect()
+    }
+
+    pub async fn serve(&self, req: Request) -> Response {
+        // 静态文件请求没有请求体，只需要保留请求行和请求头
+        let (parts, _) = req.into_parts();
+        let roots = self.roots_for(&parts.headers);
+        let res = Self::first_match(&roots, &parts, parts.uri.clone()).await;
+        if res.status() != StatusCode::NOT_FOUND {
+            return res;
+        }
+        let Some(page) = self.not_found else {
+            return res;
+        };
+        let Ok(uri) = format!("/{}", page).parse() else {
+            return res;
+        };
+        let mut res = Self::first_match(&roots, &parts, uri).await;
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        res
+    }
+
+    /**
+     * 依次交给每个目录的 ServeDir 处理，返回第一个不是 404 的响应
+     */
+    async fn first_match(roots: &[PathBuf], parts: &Parts, uri: Uri) -> Response {
+        for root in roots {
+            let mut attempt = Request::new(Body::empty());
+            *attempt.method_mut() = parts.method.clone();
+            *attempt.uri_mut() = uri.clone();
+            *attempt.headers_mut() = parts.headers.clone();
+            let res = ServeDir::new(root).oneshot(attempt).await.into_response();
+            if res.status() != StatusCode::NOT_FOUND {
+                return res;
+            }
+        }
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/**
+ * 实现 tower 的 Service，可以直接用于 nest_service 和 fallback_service
+ */
+impl Service<Request> for Overlay {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let overlay = self.clone();
+        Box::pin(async move { Ok(overlay.serve(req).await) })
+    }
+}
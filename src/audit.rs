@@ -0,0 +1,232 @@
+use std::net::SocketAddr;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts, MatchedPath, Query, State},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    auth::{request_token, JwtKeys},
+    db::{
+        repo::{self, ActionStats, AuditFilter},
+        ConnectionPool,
+    },
+    error::internal_error,
+    jobs::{self, JobResult},
+    pagination::{decode_cursor, CursorPage, Paginated, Pagination},
+    permissions::{AuditRead, Authorize},
+    AppState,
+};
+
+/**
+ * 审计上下文提取器
+ * 记录安全相关操作（登录、登录失败、数据修改、管理操作）时需要知道请求来源的 IP 和路由，
+ * handler 里声明 audit: Audit 参数就能拿到，再调用 audit.record 写入 audit_log 表
+ */
+#[derive(Debug, Clone)]
+pub struct Audit {
+    ip: Option<String>,
+    route: String,
+    impersonator_id: Option<i64>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Audit
+where
+    JwtKeys: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // 需要使用 into_make_service_with_connect_info 启动服务，才能拿到对端地址
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        // 优先记录路由模式（如 /users/:id），而不是具体的 url
+        let route = parts
+            .extensions
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| parts.uri.path().to_string());
+        // 管理员模拟登录期间的所有操作都要标记出实际操作人
+        // 和 AuthUser 一样，会话里的 token 也算，浏览器里模拟登录时用的就是会话
+        let impersonator_id = request_token(parts)
+            .and_then(|token| JwtKeys::from_ref(state).decode(&token))
+            .and_then(|claims| claims.impersonator);
+        Ok(Audit {
+            ip,
+            route,
+            impersonator_id,
+        })
+    }
+}
+
+impl Audit {
+    pub fn ip(&self) -> Option<&str> {
+        self.ip.as_deref()
+    }
+
+    /**
+     * 写入一条审计日志
+     * 审计日志写入失败不应该影响业务本身，所以这里只打印告警，不向上返回错误
+     * payload 只记录摘要，调用方需要自己保证不把密码等敏感信息放进去
+     */
+    pub async fn record(
+        &self,
+        pool: &ConnectionPool,
+        actor_id: Option<i64>,
+        actor: &str,
+        action: &str,
+        payload: Value,
+    ) {
+        let result = async {
+            let conn = pool.get().await.map_err(internal_error)?;
+            conn.execute(
+                "INSERT INTO audit_log (actor_id, actor, ip, route, action, payload, impersonator_id)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &actor_id,
+                    &actor,
+                    &self.ip,
+                    &self.route,
+                    &action,
+                    &payload,
+                    &self.impersonator_id,
+                ],
+            )
+            .await
+            .map_err(internal_error)
+        }
+        .await;
+
+        if let Err((_, err)) = result {
+            tracing::warn!("write audit log {} failed: {}", action, err);
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/audit_log", get(list_audit_log))
+        .route("/admin/audit_log/report", post(report_audit_log))
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditQuery {
+    action: Option<String>,
+    actor_id: Option<i64>,
+    // 时间范围 [since, until)，RFC 3339 格式；审计日志按月分区，带上范围时只查询对应月份的分区
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    // 带上这个参数（第一页为空字符串）时使用游标分页
+    cursor: Option<String>,
+}
+
+/**
+ * 分页查询审计日志，可以按 action、actor_id 和时间范围（since、until）过滤，需要 audit:read 权限
+ * 分页参数和返回格式见 pagination；日志量很大时翻页请使用 ?cursor=，不会扫描前面的页，也不统计总数
+ */
+async fn list_audit_log(
+    Authorize { user: admin, .. }: Authorize<AuditRead>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Query(query): Query<AuditQuery>,
+    pagination: Pagination,
+) -> Result<Response, (StatusCode, String)> {
+    // 查看审计日志本身也是一次管理操作
+    audit
+        .record(
+            &state.pool,
+            Some(admin.id),
+            &admin.username,
+            "admin.audit_log.query",
+            json!({
+                "action": query.action,
+                "actor_id": query.actor_id,
+                "since": query.since,
+                "until": query.until,
+            }),
+        )
+        .await;
+
+    let before = query.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    // 只读查询，走只读副本
+    let conn = state.read().get().await.map_err(internal_error)?;
+    let filter = AuditFilter {
+        action: query.action.as_deref(),
+        actor_id: query.actor_id,
+        since: query.since,
+        until: query.until,
+    };
+    if let Some(before) = before {
+        let items = repo::list_audit_log_before(&*conn, &filter, before, pagination.limit() + 1)
+            .await
+            .map_err(internal_error)?;
+        let page = CursorPage::new(items, pagination, |entry| entry.id);
+        return Ok(Json(page).into_response());
+    }
+
+    let total = repo::count_audit_log(&*conn, &filter)
+        .await
+        .map_err(internal_error)?;
+    let items = repo::list_audit_log(&*conn, &filter, pagination.limit(), pagination.offset())
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(Paginated::new(items, total, pagination)).into_response())
+}
+
+/**
+ * 审计日志统计报表，作为 audit_log.report 任务的结果保存
+ */
+#[derive(Serialize)]
+struct AuditReport {
+    generated_at: DateTime<Utc>,
+    actions: Vec<ActionStats>,
+}
+
+impl JobResult for AuditReport {
+    const TYPE: &'static str = "audit_log.report";
+}
+
+/**
+ * 生成审计日志统计报表：按 action 汇总次数、涉及的操作人数量和最近一次发生的时间
+ * 日志量大时统计比较慢，所以作为异步任务执行，返回 202 后通过 /api/jobs/:id 或者 /api/jobs/:id/result 获取结果
+ */
+async fn report_audit_log(
+    Authorize { user: admin, .. }: Authorize<AuditRead>,
+    State(state): State<AppState>,
+    audit: Audit,
+) -> Result<Response, (StatusCode, String)> {
+    audit
+        .record(
+            &state.pool,
+            Some(admin.id),
+            &admin.username,
+            "admin.audit_log.report",
+            json!({}),
+        )
+        .await;
+
+    jobs::accept(&state, admin.id, "audit_log.report", |state| async move {
+        let conn = state.read().get().await.map_err(internal_error)?;
+        let actions = repo::audit_log_stats(&*conn)
+            .await
+            .map_err(internal_error)?;
+        Ok(AuditReport {
+            generated_at: Utc::now(),
+            actions,
+        })
+    })
+    .await
+}
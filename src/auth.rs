@@ -0,0 +1,554 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts, StatusCode},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use chrono::{TimeZone, Utc};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    audit::Audit, config::AuthConfig, db::ConnectionPool, error::internal_error, error_report,
+    refresh, session::Session, throttle::AccountFailures, AppState,
+};
+
+/**
+ * JWT 中携带的声明
+ * jti 是每个 token 唯一的 id，吊销 token 时记录的就是它
+ * impersonator 只在管理员模拟登录时存在，记录的是实际操作的管理员 id
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: i64,
+    pub username: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<i64>,
+}
+
+/**
+ * JWT 签发与校验，使用 HS256 对称密钥
+ */
+#[derive(Clone)]
+pub struct JwtKeys {
+    encoding: Arc<EncodingKey>,
+    decoding: Arc<DecodingKey>,
+    pub access_token_ttl: Duration,
+}
+
+impl JwtKeys {
+    pub fn new(config: &AuthConfig) -> Self {
+        let secret = match &config.jwt_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        JwtKeys {
+            encoding: Arc::new(EncodingKey::from_secret(&secret)),
+            decoding: Arc::new(DecodingKey::from_secret(&secret)),
+            access_token_ttl: config.access_token_ttl,
+        }
+    }
+
+    pub fn issue(
+        &self,
+        user_id: i64,
+        username: &str,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        self.issue_with(user_id, username, None, self.access_token_ttl)
+    }
+
+    pub fn issue_with(
+        &self,
+        user_id: i64,
+        username: &str,
+        impersonator: Option<i64>,
+        ttl: Duration,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now().timestamp() as u64;
+        let claims = Claims {
+            sub: user_id,
+            username: username.to_string(),
+            iat: now,
+            exp: now + ttl.as_secs(),
+            jti: uuid::Uuid::new_v4().to_string(),
+            impersonator,
+        };
+        jsonwebtoken::encode(&Header::default(), &claims, &self.encoding)
+    }
+
+    /**
+     * 校验签名与过期时间，任何一项不通过都返回 None
+     */
+    pub fn decode(&self, token: &str) -> Option<Claims> {
+        jsonwebtoken::decode::<Claims>(token, &self.decoding, &Validation::default())
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+/**
+ * 已吊销 token 的列表，数据保存在 revoked_tokens 表中
+ * 每个请求都查一次数据库代价太高，所以在内存里缓存查询结果，缓存只保留很短的时间，
+ * 其他实例吊销 token 时，通过 invalidation 的消息清掉本实例的缓存，消息丢失时最多延迟 cache_ttl 生效。
+ */
+#[derive(Clone)]
+pub struct RevocationList {
+    cache: Arc<Mutex<HashMap<String, (bool, Instant)>>>,
+    cache_ttl: Duration,
+}
+
+impl RevocationList {
+    pub fn new(config: &AuthConfig) -> Self {
+        RevocationList {
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            cache_ttl: config.revocation_cache_ttl,
+        }
+    }
+
+    pub async fn is_revoked(
+        &self,
+        pool: &ConnectionPool,
+        jti: &str,
+    ) -> Result<bool, (StatusCode, String)> {
+        if let Some((revoked, checked_at)) = self.cache.lock().unwrap().get(jti) {
+            if checked_at.elapsed() < self.cache_ttl {
+                return Ok(*revoked);
+            }
+        }
+
+        let conn = pool.get().await.map_err(internal_error)?;
+        let row = conn
+            .query_opt("SELECT 1 FROM revoked_tokens WHERE jti = $1", &[&jti])
+            .await
+            .map_err(internal_error)?;
+        let revoked = row.is_some();
+
+        let mut cache = self.cache.lock().unwrap();
+        // 顺便清理掉已经过期的缓存项，避免缓存无限增长
+        cache.retain(|_, (_, checked_at)| checked_at.elapsed() < self.cache_ttl);
+        cache.insert(jti.to_string(), (revoked, Instant::now()));
+        Ok(revoked)
+    }
+
+    pub async fn revoke(
+        &self,
+        pool: &ConnectionPool,
+        claims: &Claims,
+    ) -> Result<(), (StatusCode, String)> {
+        let expires_at = Utc.timestamp_opt(claims.exp as i64, 0).unwrap();
+        let conn = pool.get().await.map_err(internal_error)?;
+        conn.execute(
+            "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&claims.jti, &expires_at],
+        )
+        .await
+        .map_err(internal_error)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(claims.jti.clone(), (true, Instant::now()));
+        Ok(())
+    }
+
+    /**
+     * 丢掉某个 token 的缓存，下次检查时重新查询数据库
+     */
+    pub fn forget(&self, jti: &str) {
+        self.cache.lock().unwrap().remove(jti);
+    }
+
+    /**
+     * 过期的 token 本身就无法通过校验，没必要继续保留吊销记录，由调度器定期清理
+     */
+    pub async fn prune(pool: &ConnectionPool) -> Result<u64, (StatusCode, String)> {
+        let conn = pool.get().await.map_err(internal_error)?;
+        conn.execute("DELETE FROM revoked_tokens WHERE expires_at < now()", &[])
+            .await
+            .map_err(internal_error)
+    }
+}
+
+// 浏览器页面登录后，access token 保存在会话的这个 key 下
+pub const SESSION_TOKEN_KEY: &str = "access_token";
+
+/**
+ * 请求携带的 access token：先看 Authorization: Bearer，没有时再用会话里保存的 token
+ * AuthUser 和 Audit 都按这个顺序取 token，两边看到的身份是一致的
+ */
+pub fn request_token(parts: &Parts) -> Option<String> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or_else(|| {
+            parts
+                .extensions
+                .get::<Session>()
+                .and_then(|session| session.get(SESSION_TOKEN_KEY))
+        })
+}
+
+/**
+ * 登录用户提取器
+ * 从 Authorization: Bearer <token> 中解析 JWT，并检查它是否已被吊销
+ * 没有 Authorization 头时，再尝试使用会话中保存的 token，这样浏览器里的管理页面也可以复用同一套鉴权；
+ * 会话 cookie 设置了 SameSite=Lax，跨站的表单 POST 不会带上它
+ */
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: i64,
+    pub username: String,
+    pub claims: Claims,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = || (StatusCode::UNAUTHORIZED, "invalid token".to_string());
+
+        let token = request_token(parts).ok_or_else(unauthorized)?;
+        let claims = state.jwt.decode(&token).ok_or_else(unauthorized)?;
+
+        if state
+            .revocations
+            .is_revoked(&state.pool, &claims.jti)
+            .await?
+        {
+            return Err(unauthorized());
+        }
+
+        // 这个请求之后上报的错误带着用户信息，见 error_report
+        error_report::set_user(claims.sub, &claims.username);
+        Ok(AuthUser {
+            id: claims.sub,
+            username: claims.username.clone(),
+            claims,
+        })
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/register", post(register))
+        .route("/auth/login", post(login))
+        .route("/auth/me", get(me))
+        .route("/oauth/introspect", post(introspect))
+        .route("/oauth/revoke", post(revoke))
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+    #[serde(default)]
+    email: String,
+}
+
+/**
+ * 密码使用 argon2 加盐哈希之后再入库
+ */
+pub fn hash_password(password: &str) -> Result<String, (StatusCode, String)> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(internal_error)
+}
+
+/**
+ * 注册用户
+ */
+async fn register(
+    State(state): State<AppState>,
+    audit: Audit,
+    Json(input): Json<Credentials>,
+) -> Result<(StatusCode, Json<Value>), (StatusCode, String)> {
+    let password_hash = hash_password(&input.password)?;
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let row = conn
+        .query_opt(
+            "INSERT INTO users (username, email, password_hash) VALUES ($1, $2, $3)
+             ON CONFLICT (username) DO NOTHING RETURNING id",
+            &[&input.username, &input.email, &password_hash],
+        )
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::CONFLICT, "username already exists".to_string()))?;
+
+    let id: i64 = row.get(0);
+    audit
+        .record(
+            &state.pool,
+            Some(id),
+            &input.username,
+            "user.create",
+            json!({ "username": input.username, "email": input.email }),
+        )
+        .await;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "id": id, "username": input.username })),
+    ))
+}
+
+/**
+ * 校验用户名和密码，返回用户 id（用户不存在时为 None）以及密码是否正确
+ * 密码错误时也返回用户 id，方便调用方记录审计日志
+ * 校验之前会先检查登录限流，处于退避或锁定状态时直接返回 429，不再校验密码
+ */
+pub async fn verify_credentials(
+    state: &AppState,
+    ip: Option<&str>,
+    username: &str,
+    password: &str,
+) -> Result<(Option<i64>, bool), (StatusCode, String)> {
+    let throttle = &state.login_throttle;
+    throttle.check_ip(ip)?;
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let row = conn
+        .query_opt(
+            "SELECT id, password_hash, failed_logins, last_failed_login_at, locked_until
+             FROM users WHERE username = $1 AND deleted_at IS NULL",
+            &[&username],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let Some(row) = row else {
+        throttle.record_ip_failure(ip);
+        return Ok((None, false));
+    };
+    let id: i64 = row.get(0);
+    throttle.check_account(&AccountFailures {
+        failed_logins: row.get(2),
+        last_failed_login_at: row.get(3),
+        locked_until: row.get(4),
+    })?;
+
+    let password_hash: String = row.get(1);
+    let parsed = PasswordHash::new(&password_hash).map_err(internal_error)?;
+    let verified = Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok();
+
+    if verified {
+        throttle.reset_ip(ip);
+        conn.execute(
+            "UPDATE users SET failed_logins = 0, last_failed_login_at = NULL, locked_until = NULL
+             WHERE id = $1",
+            &[&id],
+        )
+        .await
+        .map_err(internal_error)?;
+    } else {
+        throttle.record_ip_failure(ip);
+        let locked_until = Utc::now() + chrono::Duration::from_std(throttle.lockout).unwrap();
+        // 失败次数达到上限时锁定账号
+        conn.execute(
+            "UPDATE users SET failed_logins = failed_logins + 1, last_failed_login_at = now(),
+                 locked_until = CASE WHEN failed_logins + 1 >= $2 THEN $3 ELSE locked_until END
+             WHERE id = $1",
+            &[&id, &throttle.max_failures, &locked_until],
+        )
+        .await
+        .map_err(internal_error)?;
+    }
+    Ok((Some(id), verified))
+}
+
+async fn login(
+    State(state): State<AppState>,
+    audit: Audit,
+    Json(input): Json<Credentials>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let (actor_id, verified) =
+        verify_credentials(&state, audit.ip(), &input.username, &input.password).await?;
+    // 用户不存在和密码错误返回同样的错误信息，避免被用来探测用户名
+    if !verified {
+        audit
+            .record(
+                &state.pool,
+                actor_id,
+                &input.username,
+                "auth.login_failed",
+                json!({ "username": input.username }),
+            )
+            .await;
+        return Err((StatusCode::UNAUTHORIZED, "invalid credentials".to_string()));
+    }
+    let id = actor_id.unwrap();
+    audit
+        .record(
+            &state.pool,
+            Some(id),
+            &input.username,
+            "auth.login",
+            json!({}),
+        )
+        .await;
+
+    token_response(&state, id, &input.username, None)
+        .await
+        .map(Json)
+}
+
+/**
+ * 签发 access token 和 refresh token，并组装成 OAuth 风格的响应
+ * family 为 None 表示一次新的登录，刷新 token 时传入原来的 family
+ */
+pub async fn token_response(
+    state: &AppState,
+    user_id: i64,
+    username: &str,
+    family: Option<Uuid>,
+) -> Result<Value, (StatusCode, String)> {
+    let token = state.jwt.issue(user_id, username).map_err(internal_error)?;
+    let refresh_token = refresh::issue(state, user_id, family).await?;
+    Ok(json!({
+        "access_token": token,
+        "token_type": "Bearer",
+        "expires_in": state.jwt.access_token_ttl.as_secs(),
+        "refresh_token": refresh_token,
+    }))
+}
+
+/**
+ * 返回当前登录用户，需要在请求头里携带有效的 token
+ */
+async fn me(user: AuthUser) -> Json<Value> {
+    Json(json!({ "id": user.id, "username": user.username, "exp": user.claims.exp }))
+}
+
+/**
+ * RFC 7662 / RFC 7009 的请求参数，使用 application/x-www-form-urlencoded 提交
+ */
+#[derive(Deserialize)]
+struct TokenRequest {
+    token: String,
+    token_type_hint: Option<String>,
+}
+
+/**
+ * Token 自省（RFC 7662）
+ * 调用方本身需要携带有效的 token，返回被查询 token 是否有效以及它的声明
+ * 无效、过期或已吊销的 token 一律只返回 { "active": false }，不透露具体原因
+ */
+async fn introspect(
+    _caller: AuthUser,
+    State(state): State<AppState>,
+    Form(input): Form<TokenRequest>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let Some(claims) = state.jwt.decode(&input.token) else {
+        return Ok(Json(json!({ "active": false })));
+    };
+    if state
+        .revocations
+        .is_revoked(&state.pool, &claims.jti)
+        .await?
+    {
+        return Ok(Json(json!({ "active": false })));
+    }
+
+    Ok(Json(json!({
+        "active": true,
+        "token_type": "Bearer",
+        "sub": claims.sub.to_string(),
+        "username": claims.username,
+        "iat": claims.iat,
+        "exp": claims.exp,
+        "jti": claims.jti,
+    })))
+}
+
+/**
+ * Token 吊销（RFC 7009）
+ * 可以吊销 access token（记录 jti）和 refresh token（吊销它所在的整个 family，之后轮换出来的也一起失效）。
+ * token_type_hint 只决定先按哪一种查找，找不到时再按另一种查找，和规范的要求一致。
+ * 按规范，无论传入的 token 是否有效都返回 200，避免调用方借此探测 token
+ */
+async fn revoke(
+    State(state): State<AppState>,
+    audit: Audit,
+    Form(input): Form<TokenRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let refresh_first = input.token_type_hint.as_deref() == Some("refresh_token");
+    if refresh_first {
+        if !revoke_refresh_token(&state, &audit, &input.token).await? {
+            revoke_access_token(&state, &audit, &input.token).await?;
+        }
+    } else if !revoke_access_token(&state, &audit, &input.token).await? {
+        revoke_refresh_token(&state, &audit, &input.token).await?;
+    }
+    Ok(StatusCode::OK)
+}
+
+async fn revoke_access_token(
+    state: &AppState,
+    audit: &Audit,
+    token: &str,
+) -> Result<bool, (StatusCode, String)> {
+    let Some(claims) = state.jwt.decode(token) else {
+        return Ok(false);
+    };
+    state.revocations.revoke(&state.pool, &claims).await?;
+    audit
+        .record(
+            &state.pool,
+            Some(claims.sub),
+            &claims.username,
+            "auth.revoke",
+            json!({ "jti": claims.jti }),
+        )
+        .await;
+    Ok(true)
+}
+
+async fn revoke_refresh_token(
+    state: &AppState,
+    audit: &Audit,
+    token: &str,
+) -> Result<bool, (StatusCode, String)> {
+    let Some((family, user_id, username)) = refresh::revoke_token(&state.pool, token).await? else {
+        return Ok(false);
+    };
+    audit
+        .record(
+            &state.pool,
+            Some(user_id),
+            &username,
+            "auth.revoke",
+            json!({ "family_id": family }),
+        )
+        .await;
+    Ok(true)
+}
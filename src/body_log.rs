@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+
+use crate::config::BodyLogConfig;
+
+/*
+ * 请求体和响应体日志，排查问题时临时打开（BODY_LOG=true），默认关闭
+ * 每个请求打印两条日志（target 是 body_log），分别是请求体和响应体：
+ * - 只记录长度已知、不超过 BODY_LOG_MAX_BYTES 的 JSON、表单和文本，
+ *   更大的、长度未知的（比如 SSE 和文件下载）和二进制的只记录类型和长度，不会为了打日志把整个响应读进内存
+ * - JSON 和表单里字段名包含 BODY_LOG_REDACT 中任意一项（不区分大小写，默认 password、token、secret 等）的字段，
+ *   值替换成 [REDACTED]；HTML 和纯文本里看起来像邮箱地址的内容也会被替换
+ * 放在 compression 里面，记录的是压缩之前的内容；在 TraceLayer 的 span 里，日志带有 request_id。
+ */
+
+const REDACTED: &str = "[REDACTED]";
+
+enum Kind {
+    Json,
+    Form,
+    Text,
+    Other,
+}
+
+fn kind(headers: &HeaderMap) -> Kind {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("application/json") {
+        Kind::Json
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        Kind::Form
+    } else if content_type.starts_with("text/") && !content_type.starts_with("text/event-stream") {
+        Kind::Text
+    } else {
+        Kind::Other
+    }
+}
+
+/**
+ * 把看起来像邮箱地址的片段（local@domain.tld）替换成 [REDACTED]
+ */
+fn mask_emails(text: &str) -> String {
+    let is_part = |c: char| c.is_ascii_alphanumeric() || "._%+-".contains(c);
+    let chars: Vec<char> = text.chars().collect();
+    let mut masked = String::with_capacity(text.len());
+    let mut index = 0;
+    while index < chars.len() {
+        if chars[index] == '@' {
+            let local = masked.chars().rev().take_while(|&c| is_part(c)).count();
+            let domain = chars[index + 1..]
+                .iter()
+                .take_while(|&&c| is_part(c))
+                .count();
+            let domain_text: String = chars[index + 1..index + 1 + domain].iter().collect();
+            if local > 0 && domain_text.trim_end_matches('.').contains('.') {
+                for _ in 0..local {
+                    masked.pop();
+                }
+                masked.push_str(REDACTED);
+                index += 1 + domain;
+                continue;
+            }
+        }
+        masked.push(chars[index]);
+        index += 1;
+    }
+    masked
+}
+
+struct Redactor<'a> {
+    fields: &'a [String],
+}
+
+impl Redactor<'_> {
+    fn sensitive(&self, key: &str) -> bool {
+        let key = key.to_lowercase();
+        self.fields.iter().any(|field| key.contains(field.as_str()))
+    }
+
+    fn json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.sensitive(key) {
+                        *value = Value::String(REDACTED.to_string());
+                    } else {
+                        self.json(value);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.json(item)),
+            Value::String(text) => *text = mask_emails(text),
+            _ => {}
+        }
+    }
+
+    fn form(&self, body: &[u8]) -> String {
+        form_urlencoded::parse(body)
+            .map(|(key, value)| {
+                let value = if self.sensitive(&key) {
+                    REDACTED.to_string()
+                } else {
+                    mask_emails(&value)
+                };
+                format!("{}={}", key, value)
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /**
+     * 脱敏之后可以写进日志的内容
+     */
+    fn body(&self, kind: &Kind, body: &[u8]) -> String {
+        match kind {
+            Kind::Json => match serde_json::from_slice::<Value>(body) {
+                Ok(mut value) => {
+                    self.json(&mut value);
+                    value.to_string()
+                }
+                Err(_) => format!("[invalid JSON, {} bytes]", body.len()),
+            },
+            Kind::Form => self.form(body),
+            Kind::Text => mask_emails(&String::from_utf8_lossy(body)),
+            Kind::Other => format!("[{} bytes]", body.len()),
+        }
+    }
+}
+
+/**
+ * 长度已知并且不超过上限时读出整个 body，否则原样返回，只记录长度
+ */
+async fn buffer(
+    body: Body,
+    headers: &HeaderMap,
+    config: &BodyLogConfig,
+) -> (Body, Result<Bytes, String>) {
+    if body.is_end_stream() {
+        return (Body::empty(), Ok(Bytes::new()));
+    }
+    if matches!(kind(headers), Kind::Other) {
+        let size = body.size_hint().exact();
+        let description = match size {
+            Some(size) => format!("[{} bytes]", size),
+            None => "[streamed]".to_string(),
+        };
+        return (body, Err(description));
+    }
+    match body.size_hint().exact() {
+        Some(size) if size <= config.max_bytes as u64 => {
+            match to_bytes(body, config.max_bytes).await {
+                Ok(bytes) => (Body::from(bytes.clone()), Ok(bytes)),
+                Err(err) => (Body::empty(), Err(format!("[read failed: {}]", err))),
+            }
+        }
+        Some(size) => (body, Err(format!("[{} bytes, not logged]", size))),
+        None => (body, Err("[streamed, not logged]".to_string())),
+    }
+}
+
+pub async fn log_bodies(
+    State(config): State<Arc<BodyLogConfig>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !config.enabled {
+        return next.run(req).await;
+    }
+    let redactor = Redactor {
+        fields: &config.redact,
+    };
+
+    let (parts, body) = req.into_parts();
+    let (body, logged) = buffer(body, &parts.headers, &config).await;
+    let request_body = logged
+        .map(|bytes| redactor.body(&kind(&parts.headers), &bytes))
+        .unwrap_or_else(|description| description);
+    tracing::info!(
+        target: "body_log",
+        method = %parts.method,
+        path = %parts.uri.path(),
+        body = %request_body,
+        "request body"
+    );
+    let res = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = res.into_parts();
+    let (body, logged) = buffer(body, &parts.headers, &config).await;
+    let response_body = logged
+        .map(|bytes| redactor.body(&kind(&parts.headers), &bytes))
+        .unwrap_or_else(|description| description);
+    tracing::info!(
+        target: "body_log",
+        status = parts.status.as_u16(),
+        body = %response_body,
+        "response body"
+    );
+    Response::from_parts(parts, body)
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::BytesRejection, FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    error::{internal_error, json_error},
+    AppState, Input,
+};
+
+/*
+ * CBOR（RFC 8949）格式的请求和响应，Content-Type: application/cbor，用法和 Json、MsgPack 一样：
+ *   async fn handler(Cbor(input): Cbor<Input>) -> Cbor<Output>
+ * CBOR 和 MessagePack 类似，也是二进制的 JSON，但是有 IETF 标准，CoAP、COSE、WebAuthn 这些物联网和安全相关的协议都用它，
+ * 嵌入式设备上的库比较多，所以 IoT 设备一般选 CBOR。
+ * 用的是 ciborium，结构体和 JSON 接口共用，字段名会写进数据里。
+ * POST /cbor 是一个示例接口，把收到的 Input 原样返回。
+ * 解析失败时返回 CborRejection：
+ * - MissingCborContentType  没有 Content-Type: application/cbor（或者带 +cbor 后缀的类型），415
+ * - CborError               不是合法的 CBOR，或者和目标类型对不上，400
+ * - BytesRejection          读取请求体失败，比如超出大小限制
+ */
+
+const CBOR: &str = "application/cbor";
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/cbor", post(echo))
+}
+
+pub struct Cbor<T>(pub T);
+
+#[derive(Debug)]
+pub enum CborRejection {
+    MissingCborContentType,
+    CborError(ciborium::de::Error<std::io::Error>),
+    BytesRejection(BytesRejection),
+}
+
+impl fmt::Display for CborRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CborRejection::MissingCborContentType => {
+                f.write_str("expected request with `Content-Type: application/cbor`")
+            }
+            // ciborium 的错误只实现了 Debug 格式的输出，类型不对时取出里面的消息
+            CborRejection::CborError(ciborium::de::Error::Semantic(_, message)) => {
+                write!(f, "failed to parse the CBOR body: {}", message)
+            }
+            CborRejection::CborError(err) => write!(f, "failed to parse the CBOR body: {}", err),
+            CborRejection::BytesRejection(err) => err.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for CborRejection {
+    fn into_response(self) -> Response {
+        let status = match self {
+            CborRejection::MissingCborContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            CborRejection::CborError(_) => StatusCode::BAD_REQUEST,
+            CborRejection::BytesRejection(err) => return err.into_response(),
+        };
+        json_error(status, &self.to_string())
+    }
+}
+
+/**
+ * application/cbor，以及 application/senml+cbor 这种带 +cbor 后缀的类型
+ */
+fn is_cbor_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == CBOR || mime.ends_with("+cbor")
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Cbor<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = CborRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_cbor_content_type(req.headers()) {
+            return Err(CborRejection::MissingCborContentType);
+        }
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(CborRejection::BytesRejection)?;
+        ciborium::from_reader(&*bytes)
+            .map(Cbor)
+            .map_err(CborRejection::CborError)
+    }
+}
+
+impl<T> IntoResponse for Cbor<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut body = Vec::new();
+        if let Err(err) = ciborium::into_writer(&self.0, &mut body) {
+            return internal_error(err).into_response();
+        }
+        (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(CBOR))],
+            body,
+        )
+            .into_response()
+    }
+}
+
+async fn echo(Cbor(input): Cbor<Input>) -> Cbor<Input> {
+    tracing::debug!("cbor params {:?}", input);
+    Cbor(input)
+}
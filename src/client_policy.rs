@@ -0,0 +1,180 @@
+use std::sync::{Arc, RwLock};
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    db::ConnectionPool,
+    error::{internal_error, json_error},
+    permissions::{Authorize, ConfigRead},
+    AppState,
+};
+
+/*
+ * 按 User-Agent 区分客户端（浏览器、爬虫、SDK 等），不同类别使用不同的策略
+ * - rate_limit：接口配额，见 quota，比如给爬虫更低的配额，控制它们的抓取速度
+ * - cache_max_age：GET 响应的默认缓存时间，让爬虫少重复抓取没有变化的页面
+ * - denied_paths：不开放给这一类客户端的功能，比如爬虫不需要访问同步、推送和登录接口
+ * 策略保存在 client_policies 表里（见 V12 迁移），后台任务每隔 CLIENT_POLICY_RELOAD_SECS 秒重新加载一次，
+ * 修改表之后不需要重启；加载失败（比如数据库暂时不可用）时继续使用上一次加载的策略。
+ * 没有匹配到任何一类的客户端使用默认配置，不做限制。
+ * User-Agent 是客户端自己填的，伪装成浏览器就能绕过爬虫的策略，所以这里只用来引导守规矩的客户端，不是安全边界。
+ * GET /admin/client_policies 查看当前生效的策略，加上 ?user_agent= 可以看某个 User-Agent 会被分到哪一类，需要 config:read 权限。
+ */
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientPolicy {
+    pub class: String,
+    // 小写的 User-Agent 片段
+    pub user_agent_patterns: Vec<String>,
+    pub rate_limit: Option<u32>,
+    pub cache_max_age: Option<u32>,
+    pub denied_paths: Vec<String>,
+}
+
+impl ClientPolicy {
+    fn matches(&self, user_agent: &str) -> bool {
+        self.user_agent_patterns
+            .iter()
+            .any(|pattern| user_agent.contains(pattern.as_str()))
+    }
+
+    fn denies(&self, path: &str) -> bool {
+        self.denied_paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/**
+ * 当前生效的策略，按 priority 排好序
+ */
+#[derive(Clone, Default)]
+pub struct ClientPolicies {
+    policies: Arc<RwLock<Arc<Vec<Arc<ClientPolicy>>>>>,
+}
+
+impl ClientPolicies {
+    /**
+     * 从数据库重新加载，失败时保留原来的策略
+     */
+    pub async fn reload(&self, pool: &ConnectionPool) -> Result<(), (StatusCode, String)> {
+        let conn = pool.get().await.map_err(internal_error)?;
+        let rows = conn
+            .query(
+                "SELECT class, user_agent_patterns, rate_limit, cache_max_age, denied_paths
+                 FROM client_policies ORDER BY priority, class",
+                &[],
+            )
+            .await
+            .map_err(internal_error)?;
+        let policies = rows
+            .iter()
+            .map(|row| {
+                let patterns: Vec<String> = row.get(1);
+                let rate_limit: Option<i32> = row.get(2);
+                let cache_max_age: Option<i32> = row.get(3);
+                Arc::new(ClientPolicy {
+                    class: row.get(0),
+                    user_agent_patterns: patterns
+                        .iter()
+                        .map(|pattern| pattern.to_lowercase())
+                        .filter(|pattern| !pattern.is_empty())
+                        .collect(),
+                    rate_limit: rate_limit.map(|limit| limit as u32),
+                    cache_max_age: cache_max_age.map(|age| age as u32),
+                    denied_paths: row.get(4),
+                })
+            })
+            .collect();
+        *self.policies.write().unwrap() = Arc::new(policies);
+        Ok(())
+    }
+
+    fn all(&self) -> Arc<Vec<Arc<ClientPolicy>>> {
+        self.policies.read().unwrap().clone()
+    }
+
+    /**
+     * User-Agent 属于哪一类，没有匹配到时返回 None
+     */
+    pub fn classify(&self, user_agent: &str) -> Option<Arc<ClientPolicy>> {
+        let user_agent = user_agent.to_lowercase();
+        self.all()
+            .iter()
+            .find(|policy| policy.matches(&user_agent))
+            .cloned()
+    }
+}
+
+/**
+ * 客户端分类中间件，需要放在 quota 外面：分到的策略放进请求的 extensions，quota 从中读取配额
+ */
+pub async fn apply(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let Some(policy) = state.client_policies.classify(user_agent) else {
+        return next.run(req).await;
+    };
+    if policy.denies(req.uri().path()) {
+        return json_error(
+            StatusCode::FORBIDDEN,
+            &format!("not available to {} clients", policy.class),
+        );
+    }
+
+    let cacheable = req.method() == Method::GET;
+    req.extensions_mut().insert(policy.clone());
+    let mut res = next.run(req).await;
+    if let Some(max_age) = policy.cache_max_age {
+        if cacheable
+            && res.status() == StatusCode::OK
+            && !res.headers().contains_key(header::CACHE_CONTROL)
+        {
+            res.headers_mut().insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_str(&format!("max-age={}", max_age)).unwrap(),
+            );
+        }
+    }
+    res
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/client_policies", get(list))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    user_agent: Option<String>,
+}
+
+async fn list(
+    _auth: Authorize<ConfigRead>,
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> Json<Value> {
+    let policies = state.client_policies.all();
+    let policies: Vec<&ClientPolicy> = policies.iter().map(|policy| &**policy).collect();
+    let mut body = json!({ "policies": policies });
+    if let Some(user_agent) = query.user_agent {
+        let class = state
+            .client_policies
+            .classify(&user_agent)
+            .map(|policy| policy.class.clone());
+        body["user_agent"] = json!(user_agent);
+        body["class"] = json!(class);
+    }
+    Json(body)
+}
@@ -0,0 +1,377 @@
+use std::sync::{Arc, RwLock};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{MatchedPath, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    client_policy::ClientPolicy,
+    db::ConnectionPool,
+    error::{internal_error, json_error},
+    permissions::{Authorize, ConfigRead},
+    AppState,
+};
+
+/*
+ * 给老版本客户端做兼容：接口改了字段名、补了必填字段、调整了格式之后，已经发出去的客户端和第三方集成不用跟着改
+ * 规则保存在 compat_rules 表里（见 V14 迁移），按客户端类别（client_policy 按 User-Agent 分出来的 class）和路由生效：
+ * - 请求：在 handler 之前改 JSON 请求体和请求头，比如把老字段名改成新的、补上新增字段的默认值
+ * - 响应：在 handler 之后改 JSON 响应体和响应头，比如把新字段名改回老的、把数字 id 改成字符串、把时间改成时间戳
+ * handler 只需要处理现在的格式，兼容的逻辑都在这里。
+ * 规则和 client_policies 一起每隔 CLIENT_POLICY_RELOAD_SECS 秒重新加载，加载失败时继续使用上一次的规则。
+ * 只转换 Content-Type 为 application/json 的请求体和响应体，转换过的响应带上 Vary: User-Agent。
+ * GET /admin/compat_rules?class= 查看当前生效的规则，需要 config:read 权限。
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Body,
+    Header,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", content = "value", rename_all = "snake_case")]
+pub enum Action {
+    // 改名为这个名字
+    Rename(String),
+    // 字段不存在时补上这个值
+    Default(Value),
+    // 数字改成字符串
+    Stringify,
+    // RFC 3339 格式的时间改成 Unix 时间戳（秒）
+    UnixTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompatRule {
+    pub client_class: String,
+    // 为空时对所有路由生效
+    pub route: Option<String>,
+    pub direction: Direction,
+    pub target: Target,
+    pub field: String,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+impl CompatRule {
+    fn new(
+        client_class: String,
+        route: Option<String>,
+        direction: &str,
+        target: &str,
+        action: &str,
+        field: String,
+        value: Option<Value>,
+    ) -> Result<Self, String> {
+        let direction = match direction {
+            "request" => Direction::Request,
+            "response" => Direction::Response,
+            other => return Err(format!("unknown direction {:?}", other)),
+        };
+        let target = match target {
+            "body" => Target::Body,
+            "header" => Target::Header,
+            other => return Err(format!("unknown target {:?}", other)),
+        };
+        let action = match (action, value) {
+            ("rename", Some(Value::String(to))) => Action::Rename(to),
+            ("default", Some(value)) => Action::Default(value),
+            ("stringify", _) => Action::Stringify,
+            ("unix_time", _) => Action::UnixTime,
+            (action, _) => return Err(format!("{} of {:?}: invalid value", action, field)),
+        };
+        if target == Target::Header {
+            let valid = match &action {
+                Action::Rename(to) => HeaderName::from_bytes(to.as_bytes()).is_ok(),
+                Action::Default(Value::String(value)) => HeaderValue::from_str(value).is_ok(),
+                _ => false,
+            };
+            if !valid || HeaderName::from_bytes(field.as_bytes()).is_err() {
+                return Err(format!("header {:?}: invalid name or value", field));
+            }
+        }
+        Ok(CompatRule {
+            client_class,
+            route,
+            direction,
+            target,
+            field,
+            action,
+        })
+    }
+
+    fn matches(&self, class: &str, route: Option<&str>) -> bool {
+        self.client_class == class
+            && self
+                .route
+                .as_deref()
+                .is_none_or(|expected| Some(expected) == route)
+    }
+}
+
+/**
+ * 按 . 分隔的路径找到字段所在的对象，对最后一段执行转换；路过的数组对每个元素都执行一次
+ */
+fn transform_value(value: &mut Value, path: &[&str], action: &Action) {
+    if let Value::Array(items) = value {
+        for item in items {
+            transform_value(item, path, action);
+        }
+        return;
+    }
+    let (Some(object), Some((field, rest))) = (value.as_object_mut(), path.split_first()) else {
+        return;
+    };
+    if !rest.is_empty() {
+        if let Some(child) = object.get_mut(*field) {
+            transform_value(child, rest, action);
+        }
+        return;
+    }
+    match action {
+        Action::Rename(to) => {
+            if let Some(value) = object.remove(*field) {
+                object.insert(to.clone(), value);
+            }
+        }
+        Action::Default(default) => {
+            object
+                .entry(field.to_string())
+                .or_insert_with(|| default.clone());
+        }
+        Action::Stringify => {
+            if let Some(value @ Value::Number(_)) = object.get_mut(*field) {
+                *value = Value::String(value.to_string());
+            }
+        }
+        Action::UnixTime => {
+            if let Some(value) = object.get_mut(*field) {
+                let timestamp = value
+                    .as_str()
+                    .and_then(|time| DateTime::parse_from_rfc3339(time).ok());
+                if let Some(timestamp) = timestamp {
+                    *value = Value::from(timestamp.timestamp());
+                }
+            }
+        }
+    }
+}
+
+fn transform_headers(headers: &mut HeaderMap, rules: &[&CompatRule]) {
+    for rule in rules.iter().filter(|rule| rule.target == Target::Header) {
+        // 名字和值都在加载时检查过
+        let Ok(name) = HeaderName::from_bytes(rule.field.as_bytes()) else {
+            continue;
+        };
+        match &rule.action {
+            Action::Rename(to) => {
+                let Ok(to) = HeaderName::from_bytes(to.as_bytes()) else {
+                    continue;
+                };
+                if headers.contains_key(&to) {
+                    continue;
+                }
+                let values: Vec<HeaderValue> = headers.get_all(&name).iter().cloned().collect();
+                headers.remove(&name);
+                for value in values {
+                    headers.append(to.clone(), value);
+                }
+            }
+            Action::Default(Value::String(value)) => {
+                if let (false, Ok(value)) = (headers.contains_key(&name), value.parse()) {
+                    headers.insert(name, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+/**
+ * 转换 JSON 请求体或响应体，不是合法的 JSON 时原样返回，交给 handler 或者客户端报错
+ */
+fn transform_body(bytes: &[u8], rules: &[&CompatRule]) -> Option<Vec<u8>> {
+    let mut value: Value = serde_json::from_slice(bytes).ok()?;
+    for rule in rules.iter().filter(|rule| rule.target == Target::Body) {
+        let path: Vec<&str> = rule.field.split('.').collect();
+        transform_value(&mut value, &path, &rule.action);
+    }
+    serde_json::to_vec(&value).ok()
+}
+
+/**
+ * 当前生效的规则，按 priority 排好序
+ */
+#[derive(Clone, Default)]
+pub struct CompatRules {
+    rules: Arc<RwLock<Arc<Vec<CompatRule>>>>,
+}
+
+impl CompatRules {
+    /**
+     * 从数据库重新加载，失败时保留原来的规则
+     */
+    pub async fn reload(&self, pool: &ConnectionPool) -> Result<(), (StatusCode, String)> {
+        let conn = pool.get().await.map_err(internal_error)?;
+        let rows = conn
+            .query(
+                "SELECT client_class, route, direction, target, action, field, value
+                 FROM compat_rules ORDER BY priority, id",
+                &[],
+            )
+            .await
+            .map_err(internal_error)?;
+        let mut rules = Vec::new();
+        for row in rows {
+            let direction: String = row.get(2);
+            let target: String = row.get(3);
+            let action: String = row.get(4);
+            // 表上的约束已经保证了大部分格式，这里只会因为请求头的名字或者值不合法而失败，跳过这一条
+            match CompatRule::new(
+                row.get(0),
+                row.get(1),
+                &direction,
+                &target,
+                &action,
+                row.get(5),
+                row.get(6),
+            ) {
+                Ok(rule) => rules.push(rule),
+                Err(err) => tracing::warn!("skipped compat rule: {}", err),
+            }
+        }
+        *self.rules.write().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    fn all(&self) -> Arc<Vec<CompatRule>> {
+        self.rules.read().unwrap().clone()
+    }
+}
+
+/**
+ * 兼容转换中间件，需要放在 client_policy::apply 里面，从 extensions 里拿到客户端类别
+ * 放在 Router 的 layer 里，已经匹配到路由，可以按 MatchedPath 选择规则
+ */
+pub async fn apply(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(policy) = req.extensions().get::<Arc<ClientPolicy>>().cloned() else {
+        return next.run(req).await;
+    };
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let all = state.compat_rules.all();
+    let rules: Vec<&CompatRule> = all
+        .iter()
+        .filter(|rule| rule.matches(&policy.class, route.as_deref()))
+        .collect();
+    if rules.is_empty() {
+        return next.run(req).await;
+    }
+    let (request_rules, response_rules): (Vec<&CompatRule>, Vec<&CompatRule>) = rules
+        .into_iter()
+        .partition(|rule| rule.direction == Direction::Request);
+
+    let (mut parts, body) = req.into_parts();
+    transform_headers(&mut parts.headers, &request_rules);
+    let body = if is_json(&parts.headers)
+        && request_rules.iter().any(|rule| rule.target == Target::Body)
+    {
+        // 各个路由自己的大小限制在里层，这里按最大的上传限制先读出来，转换之后仍然会经过路由的限制
+        let Ok(bytes) = to_bytes(body, state.config.body_limit.upload).await else {
+            return json_error(StatusCode::PAYLOAD_TOO_LARGE, "request body too large");
+        };
+        match transform_body(&bytes, &request_rules) {
+            Some(transformed) => {
+                parts
+                    .headers
+                    .insert(header::CONTENT_LENGTH, HeaderValue::from(transformed.len()));
+                Body::from(transformed)
+            }
+            None => Body::from(bytes),
+        }
+    } else {
+        body
+    };
+
+    let res = next.run(Request::from_parts(parts, body)).await;
+    if response_rules.is_empty() {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    transform_headers(&mut parts.headers, &response_rules);
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("user-agent"));
+    if !is_json(&parts.headers)
+        || !response_rules
+            .iter()
+            .any(|rule| rule.target == Target::Body)
+    {
+        return Response::from_parts(parts, body);
+    }
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    match transform_body(&bytes, &response_rules) {
+        Some(transformed) => {
+            // body 长度变了，需要去掉原来的 Content-Length
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(transformed))
+        }
+        None => Response::from_parts(parts, Body::from(bytes)),
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/compat_rules", get(list))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    class: Option<String>,
+}
+
+async fn list(
+    _auth: Authorize<ConfigRead>,
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> Json<Value> {
+    let rules = state.compat_rules.all();
+    let rules: Vec<&CompatRule> = rules
+        .iter()
+        .filter(|rule| {
+            query
+                .class
+                .as_ref()
+                .is_none_or(|class| &rule.client_class == class)
+        })
+        .collect();
+    Json(json!({ "rules": rules }))
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Extensions, HeaderMap, HeaderValue, StatusCode, Version},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::compression::{
+    predicate::{Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+use crate::config::CompressionConfig;
+
+/*
+ * 响应压缩：按请求的 Accept-Encoding 用 brotli 或者 gzip 压缩响应体，带上 Content-Encoding，可能压缩的响应都带上 Vary: Accept-Encoding
+ * - COMPRESSION_MIN_BYTES  小于这个大小的响应不压缩，压缩省下的流量抵不上 CPU 的开销；长度未知的流式响应总是压缩
+ * - COMPRESSION_TYPES      只压缩这些 Content-Type（按前缀匹配），图片、压缩包这类已经压缩过的格式再压缩没有意义
+ * SSE（text/event-stream）不能压缩，压缩会把事件攒在缓冲区里，浏览器收不到实时的推送，加载配置时会拒绝。
+ * 已经带有 Content-Encoding 的响应（比如 assets 返回的预压缩文件）原样返回。
+ * 这一层放在 request_id::annotate_errors 外面，错误响应体改完之后再压缩；降级缓存（degraded）保存的是压缩之前的内容。
+ */
+
+/**
+ * Content-Type 是否在 COMPRESSION_TYPES 里（按前缀匹配）
+ */
+fn compressible(content_types: &[String], headers: &HeaderMap) -> bool {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    content_types
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix.as_str()))
+}
+
+pub fn layer(config: &CompressionConfig) -> CompressionLayer<impl Predicate> {
+    let content_types = Arc::new(config.content_types.clone());
+    let matches_type = move |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+        compressible(&content_types, headers)
+    };
+    CompressionLayer::new().compress_when(SizeAbove::new(config.min_size).and(matches_type))
+}
+
+/**
+ * 可能被压缩的响应带上 Vary: Accept-Encoding，CDN 和浏览器缓存按 Accept-Encoding 分开保存，
+ * 不会把 brotli 的内容返回给不支持的客户端（tower-http 0.5 的 CompressionLayer 不会加这个响应头）
+ * 放在 layer 里面，看到的是压缩之前的响应
+ */
+pub async fn vary(
+    State(config): State<Arc<CompressionConfig>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut res = next.run(req).await;
+    if compressible(&config.content_types, res.headers()) {
+        res.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    res
+}
@@ -0,0 +1,102 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{db, session, AppState};
+
+/*
+ * 读己之写（read-your-writes）
+ * 只读查询默认发到只读副本，副本的数据比主库稍晚一点，用户刚创建的数据在下一个请求里可能还查不到。
+ * - 配置了只读副本时，成功的写请求（非 GET/HEAD 的 2xx/3xx）在响应里带上一致性令牌：
+ *   事务提交之后主库的 WAL 位置（LSN），形如 16/B374D848，
+ *   放在 X-Consistency-Token 响应头里，同时写一个 consistency_token cookie（有效期 CONSISTENCY_TOKEN_TTL_SECS 秒）
+ * - 之后的请求通过 X-Consistency-Token 请求头或者 cookie 带回令牌，这个请求里的只读查询（AppState::read）
+ *   只使用已经回放到这个位置的副本，都还没追上时改用主库；副本追上之后令牌自然失效，不需要客户端清除
+ * 副本回放到的位置每隔 DB_REPLICA_LSN_POLL_MS 毫秒在后台查询一次（见 db::Replicas），选择副本时不需要额外的查询。
+ * 浏览器里的表单提交之后重定向到的页面通过 cookie 也能读到刚写入的数据；API 客户端需要自己保存并带上请求头。
+ * 放在 db::transaction_layer 外面，拿到的是请求级事务提交之后的位置。
+ */
+
+pub const X_CONSISTENCY_TOKEN: HeaderName = HeaderName::from_static("x-consistency-token");
+const COOKIE_NAME: &str = "consistency_token";
+
+tokio::task_local! {
+    // 当前请求要求副本至少回放到的 LSN
+    static REQUIRED_LSN: Option<u64>;
+}
+
+/**
+ * 当前请求的令牌要求的 LSN，请求之外（比如后台任务）返回 None
+ */
+pub fn required_lsn() -> Option<u64> {
+    REQUIRED_LSN.try_with(|lsn| *lsn).ok().flatten()
+}
+
+/**
+ * 按 Postgres pg_lsn 的格式输出，高 32 位和低 32 位分别写成十六进制
+ */
+fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+fn parse_lsn(token: &str) -> Option<u64> {
+    let (high, low) = token.trim().split_once('/')?;
+    let high = u32::from_str_radix(high, 16).ok()?;
+    let low = u32::from_str_radix(low, 16).ok()?;
+    Some((u64::from(high) << 32) | u64::from(low))
+}
+
+/**
+ * 请求头和 cookie 里的令牌，都有时取较新的那个，格式不对的忽略
+ */
+fn request_token(req: &Request) -> Option<u64> {
+    let header = req
+        .headers()
+        .get(&X_CONSISTENCY_TOKEN)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_lsn);
+    let cookie = session::cookie_value(req.headers(), COOKIE_NAME).and_then(parse_lsn);
+    header.max(cookie)
+}
+
+pub async fn apply(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if state.replicas.all().is_empty() {
+        return next.run(req).await;
+    }
+    let write = req.method() != Method::GET && req.method() != Method::HEAD;
+    let required = request_token(&req);
+    let mut res = REQUIRED_LSN.scope(required, next.run(req)).await;
+    if !write || !(res.status().is_success() || res.status().is_redirection()) {
+        return res;
+    }
+
+    let lsn = match state.write().get().await {
+        Ok(conn) => db::current_lsn(&conn).await,
+        Err(err) => {
+            tracing::warn!("get consistency token failed: {}", err);
+            return res;
+        }
+    };
+    match lsn {
+        Ok(lsn) => {
+            let token = format_lsn(lsn);
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                COOKIE_NAME,
+                token,
+                state.config.database.consistency_token_ttl.as_secs()
+            );
+            if let Ok(value) = HeaderValue::from_str(&token) {
+                res.headers_mut().insert(X_CONSISTENCY_TOKEN, value);
+            }
+            if let Ok(value) = HeaderValue::from_str(&cookie) {
+                res.headers_mut().append(header::SET_COOKIE, value);
+            }
+        }
+        Err(err) => tracing::warn!("get consistency token failed: {}", err),
+    }
+    res
+}
@@ -0,0 +1,425 @@
+use askama::Template;
+use axum::{http::StatusCode, response::Html, routing::get, Router};
+
+use crate::{admin::AdminPage, error::internal_error, permissions::ConsoleUse, AppState};
+
+/*
+ * 接口调试页面
+ * 列出常用的 JSON 接口，点击后自动填好请求方法、路径和示例请求体，在页面里直接发送请求并查看响应，
+ * 开发时不需要再借助 curl 或者 Postman。
+ * 页面和发出的请求都会带上会话 cookie，登录 /admin/login 之后调用需要登录的接口不用手动填写 token；
+ * 也可以在请求头里自己填写 Authorization 以其他身份调用。需要 console:use 权限。
+ */
+
+/**
+ * 接口清单
+ * axum 的 Router 没有提供列出已注册路由的方法，新增接口时需要同步在这里登记
+ */
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    description: &'static str,
+    body: &'static str,
+}
+
+const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: "POST",
+        path: "/auth/register",
+        description: "Register a user",
+        body: r#"{"username": "", "password": "", "email": ""}"#,
+    },
+    Endpoint {
+        method: "POST",
+        path: "/auth/login",
+        description: "Log in and get an access token and refresh token",
+        body: r#"{"username": "", "password": ""}"#,
+    },
+    Endpoint {
+        method: "GET",
+        path: "/auth/me",
+        description: "Current user",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/auth/refresh",
+        description: "Rotate a refresh token",
+        body: r#"{"refresh_token": ""}"#,
+    },
+    Endpoint {
+        method: "POST",
+        path: "/auth/logout",
+        description: "Revoke a refresh token family",
+        body: r#"{"refresh_token": ""}"#,
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/users",
+        description: "List users, supports ?page, ?per_page, ?include_deleted=true, ?filter[role]=admin, ?sort=-created_at and ?modified_since= / If-Modified-Since",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/users",
+        description: "Create a user",
+        body: r#"{"username": "", "password": "", "email": "", "role": "user"}"#,
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/users.csv",
+        description: "Export users as CSV, same filters as the list without pagination",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/users.xlsx",
+        description: "Export users as an Excel spreadsheet, same filters as the CSV export",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/users.ndjson",
+        description: "Stream users as newline-delimited JSON, same filters as the CSV export",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/users/:id",
+        description: "Show a user",
+        body: "",
+    },
+    Endpoint {
+        method: "PUT",
+        path: "/api/users/:id",
+        description: "Update a user, version must match the ETag of the user",
+        body: r#"{"username": "", "email": "", "role": "user", "version": 1}"#,
+    },
+    Endpoint {
+        method: "DELETE",
+        path: "/api/users/:id",
+        description: "Delete a user (soft delete)",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/users/:id/restore",
+        description: "Restore a deleted user",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/users/bulk",
+        description: "Create, update and delete users in one request",
+        body: r#"{"atomic": false, "operations": [{"op": "delete", "id": 0}]}"#,
+    },
+    Endpoint {
+        method: "GET",
+        path: "/todos.xlsx",
+        description: "Export todos as an Excel spreadsheet, same filters as the todos page",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/todos.ndjson",
+        description: "Stream todos as newline-delimited JSON, same filters as the todos page",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/todos/:id/report.pdf?download=true",
+        description: "Render one todo as a PDF report",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/todos/import",
+        description: "Import todos from CSV or NDJSON (set Content-Type accordingly)",
+        body: "title,done\nfirst,false\nsecond,true",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/users/import",
+        description: "Import users from CSV (set Content-Type: text/csv), returns per-row errors",
+        body: "username,email,role,password\nimported,imported@example.com,user,secret",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/todos/:id/restore",
+        description: "Restore a deleted todo",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/search?q=",
+        description: "Full-text search over todos, ranked with highlighted snippets",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/sync/todos?since=0",
+        description: "Change feed of todos with a higher seq, including deleted ones",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/sync/todos",
+        description: "Apply offline mutations, conflict is lww, server_wins or merge",
+        body: r#"{"conflict": "lww", "mutations": [{"client_id": "", "op": "upsert", "title": "", "updated_at": "2024-01-01T00:00:00Z"}]}"#,
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/push/public-key",
+        description: "VAPID public key used as applicationServerKey for Web Push",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/api/push/subscribe",
+        description: "Save a browser push subscription (PushSubscription.toJSON())",
+        body: r#"{"endpoint": "https://", "keys": {"p256dh": "", "auth": ""}}"#,
+    },
+    Endpoint {
+        method: "DELETE",
+        path: "/api/push/subscribe",
+        description: "Remove a push subscription",
+        body: r#"{"endpoint": "https://"}"#,
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/jobs/:id",
+        description: "Poll a background job",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/jobs/:id/result",
+        description: "Fetch the typed result of a finished background job",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/audit_log",
+        description: "Query the audit log, use ?cursor= for cursor pagination",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/admin/audit_log/report",
+        description: "Generate an audit log report as a background job",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/audit_log/archives",
+        description: "List audit log archives in object storage, use ?since= to filter",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/config",
+        description: "Show the effective configuration, add ?changed=true for env overrides only",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/client_policies?user_agent=",
+        description: "Show the per user agent client policies and which class a user agent falls into",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/compat_rules?class=",
+        description: "List the request and response transformations applied to legacy clients",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/deprecations",
+        description: "List deprecated routes, their sunset dates and who still calls them",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/redirects?path=",
+        description: "Show the redirect and rewrite rules and how a path would be handled",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/metrics",
+        description: "Prometheus metrics: requests per route and status, connections and pool usage",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/healthz",
+        description: "Liveness check: the process is up, with version and uptime",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/readyz",
+        description: "Readiness check: database, applied migrations and schema, each with its own status",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/admin/drain",
+        description: "Fail readiness, wait for the load balancer, then shut down gracefully",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/experiments",
+        description: "List A/B experiments with exposures per variant",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api/status",
+        description: "Public status of each component, with incidents and uptime",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/feed.xml",
+        description: "RSS 2.0 feed of status page incidents",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/feed.atom",
+        description: "Atom feed of status page incidents",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/sitemap.xml",
+        description: "Public pages and todos with their last modification time",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/pages/about",
+        description: "Render content/about.md as a page",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/preview?fragment=true",
+        description: "Render Markdown from the request body, without ?fragment=true the full page is returned",
+        body: "# Title\n\n```rust\nfn main() {}\n```",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/admin/incidents",
+        description: "Open an incident on the status page, impact is minor, major or critical",
+        body: r#"{"title": "", "impact": "minor", "components": ["database"], "message": ""}"#,
+    },
+    Endpoint {
+        method: "POST",
+        path: "/admin/incidents/:id/updates",
+        description: "Post an incident update, status resolved closes it",
+        body: r#"{"status": "identified", "message": ""}"#,
+    },
+    Endpoint {
+        method: "GET",
+        path: "/debug/pool",
+        description: "Connection pool state: connections, pending checkouts and wait p95",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/runtime",
+        description: "Tokio runtime stats: workers, alive tasks and queue depth",
+        body: "",
+    },
+    Endpoint {
+        method: "DELETE",
+        path: "/admin/response_cache",
+        description: "Purge cached responses on every instance, use ?prefix= to limit",
+        body: "",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/admin/roles/:role/permissions",
+        description: "List the permissions of a role",
+        body: "",
+    },
+    Endpoint {
+        method: "PUT",
+        path: "/admin/roles/:role/permissions/:permission",
+        description: "Grant a permission to a role",
+        body: r#"{"scope": "any"}"#,
+    },
+    Endpoint {
+        method: "DELETE",
+        path: "/admin/roles/:role/permissions/:permission",
+        description: "Revoke a permission from a role",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/admin/impersonate/:user_id",
+        description: "Start impersonating a user",
+        body: "",
+    },
+    Endpoint {
+        method: "DELETE",
+        path: "/admin/impersonate",
+        description: "Stop impersonating",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/admin/users/:id/unlock",
+        description: "Unlock a locked account",
+        body: "",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/json",
+        description: "JSON body example",
+        body: r#"{"name": "", "email": ""}"#,
+    },
+    Endpoint {
+        method: "POST",
+        path: "/xml",
+        description: "XML body example, change the header to Content-Type: application/xml",
+        body: "<signup><name></name><email></email></signup>",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/yaml",
+        description: "YAML body example, change the header to Content-Type: application/yaml",
+        body: "name: \"\"\nemail: \"\"\n",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/msgpack/benchmark?items=1000",
+        description: "Compare JSON and MessagePack size and speed",
+        body: "",
+    },
+];
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/console", get(console))
+}
+
+#[derive(Template)]
+#[template(path = "console.html")]
+struct ConsoleTemplate {
+    username: String,
+    endpoints: &'static [Endpoint],
+}
+
+async fn console(
+    AdminPage { user, .. }: AdminPage<ConsoleUse>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let page = ConsoleTemplate {
+        username: user.username,
+        endpoints: ENDPOINTS,
+    };
+    Ok(Html(page.render().map_err(internal_error)?))
+}
@@ -0,0 +1,94 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, StreamExt};
+use tokio_postgres::{Row, RowStream};
+
+use crate::db::{repo::FromRow, Connection};
+
+/*
+ * 把查询结果流式导出为 CSV，比如 GET /api/users.csv
+ * 数据库那边用 query_raw 逐行读取（见 repo::fetch_stream），每攒够一批行就编码成 CSV 发给客户端，
+ * 内存里最多只有一批数据，导出几百万行也不会把整个结果集读进内存；客户端读得慢时数据库那边也会跟着等。
+ * 响应头：
+ * - Content-Type: text/csv; charset=utf-8
+ * - Content-Disposition: attachment; filename="users.csv"，浏览器里直接下载成文件
+ * 字段按 RFC 4180 转义：包含逗号、引号或者换行的字段用双引号括起来，里面的双引号写两遍。
+ * 第一行是表头。响应头发出之后才出错（比如连接断开）时只能中断响应，客户端会收到不完整的文件，错误记在日志里。
+ * 导出新的表只需要给行的结构体实现 CsvRecord，再在 handler 里调用 stream。
+ */
+
+// 每批编码多少行
+const ROWS_PER_CHUNK: usize = 500;
+
+/**
+ * 可以导出为 CSV 的行，HEADER 和 fields 的顺序要一致
+ */
+pub trait CsvRecord: FromRow {
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+}
+
+/**
+ * 把一行写到 out 里，需要时给字段加上引号
+ */
+fn write_line<S: AsRef<str>>(out: &mut String, fields: &[S]) {
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        let field = field.as_ref();
+        if field.contains([',', '"', '\n', '\r']) {
+            out.push('"');
+            out.push_str(&field.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(field);
+        }
+    }
+    out.push_str("\r\n");
+}
+
+fn encode<T: CsvRecord>(
+    rows: Vec<Result<Row, tokio_postgres::Error>>,
+) -> Result<Bytes, tokio_postgres::Error> {
+    let mut out = String::new();
+    for row in rows {
+        write_line(&mut out, &T::from_row(&row?)?.fields());
+    }
+    Ok(Bytes::from(out))
+}
+
+/**
+ * CSV 下载响应，conn 是执行查询的连接，rows 读完或者客户端断开之后才还回连接池
+ */
+pub fn stream<T>(conn: Connection, rows: RowStream, filename: &str) -> Response
+where
+    T: CsvRecord + 'static,
+{
+    let mut first = String::new();
+    write_line(&mut first, T::HEADER);
+    let lines = rows.ready_chunks(ROWS_PER_CHUNK).map(move |batch| {
+        let _conn = &conn;
+        encode::<T>(batch).inspect_err(|err| tracing::error!("CSV export failed: {}", err))
+    });
+    let body = stream::once(async { Ok(Bytes::from(first)) }).chain(lines);
+
+    let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename))
+        .expect("CSV file names are valid header values");
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/csv; charset=utf-8"),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
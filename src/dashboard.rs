@@ -0,0 +1,126 @@
+use std::{sync::atomic::Ordering, time::Instant};
+
+use askama::Template;
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+
+use crate::{
+    admin::AdminPage, error::internal_error, health, metrics::RecentError,
+    permissions::ServerManage, AppState,
+};
+
+/*
+ * 给运维人员看的 HTML 状态面板，GET /admin/status，需要 server:manage 权限，未登录时跳转到 /admin/login
+ * 和 /metrics 的数据来源相同，只是不需要 Prometheus 也能直接在浏览器里看：
+ * - 版本、编译时的 git commit（见 build.rs）和运行时间
+ * - 请求数（按 2xx/3xx/4xx/5xx 分类）、正在处理的请求数、打开的连接数、过载时拒绝的请求数
+ * - 最近的 5xx 请求，可以拿 request id 去日志里查
+ * - 数据库：主库能不能连上、各个连接池的状态（同 GET /debug/pool）、表结构是否和迁移一致
+ * 计数都是本实例启动以来的，多个实例时每个实例看到的不一样。
+ * /status 是给用户看的公开状态页（见 status），不显示这些内部信息。
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/status", get(dashboard))
+}
+
+struct PoolRow {
+    name: String,
+    connections: u32,
+    idle: u32,
+    pending: u64,
+    timeouts: u64,
+    wait_p95_ms: Option<f64>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/status.html")]
+struct DashboardTemplate {
+    username: String,
+    version: &'static str,
+    git_sha: &'static str,
+    uptime: String,
+    requests: [u64; 5],
+    in_flight: i64,
+    connections: i64,
+    shed: u64,
+    errors: Vec<RecentError>,
+    // 主库检查失败的原因
+    database_error: Option<String>,
+    database_ms: u128,
+    pools: Vec<PoolRow>,
+    drift: Vec<String>,
+}
+
+/**
+ * 形如 3d 4h 5m 的运行时间
+ */
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, secs % 60)
+    }
+}
+
+async fn dashboard(
+    AdminPage { user, .. }: AdminPage<ServerManage>,
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let started = Instant::now();
+    let database_error = health::ping(&state.pool).await.err();
+    let database_ms = started.elapsed().as_millis();
+
+    let mut pools = vec![("primary".to_string(), &state.pool)];
+    for (index, pool) in state.replicas.all().iter().enumerate() {
+        pools.push((format!("replica{}", index), pool));
+    }
+    let pools = pools
+        .into_iter()
+        .map(|(name, pool)| {
+            let pool_state = pool.state();
+            let stats = pool.stats();
+            PoolRow {
+                name,
+                connections: pool_state.connections,
+                idle: pool_state.idle_connections,
+                pending: stats.pending.load(Ordering::Relaxed),
+                timeouts: stats.timeouts.load(Ordering::Relaxed),
+                wait_p95_ms: stats
+                    .recent_wait_quantile(0.95)
+                    .map(|wait| wait.as_micros() as f64 / 1000.0),
+            }
+        })
+        .collect();
+
+    let page = DashboardTemplate {
+        username: user.username,
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        uptime: format_uptime(health::uptime().as_secs()),
+        requests: state.metrics.requests_by_class(),
+        in_flight: state.metrics.in_flight(),
+        connections: state.metrics.connections(),
+        shed: state.metrics.shed(),
+        errors: state.metrics.recent_errors(),
+        database_error,
+        database_ms,
+        pools,
+        drift: state.schema_drift.report().problems().cloned().collect(),
+    }
+    .render()
+    .map_err(internal_error)?;
+    Ok((
+        [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        Html(page),
+    )
+        .into_response())
+}
@@ -0,0 +1,599 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    future::Future,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use bb8::{Pool, PooledConnection, RunError};
+use bb8_postgres::PostgresConnectionManager;
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio_postgres::{error::SqlState, Client};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::{
+    config::DatabaseConfig,
+    error::{internal_error, json_error},
+    metrics::Histogram,
+    permissions::{Authorize, ServerManage},
+    AppState,
+};
+
+pub mod partitions;
+pub mod repo;
+pub mod schema;
+pub mod tls;
+
+type Manager = PostgresConnectionManager<MakeRustlsConnect>;
+
+/**
+ * bb8 连接池，多记录一下获取连接的等待时间、超时次数和正在等待的请求数，见 metrics 和 GET /debug/pool
+ * 其余的用法和 bb8::Pool 相同
+ */
+#[derive(Clone)]
+pub struct ConnectionPool {
+    pool: Pool<Manager>,
+    stats: Arc<PoolStats>,
+}
+
+// 计算等待时间 p95 时使用最近多少次获取连接的记录
+const RECENT_WAITS: usize = 1024;
+
+#[derive(Default)]
+pub struct PoolStats {
+    pub wait: Histogram,
+    pub timeouts: AtomicU64,
+    // 正在等待连接的请求数
+    pub pending: AtomicU64,
+    // 最近 RECENT_WAITS 次的等待时间，直方图的桶太粗，算不准分位数
+    recent: std::sync::Mutex<VecDeque<Duration>>,
+}
+
+impl PoolStats {
+    /**
+     * 最近的等待时间的 p 分位数（0 到 1），还没有记录时返回 None
+     */
+    pub fn recent_wait_quantile(&self, p: f64) -> Option<Duration> {
+        let mut waits: Vec<Duration> = self.recent.lock().unwrap().iter().copied().collect();
+        if waits.is_empty() {
+            return None;
+        }
+        waits.sort();
+        let index = ((waits.len() as f64 * p).ceil() as usize).clamp(1, waits.len()) - 1;
+        Some(waits[index])
+    }
+}
+
+/**
+ * 等待连接期间计入 pending，请求被取消（比如超时）时 drop 也会减回去
+ */
+struct Pending<'a>(&'a AtomicU64);
+
+impl<'a> Pending<'a> {
+    fn start(pending: &'a AtomicU64) -> Self {
+        pending.fetch_add(1, Ordering::Relaxed);
+        Pending(pending)
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionPool {
+    fn record<T>(&self, started: Instant, result: &Result<T, RunError<tokio_postgres::Error>>) {
+        let elapsed = started.elapsed();
+        self.stats.wait.observe(elapsed);
+        if let Err(RunError::TimedOut) = result {
+            self.stats.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut recent = self.stats.recent.lock().unwrap();
+        if recent.len() == RECENT_WAITS {
+            recent.pop_front();
+        }
+        recent.push_back(elapsed);
+    }
+
+    pub async fn get(
+        &self,
+    ) -> Result<PooledConnection<'_, Manager>, RunError<tokio_postgres::Error>> {
+        let started = Instant::now();
+        let pending = Pending::start(&self.stats.pending);
+        let result = self.pool.get().await;
+        drop(pending);
+        self.record(started, &result);
+        result
+    }
+
+    pub async fn get_owned(&self) -> Result<Connection, RunError<tokio_postgres::Error>> {
+        let started = Instant::now();
+        let pending = Pending::start(&self.stats.pending);
+        let result = self.pool.get_owned().await;
+        drop(pending);
+        self.record(started, &result);
+        result
+    }
+
+    pub fn state(&self) -> bb8::State {
+        self.pool.state()
+    }
+
+    pub fn stats(&self) -> &PoolStats {
+        &self.stats
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/debug/pool", get(pool_stats))
+}
+
+/**
+ * 各个连接池的当前状态，请求卡在获取连接上时用来确认是不是连接池满了，需要 server:manage 权限
+ * - connections / idle / in_use   bb8 打开的连接数，其中空闲的和正在使用的
+ * - pending                       正在等待连接的请求数，持续大于 0 说明连接池不够用或者有连接没有归还
+ * - checkouts / timeouts          启动以来获取连接的次数和超时次数
+ * - wait_p95_ms / wait_max_ms     最近 1024 次获取连接的等待时间
+ */
+async fn pool_stats(_auth: Authorize<ServerManage>, State(state): State<AppState>) -> Json<Value> {
+    let mut pools = vec![("primary".to_string(), &state.pool)];
+    for (index, pool) in state.replicas.all().iter().enumerate() {
+        pools.push((format!("replica{}", index), pool));
+    }
+    let millis = |wait: Option<Duration>| wait.map(|wait| wait.as_micros() as f64 / 1000.0);
+    let pools: Vec<Value> = pools
+        .into_iter()
+        .map(|(name, pool)| {
+            let pool_state = pool.state();
+            let stats = pool.stats();
+            json!({
+                "pool": name,
+                "max_size": state.config.database.pool.max_size,
+                "connections": pool_state.connections,
+                "idle": pool_state.idle_connections,
+                "in_use": pool_state.connections - pool_state.idle_connections,
+                "pending": stats.pending.load(Ordering::Relaxed),
+                "checkouts": stats.wait.count(),
+                "timeouts": stats.timeouts.load(Ordering::Relaxed),
+                "wait_p95_ms": millis(stats.recent_wait_quantile(0.95)),
+                "wait_max_ms": millis(stats.recent_wait_quantile(1.0)),
+            })
+        })
+        .collect();
+    Json(json!({ "pools": pools }))
+}
+
+/*
+ * 数据库迁移
+ * migrations 目录下的 SQL 文件按 V{版本号}__{描述}.sql 命名，编译时由 embed_migrations! 嵌入到二进制中，
+ * 执行过的版本记录在 refinery_schema_history 表里，每个版本只会执行一次。
+ */
+mod embedded {
+    refinery::embed_migrations!("migrations");
+}
+
+/**
+ * 创建连接池，并立即获取一次连接
+ * bb8 默认是懒连接的，数据库地址写错或者数据库没启动时，要等到第一个请求进来才会报错，
+ * 这里在启动阶段就检查一次，连接不上时直接返回错误，由调用方打印并退出
+ * 主库和只读副本使用相同的连接池参数和 TLS 设置
+ */
+pub async fn connect(
+    url: &str,
+    database: &DatabaseConfig,
+) -> Result<ConnectionPool, tokio_postgres::Error> {
+    // TLS 证书在加载配置时已经检查过
+    let connector = tls::connector(&database.tls).expect("invalid TLS settings");
+    let manager = PostgresConnectionManager::new(tls::pg_config(url, &database.tls)?, connector);
+    let config = &database.pool;
+
+    // 连接池对象
+    let pool = Pool::builder()
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(config.connection_timeout)
+        .max_lifetime(config.max_lifetime)
+        .test_on_check_out(config.test_on_checkout)
+        .build(manager)
+        .await?;
+
+    // 单独建立一个不放入连接池的连接，连接失败时能拿到具体的错误原因，而不是等待超时
+    pool.dedicated_connection().await?;
+    Ok(ConnectionPool {
+        pool,
+        stats: Arc::default(),
+    })
+}
+
+/**
+ * 只读副本的连接池
+ * 有多个副本时按顺序轮流使用，分摊读请求；没有配置副本时由 AppState::read 退回到主库
+ * 每个副本已经回放到的 WAL 位置（LSN）由 refresh_replay_lsn 定期更新，
+ * 带着一致性令牌的请求只会用已经追上的副本，见 consistency
+ */
+#[derive(Clone, Default)]
+pub struct Replicas {
+    pools: Arc<Vec<ConnectionPool>>,
+    next: Arc<AtomicUsize>,
+    // 和 pools 一一对应，0 表示还不知道
+    replayed: Arc<Vec<AtomicU64>>,
+}
+
+impl Replicas {
+    pub fn new(pools: Vec<ConnectionPool>) -> Self {
+        let replayed = pools.iter().map(|_| AtomicU64::new(0)).collect();
+        Replicas {
+            pools: Arc::new(pools),
+            next: Arc::default(),
+            replayed: Arc::new(replayed),
+        }
+    }
+
+    pub fn all(&self) -> &[ConnectionPool] {
+        &self.pools
+    }
+
+    pub fn pick(&self) -> Option<&ConnectionPool> {
+        if self.pools.is_empty() {
+            return None;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.pools.len();
+        Some(&self.pools[index])
+    }
+
+    /**
+     * 轮流挑一个已经回放到 lsn 的副本，都还没追上时返回 None
+     */
+    pub fn pick_caught_up(&self, lsn: u64) -> Option<&ConnectionPool> {
+        if self.pools.is_empty() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.pools.len())
+            .map(|offset| (start + offset) % self.pools.len())
+            .find(|&index| self.replayed[index].load(Ordering::Relaxed) >= lsn)
+            .map(|index| &self.pools[index])
+    }
+
+    /**
+     * 查询每个副本已经回放到的 LSN，查询失败的副本记为 0，带令牌的读请求不会再用它
+     */
+    pub async fn refresh_replay_lsn(&self) {
+        for (pool, replayed) in self.pools.iter().zip(self.replayed.iter()) {
+            let lsn = match pool.get().await {
+                Ok(conn) => replay_lsn(&conn).await.unwrap_or_else(|err| {
+                    tracing::warn!("query replica replay LSN failed: {}", err);
+                    0
+                }),
+                Err(err) => {
+                    tracing::warn!("query replica replay LSN failed: {}", err);
+                    0
+                }
+            };
+            replayed.store(lsn, Ordering::Relaxed);
+        }
+    }
+}
+
+/**
+ * 主库当前的 WAL 位置，刚提交的事务都在这个位置之前
+ */
+pub async fn current_lsn(client: &Client) -> Result<u64, tokio_postgres::Error> {
+    let lsn: i64 = client
+        .query_one("SELECT (pg_current_wal_lsn() - '0/0')::BIGINT", &[])
+        .await?
+        .get(0);
+    Ok(lsn as u64)
+}
+
+/**
+ * 副本已经回放到的 WAL 位置；配置成副本的其实是主库（比如本地开发）时返回主库当前的位置
+ */
+async fn replay_lsn(client: &Client) -> Result<u64, tokio_postgres::Error> {
+    let lsn: Option<i64> = client
+        .query_one(
+            "SELECT (CASE WHEN pg_is_in_recovery() THEN pg_last_wal_replay_lsn()
+                          ELSE pg_current_wal_lsn() END - '0/0')::BIGINT",
+            &[],
+        )
+        .await?
+        .get(0);
+    Ok(lsn.unwrap_or(0) as u64)
+}
+
+/**
+ * 执行所有尚未执行的迁移
+ */
+pub async fn migrate(
+    pool: &ConnectionPool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = pool.get().await?;
+    let report = embedded::migrations::runner().run_async(&mut *conn).await?;
+    for migration in report.applied_migrations() {
+        tracing::info!("applied migration {}", migration);
+    }
+    Ok(())
+}
+
+/**
+ * 数据库断路器
+ * with_retry 的重试用完之后断路器打开，冷却时间（DB_BREAKER_COOLDOWN_SECS）内的查询直接返回 503，
+ * 不再每个请求都等一遍连接超时和重试，请求也不会在数据库恢复前堆积起来。
+ * 冷却时间过后放行一个请求去探测，成功就关闭断路器，失败则再打开一个冷却时间。
+ * 断路器打开期间 degraded 中间件会改为返回缓存的数据或者静态的提示页面。
+ */
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    // 断路器打开时，下一次放行探测请求的时间
+    open_until: Arc<std::sync::Mutex<Option<Instant>>>,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(cooldown: Duration) -> Self {
+        CircuitBreaker {
+            open_until: Arc::new(std::sync::Mutex::new(None)),
+            cooldown,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_until.lock().unwrap().is_some()
+    }
+
+    /**
+     * 断路器打开并且还没到下一次探测的时间，这段时间里访问数据库一定会直接失败
+     */
+    pub fn is_cooling_down(&self) -> bool {
+        self.open_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /**
+     * 是否可以访问数据库；冷却时间到了之后只放行一个探测请求，其它请求继续等下一个冷却时间
+     */
+    fn try_acquire(&self) -> bool {
+        let now = Instant::now();
+        let mut open_until = self.open_until.lock().unwrap();
+        match *open_until {
+            None => true,
+            Some(until) if now >= until => {
+                *open_until = Some(now + self.cooldown);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    fn succeed(&self) {
+        if self.open_until.lock().unwrap().take().is_some() {
+            tracing::info!("database is reachable again, circuit breaker closed");
+        }
+    }
+
+    fn trip(&self) {
+        let until = Instant::now() + self.cooldown;
+        if self.open_until.lock().unwrap().replace(until).is_none() {
+            tracing::warn!("circuit breaker opened for {:?}", self.cooldown);
+        }
+    }
+}
+
+// 单次重试等待时间的上限
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/**
+ * 判断是否是连接层面的临时错误：连接已断开、网络错误、数据库正在关闭或启动中（SQLSTATE 08xxx、57P01~57P03）
+ * 语法错误、违反约束这类错误重试也不会成功，直接返回
+ */
+fn is_transient(err: &tokio_postgres::Error) -> bool {
+    if err.is_closed() {
+        return true;
+    }
+    match err.code() {
+        Some(code) => {
+            code.code().starts_with("08")
+                || [
+                    SqlState::ADMIN_SHUTDOWN,
+                    SqlState::CRASH_SHUTDOWN,
+                    SqlState::CANNOT_CONNECT_NOW,
+                ]
+                .contains(code)
+        }
+        None => err
+            .source()
+            .is_some_and(|source| source.is::<std::io::Error>()),
+    }
+}
+
+/**
+ * 带重试的数据库访问
+ * 获取连接超时、连接失败、执行过程中连接断开时，按带随机抖动的指数退避（full jitter）等待后重试，
+ * 重试次数用完后返回 503，避免数据库短暂重启时请求直接失败。
+ * 连接断开时语句可能已经执行成功，所以只应该用于只读查询等可以安全重复执行的操作。
+ * 只读查询优先发到只读副本；副本出错重试时改用主库，副本全部不可用时读请求也能继续工作。
+ * 副本的数据可能稍有延迟，刚写入就要读到的场景应该直接使用主库。
+ * 重试用完后会打开断路器，见 CircuitBreaker。
+ */
+pub async fn with_retry<T, F, Fut>(
+    state: &AppState,
+    mut query: F,
+) -> Result<T, (StatusCode, String)>
+where
+    F: FnMut(Connection) -> Fut,
+    Fut: Future<Output = Result<T, tokio_postgres::Error>>,
+{
+    let retry = &state.config.database.retry;
+    if !state.breaker.try_acquire() {
+        return Err(unavailable());
+    }
+    let mut attempt = 0;
+    loop {
+        let pool = if attempt == 0 {
+            state.read()
+        } else {
+            state.write()
+        };
+        let err = match pool.get_owned().await {
+            Ok(conn) => match query(conn).await {
+                Ok(value) => {
+                    state.breaker.succeed();
+                    return Ok(value);
+                }
+                Err(err) if is_transient(&err) => err.to_string(),
+                // 语句本身出错说明数据库是可以访问的
+                Err(err) => {
+                    state.breaker.succeed();
+                    return Err(internal_error(err));
+                }
+            },
+            Err(RunError::User(err)) if is_transient(&err) => err.to_string(),
+            Err(RunError::User(err)) => return Err(internal_error(err)),
+            Err(RunError::TimedOut) => "timed out waiting for a connection".to_string(),
+        };
+
+        attempt += 1;
+        if attempt > retry.attempts {
+            tracing::warn!("database unavailable after {} attempts: {}", attempt, err);
+            state.breaker.trip();
+            return Err(unavailable());
+        }
+        let cap = retry
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_RETRY_DELAY);
+        let delay = cap.mul_f64(rand::thread_rng().gen::<f64>());
+        tracing::warn!(
+            "transient database error, retry {} in {:?}: {}",
+            attempt,
+            delay,
+            err
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn unavailable() -> (StatusCode, String) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "database temporarily unavailable".to_string(),
+    )
+}
+
+pub type Connection = PooledConnection<'static, Manager>;
+
+/**
+ * 已经执行了 BEGIN 的连接
+ * 正常情况下由 transaction_layer 提交或回滚；如果请求中途被取消（比如客户端断开连接）导致它被直接 drop，
+ * 则在后台回滚之后再把连接还给连接池，避免把处于事务中的连接交给下一个请求
+ */
+struct OpenTransaction(Option<Connection>);
+
+impl OpenTransaction {
+    async fn finish(mut self, commit: bool) -> Result<(), tokio_postgres::Error> {
+        let conn = self.0.take().unwrap();
+        conn.batch_execute(if commit { "COMMIT" } else { "ROLLBACK" })
+            .await
+    }
+}
+
+impl Drop for OpenTransaction {
+    fn drop(&mut self) {
+        if let Some(conn) = self.0.take() {
+            tokio::spawn(async move {
+                if let Err(err) = conn.batch_execute("ROLLBACK").await {
+                    tracing::warn!("rollback abandoned transaction failed: {}", err);
+                }
+            });
+        }
+    }
+}
+
+// 由 transaction_layer 放进 request extensions，Tx 提取器第一次被使用时才会真正获取连接并开启事务
+#[derive(Clone, Default)]
+struct TransactionSlot(Arc<Mutex<Option<OpenTransaction>>>);
+
+/**
+ * 请求级事务提取器
+ * handler 声明 tx: Tx 参数后，handler 里的所有语句都在同一个事务中执行：
+ * handler 返回成功（2xx/3xx）时提交，返回错误时回滚，多条语句的 handler 不再需要手动管理事务。
+ * 需要配合 transaction_layer 中间件使用，Tx 可以直接当作 tokio_postgres::Client 调用 query/execute 等方法。
+ */
+pub struct Tx(OwnedMutexGuard<Option<OpenTransaction>>);
+
+impl Deref for Tx {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.0.as_ref().and_then(|tx| tx.0.as_ref()).unwrap()
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tx {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let slot = parts.extensions.get::<TransactionSlot>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Tx requires db::transaction_layer".to_string(),
+        ))?;
+        let mut guard = slot.0.try_lock_owned().map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Tx can only be extracted once per request".to_string(),
+            )
+        })?;
+        if guard.is_none() {
+            let conn = state.pool.get_owned().await.map_err(internal_error)?;
+            conn.batch_execute("BEGIN").await.map_err(internal_error)?;
+            *guard = Some(OpenTransaction(Some(conn)));
+        }
+        Ok(Tx(guard))
+    }
+}
+
+/**
+ * 请求级事务中间件，根据响应状态码提交或回滚 Tx 开启的事务
+ * 只在 handler 用到 Tx 时才会占用连接，其它请求只是多了一次 extensions 插入
+ */
+pub async fn transaction_layer(mut req: Request, next: Next) -> Response {
+    let slot = TransactionSlot::default();
+    req.extensions_mut().insert(slot.clone());
+
+    let res = next.run(req).await;
+    let Some(tx) = slot.0.lock().await.take() else {
+        return res;
+    };
+    let commit = res.status().is_success() || res.status().is_redirection();
+    match tx.finish(commit).await {
+        Ok(()) => res,
+        Err(err) if commit => json_error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        Err(err) => {
+            tracing::warn!("rollback failed: {}", err);
+            res
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+
+use super::{repo, ConnectionPool};
+use crate::{config::PartitionConfig, error::internal_error};
+
+/*
+ * 按月分区的大表（现在只有 audit_log，见 V19 迁移）
+ * 每个月一个分区，名字形如 audit_log_2024_01，范围是这个月的 [1 日 0 点, 下个月 1 日 0 点)（UTC），
+ * 另外有一个默认分区 audit_log_default，接住不属于任何分区的记录。
+ * 启动时和之后每天执行一次 maintain：
+ * - 提前创建当前月份之后 PARTITIONS_AHEAD_MONTHS 个月的分区；默认分区里已经有这个月的记录时先搬过去再挂上
+ * - 设置了 AUDIT_LOG_RETENTION_DAYS 时，整个月都早于保留期限的分区卸下（DETACH），
+ *   已经空了（比如已经归档，见 archive）就直接删除，否则留下这张表并打印警告，由管理员处理
+ * 多个实例同时执行时用 advisory lock 保证同一时间只有一个在改分区。
+ * 查询时带上 created_at 的范围（见 repo::AuditFilter 的 since 和 until），Postgres 只扫描范围内的分区。
+ */
+
+pub const MAINTAIN_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+// 修改分区的 advisory lock
+const LOCK_ID: i64 = 0x7061_7274_6974_696f;
+
+/**
+ * 一张按月分区的表
+ */
+struct Partitioned {
+    table: &'static str,
+    // 分区键
+    column: &'static str,
+    // 保留多久，None 表示一直保留
+    retention: Option<Duration>,
+}
+
+fn tables(config: &PartitionConfig) -> [Partitioned; 1] {
+    [Partitioned {
+        table: "audit_log",
+        column: "created_at",
+        retention: config.audit_log_retention,
+    }]
+}
+
+fn month_start(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap_or(date)
+}
+
+fn to_utc(date: NaiveDate) -> DateTime<Utc> {
+    date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+}
+
+fn partition_name(table: &str, month: NaiveDate) -> String {
+    format!("{}_{}", table, month.format("%Y_%m"))
+}
+
+/**
+ * 从分区名里解析出月份，默认分区和不认识的名字返回 None
+ */
+fn partition_month(table: &str, name: &str) -> Option<NaiveDate> {
+    let suffix = name.strip_prefix(table)?.strip_prefix('_')?;
+    NaiveDate::parse_from_str(&format!("{}_01", suffix), "%Y_%m_%d").ok()
+}
+
+/**
+ * 创建当前月份和之后 ahead 个月中还不存在的分区，返回新建的分区名
+ */
+async fn create_upcoming(
+    pool: &ConnectionPool,
+    table: &Partitioned,
+    ahead: u32,
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let mut conn = pool.get().await.map_err(internal_error)?;
+    let existing = repo::list_partitions(&*conn, table.table)
+        .await
+        .map_err(internal_error)?;
+    let current = month_start(Utc::now().date_naive());
+    let mut created = Vec::new();
+    for offset in 0..=ahead {
+        let Some(month) = current.checked_add_months(Months::new(offset)) else {
+            break;
+        };
+        let name = partition_name(table.table, month);
+        if existing.contains(&name) {
+            continue;
+        }
+        let next = month.checked_add_months(Months::new(1)).ok_or_else(|| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{}: month out of range", name),
+            )
+        })?;
+        let tx = conn.transaction().await.map_err(internal_error)?;
+        let moved = repo::create_partition(
+            &tx,
+            table.table,
+            table.column,
+            &name,
+            to_utc(month),
+            to_utc(next),
+        )
+        .await
+        .map_err(internal_error)?;
+        tx.commit().await.map_err(internal_error)?;
+        if moved > 0 {
+            tracing::info!(
+                "moved {} rows from the default partition into {}",
+                moved,
+                name
+            );
+        }
+        created.push(name);
+    }
+    Ok(created)
+}
+
+/**
+ * 卸下整个月都早于保留期限的分区，空的分区直接删除，返回卸下的分区名
+ */
+async fn detach_expired(
+    pool: &ConnectionPool,
+    table: &Partitioned,
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let Some(retention) = table.retention else {
+        return Ok(Vec::new());
+    };
+    let cutoff = Utc::now() - retention;
+    let mut conn = pool.get().await.map_err(internal_error)?;
+    let existing = repo::list_partitions(&*conn, table.table)
+        .await
+        .map_err(internal_error)?;
+    let mut detached = Vec::new();
+    for name in existing {
+        let Some(month) = partition_month(table.table, &name) else {
+            continue;
+        };
+        let expired = month
+            .checked_add_months(Months::new(1))
+            .is_some_and(|next| to_utc(next) <= cutoff);
+        if !expired {
+            continue;
+        }
+        let tx = conn.transaction().await.map_err(internal_error)?;
+        let rows = repo::detach_partition(&tx, table.table, &name)
+            .await
+            .map_err(internal_error)?;
+        if rows == 0 {
+            repo::drop_table(&tx, &name).await.map_err(internal_error)?;
+        } else {
+            tracing::warn!(
+                "detached partition {} still has {} rows, kept as a standalone table",
+                name,
+                rows
+            );
+        }
+        tx.commit().await.map_err(internal_error)?;
+        detached.push(name);
+    }
+    Ok(detached)
+}
+
+/**
+ * 创建之后几个月的分区，卸下过期的分区；其他实例正在执行时直接返回
+ */
+pub async fn maintain(
+    pool: &ConnectionPool,
+    config: &PartitionConfig,
+) -> Result<(), (StatusCode, String)> {
+    // session 级别的锁，持有这个连接直到执行结束
+    let lock = pool.get().await.map_err(internal_error)?;
+    let locked: bool = lock
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&LOCK_ID])
+        .await
+        .map_err(internal_error)?
+        .get(0);
+    if !locked {
+        return Ok(());
+    }
+
+    let mut outcome = Ok(());
+    for table in tables(config) {
+        let result = async {
+            let created = create_upcoming(pool, &table, config.ahead).await?;
+            if !created.is_empty() {
+                tracing::info!("created partitions {}", created.join(", "));
+            }
+            let detached = detach_expired(pool, &table).await?;
+            if !detached.is_empty() {
+                tracing::info!("detached expired partitions {}", detached.join(", "));
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            outcome = Err(err);
+            break;
+        }
+    }
+
+    let _ = lock
+        .execute("SELECT pg_advisory_unlock($1)", &[&LOCK_ID])
+        .await;
+    outcome
+}
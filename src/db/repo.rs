@@ -0,0 +1,1824 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_postgres::{types::ToSql, Error, GenericClient, Row, RowStream};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::listing::{Field, FieldType, ListSpec, Listing};
+
+/*
+ * 数据访问层
+ * SQL 语句和查询结果到结构体的转换都集中在这里，handler 拿到的是有类型的结构体，不需要再按下标或列名手动取值。
+ * 参数都是 GenericClient，可以传普通连接、事务或者 Tx；错误原样返回 tokio_postgres::Error，
+ * 由调用方决定转换成什么样的 HTTP 错误（比如唯一约束冲突返回 409）。
+ * todos 按租户隔离，读写 todos 的函数都需要传入租户 id（见 tenant 模块）。
+ * users 和 todos 是软删除的：删除只设置 deleted_at，查询默认排除已删除的记录，可以通过 restore_* 恢复。
+ * users 和 todos 的每次修改都会把 version 加一，update_* 需要传入读取时的版本号（乐观锁）。
+ * 每条语句都在一个 db.query 的 tracing span 里执行，span 上记录 SQL 和耗时（参数值不会出现在日志里），
+ * 耗时超过 DB_SLOW_QUERY_MS 的语句额外打印一条 WARN 日志。
+ */
+
+// 慢查询阈值（毫秒），启动时由 set_slow_query_threshold 根据配置设置
+static SLOW_QUERY_MS: AtomicU64 = AtomicU64::new(200);
+
+pub fn set_slow_query_threshold(threshold: Duration) {
+    SLOW_QUERY_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+/**
+ * 日志里显示的 SQL：合并多余的空白，引号里的字面量替换成 '?'
+ * 参数本来就是通过 $1、$2 传递的，这里只是防止写死在 SQL 里的值被打印出来
+ */
+fn redact(sql: &str) -> String {
+    let mut redacted = String::with_capacity(sql.len());
+    let mut quoted = false;
+    for c in sql.chars() {
+        match (quoted, c) {
+            (false, '\'') => {
+                quoted = true;
+                redacted.push_str("'?");
+            }
+            (true, '\'') => {
+                quoted = false;
+                redacted.push('\'');
+            }
+            (true, _) => {}
+            (false, c) if c.is_whitespace() => {
+                if !redacted.ends_with(' ') {
+                    redacted.push(' ');
+                }
+            }
+            (false, c) => redacted.push(c),
+        }
+    }
+    redacted.trim().to_string()
+}
+
+/**
+ * 在 db.query span 里执行一条语句，记录耗时，超过阈值时打印慢查询日志
+ */
+async fn traced<T>(sql: &str, query: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    let statement = redact(sql);
+    let span = tracing::info_span!(
+        "db.query",
+        sql = %statement,
+        elapsed_ms = tracing::field::Empty
+    );
+    let started = Instant::now();
+    let result = query.instrument(span.clone()).await;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    span.record("elapsed_ms", elapsed_ms);
+    span.in_scope(|| {
+        if elapsed_ms >= SLOW_QUERY_MS.load(Ordering::Relaxed) {
+            tracing::warn!("slow query took {} ms", elapsed_ms);
+        } else {
+            tracing::debug!("query took {} ms", elapsed_ms);
+        }
+    });
+    result
+}
+
+/**
+ * 从一行查询结果构造结构体
+ * 使用 try_get 而不是 get：列不存在或者类型对不上时返回错误，而不是直接 panic
+ */
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, Error>;
+}
+
+type Params<'a> = &'a [&'a (dyn ToSql + Sync)];
+
+async fn fetch_all<T: FromRow>(
+    client: &impl GenericClient,
+    sql: &str,
+    params: Params<'_>,
+) -> Result<Vec<T>, Error> {
+    traced(sql, client.query(sql, params))
+        .await?
+        .iter()
+        .map(T::from_row)
+        .collect()
+}
+
+async fn fetch_opt<T: FromRow>(
+    client: &impl GenericClient,
+    sql: &str,
+    params: Params<'_>,
+) -> Result<Option<T>, Error> {
+    traced(sql, client.query_opt(sql, params))
+        .await?
+        .as_ref()
+        .map(T::from_row)
+        .transpose()
+}
+
+/**
+ * 逐行读取查询结果，不会把所有行都读进内存，span 里记录的耗时只到收到第一行为止
+ * 返回的 RowStream 不借用 client，但是读完之前连接不能还回连接池
+ */
+async fn fetch_stream(
+    client: &impl GenericClient,
+    sql: &str,
+    params: Params<'_>,
+) -> Result<RowStream, Error> {
+    traced(sql, client.query_raw(sql, params.iter().copied())).await
+}
+
+async fn fetch_one<T: FromRow>(
+    client: &impl GenericClient,
+    sql: &str,
+    params: Params<'_>,
+) -> Result<T, Error> {
+    T::from_row(&traced(sql, client.query_one(sql, params)).await?)
+}
+
+/**
+ * 带版本号的更新结果
+ * 版本号对不上时返回当前的版本号，调用方据此返回 412，让客户端重新读取之后再修改
+ */
+pub enum Versioned<T> {
+    Updated(T),
+    Conflict(i32),
+    NotFound,
+}
+
+/**
+ * 带版本号的更新没有修改任何一行时，区分是记录不存在还是版本号不一致
+ * table 只会是代码里写死的表名；按租户隔离的表需要传入 tenant，其它租户的记录算作不存在
+ */
+async fn versioned<T>(
+    client: &impl GenericClient,
+    table: &str,
+    tenant: Option<&str>,
+    id: i64,
+    updated: Option<T>,
+) -> Result<Versioned<T>, Error> {
+    if let Some(updated) = updated {
+        return Ok(Versioned::Updated(updated));
+    }
+    let row = match tenant {
+        Some(tenant) => {
+            let sql = format!(
+                "SELECT version FROM {} WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL",
+                table
+            );
+            traced(&sql, client.query_opt(&sql, &[&id, &tenant])).await?
+        }
+        None => {
+            let sql = format!(
+                "SELECT version FROM {} WHERE id = $1 AND deleted_at IS NULL",
+                table
+            );
+            traced(&sql, client.query_opt(&sql, &[&id])).await?
+        }
+    };
+    Ok(match row {
+        Some(row) => Versioned::Conflict(row.try_get("version")?),
+        None => Versioned::NotFound,
+    })
+}
+
+async fn execute(client: &impl GenericClient, sql: &str, params: Params<'_>) -> Result<u64, Error> {
+    traced(sql, client.execute(sql, params)).await
+}
+
+/*
+ * users
+ */
+
+const USER_COLUMNS: &str = "id, username, email, role, created_at, deleted_at, version, updated_at";
+
+/**
+ * 用户信息，不包含密码哈希等敏感字段，可以直接返回给客户端
+ */
+#[derive(Serialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub email: String,
+    pub role: String,
+    pub created_at: DateTime<Utc>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub version: i32,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl FromRow for User {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(User {
+            id: row.try_get("id")?,
+            username: row.try_get("username")?,
+            email: row.try_get("email")?,
+            role: row.try_get("role")?,
+            created_at: row.try_get("created_at")?,
+            deleted_at: row.try_get("deleted_at")?,
+            version: row.try_get("version")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+/**
+ * 创建和修改用户时写入的字段，修改时 password_hash 为 None 表示保留原密码
+ */
+pub struct UserFields<'a> {
+    pub username: &'a str,
+    pub email: &'a str,
+    pub role: &'a str,
+    pub password_hash: Option<&'a str>,
+}
+
+/**
+ * 用户列表允许的过滤和排序字段
+ */
+pub struct UserList;
+
+impl ListSpec for UserList {
+    const FIELDS: &'static [Field] = &[
+        Field {
+            name: "id",
+            column: "id",
+            ty: FieldType::Integer,
+            filter: false,
+            sort: true,
+        },
+        Field {
+            name: "username",
+            column: "username",
+            ty: FieldType::Text,
+            filter: true,
+            sort: true,
+        },
+        Field {
+            name: "email",
+            column: "email",
+            ty: FieldType::Text,
+            filter: true,
+            sort: false,
+        },
+        Field {
+            name: "role",
+            column: "role",
+            ty: FieldType::Text,
+            filter: true,
+            sort: true,
+        },
+        Field {
+            name: "created_at",
+            column: "created_at",
+            ty: FieldType::Text,
+            filter: false,
+            sort: true,
+        },
+    ];
+    const DEFAULT_SORT: &'static str = "id";
+}
+
+/**
+ * include_deleted 为 true 时也返回已删除的用户
+ */
+pub async fn list_users(
+    client: &impl GenericClient,
+    include_deleted: bool,
+    modified_since: Option<DateTime<Utc>>,
+    listing: &Listing<UserList>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<User>, Error> {
+    let params: Vec<&(dyn ToSql + Sync)> =
+        [&include_deleted as &(dyn ToSql + Sync), &modified_since]
+            .into_iter()
+            .chain(listing.params())
+            .chain([&limit as _, &offset as _])
+            .collect();
+    let next = listing.param_count() + 3;
+    fetch_all(
+        client,
+        &format!(
+            "SELECT {} FROM users WHERE ($1 OR deleted_at IS NULL) AND ($2::timestamptz IS NULL OR updated_at > $2){} ORDER BY {} LIMIT ${} OFFSET ${}",
+            USER_COLUMNS,
+            listing.conditions(3),
+            listing.order_by(),
+            next,
+            next + 1
+        ),
+        &params,
+    )
+    .await
+}
+
+/**
+ * 和 list_users 的条件相同，但是不分页，用于导出
+ */
+pub async fn stream_users(
+    client: &impl GenericClient,
+    include_deleted: bool,
+    modified_since: Option<DateTime<Utc>>,
+    listing: &Listing<UserList>,
+) -> Result<RowStream, Error> {
+    let params: Vec<&(dyn ToSql + Sync)> =
+        [&include_deleted as &(dyn ToSql + Sync), &modified_since]
+            .into_iter()
+            .chain(listing.params())
+            .collect();
+    fetch_stream(
+        client,
+        &format!(
+            "SELECT {} FROM users WHERE ($1 OR deleted_at IS NULL) AND ($2::timestamptz IS NULL OR updated_at > $2){} ORDER BY {}",
+            USER_COLUMNS,
+            listing.conditions(3),
+            listing.order_by(),
+        ),
+        &params,
+    )
+    .await
+}
+
+pub async fn count_users(
+    client: &impl GenericClient,
+    include_deleted: bool,
+    modified_since: Option<DateTime<Utc>>,
+    listing: &Listing<UserList>,
+) -> Result<i64, Error> {
+    let params: Vec<&(dyn ToSql + Sync)> =
+        [&include_deleted as &(dyn ToSql + Sync), &modified_since]
+            .into_iter()
+            .chain(listing.params())
+            .collect();
+    let sql = format!(
+        "SELECT count(*) FROM users WHERE ($1 OR deleted_at IS NULL) AND ($2::timestamptz IS NULL OR updated_at > $2){}",
+        listing.conditions(3)
+    );
+    traced(&sql, client.query_one(&sql, &params))
+        .await?
+        .try_get(0)
+}
+
+pub async fn find_user(
+    client: &impl GenericClient,
+    id: i64,
+    include_deleted: bool,
+) -> Result<Option<User>, Error> {
+    fetch_opt(
+        client,
+        &format!(
+            "SELECT {} FROM users WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+            USER_COLUMNS
+        ),
+        &[&id, &include_deleted],
+    )
+    .await
+}
+
+pub async fn insert_user(
+    client: &impl GenericClient,
+    fields: &UserFields<'_>,
+) -> Result<User, Error> {
+    fetch_one(
+        client,
+        &format!(
+            "INSERT INTO users (username, email, role, password_hash) VALUES ($1, $2, $3, $4)
+             RETURNING {}",
+            USER_COLUMNS
+        ),
+        &[
+            &fields.username,
+            &fields.email,
+            &fields.role,
+            &fields.password_hash,
+        ],
+    )
+    .await
+}
+
+/**
+ * 批量插入用户，一条语句插入所有行，fields 的 password_hash 不能为空
+ * 用户名已经存在的行会被跳过，返回实际插入的用户名
+ */
+pub async fn insert_users(
+    client: &impl GenericClient,
+    fields: &[UserFields<'_>],
+) -> Result<Vec<String>, Error> {
+    let usernames: Vec<&str> = fields.iter().map(|fields| fields.username).collect();
+    let emails: Vec<&str> = fields.iter().map(|fields| fields.email).collect();
+    let roles: Vec<&str> = fields.iter().map(|fields| fields.role).collect();
+    let password_hashes: Vec<&str> = fields
+        .iter()
+        .map(|fields| fields.password_hash.unwrap_or_default())
+        .collect();
+    let sql = "INSERT INTO users (username, email, role, password_hash)
+         SELECT * FROM unnest($1::text[], $2::text[], $3::text[], $4::text[])
+         ON CONFLICT (username) DO NOTHING
+         RETURNING username";
+    let rows = traced(
+        sql,
+        client.query(sql, &[&usernames, &emails, &roles, &password_hashes]),
+    )
+    .await?;
+    rows.iter().map(|row| row.try_get(0)).collect()
+}
+
+/**
+ * 可以分配给用户的角色：role_permissions 里出现过的角色，加上没有任何权限的默认角色 user
+ */
+pub async fn list_roles(client: &impl GenericClient) -> Result<Vec<String>, Error> {
+    let sql = "SELECT 'user' UNION SELECT DISTINCT role FROM role_permissions";
+    let rows = traced(sql, client.query(sql, &[])).await?;
+    rows.iter().map(|row| row.try_get(0)).collect()
+}
+
+pub async fn update_user(
+    client: &impl GenericClient,
+    id: i64,
+    version: i32,
+    fields: &UserFields<'_>,
+) -> Result<Versioned<User>, Error> {
+    let updated = fetch_opt(
+        client,
+        &format!(
+            "UPDATE users SET username = $2, email = $3, role = $4,
+                 password_hash = COALESCE($5, password_hash), version = version + 1
+             WHERE id = $1 AND version = $6 AND deleted_at IS NULL RETURNING {}",
+            USER_COLUMNS
+        ),
+        &[
+            &id,
+            &fields.username,
+            &fields.email,
+            &fields.role,
+            &fields.password_hash,
+            &version,
+        ],
+    )
+    .await?;
+    versioned(client, "users", None, id, updated).await
+}
+
+/**
+ * 软删除用户，同时作废该用户所有的 refresh token，返回被删除的用户名
+ * 其它表里引用了该用户的数据都保留下来，恢复之后和删除之前一样
+ * 涉及多条语句，调用方需要传入事务（或者 Tx）
+ */
+pub async fn delete_user(client: &impl GenericClient, id: i64) -> Result<Option<String>, Error> {
+    execute(
+        client,
+        "UPDATE refresh_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+        &[&id],
+    )
+    .await?;
+    let sql = "UPDATE users SET deleted_at = now(), version = version + 1
+               WHERE id = $1 AND deleted_at IS NULL RETURNING username";
+    traced(sql, client.query_opt(sql, &[&id]))
+        .await?
+        .map(|row| row.try_get("username"))
+        .transpose()
+}
+
+/**
+ * 恢复已删除的用户，用户不存在或者没有被删除时返回 None
+ */
+pub async fn restore_user(client: &impl GenericClient, id: i64) -> Result<Option<User>, Error> {
+    fetch_opt(
+        client,
+        &format!(
+            "UPDATE users SET deleted_at = NULL, version = version + 1
+             WHERE id = $1 AND deleted_at IS NOT NULL RETURNING {}",
+            USER_COLUMNS
+        ),
+        &[&id],
+    )
+    .await
+}
+
+/*
+ * todos
+ */
+
+#[derive(Serialize)]
+pub struct Todo {
+    pub id: i64,
+    pub title: String,
+    pub done: bool,
+    pub created_at: DateTime<Utc>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub version: i32,
+    pub updated_at: DateTime<Utc>,
+    // 同步用的变更序号，见 sync 模块
+    pub seq: i64,
+}
+
+impl FromRow for Todo {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(Todo {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            done: row.try_get("done")?,
+            created_at: row.try_get("created_at")?,
+            deleted_at: row.try_get("deleted_at")?,
+            version: row.try_get("version")?,
+            updated_at: row.try_get("updated_at")?,
+            seq: row.try_get("seq")?,
+        })
+    }
+}
+
+const TODO_COLUMNS: &str = "id, title, done, created_at, deleted_at, version, updated_at, seq";
+
+/**
+ * 待办事项列表允许的过滤和排序字段
+ */
+pub struct TodoList;
+
+impl ListSpec for TodoList {
+    const FIELDS: &'static [Field] = &[
+        Field {
+            name: "id",
+            column: "id",
+            ty: FieldType::Integer,
+            filter: false,
+            sort: true,
+        },
+        Field {
+            name: "title",
+            column: "title",
+            ty: FieldType::Text,
+            filter: false,
+            sort: true,
+        },
+        Field {
+            name: "done",
+            column: "done",
+            ty: FieldType::Boolean,
+            filter: true,
+            sort: true,
+        },
+        Field {
+            name: "created_at",
+            column: "created_at",
+            ty: FieldType::Text,
+            filter: false,
+            sort: true,
+        },
+    ];
+    const DEFAULT_SORT: &'static str = "id";
+}
+
+/**
+ * include_deleted 为 true 时也返回已删除的待办事项
+ */
+pub async fn list_todos(
+    client: &impl GenericClient,
+    tenant: &str,
+    include_deleted: bool,
+    modified_since: Option<DateTime<Utc>>,
+    listing: &Listing<TodoList>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Todo>, Error> {
+    let params: Vec<&(dyn ToSql + Sync)> = [
+        &tenant as &(dyn ToSql + Sync),
+        &include_deleted,
+        &modified_since,
+    ]
+    .into_iter()
+    .chain(listing.params())
+    .chain([&limit as _, &offset as _])
+    .collect();
+    let next = listing.param_count() + 4;
+    fetch_all(
+        client,
+        &format!(
+            "SELECT {} FROM todos WHERE tenant_id = $1 AND ($2 OR deleted_at IS NULL) AND ($3::timestamptz IS NULL OR updated_at > $3){} ORDER BY {} LIMIT ${} OFFSET ${}",
+            TODO_COLUMNS,
+            listing.conditions(4),
+            listing.order_by(),
+            next,
+            next + 1
+        ),
+        &params,
+    )
+    .await
+}
+
+pub async fn count_todos(
+    client: &impl GenericClient,
+    tenant: &str,
+    include_deleted: bool,
+    modified_since: Option<DateTime<Utc>>,
+    listing: &Listing<TodoList>,
+) -> Result<i64, Error> {
+    let params: Vec<&(dyn ToSql + Sync)> = [
+        &tenant as &(dyn ToSql + Sync),
+        &include_deleted,
+        &modified_since,
+    ]
+    .into_iter()
+    .chain(listing.params())
+    .collect();
+    let sql = format!(
+        "SELECT count(*) FROM todos WHERE tenant_id = $1 AND ($2 OR deleted_at IS NULL) AND ($3::timestamptz IS NULL OR updated_at > $3){}",
+        listing.conditions(4)
+    );
+    traced(&sql, client.query_one(&sql, &params))
+        .await?
+        .try_get(0)
+}
+
+/**
+ * 和 list_todos 的条件相同，逐行读取，用于导出
+ */
+pub async fn stream_todos(
+    client: &impl GenericClient,
+    tenant: &str,
+    include_deleted: bool,
+    modified_since: Option<DateTime<Utc>>,
+    listing: &Listing<TodoList>,
+) -> Result<RowStream, Error> {
+    let params: Vec<&(dyn ToSql + Sync)> = [
+        &tenant as &(dyn ToSql + Sync),
+        &include_deleted,
+        &modified_since,
+    ]
+    .into_iter()
+    .chain(listing.params())
+    .collect();
+    fetch_stream(
+        client,
+        &format!(
+            "SELECT {} FROM todos WHERE tenant_id = $1 AND ($2 OR deleted_at IS NULL) AND ($3::timestamptz IS NULL OR updated_at > $3){} ORDER BY {}",
+            TODO_COLUMNS,
+            listing.conditions(4),
+            listing.order_by()
+        ),
+        &params,
+    )
+    .await
+}
+
+/**
+ * sitemap 里列出的待办事项的 id 和修改时间，最近修改的在前面，不包括已删除的
+ */
+pub async fn sitemap_todos(
+    client: &impl GenericClient,
+    tenant: &str,
+    limit: i64,
+) -> Result<Vec<(i64, DateTime<Utc>)>, Error> {
+    let sql = "SELECT id, updated_at FROM todos
+               WHERE tenant_id = $1 AND deleted_at IS NULL
+               ORDER BY updated_at DESC LIMIT $2";
+    let rows = traced(sql, client.query(sql, &[&tenant, &limit])).await?;
+    rows.iter()
+        .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+        .collect()
+}
+
+/**
+ * 最近创建的待办事项，不包括已删除的
+ */
+pub async fn latest_todos(
+    client: &impl GenericClient,
+    tenant: &str,
+    limit: i64,
+) -> Result<Vec<Todo>, Error> {
+    fetch_all(
+        client,
+        &format!(
+            "SELECT {} FROM todos WHERE tenant_id = $1 AND deleted_at IS NULL
+             ORDER BY created_at DESC, id DESC LIMIT $2",
+            TODO_COLUMNS
+        ),
+        &[&tenant, &limit],
+    )
+    .await
+}
+
+pub async fn find_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+) -> Result<Option<Todo>, Error> {
+    fetch_opt(
+        client,
+        &format!(
+            "SELECT {} FROM todos WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL",
+            TODO_COLUMNS
+        ),
+        &[&id, &tenant],
+    )
+    .await
+}
+
+pub async fn insert_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    title: &str,
+    owner_id: i64,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "INSERT INTO todos (tenant_id, title, owner_id) VALUES ($1, $2, $3)",
+        &[&tenant, &title, &owner_id],
+    )
+    .await?;
+    Ok(())
+}
+
+/**
+ * 待办事项的创建者，用于判断能否修改，不存在时返回 None，没有创建者时是 Some(None)
+ */
+pub async fn todo_owner(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+) -> Result<Option<Option<i64>>, Error> {
+    let sql = "SELECT owner_id FROM todos WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL";
+    match traced(sql, client.query_opt(sql, &[&id, &tenant])).await? {
+        Some(row) => Ok(Some(row.try_get(0)?)),
+        None => Ok(None),
+    }
+}
+
+/*
+ * 下面几个修改操作中，已删除的记录和不存在一样
+ * 切换状态和删除不需要版本号，返回是否找到了对应的记录
+ */
+
+pub async fn update_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+    version: i32,
+    title: &str,
+    done: bool,
+) -> Result<Versioned<Todo>, Error> {
+    let updated = fetch_opt(
+        client,
+        &format!(
+            "UPDATE todos SET title = $2, done = $3, version = version + 1
+             WHERE id = $1 AND version = $4 AND tenant_id = $5 AND deleted_at IS NULL
+             RETURNING {}",
+            TODO_COLUMNS
+        ),
+        &[&id, &title, &done, &version, &tenant],
+    )
+    .await?;
+    versioned(client, "todos", Some(tenant), id, updated).await
+}
+
+pub async fn toggle_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+) -> Result<bool, Error> {
+    let updated = execute(
+        client,
+        "UPDATE todos SET done = NOT done, version = version + 1
+         WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL",
+        &[&id, &tenant],
+    )
+    .await?;
+    Ok(updated > 0)
+}
+
+pub async fn delete_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+) -> Result<bool, Error> {
+    let deleted = execute(
+        client,
+        "UPDATE todos SET deleted_at = now(), version = version + 1
+         WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL",
+        &[&id, &tenant],
+    )
+    .await?;
+    Ok(deleted > 0)
+}
+
+/**
+ * 恢复已删除的待办事项，不存在或者没有被删除时返回 None
+ */
+pub async fn restore_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+) -> Result<Option<Todo>, Error> {
+    fetch_opt(
+        client,
+        &format!(
+            "UPDATE todos SET deleted_at = NULL, version = version + 1
+             WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NOT NULL RETURNING {}",
+            TODO_COLUMNS
+        ),
+        &[&id, &tenant],
+    )
+    .await
+}
+
+/*
+ * 离线同步用到的查询，已删除的记录也会返回，客户端据此删除本地的数据
+ */
+
+/**
+ * seq 大于 since 的变化，按 seq 排序，多查一条用来判断后面还有没有
+ */
+pub async fn todo_changes(
+    client: &impl GenericClient,
+    tenant: &str,
+    since: i64,
+    limit: i64,
+) -> Result<Vec<Todo>, Error> {
+    fetch_all(
+        client,
+        &format!(
+            "SELECT {} FROM todos WHERE tenant_id = $1 AND seq > $2 ORDER BY seq LIMIT $3",
+            TODO_COLUMNS
+        ),
+        &[&tenant, &since, &limit],
+    )
+    .await
+}
+
+/**
+ * 锁住要修改的记录，同一个事务里判断冲突和写入之间不会被别人修改
+ */
+pub async fn lock_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+) -> Result<Option<Todo>, Error> {
+    fetch_opt(
+        client,
+        &format!(
+            "SELECT {} FROM todos WHERE id = $1 AND tenant_id = $2 FOR UPDATE",
+            TODO_COLUMNS
+        ),
+        &[&id, &tenant],
+    )
+    .await
+}
+
+/**
+ * 客户端离线创建的记录，client_id 已经存在时（重复提交）返回已有的那条
+ */
+pub async fn insert_synced_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    client_id: &str,
+    title: &str,
+    done: bool,
+    updated_at: DateTime<Utc>,
+    owner_id: i64,
+) -> Result<Todo, Error> {
+    let inserted = fetch_opt(
+        client,
+        &format!(
+            "INSERT INTO todos (tenant_id, client_id, title, done, updated_at, owner_id)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (tenant_id, client_id) WHERE client_id IS NOT NULL DO NOTHING
+             RETURNING {}",
+            TODO_COLUMNS
+        ),
+        &[&tenant, &client_id, &title, &done, &updated_at, &owner_id],
+    )
+    .await?;
+    match inserted {
+        Some(todo) => Ok(todo),
+        None => {
+            fetch_one(
+                client,
+                &format!(
+                    "SELECT {} FROM todos WHERE tenant_id = $1 AND client_id = $2",
+                    TODO_COLUMNS
+                ),
+                &[&tenant, &client_id],
+            )
+            .await
+        }
+    }
+}
+
+/**
+ * 写入冲突处理之后的结果，deleted 为 true 时同时标记为已删除
+ */
+pub async fn write_synced_todo(
+    client: &impl GenericClient,
+    tenant: &str,
+    id: i64,
+    title: &str,
+    done: bool,
+    deleted: bool,
+    updated_at: DateTime<Utc>,
+) -> Result<Todo, Error> {
+    fetch_one(
+        client,
+        &format!(
+            "UPDATE todos SET title = $3, done = $4, updated_at = $5, version = version + 1,
+                 deleted_at = CASE WHEN $6 THEN coalesce(deleted_at, now()) END
+             WHERE id = $1 AND tenant_id = $2 RETURNING {}",
+            TODO_COLUMNS
+        ),
+        &[&id, &tenant, &title, &done, &updated_at, &deleted],
+    )
+    .await
+}
+
+/**
+ * 全文检索的一条结果，snippet 是 ts_headline 生成的片段，匹配的词两边是 \u{2} 和 \u{3}，
+ * 由调用方转义 HTML 之后再替换成高亮标签，避免标题里的内容被当成 HTML
+ */
+pub struct SearchHit {
+    pub id: i64,
+    pub title: String,
+    pub done: bool,
+    pub rank: f32,
+    pub snippet: String,
+}
+
+impl FromRow for SearchHit {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(SearchHit {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            done: row.try_get("done")?,
+            rank: row.try_get("rank")?,
+            snippet: row.try_get("snippet")?,
+        })
+    }
+}
+
+/**
+ * 按相关度搜索未删除的待办事项，query 使用 websearch_to_tsquery 的语法（"短语"、or、-排除）
+ */
+pub async fn search_todos(
+    client: &impl GenericClient,
+    tenant: &str,
+    query: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<SearchHit>, Error> {
+    fetch_all(
+        client,
+        "SELECT id, title, done, ts_rank(search, q) AS rank,
+                ts_headline('simple', title, q, 'StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS snippet
+         FROM todos, websearch_to_tsquery('simple', $1) q
+         WHERE tenant_id = $2 AND deleted_at IS NULL AND search @@ q
+         ORDER BY rank DESC, id LIMIT $3 OFFSET $4",
+        &[&query, &tenant, &limit, &offset],
+    )
+    .await
+}
+
+pub async fn count_search_todos(
+    client: &impl GenericClient,
+    tenant: &str,
+    query: &str,
+) -> Result<i64, Error> {
+    let sql = "SELECT count(*) FROM todos, websearch_to_tsquery('simple', $1) q
+               WHERE tenant_id = $2 AND deleted_at IS NULL AND search @@ q";
+    traced(sql, client.query_one(sql, &[&query, &tenant]))
+        .await?
+        .try_get(0)
+}
+
+/*
+ * jobs
+ */
+
+/**
+ * 任务和它的结果，结果过期被删除之后 result 为空，result_expired 为 true
+ */
+#[derive(Serialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: String,
+    pub owner_id: i64,
+    pub status: String,
+    pub result_type: Option<String>,
+    pub result: Option<Value>,
+    pub result_expires_at: Option<DateTime<Utc>>,
+    pub result_expired: bool,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for Job {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        let status: String = row.try_get("status")?;
+        let result: Option<Value> = row.try_get("result")?;
+        Ok(Job {
+            id: row.try_get("id")?,
+            kind: row.try_get("kind")?,
+            owner_id: row.try_get("owner_id")?,
+            result_expired: status == "succeeded" && result.is_none(),
+            status,
+            result_type: row.try_get("result_type")?,
+            result,
+            result_expires_at: row.try_get("result_expires_at")?,
+            error: row.try_get("error")?,
+            created_at: row.try_get("created_at")?,
+            finished_at: row.try_get("finished_at")?,
+        })
+    }
+}
+
+pub async fn insert_job(
+    client: &impl GenericClient,
+    id: Uuid,
+    kind: &str,
+    owner_id: i64,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "INSERT INTO jobs (id, kind, owner_id) VALUES ($1, $2, $3)",
+        &[&id, &kind, &owner_id],
+    )
+    .await?;
+    Ok(())
+}
+
+/**
+ * 更新任务状态，状态变为 succeeded 或 failed 时同时记录完成时间
+ */
+pub async fn set_job_status(
+    client: &impl GenericClient,
+    id: Uuid,
+    status: &str,
+    error: Option<String>,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "UPDATE jobs SET status = $2, error = $3,
+                 finished_at = CASE WHEN $2 IN ('succeeded', 'failed') THEN now() END
+             WHERE id = $1",
+        &[&id, &status, &error],
+    )
+    .await?;
+    Ok(())
+}
+
+/**
+ * 保存任务的结果，ttl 之后过期
+ */
+pub async fn insert_job_result(
+    client: &impl GenericClient,
+    id: Uuid,
+    result_type: &str,
+    result: &Value,
+    ttl: Duration,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "INSERT INTO job_results (job_id, result_type, result, expires_at)
+             VALUES ($1, $2, $3, now() + make_interval(secs => $4))",
+        &[&id, &result_type, result, &ttl.as_secs_f64()],
+    )
+    .await?;
+    Ok(())
+}
+
+/**
+ * 在频道上发出 NOTIFY，事务里调用时提交之后才会发出
+ */
+pub async fn notify(
+    client: &impl GenericClient,
+    channel: &str,
+    payload: &str,
+) -> Result<(), Error> {
+    execute(client, "SELECT pg_notify($1, $2)", &[&channel, &payload]).await?;
+    Ok(())
+}
+
+/**
+ * 删除过期的任务结果，返回删除的条数
+ */
+pub async fn prune_job_results(client: &impl GenericClient) -> Result<u64, Error> {
+    execute(
+        client,
+        "DELETE FROM job_results WHERE expires_at < now()",
+        &[],
+    )
+    .await
+}
+
+/**
+ * 把所有未完成的任务标记为失败，返回受影响的任务数
+ */
+pub async fn fail_unfinished_jobs(client: &impl GenericClient, error: &str) -> Result<u64, Error> {
+    execute(
+        client,
+        "UPDATE jobs SET status = 'failed', error = $1, finished_at = now()
+             WHERE status IN ('pending', 'running')",
+        &[&error],
+    )
+    .await
+}
+
+/**
+ * 任务只有创建者本人可以查看，其他人查询时和任务不存在一样返回 None
+ */
+pub async fn find_job(
+    client: &impl GenericClient,
+    id: Uuid,
+    owner_id: i64,
+) -> Result<Option<Job>, Error> {
+    fetch_opt(
+        client,
+        "SELECT j.id, j.kind, j.owner_id, j.status, j.error, j.created_at, j.finished_at,
+                r.result_type, r.result, r.expires_at AS result_expires_at
+         FROM jobs j
+         LEFT JOIN job_results r ON r.job_id = j.id AND r.expires_at > now()
+         WHERE j.id = $1 AND j.owner_id = $2",
+        &[&id, &owner_id],
+    )
+    .await
+}
+
+/*
+ * audit_log
+ */
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor_id: Option<i64>,
+    pub actor: String,
+    pub ip: Option<String>,
+    pub route: String,
+    pub action: String,
+    pub payload: Value,
+    pub impersonator_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl FromRow for AuditEntry {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(AuditEntry {
+            id: row.try_get("id")?,
+            actor_id: row.try_get("actor_id")?,
+            actor: row.try_get("actor")?,
+            ip: row.try_get("ip")?,
+            route: row.try_get("route")?,
+            action: row.try_get("action")?,
+            payload: row.try_get("payload")?,
+            impersonator_id: row.try_get("impersonator_id")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+/**
+ * 审计日志的过滤条件，字段为 None 时不过滤
+ * `$1 IS NULL OR ...` 的写法会让该条件恒为真，避免手动拼接 SQL
+ */
+pub struct AuditFilter<'a> {
+    pub action: Option<&'a str>,
+    pub actor_id: Option<i64>,
+    // 时间范围 [since, until)，audit_log 按 created_at 分区，只会扫描范围内的分区
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+pub async fn count_audit_log(
+    client: &impl GenericClient,
+    filter: &AuditFilter<'_>,
+) -> Result<i64, Error> {
+    let sql = "SELECT count(*) FROM audit_log
+         WHERE ($1::TEXT IS NULL OR action = $1) AND ($2::BIGINT IS NULL OR actor_id = $2)
+           AND created_at >= COALESCE($3::TIMESTAMPTZ, '-infinity') AND created_at < COALESCE($4::TIMESTAMPTZ, 'infinity')";
+    traced(
+        sql,
+        client.query_one(
+            sql,
+            &[
+                &filter.action,
+                &filter.actor_id,
+                &filter.since,
+                &filter.until,
+            ],
+        ),
+    )
+    .await?
+    .try_get(0)
+}
+
+pub async fn list_audit_log(
+    client: &impl GenericClient,
+    filter: &AuditFilter<'_>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AuditEntry>, Error> {
+    fetch_all(
+        client,
+        "SELECT id, actor_id, actor, ip, route, action, payload, impersonator_id, created_at
+         FROM audit_log
+         WHERE ($1::TEXT IS NULL OR action = $1) AND ($2::BIGINT IS NULL OR actor_id = $2)
+           AND created_at >= COALESCE($3::TIMESTAMPTZ, '-infinity') AND created_at < COALESCE($4::TIMESTAMPTZ, 'infinity')
+         ORDER BY id DESC LIMIT $5 OFFSET $6",
+        &[
+            &filter.action,
+            &filter.actor_id,
+            &filter.since,
+            &filter.until,
+            &limit,
+            &offset,
+        ],
+    )
+    .await
+}
+
+/**
+ * 游标分页：返回 id 小于 before 的记录（before 为 None 时从最新的开始），按 id 从新到旧排列
+ */
+pub async fn list_audit_log_before(
+    client: &impl GenericClient,
+    filter: &AuditFilter<'_>,
+    before: Option<i64>,
+    limit: i64,
+) -> Result<Vec<AuditEntry>, Error> {
+    fetch_all(
+        client,
+        "SELECT id, actor_id, actor, ip, route, action, payload, impersonator_id, created_at
+         FROM audit_log
+         WHERE ($1::TEXT IS NULL OR action = $1) AND ($2::BIGINT IS NULL OR actor_id = $2)
+           AND created_at >= COALESCE($3::TIMESTAMPTZ, '-infinity') AND created_at < COALESCE($4::TIMESTAMPTZ, 'infinity')
+           AND ($5::BIGINT IS NULL OR id < $5)
+         ORDER BY id DESC LIMIT $6",
+        &[
+            &filter.action,
+            &filter.actor_id,
+            &filter.since,
+            &filter.until,
+            &before,
+            &limit,
+        ],
+    )
+    .await
+}
+
+/**
+ * 按 action 汇总的审计日志统计
+ */
+#[derive(Serialize)]
+pub struct ActionStats {
+    pub action: String,
+    pub count: i64,
+    pub actors: i64,
+    pub last_at: DateTime<Utc>,
+}
+
+impl FromRow for ActionStats {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(ActionStats {
+            action: row.try_get("action")?,
+            count: row.try_get("count")?,
+            actors: row.try_get("actors")?,
+            last_at: row.try_get("last_at")?,
+        })
+    }
+}
+
+pub async fn audit_log_stats(client: &impl GenericClient) -> Result<Vec<ActionStats>, Error> {
+    fetch_all(
+        client,
+        "SELECT action, count(*) AS count, count(DISTINCT actor_id) AS actors, max(created_at) AS last_at
+         FROM audit_log GROUP BY action ORDER BY count(*) DESC",
+        &[],
+    )
+    .await
+}
+
+/**
+ * 早于 before 的审计日志，按 id 从旧到新排列，用于归档
+ */
+pub async fn list_audit_log_older_than(
+    client: &impl GenericClient,
+    before: DateTime<Utc>,
+    limit: i64,
+) -> Result<Vec<AuditEntry>, Error> {
+    fetch_all(
+        client,
+        "SELECT id, actor_id, actor, ip, route, action, payload, impersonator_id, created_at
+         FROM audit_log WHERE created_at < $1
+         ORDER BY id LIMIT $2",
+        &[&before, &limit],
+    )
+    .await
+}
+
+pub async fn delete_audit_log(client: &impl GenericClient, ids: &[i64]) -> Result<u64, Error> {
+    execute(client, "DELETE FROM audit_log WHERE id = ANY($1)", &[&ids]).await
+}
+
+/*
+ * 分区表，见 db::partitions
+ */
+
+/**
+ * table 当前挂着的分区名（不包括已经卸下的），按名字排序
+ */
+pub async fn list_partitions(
+    client: &impl GenericClient,
+    table: &str,
+) -> Result<Vec<String>, Error> {
+    let sql = "SELECT c.relname::TEXT FROM pg_inherits i
+         JOIN pg_class c ON c.oid = i.inhrelid
+         JOIN pg_class p ON p.oid = i.inhparent
+         WHERE p.relname = $1 AND p.relnamespace = current_schema()::regnamespace
+         ORDER BY c.relname";
+    let rows = traced(sql, client.query(sql, &[&table])).await?;
+    rows.iter().map(|row| row.try_get(0)).collect()
+}
+
+/**
+ * 创建 [from, to) 范围的分区，把默认分区里落在这个范围的记录搬进去，再挂到 table 上，返回搬过去的条数
+ * 表名和列名直接拼进 SQL，只能传代码里的常量；需要在事务里调用
+ */
+pub async fn create_partition(
+    client: &impl GenericClient,
+    table: &str,
+    column: &str,
+    name: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<u64, Error> {
+    let default = format!("{}_default", table);
+    execute(
+        client,
+        &format!(
+            "CREATE TABLE \"{}\" (LIKE \"{}\" INCLUDING DEFAULTS INCLUDING CONSTRAINTS)",
+            name, table
+        ),
+        &[],
+    )
+    .await?;
+    let moved = execute(
+        client,
+        &format!(
+            "WITH moved AS (DELETE FROM \"{default}\" WHERE \"{column}\" >= $1 AND \"{column}\" < $2 RETURNING *)
+             INSERT INTO \"{name}\" SELECT * FROM moved"
+        ),
+        &[&from, &to],
+    )
+    .await?;
+    // DDL 里的分区范围不能用参数
+    execute(
+        client,
+        &format!(
+            "ALTER TABLE \"{}\" ATTACH PARTITION \"{}\" FOR VALUES FROM ('{}') TO ('{}')",
+            table,
+            name,
+            from.to_rfc3339(),
+            to.to_rfc3339()
+        ),
+        &[],
+    )
+    .await?;
+    Ok(moved)
+}
+
+/**
+ * 把分区从 table 上卸下，之后查询 table 不会再看到里面的记录；分区本身变成普通的表，返回其中还有多少条记录
+ */
+pub async fn detach_partition(
+    client: &impl GenericClient,
+    table: &str,
+    name: &str,
+) -> Result<i64, Error> {
+    execute(
+        client,
+        &format!("ALTER TABLE \"{}\" DETACH PARTITION \"{}\"", table, name),
+        &[],
+    )
+    .await?;
+    let sql = format!("SELECT count(*) FROM \"{}\"", name);
+    traced(&sql, client.query_one(&sql, &[])).await?.try_get(0)
+}
+
+pub async fn drop_table(client: &impl GenericClient, name: &str) -> Result<u64, Error> {
+    execute(client, &format!("DROP TABLE \"{}\"", name), &[]).await
+}
+
+/*
+ * audit_archives
+ */
+
+#[derive(Debug, Serialize)]
+pub struct AuditArchive {
+    pub bucket: String,
+    pub object_key: String,
+    pub first_id: i64,
+    pub last_id: i64,
+    pub row_count: i64,
+    pub bytes: i64,
+    pub sha256: String,
+    pub first_at: DateTime<Utc>,
+    pub last_at: DateTime<Utc>,
+}
+
+impl FromRow for AuditArchive {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(AuditArchive {
+            bucket: row.try_get("bucket")?,
+            object_key: row.try_get("object_key")?,
+            first_id: row.try_get("first_id")?,
+            last_id: row.try_get("last_id")?,
+            row_count: row.try_get("row_count")?,
+            bytes: row.try_get("bytes")?,
+            sha256: row.try_get("sha256")?,
+            first_at: row.try_get("first_at")?,
+            last_at: row.try_get("last_at")?,
+        })
+    }
+}
+
+/**
+ * 记录一个归档文件，同一个对象重新上传时（上次上传之后没来得及删除数据库里的行）覆盖原来的记录
+ */
+pub async fn upsert_audit_archive(
+    client: &impl GenericClient,
+    archive: &AuditArchive,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "INSERT INTO audit_archives
+             (bucket, object_key, first_id, last_id, row_count, bytes, sha256, first_at, last_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+         ON CONFLICT (object_key) DO UPDATE SET
+             bucket = EXCLUDED.bucket, first_id = EXCLUDED.first_id, last_id = EXCLUDED.last_id,
+             row_count = EXCLUDED.row_count, bytes = EXCLUDED.bytes, sha256 = EXCLUDED.sha256,
+             first_at = EXCLUDED.first_at, last_at = EXCLUDED.last_at, created_at = now()",
+        &[
+            &archive.bucket,
+            &archive.object_key,
+            &archive.first_id,
+            &archive.last_id,
+            &archive.row_count,
+            &archive.bytes,
+            &archive.sha256,
+            &archive.first_at,
+            &archive.last_at,
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+/**
+ * 按时间从新到旧列出归档文件，since 不为空时只返回包含这个时间之后的记录的文件
+ */
+pub async fn list_audit_archives(
+    client: &impl GenericClient,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AuditArchive>, Error> {
+    fetch_all(
+        client,
+        "SELECT bucket, object_key, first_id, last_id, row_count, bytes, sha256, first_at, last_at
+         FROM audit_archives
+         WHERE ($1::TIMESTAMPTZ IS NULL OR last_at >= $1)
+         ORDER BY first_at DESC LIMIT $2 OFFSET $3",
+        &[&since, &limit, &offset],
+    )
+    .await
+}
+
+pub async fn count_audit_archives(
+    client: &impl GenericClient,
+    since: Option<DateTime<Utc>>,
+) -> Result<i64, Error> {
+    let sql =
+        "SELECT count(*) FROM audit_archives WHERE ($1::TIMESTAMPTZ IS NULL OR last_at >= $1)";
+    traced(sql, client.query_one(sql, &[&since]))
+        .await?
+        .try_get(0)
+}
+
+/*
+ * push_subscriptions
+ */
+
+pub struct PushSubscription {
+    pub id: i64,
+    pub endpoint: String,
+}
+
+impl FromRow for PushSubscription {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(PushSubscription {
+            id: row.try_get("id")?,
+            endpoint: row.try_get("endpoint")?,
+        })
+    }
+}
+
+/**
+ * 同一个 endpoint 再次订阅时更新密钥和所属用户（比如浏览器里换了一个账号登录）
+ */
+pub async fn save_push_subscription(
+    client: &impl GenericClient,
+    user_id: i64,
+    endpoint: &str,
+    p256dh: &str,
+    auth: &str,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "INSERT INTO push_subscriptions (user_id, endpoint, p256dh, auth) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (endpoint) DO UPDATE
+         SET user_id = EXCLUDED.user_id, p256dh = EXCLUDED.p256dh, auth = EXCLUDED.auth",
+        &[&user_id, &endpoint, &p256dh, &auth],
+    )
+    .await?;
+    Ok(())
+}
+
+/**
+ * 用户取消自己的订阅，返回是否找到了对应的订阅
+ */
+pub async fn delete_push_subscription(
+    client: &impl GenericClient,
+    user_id: i64,
+    endpoint: &str,
+) -> Result<bool, Error> {
+    let deleted = execute(
+        client,
+        "DELETE FROM push_subscriptions WHERE user_id = $1 AND endpoint = $2",
+        &[&user_id, &endpoint],
+    )
+    .await?;
+    Ok(deleted > 0)
+}
+
+/**
+ * 需要推送的订阅，已删除用户的订阅不再推送
+ */
+pub async fn list_push_subscriptions(
+    client: &impl GenericClient,
+) -> Result<Vec<PushSubscription>, Error> {
+    fetch_all(
+        client,
+        "SELECT s.id, s.endpoint FROM push_subscriptions s
+         JOIN users u ON u.id = s.user_id WHERE u.deleted_at IS NULL ORDER BY s.id",
+        &[],
+    )
+    .await
+}
+
+/**
+ * 推送服务返回 404/410 说明订阅已经失效，删除之后不再推送
+ */
+pub async fn delete_expired_push_subscription(
+    client: &impl GenericClient,
+    id: i64,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "DELETE FROM push_subscriptions WHERE id = $1",
+        &[&id],
+    )
+    .await?;
+    Ok(())
+}
+
+/*
+ * incidents、incident_updates 和 status_samples，见 status
+ */
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncidentUpdate {
+    pub status: String,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/**
+ * 故障和它的处理进展（从新到旧）
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct Incident {
+    pub id: i64,
+    pub title: String,
+    pub status: String,
+    pub impact: String,
+    pub components: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub updates: Vec<IncidentUpdate>,
+}
+
+impl FromRow for Incident {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        let updates: Value = row.try_get("updates")?;
+        Ok(Incident {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            status: row.try_get("status")?,
+            impact: row.try_get("impact")?,
+            components: row.try_get("components")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+            resolved_at: row.try_get("resolved_at")?,
+            updates: serde_json::from_value(updates).unwrap_or_default(),
+        })
+    }
+}
+
+const INCIDENT_COLUMNS: &str = "i.id, i.title, i.status, i.impact, i.components,
+    i.created_at, i.updated_at, i.resolved_at,
+    COALESCE((SELECT json_agg(json_build_object(
+            'status', u.status, 'message', u.message, 'created_at', u.created_at
+        ) ORDER BY u.created_at DESC, u.id DESC)
+        FROM incident_updates u WHERE u.incident_id = i.id), '[]'::json)::JSONB AS updates";
+
+/**
+ * 还没有解决的故障，和 since 之后发生的故障，从新到旧排列
+ */
+pub async fn list_incidents(
+    client: &impl GenericClient,
+    since: DateTime<Utc>,
+) -> Result<Vec<Incident>, Error> {
+    fetch_all(
+        client,
+        &format!(
+            "SELECT {} FROM incidents i
+             WHERE i.resolved_at IS NULL OR i.created_at >= $1
+             ORDER BY i.created_at DESC",
+            INCIDENT_COLUMNS
+        ),
+        &[&since],
+    )
+    .await
+}
+
+/**
+ * 最近更新过的 limit 个故障，从新到旧排列，用于 feed
+ */
+pub async fn latest_incidents(
+    client: &impl GenericClient,
+    limit: i64,
+) -> Result<Vec<Incident>, Error> {
+    fetch_all(
+        client,
+        &format!(
+            "SELECT {} FROM incidents i ORDER BY i.updated_at DESC, i.id DESC LIMIT $1",
+            INCIDENT_COLUMNS
+        ),
+        &[&limit],
+    )
+    .await
+}
+
+/**
+ * 最近一次修改故障的时间，没有故障时为 None
+ */
+pub async fn incidents_updated_at(
+    client: &impl GenericClient,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    let sql = "SELECT max(updated_at) FROM incidents";
+    traced(sql, client.query_one(sql, &[])).await?.try_get(0)
+}
+
+pub async fn find_incident(
+    client: &impl GenericClient,
+    id: i64,
+) -> Result<Option<Incident>, Error> {
+    fetch_opt(
+        client,
+        &format!(
+            "SELECT {} FROM incidents i WHERE i.id = $1",
+            INCIDENT_COLUMNS
+        ),
+        &[&id],
+    )
+    .await
+}
+
+/**
+ * 创建一个故障，message 作为第一条进展，状态是 investigating，返回新故障的 id
+ */
+pub async fn create_incident(
+    client: &impl GenericClient,
+    title: &str,
+    impact: &str,
+    components: &[String],
+    message: &str,
+) -> Result<i64, Error> {
+    let sql = "WITH incident AS (
+             INSERT INTO incidents (title, impact, components) VALUES ($1, $2, $3) RETURNING id, status
+         )
+         INSERT INTO incident_updates (incident_id, status, message)
+         SELECT id, status, $4 FROM incident RETURNING incident_id";
+    traced(
+        sql,
+        client.query_one(sql, &[&title, &impact, &components, &message]),
+    )
+    .await?
+    .try_get(0)
+}
+
+/**
+ * 记录一条进展并把故障改成这个状态，resolved 时记下解决的时间；故障不存在时返回 false
+ */
+pub async fn add_incident_update(
+    client: &impl GenericClient,
+    id: i64,
+    status: &str,
+    message: &str,
+) -> Result<bool, Error> {
+    let inserted = execute(
+        client,
+        "WITH incident AS (
+             UPDATE incidents SET status = $2, updated_at = now(),
+                 resolved_at = CASE WHEN $2 = 'resolved' THEN COALESCE(resolved_at, now()) END
+             WHERE id = $1 RETURNING id
+         )
+         INSERT INTO incident_updates (incident_id, status, message)
+         SELECT id, $2, $3 FROM incident",
+        &[&id, &status, &message],
+    )
+    .await?;
+    Ok(inserted > 0)
+}
+
+/**
+ * 一个组件一天的检查次数
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusSample {
+    pub component: String,
+    pub day: NaiveDate,
+    pub checks: i64,
+    pub degraded: i64,
+    pub outages: i64,
+}
+
+impl FromRow for StatusSample {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(StatusSample {
+            component: row.try_get("component")?,
+            day: row.try_get("day")?,
+            checks: row.try_get("checks")?,
+            degraded: row.try_get("degraded")?,
+            outages: row.try_get("outages")?,
+        })
+    }
+}
+
+/**
+ * 记一次检查的结果，按 UTC 的日期累加
+ */
+pub async fn record_status_sample(
+    client: &impl GenericClient,
+    component: &str,
+    degraded: bool,
+    outage: bool,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "INSERT INTO status_samples (component, day, checks, degraded, outages)
+         VALUES ($1, (now() AT TIME ZONE 'UTC')::DATE, 1, $2::BOOLEAN::INT, $3::BOOLEAN::INT)
+         ON CONFLICT (component, day) DO UPDATE SET
+             checks = status_samples.checks + 1,
+             degraded = status_samples.degraded + EXCLUDED.degraded,
+             outages = status_samples.outages + EXCLUDED.outages",
+        &[&component, &degraded, &outage],
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn list_status_samples(
+    client: &impl GenericClient,
+    since: NaiveDate,
+) -> Result<Vec<StatusSample>, Error> {
+    fetch_all(
+        client,
+        "SELECT component, day, checks, degraded, outages FROM status_samples
+         WHERE day >= $1 ORDER BY component, day",
+        &[&since],
+    )
+    .await
+}
+
+pub async fn prune_status_samples(
+    client: &impl GenericClient,
+    before: NaiveDate,
+) -> Result<u64, Error> {
+    execute(
+        client,
+        "DELETE FROM status_samples WHERE day < $1",
+        &[&before],
+    )
+    .await
+}
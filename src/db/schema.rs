@@ -0,0 +1,382 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio_postgres::Client;
+
+use super::{embedded, ConnectionPool};
+use crate::error::internal_error;
+
+/*
+ * 数据库结构漂移检查
+ * 有人手动改过表（删了列、改了类型），或者迁移没有执行（MIGRATE_ON_STARTUP=false 时忘了执行 migrate 子命令），
+ * 查询要等到执行时才报出 column does not exist 之类的错误，很难和真正的原因联系起来。
+ * 这里在启动时和之后每隔 SCHEMA_CHECK_INTERVAL_SECS 秒检查一次：
+ * - 迁移：嵌入的迁移是否都已执行、数据库里是否有这个版本不认识的迁移、已执行的迁移文件是否被修改过（checksum 不同）
+ * - 表结构：代码用到的表和列是否存在，列的类型是否和迁移建出来的一致
+ * 发现问题时只打印警告，不阻止启动，结果显示在 /readyz 和管理后台首页上。
+ * EXPECTED 需要和 migrations 目录保持一致，新增迁移修改了表结构时要同步修改这里。
+ */
+
+// 每张表的列和 format_type 输出的类型
+const EXPECTED: &[(&str, &[(&str, &str)])] = &[
+    (
+        "audit_archives",
+        &[
+            ("id", "bigint"),
+            ("bucket", "text"),
+            ("object_key", "text"),
+            ("first_id", "bigint"),
+            ("last_id", "bigint"),
+            ("row_count", "bigint"),
+            ("bytes", "bigint"),
+            ("sha256", "text"),
+            ("first_at", "timestamp with time zone"),
+            ("last_at", "timestamp with time zone"),
+            ("created_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "audit_log",
+        &[
+            ("id", "bigint"),
+            ("actor_id", "bigint"),
+            ("actor", "text"),
+            ("ip", "text"),
+            ("route", "text"),
+            ("action", "text"),
+            ("payload", "jsonb"),
+            ("created_at", "timestamp with time zone"),
+            ("impersonator_id", "bigint"),
+        ],
+    ),
+    (
+        "client_policies",
+        &[
+            ("class", "text"),
+            ("priority", "integer"),
+            ("user_agent_patterns", "text[]"),
+            ("rate_limit", "integer"),
+            ("cache_max_age", "integer"),
+            ("denied_paths", "text[]"),
+        ],
+    ),
+    (
+        "compat_rules",
+        &[
+            ("id", "bigint"),
+            ("client_class", "text"),
+            ("route", "text"),
+            ("direction", "text"),
+            ("target", "text"),
+            ("action", "text"),
+            ("field", "text"),
+            ("value", "jsonb"),
+            ("priority", "integer"),
+        ],
+    ),
+    (
+        "deprecation_usage",
+        &[
+            ("route", "text"),
+            ("user_id", "bigint"),
+            ("requests", "bigint"),
+            ("first_seen_at", "timestamp with time zone"),
+            ("last_seen_at", "timestamp with time zone"),
+            ("notified_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "deprecations",
+        &[
+            ("route", "text"),
+            ("announced_at", "timestamp with time zone"),
+            ("sunset_at", "timestamp with time zone"),
+            ("replacement", "text"),
+            ("link", "text"),
+        ],
+    ),
+    (
+        "device_codes",
+        &[
+            ("device_code", "text"),
+            ("user_code", "text"),
+            ("client_id", "text"),
+            ("user_id", "bigint"),
+            ("status", "text"),
+            ("expires_at", "timestamp with time zone"),
+            ("last_polled_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "experiment_exposures",
+        &[
+            ("experiment", "text"),
+            ("subject", "text"),
+            ("variant", "text"),
+            ("exposures", "bigint"),
+            ("first_seen_at", "timestamp with time zone"),
+            ("last_seen_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "incident_updates",
+        &[
+            ("id", "bigint"),
+            ("incident_id", "bigint"),
+            ("status", "text"),
+            ("message", "text"),
+            ("created_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "incidents",
+        &[
+            ("id", "bigint"),
+            ("title", "text"),
+            ("status", "text"),
+            ("impact", "text"),
+            ("components", "text[]"),
+            ("created_at", "timestamp with time zone"),
+            ("updated_at", "timestamp with time zone"),
+            ("resolved_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "job_results",
+        &[
+            ("job_id", "uuid"),
+            ("result_type", "text"),
+            ("result", "jsonb"),
+            ("created_at", "timestamp with time zone"),
+            ("expires_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "jobs",
+        &[
+            ("id", "uuid"),
+            ("kind", "text"),
+            ("owner_id", "bigint"),
+            ("status", "text"),
+            ("error", "text"),
+            ("created_at", "timestamp with time zone"),
+            ("finished_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "org_members",
+        &[("org_id", "bigint"), ("user_id", "bigint")],
+    ),
+    ("organizations", &[("id", "bigint"), ("name", "text")]),
+    (
+        "push_subscriptions",
+        &[
+            ("id", "bigint"),
+            ("user_id", "bigint"),
+            ("endpoint", "text"),
+            ("p256dh", "text"),
+            ("auth", "text"),
+            ("created_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "redirect_rules",
+        &[
+            ("pattern", "text"),
+            ("target", "text"),
+            ("status", "integer"),
+            ("priority", "integer"),
+        ],
+    ),
+    (
+        "refresh_tokens",
+        &[
+            ("token_hash", "text"),
+            ("family_id", "uuid"),
+            ("user_id", "bigint"),
+            ("expires_at", "timestamp with time zone"),
+            ("revoked_at", "timestamp with time zone"),
+            ("created_at", "timestamp with time zone"),
+        ],
+    ),
+    (
+        "revoked_tokens",
+        &[("jti", "text"), ("expires_at", "timestamp with time zone")],
+    ),
+    (
+        "role_permissions",
+        &[("role", "text"), ("permission", "text"), ("scope", "text")],
+    ),
+    (
+        "status_samples",
+        &[
+            ("component", "text"),
+            ("day", "date"),
+            ("checks", "bigint"),
+            ("degraded", "bigint"),
+            ("outages", "bigint"),
+        ],
+    ),
+    (
+        "todos",
+        &[
+            ("id", "bigint"),
+            ("title", "text"),
+            ("done", "boolean"),
+            ("created_at", "timestamp with time zone"),
+            ("deleted_at", "timestamp with time zone"),
+            ("version", "integer"),
+            ("search", "tsvector"),
+            ("tenant_id", "text"),
+            ("seq", "bigint"),
+            ("updated_at", "timestamp with time zone"),
+            ("client_id", "text"),
+            ("owner_id", "bigint"),
+        ],
+    ),
+    (
+        "users",
+        &[
+            ("id", "bigint"),
+            ("username", "text"),
+            ("email", "text"),
+            ("password_hash", "text"),
+            ("created_at", "timestamp with time zone"),
+            ("role", "text"),
+            ("failed_logins", "integer"),
+            ("last_failed_login_at", "timestamp with time zone"),
+            ("locked_until", "timestamp with time zone"),
+            ("deleted_at", "timestamp with time zone"),
+            ("version", "integer"),
+            ("updated_at", "timestamp with time zone"),
+        ],
+    ),
+];
+
+/**
+ * 最近一次检查的结果，两个列表都为空表示没有发现漂移
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DriftReport {
+    // 还没有检查过时为 None
+    pub checked_at: Option<DateTime<Utc>>,
+    // 迁移没有执行、被修改过或者不认识
+    pub migrations: Vec<String>,
+    // 表和列缺失或者类型不一致
+    pub columns: Vec<String>,
+}
+
+impl DriftReport {
+    pub fn problems(&self) -> impl Iterator<Item = &String> {
+        self.migrations.iter().chain(&self.columns)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SchemaDrift {
+    report: Arc<RwLock<Arc<DriftReport>>>,
+}
+
+impl SchemaDrift {
+    pub fn report(&self) -> Arc<DriftReport> {
+        self.report.read().unwrap().clone()
+    }
+
+    /**
+     * 重新检查一次，每个问题打印一条警告；检查本身失败（比如数据库连不上）时保留上一次的结果
+     */
+    pub async fn refresh(&self, pool: &ConnectionPool) -> Result<(), (StatusCode, String)> {
+        let mut conn = pool.get().await.map_err(internal_error)?;
+        let report = DriftReport {
+            checked_at: Some(Utc::now()),
+            migrations: check_migrations(&mut conn).await?,
+            columns: check_columns(&conn).await?,
+        };
+        for problem in report.problems() {
+            tracing::warn!("schema drift: {}", problem);
+        }
+        *self.report.write().unwrap() = Arc::new(report);
+        Ok(())
+    }
+}
+
+async fn check_migrations(conn: &mut Client) -> Result<Vec<String>, (StatusCode, String)> {
+    let mut problems = Vec::new();
+    let runner = embedded::migrations::runner();
+    let applied = runner
+        .get_applied_migrations_async(conn)
+        .await
+        .map_err(internal_error)?;
+    let applied: HashMap<u32, _> = applied
+        .iter()
+        .map(|migration| (migration.version(), migration))
+        .collect();
+    let mut known = HashSet::new();
+    for migration in runner.get_migrations() {
+        known.insert(migration.version());
+        match applied.get(&migration.version()) {
+            None => problems.push(format!("migration {} has not been applied", migration)),
+            Some(done) if done.checksum() != migration.checksum() => problems.push(format!(
+                "migration {} was modified after it was applied",
+                migration
+            )),
+            Some(_) => {}
+        }
+    }
+    let mut unknown: Vec<_> = applied
+        .values()
+        .filter(|migration| !known.contains(&migration.version()))
+        .collect();
+    unknown.sort_by_key(|migration| migration.version());
+    for migration in unknown {
+        problems.push(format!(
+            "migration {} is applied but unknown to this build",
+            migration
+        ));
+    }
+    Ok(problems)
+}
+
+async fn check_columns(conn: &Client) -> Result<Vec<String>, (StatusCode, String)> {
+    let mut problems = Vec::new();
+    let rows = conn
+        .query(
+            "SELECT c.relname, a.attname, format_type(a.atttypid, a.atttypmod)
+             FROM pg_attribute a
+             JOIN pg_class c ON c.oid = a.attrelid
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE n.nspname = current_schema() AND c.relkind IN ('r', 'p')
+                 AND a.attnum > 0 AND NOT a.attisdropped",
+            &[],
+        )
+        .await
+        .map_err(internal_error)?;
+    let mut live: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for row in rows {
+        live.entry(row.get(0))
+            .or_default()
+            .insert(row.get(1), row.get(2));
+    }
+    for (table, columns) in EXPECTED {
+        let Some(live_columns) = live.get(*table) else {
+            problems.push(format!("table {} is missing", table));
+            continue;
+        };
+        for (column, expected) in *columns {
+            match live_columns.get(*column) {
+                None => problems.push(format!("column {}.{} is missing", table, column)),
+                Some(actual) if actual != expected => problems.push(format!(
+                    "column {}.{} is {}, expected {}",
+                    table, column, actual, expected
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(problems)
+}
@@ -0,0 +1,226 @@
+use std::{fmt, fs::File, io::BufReader, str::FromStr, sync::Arc};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+};
+use tokio_postgres::config::SslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::TlsConfig;
+
+/*
+ * 数据库连接的 TLS
+ * 托管的 Postgres（RDS、Cloud SQL 等）通常要求加密连接，DB_SSL_MODE 的取值和 libpq 的 sslmode 含义相同：
+ * - disable：不加密，默认值，本地开发不需要任何配置
+ * - prefer：服务端支持时加密，不校验证书
+ * - require：必须加密，不校验证书
+ * - verify-ca：必须加密，证书需要由受信任的 CA 签发，不检查主机名
+ * - verify-full：在 verify-ca 的基础上，证书里的主机名还要和连接地址一致
+ * 受信任的 CA 默认是 webpki-roots 内置的公共 CA，设置 DB_SSL_ROOT_CERT（PEM 文件）后只信任这个文件里的证书。
+ * 服务端要求客户端证书时，通过 DB_SSL_CERT 和 DB_SSL_KEY 指定 PEM 格式的证书和私钥。
+ * 连接地址里的 sslmode 参数会被 DB_SSL_MODE 覆盖，主库、只读副本和 LISTEN 连接使用同样的设置。
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl TlsMode {
+    fn ssl_mode(self) -> SslMode {
+        match self {
+            TlsMode::Disable => SslMode::Disable,
+            TlsMode::Prefer => SslMode::Prefer,
+            TlsMode::Require | TlsMode::VerifyCa | TlsMode::VerifyFull => SslMode::Require,
+        }
+    }
+}
+
+impl FromStr for TlsMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "disable" => Ok(TlsMode::Disable),
+            "prefer" => Ok(TlsMode::Prefer),
+            "require" => Ok(TlsMode::Require),
+            "verify-ca" => Ok(TlsMode::VerifyCa),
+            "verify-full" => Ok(TlsMode::VerifyFull),
+            _ => Err(format!(
+                "{:?} is not one of disable, prefer, require, verify-ca, verify-full",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TlsMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TlsMode::Disable => "disable",
+            TlsMode::Prefer => "prefer",
+            TlsMode::Require => "require",
+            TlsMode::VerifyCa => "verify-ca",
+            TlsMode::VerifyFull => "verify-full",
+        })
+    }
+}
+
+/**
+ * 解析连接地址，并用 DB_SSL_MODE 覆盖其中的 sslmode
+ */
+pub fn pg_config(
+    url: &str,
+    tls: &TlsConfig,
+) -> Result<tokio_postgres::Config, tokio_postgres::Error> {
+    let mut config: tokio_postgres::Config = url.parse()?;
+    config.ssl_mode(tls.mode.ssl_mode());
+    Ok(config)
+}
+
+fn read_pem(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("{}: {}", path, err))
+}
+
+fn root_store(tls: &TlsConfig) -> Result<RootCertStore, String> {
+    let Some(path) = &tls.root_cert else {
+        return Ok(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        });
+    };
+    let mut store = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut read_pem(path)?) {
+        let cert = cert.map_err(|err| format!("{}: {}", path, err))?;
+        store
+            .add(cert)
+            .map_err(|err| format!("{}: {}", path, err))?;
+    }
+    if store.is_empty() {
+        return Err(format!("{}: no certificates found", path));
+    }
+    Ok(store)
+}
+
+/**
+ * 创建连接时使用的 TLS connector，证书文件读不出来或者格式不对时返回错误
+ * disable 模式下不会真正建立 TLS 连接，但连接池的类型需要一致，所以同样返回一个 connector
+ */
+pub fn connector(tls: &TlsConfig) -> Result<MakeRustlsConnect, String> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(root_store(tls)?), provider.clone())
+            .build()
+            .map_err(|err| err.to_string())?;
+    let verifier = Verifier {
+        inner: verifier,
+        mode: tls.mode,
+        provider: provider.clone(),
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = rustls_pemfile::certs(&mut read_pem(cert_path)?)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| format!("{}: {}", cert_path, err))?;
+            let key = rustls_pemfile::private_key(&mut read_pem(key_path)?)
+                .map_err(|err| format!("{}: {}", key_path, err))?
+                .ok_or_else(|| format!("{}: no private key found", key_path))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|err| format!("{}: {}", cert_path, err))?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(MakeRustlsConnect::new(config))
+}
+
+/**
+ * 按 DB_SSL_MODE 决定校验到什么程度；握手签名无论哪种模式都会校验
+ */
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<WebPkiServerVerifier>,
+    mode: TlsMode,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if matches!(
+            self.mode,
+            TlsMode::Disable | TlsMode::Prefer | TlsMode::Require
+        ) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            Err(Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) if self.mode == TlsMode::VerifyCa => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use askama::Template;
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{MatchedPath, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    routing::delete,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    audit::Audit,
+    config::ResponseCacheConfig,
+    invalidation::Cache,
+    permissions::{Authorize, ServerManage},
+    AppState,
+};
+
+/*
+ * 数据库不可用时的降级模式
+ * 正常情况下，GET 请求成功返回的 HTML 页面和 JSON 数据会在内存里缓存一份。
+ * 数据库断路器（db::CircuitBreaker）打开期间，依赖数据库的请求会返回 500/503，这时：
+ * - 有缓存时返回缓存的内容，并带上 `Warning: 110 - "Response is Stale"` 和 Age 响应头，告诉客户端数据可能已经过时
+ * - 浏览器访问的 HTML 页面没有缓存时，返回一个静态的提示页面（503），而不是一行错误信息
+ * - 接口请求没有缓存时，仍然返回 quota::retry_hints 生成的 503 JSON，客户端按 backoff_hint 重试
+ * 冷却期间命中缓存的请求直接返回缓存，不再执行 handler；冷却时间过后的请求照常执行，由其中一个去探测数据库是否恢复。
+ * 写操作不会降级，失败就是失败。不依赖数据库的路由（静态文件等）不受影响。
+ * 带有 Cache-Control: no-store 的响应（比如 /readyz）不缓存，降级时也不会返回过时的内容。
+ * 缓存按 URL、请求携带的凭证（Authorization、Cookie）和租户（Host、X-Tenant-Id）区分，不同用户、不同租户之间不会看到对方的数据。
+ * 还按 User-Agent 区分，老版本客户端拿到的是 compat 转换过的格式，不能和其他客户端共用缓存。
+ * 写操作成功之后，丢掉同一组资源（路由里第一个参数之前的部分，比如 /api/todos/:id 对应 /api/todos）的缓存，
+ * 并通过 invalidation 通知其他实例也丢掉，避免数据库恢复之前又降级时返回修改之前的内容。
+ *
+ * 同一份缓存也用来减少重复的查询：RESPONSE_CACHE_ROUTES 里列出的路由（形如 /widgets/:id=30），
+ * 缓存的时间不超过设置的秒数时直接返回缓存，带上 Age 和 X-Cache: HIT，不再执行 handler。
+ * 请求带有 Cache-Control: no-cache（浏览器强制刷新时会带上）时不使用缓存，重新生成之后更新缓存。
+ * 命中缓存时不再经过鉴权，已经吊销的 token 最多在缓存时间内还能拿到吊销之前的同一份响应，缓存时间不要设置得太长。
+ * DELETE /admin/response_cache?prefix=/api/todos 清掉路径以 prefix 开头的缓存（不带 prefix 时清空），
+ * 所有实例一起生效，需要 server:manage 权限。
+ */
+
+// 最多缓存多少个响应，满了之后淘汰最早缓存的
+const MAX_ENTRIES: usize = 1000;
+// 超过这个大小的响应不缓存，长度未知的响应（比如 SSE）也不缓存
+const MAX_BODY: u64 = 256 * 1024;
+
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+// 缓存的响应保留这些响应头，其他的（比如 Set-Cookie、限流的计数）只和当时的请求有关
+const KEPT_HEADERS: [HeaderName; 6] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LANGUAGE,
+    header::CACHE_CONTROL,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::VARY,
+];
+
+struct CachedResponse {
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+}
+
+impl CachedResponse {
+    fn response(&self) -> Response {
+        let mut res = Response::new(Body::from(self.body.clone()));
+        *res.headers_mut() = self.headers.clone();
+        res.headers_mut().insert(
+            header::AGE,
+            HeaderValue::from(self.stored_at.elapsed().as_secs()),
+        );
+        res
+    }
+}
+
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<HashMap<[u8; 32], CachedResponse>>>,
+    // 路由 -> 直接返回缓存的最长时间
+    ttls: Arc<HashMap<String, Duration>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &ResponseCacheConfig) -> Self {
+        // 格式在加载配置时已经检查过
+        let ttls = config
+            .routes
+            .iter()
+            .filter_map(|route| {
+                let (path, secs) = route.split_once('=')?;
+                let secs: u64 = secs.trim().parse().ok()?;
+                Some((path.trim().to_string(), Duration::from_secs(secs)))
+            })
+            .collect();
+        ResponseCache {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttls: Arc::new(ttls),
+        }
+    }
+
+    fn store(&self, key: [u8; 32], path: String, headers: &HeaderMap, body: Bytes) {
+        let headers = KEPT_HEADERS
+            .iter()
+            .filter_map(|name| Some((name.clone(), headers.get(name)?.clone())))
+            .collect();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.stored_at)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            CachedResponse {
+                path,
+                headers,
+                body,
+                stored_at: Instant::now(),
+            },
+        );
+    }
+
+    /**
+     * 丢掉路径以 prefix 开头的缓存，返回丢掉的个数
+     */
+    pub fn invalidate(&self, prefix: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, cached| !cached.path.starts_with(prefix));
+        before - entries.len()
+    }
+
+    /**
+     * 缓存时间不超过 ttl 的缓存
+     */
+    fn fresh(&self, key: &[u8; 32], ttl: Duration) -> Option<Response> {
+        let entries = self.entries.lock().unwrap();
+        let cached = entries
+            .get(key)
+            .filter(|cached| cached.stored_at.elapsed() < ttl)?;
+        let mut res = cached.response();
+        res.headers_mut()
+            .insert("x-cache", HeaderValue::from_static("HIT"));
+        Some(res)
+    }
+
+    fn stale(&self, key: &[u8; 32]) -> Option<Response> {
+        let entries = self.entries.lock().unwrap();
+        let mut res = entries.get(key)?.response();
+        let headers = res.headers_mut();
+        headers.insert(header::WARNING, HeaderValue::from_static(STALE_WARNING));
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        Some(res)
+    }
+}
+
+/**
+ * 缓存键：URL 加上凭证、租户、User-Agent、Accept 和 If-Modified-Since 的摘要，不在内存里保存 token 原文
+ * 带 If-Modified-Since 的请求只返回变化的部分，不能和完整的列表共用缓存；同一个 URL 按 Accept 返回不同格式（见 negotiate）
+ */
+fn cache_key(req: &Request) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(req.uri().to_string());
+    for name in [
+        header::AUTHORIZATION,
+        header::COOKIE,
+        header::HOST,
+        HeaderName::from_static("x-tenant-id"),
+        header::USER_AGENT,
+        header::ACCEPT,
+        header::IF_MODIFIED_SINCE,
+    ] {
+        hasher.update([0]);
+        if let Some(value) = req.headers().get(name) {
+            hasher.update(value.as_bytes());
+        }
+    }
+    hasher.finalize().into()
+}
+
+/**
+ * 写操作影响的资源：匹配到的路由里第一个参数之前的部分，没有匹配到路由时为 None
+ */
+fn resource_prefix(req: &Request) -> Option<String> {
+    let route = req.extensions().get::<MatchedPath>()?.as_str();
+    let prefix = match route.find("/:").or_else(|| route.find("/*")) {
+        Some(index) => &route[..index],
+        None => route,
+    };
+    Some(prefix.to_string())
+}
+
+/**
+ * 客户端要求重新验证（Cache-Control: no-cache 或者 Pragma: no-cache）时不使用缓存
+ */
+fn bypasses_cache(headers: &HeaderMap) -> bool {
+    let has = |name: header::HeaderName| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.contains("no-cache") || value.contains("no-store"))
+    };
+    has(header::CACHE_CONTROL) || has(header::PRAGMA)
+}
+
+fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("text/html"))
+}
+
+fn is_cacheable(res: &Response) -> bool {
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let no_store = res
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("no-store"));
+    res.status() == StatusCode::OK
+        && !no_store
+        && (content_type.starts_with("text/html") || content_type.starts_with("application/json"))
+        && res
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|size| size <= MAX_BODY)
+}
+
+#[derive(Template)]
+#[template(path = "degraded.html")]
+struct DegradedTemplate;
+
+pub async fn degraded(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if req.method() != Method::GET {
+        let prefix = resource_prefix(&req);
+        let res = next.run(req).await;
+        // 表单提交成功后会重定向（303），也算成功
+        let succeeded = res.status().is_success() || res.status().is_redirection();
+        if let (true, Some(prefix)) = (succeeded, prefix) {
+            state.response_cache.invalidate(&prefix);
+            state
+                .invalidations
+                .publish(&state.pool, Cache::Response, &prefix);
+        }
+        return res;
+    }
+    let path = req.uri().path().to_string();
+    let key = cache_key(&req);
+    let html = wants_html(req.headers());
+    let ttl = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|route| state.response_cache.ttls.get(route.as_str()).copied());
+    let bypass = bypasses_cache(req.headers());
+    if let (Some(ttl), false) = (ttl, bypass) {
+        if let Some(fresh) = state.response_cache.fresh(&key, ttl) {
+            return fresh;
+        }
+    }
+    // 冷却期间有缓存就直接返回，不用再等鉴权等没有经过断路器的数据库访问超时
+    if state.breaker.is_cooling_down() {
+        if let Some(stale) = state.response_cache.stale(&key) {
+            return stale;
+        }
+    }
+
+    let mut res = next.run(req).await;
+    if ttl.is_some() {
+        let status = if bypass { "BYPASS" } else { "MISS" };
+        res.headers_mut()
+            .insert("x-cache", HeaderValue::from_static(status));
+    }
+    if is_cacheable(&res) {
+        let (parts, body) = res.into_parts();
+        let Ok(bytes) = to_bytes(body, MAX_BODY as usize).await else {
+            return Response::from_parts(parts, Body::empty());
+        };
+        state
+            .response_cache
+            .store(key, path, &parts.headers, bytes.clone());
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+
+    if !res.status().is_server_error() || !state.breaker.is_open() {
+        return res;
+    }
+    if let Some(stale) = state.response_cache.stale(&key) {
+        return stale;
+    }
+    if !html {
+        return res;
+    }
+    let retry_after = res.headers().get(header::RETRY_AFTER).cloned();
+    let mut fallback = match DegradedTemplate.render() {
+        Ok(page) => (StatusCode::SERVICE_UNAVAILABLE, Html(page)).into_response(),
+        Err(_) => return res,
+    };
+    if let Some(retry_after) = retry_after {
+        fallback
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after);
+    }
+    fallback
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/response_cache", delete(purge))
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    prefix: Option<String>,
+}
+
+async fn purge(
+    Authorize { user: admin, .. }: Authorize<ServerManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Query(query): Query<PurgeQuery>,
+) -> Json<serde_json::Value> {
+    let prefix = query.prefix.unwrap_or_else(|| "/".to_string());
+    let purged = state.response_cache.invalidate(&prefix);
+    state
+        .invalidations
+        .publish(&state.pool, Cache::Response, &prefix);
+    audit
+        .record(
+            &state.pool,
+            Some(admin.id),
+            &admin.username,
+            "admin.response_cache.purge",
+            json!({ "prefix": prefix }),
+        )
+        .await;
+    Json(json!({ "prefix": prefix, "purged": purged }))
+}
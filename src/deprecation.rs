@@ -0,0 +1,448 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use askama::Template;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    db::ConnectionPool,
+    delta::HTTP_DATE,
+    error::internal_error,
+    experiments::{self, Assignment, Experiments},
+    mail::Mailer,
+    pagination::{Paginated, Pagination},
+    permissions::{Authorize, ConfigRead},
+    AppState,
+};
+
+/*
+ * 接口的废弃和下线流程
+ * 准备下线的接口记录在 deprecations 表里（见 V15 迁移），后台任务每隔 DEPRECATION_RELOAD_SECS 秒重新加载：
+ * - 下线之前，接口照常工作，响应带上 Deprecation（RFC 9745）、Sunset（RFC 8594）和 Link 响应头，
+ *   客户端和 SDK 可以据此打印警告，找到替代的接口和迁移说明
+ * - 过了 sunset_at 之后返回 410 Gone，响应体里带上替代的接口
+ * 登录用户（Bearer token 里的用户）每次调用废弃的接口都会计数，先记在内存里，随着重新加载写进 deprecation_usage 表；
+ * 所有调用同时计入 /metrics 的 http_deprecated_requests_total。
+ * 下线前 DEPRECATION_NOTICE_DAYS 天内还在调用的用户，后台任务每小时检查一次，给他们发一封提醒邮件（见 mail），
+ * 每个用户每个接口只提醒一次；没有配置 SMTP_URL 时不发送，等配置好之后再补发。
+ * GET /admin/deprecations 按路由分页查看废弃的接口和每个用户的调用情况（见 pagination），需要 config:read 权限。
+ */
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Deprecation {
+    pub route: String,
+    pub announced_at: DateTime<Utc>,
+    pub sunset_at: Option<DateTime<Utc>>,
+    pub replacement: Option<String>,
+    pub link: Option<String>,
+}
+
+impl Deprecation {
+    fn is_sunset(&self) -> bool {
+        self.sunset_at
+            .is_some_and(|sunset_at| sunset_at <= Utc::now())
+    }
+
+    /**
+     * Deprecation、Sunset 和 Link 响应头
+     */
+    fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        // 时间戳和 HTTP 日期都只有 ASCII 字符
+        let mut headers = vec![(
+            HeaderName::from_static("deprecation"),
+            HeaderValue::from_str(&format!("@{}", self.announced_at.timestamp())).unwrap(),
+        )];
+        if let Some(sunset_at) = self.sunset_at {
+            headers.push((
+                HeaderName::from_static("sunset"),
+                HeaderValue::from_str(&sunset_at.format(HTTP_DATE).to_string()).unwrap(),
+            ));
+        }
+        for (target, rel) in [
+            (&self.replacement, "successor-version"),
+            (&self.link, "deprecation"),
+        ] {
+            let Some(target) = target else {
+                continue;
+            };
+            if let Ok(value) = HeaderValue::from_str(&format!("<{}>; rel=\"{}\"", target, rel)) {
+                headers.push((header::LINK, value));
+            }
+        }
+        headers
+    }
+}
+
+struct Usage {
+    requests: i64,
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Default)]
+pub struct Deprecations {
+    routes: Arc<RwLock<Arc<HashMap<String, Deprecation>>>>,
+    // 还没有写进数据库的调用次数，按 (route, user_id) 统计
+    usage: Arc<Mutex<HashMap<(String, i64), Usage>>>,
+}
+
+impl Deprecations {
+    /**
+     * 先把调用次数写进数据库，再重新加载 deprecations 表，加载失败时保留原来的配置
+     */
+    pub async fn reload(&self, pool: &ConnectionPool) -> Result<(), (StatusCode, String)> {
+        self.flush(pool).await?;
+        let conn = pool.get().await.map_err(internal_error)?;
+        let rows = conn
+            .query(
+                "SELECT route, announced_at, sunset_at, replacement, link FROM deprecations",
+                &[],
+            )
+            .await
+            .map_err(internal_error)?;
+        let routes = rows
+            .iter()
+            .map(|row| {
+                let deprecation = Deprecation {
+                    route: row.get(0),
+                    announced_at: row.get(1),
+                    sunset_at: row.get(2),
+                    replacement: row.get(3),
+                    link: row.get(4),
+                };
+                (deprecation.route.clone(), deprecation)
+            })
+            .collect();
+        *self.routes.write().unwrap() = Arc::new(routes);
+        Ok(())
+    }
+
+    fn all(&self) -> Arc<HashMap<String, Deprecation>> {
+        self.routes.read().unwrap().clone()
+    }
+
+    fn record(&self, route: &str, user_id: i64) {
+        let now = Utc::now();
+        self.usage
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), user_id))
+            .and_modify(|usage| {
+                usage.requests += 1;
+                usage.last_seen_at = now;
+            })
+            .or_insert(Usage {
+                requests: 1,
+                first_seen_at: now,
+                last_seen_at: now,
+            });
+    }
+
+    /**
+     * 把内存里的调用次数累加到 deprecation_usage 表，写入失败的记录会丢掉，只影响统计
+     */
+    async fn flush(&self, pool: &ConnectionPool) -> Result<(), (StatusCode, String)> {
+        let usage = std::mem::take(&mut *self.usage.lock().unwrap());
+        if usage.is_empty() {
+            return Ok(());
+        }
+        let conn = pool.get().await.map_err(internal_error)?;
+        for ((route, user_id), usage) in usage {
+            let result = conn
+                .execute(
+                    "INSERT INTO deprecation_usage (route, user_id, requests, first_seen_at, last_seen_at)
+                     VALUES ($1, $2, $3, $4, $5)
+                     ON CONFLICT (route, user_id) DO UPDATE SET
+                         requests = deprecation_usage.requests + EXCLUDED.requests,
+                         last_seen_at = GREATEST(deprecation_usage.last_seen_at, EXCLUDED.last_seen_at)",
+                    &[
+                        &route,
+                        &user_id,
+                        &usage.requests,
+                        &usage.first_seen_at,
+                        &usage.last_seen_at,
+                    ],
+                )
+                .await;
+            if let Err(err) = result {
+                tracing::warn!("record usage of deprecated route {} failed: {}", route, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * 废弃接口的中间件，放在 Router 的 layer 里，按 MatchedPath 查找
+ */
+pub async fn apply(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let routes = state.deprecations.all();
+    let Some(deprecation) = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|path| routes.get(path.as_str()))
+    else {
+        return next.run(req).await;
+    };
+
+    state.metrics.count_deprecated(&deprecation.route);
+    // 只用来统计，不需要检查 token 是否已被吊销
+    let user_id = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| state.jwt.decode(token))
+        .map(|claims| claims.sub);
+    if let Some(user_id) = user_id {
+        state.deprecations.record(&deprecation.route, user_id);
+    }
+
+    let mut res = if deprecation.is_sunset() {
+        (
+            StatusCode::GONE,
+            Json(json!({
+                "error": format!("{} was retired", deprecation.route),
+                "sunset_at": deprecation.sunset_at,
+                "replacement": deprecation.replacement,
+                "link": deprecation.link,
+            })),
+        )
+            .into_response()
+    } else {
+        next.run(req).await
+    };
+    let headers = res.headers_mut();
+    for (name, value) in deprecation.headers() {
+        headers.append(name, value);
+    }
+    res
+}
+
+/**
+ * 发给一个用户的提醒，lines 是每个接口的说明
+ */
+struct Notice {
+    user_id: i64,
+    username: String,
+    email: String,
+    routes: Vec<String>,
+    lines: Vec<String>,
+}
+
+/**
+ * 提醒邮件的正文，文案由 deprecation_email 实验决定（见 experiments）
+ */
+#[derive(Template)]
+#[template(path = "mail/deprecation_notice.txt")]
+struct NoticeTemplate<'a> {
+    username: &'a str,
+    lines: String,
+    experiments: Assignment,
+}
+
+/**
+ * 给下线前 notice_before 之内还在调用的用户发提醒，每个用户一封，列出他调用过的所有接口
+ */
+pub async fn notify(
+    pool: &ConnectionPool,
+    mailer: &Mailer,
+    experiments: &Experiments,
+    notice_before: Duration,
+) -> Result<(), (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    let rows = conn
+        .query(
+            "SELECT u.id, u.username, u.email, d.route, d.sunset_at, d.replacement, d.link,
+                 usage.requests, usage.last_seen_at
+             FROM deprecation_usage usage
+             JOIN deprecations d ON d.route = usage.route
+             JOIN users u ON u.id = usage.user_id
+             WHERE usage.notified_at IS NULL AND u.deleted_at IS NULL AND u.email <> ''
+                 AND d.sunset_at > now() AND d.sunset_at <= now() + make_interval(secs => $1)
+             ORDER BY u.id, d.sunset_at",
+            &[&notice_before.as_secs_f64()],
+        )
+        .await
+        .map_err(internal_error)?;
+    if rows.is_empty() {
+        return Ok(());
+    }
+    if !mailer.enabled() {
+        tracing::info!(
+            "{} deprecation notices are waiting for SMTP_URL to be set",
+            rows.len()
+        );
+        return Ok(());
+    }
+
+    let mut notices: Vec<Notice> = Vec::new();
+    for row in &rows {
+        let user_id: i64 = row.get(0);
+        let route: String = row.get(3);
+        let sunset_at: DateTime<Utc> = row.get(4);
+        let replacement: Option<String> = row.get(5);
+        let link: Option<String> = row.get(6);
+        let requests: i64 = row.get(7);
+        let last_seen_at: DateTime<Utc> = row.get(8);
+        let mut line = format!(
+            "- {} will be retired on {} (called {} times, last on {})",
+            route,
+            sunset_at.format("%Y-%m-%d %H:%M UTC"),
+            requests,
+            last_seen_at.format("%Y-%m-%d"),
+        );
+        if let Some(replacement) = replacement {
+            line.push_str(&format!("\n  use {} instead", replacement));
+        }
+        if let Some(link) = link {
+            line.push_str(&format!("\n  migration guide: {}", link));
+        }
+        match notices.last_mut() {
+            Some(notice) if notice.user_id == user_id => {
+                notice.routes.push(route);
+                notice.lines.push(line);
+            }
+            _ => notices.push(Notice {
+                user_id,
+                username: row.get(1),
+                email: row.get(2),
+                routes: vec![route],
+                lines: vec![line],
+            }),
+        }
+    }
+
+    for notice in notices {
+        let assignment = experiments
+            .assign(
+                pool,
+                &experiments::user_subject(notice.user_id),
+                &["deprecation_email"],
+            )
+            .await;
+        let body = NoticeTemplate {
+            username: &notice.username,
+            lines: notice.lines.join("\n"),
+            experiments: assignment,
+        }
+        .render()
+        .map_err(internal_error)?;
+        if let Err(err) = mailer
+            .send(
+                &notice.email,
+                "API endpoints you use are being retired",
+                body,
+            )
+            .await
+        {
+            // 没有标记为已提醒，下一次检查时重试
+            tracing::warn!(
+                "send deprecation notice to {} failed: {}",
+                notice.email,
+                err
+            );
+            continue;
+        }
+        conn.execute(
+            "UPDATE deprecation_usage SET notified_at = now() WHERE user_id = $1 AND route = ANY($2)",
+            &[&notice.user_id, &notice.routes],
+        )
+        .await
+        .map_err(internal_error)?;
+    }
+    Ok(())
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/deprecations", get(list))
+}
+
+/**
+ * 废弃的接口和每个用户的调用情况，先把内存里的计数写进数据库
+ */
+async fn list(
+    _auth: Authorize<ConfigRead>,
+    State(state): State<AppState>,
+    pagination: Pagination,
+) -> Result<Json<Paginated<Value>>, (StatusCode, String)> {
+    state.deprecations.flush(&state.pool).await?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let deprecations: Vec<Deprecation> = conn
+        .query(
+            "SELECT route, announced_at, sunset_at, replacement, link FROM deprecations
+             ORDER BY route LIMIT $1 OFFSET $2",
+            &[&pagination.limit(), &pagination.offset()],
+        )
+        .await
+        .map_err(internal_error)?
+        .iter()
+        .map(|row| Deprecation {
+            route: row.get(0),
+            announced_at: row.get(1),
+            sunset_at: row.get(2),
+            replacement: row.get(3),
+            link: row.get(4),
+        })
+        .collect();
+    let total: i64 = conn
+        .query_one("SELECT count(*) FROM deprecations", &[])
+        .await
+        .map_err(internal_error)?
+        .get(0);
+    let routes: Vec<&str> = deprecations
+        .iter()
+        .map(|deprecation| deprecation.route.as_str())
+        .collect();
+    let rows = conn
+        .query(
+            "SELECT usage.route, u.id, u.username, u.email, usage.requests,
+                 usage.first_seen_at, usage.last_seen_at, usage.notified_at
+             FROM deprecation_usage usage
+             JOIN users u ON u.id = usage.user_id
+             WHERE usage.route = ANY($1)
+             ORDER BY usage.route, usage.requests DESC",
+            &[&routes],
+        )
+        .await
+        .map_err(internal_error)?;
+    let mut usage: HashMap<String, Vec<Value>> = HashMap::new();
+    for row in rows {
+        let route: String = row.get(0);
+        let first_seen_at: DateTime<Utc> = row.get(5);
+        let last_seen_at: DateTime<Utc> = row.get(6);
+        let notified_at: Option<DateTime<Utc>> = row.get(7);
+        usage.entry(route).or_default().push(json!({
+            "user_id": row.get::<_, i64>(1),
+            "username": row.get::<_, String>(2),
+            "email": row.get::<_, String>(3),
+            "requests": row.get::<_, i64>(4),
+            "first_seen_at": first_seen_at,
+            "last_seen_at": last_seen_at,
+            "notified_at": notified_at,
+        }));
+    }
+
+    let deprecations: Vec<Value> = deprecations
+        .into_iter()
+        .map(|deprecation| {
+            let mut value = json!(deprecation);
+            value["usage"] = json!(usage.remove(&deprecation.route).unwrap_or_default());
+            value
+        })
+        .collect();
+    Ok(Json(Paginated::new(deprecations, total, pagination)))
+}
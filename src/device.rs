@@ -0,0 +1,285 @@
+use std::time::Duration;
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    audit::Audit,
+    auth::{token_response, verify_credentials},
+    error::{internal_error, json_error},
+    AppState,
+};
+
+/*
+ * OAuth 设备授权（RFC 8628）
+ * 1. CLI 调用 POST /oauth/device/code 拿到 device_code 和一个便于输入的 user_code
+ * 2. 用户在浏览器打开 /oauth/device，输入 user_code 并登录，批准授权
+ * 3. CLI 按 interval 轮询 POST /oauth/token，授权通过后拿到 access token
+ * 整个过程 CLI 不需要接触用户密码，也不需要在本地启动回调服务器
+ */
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const CODE_TTL: Duration = Duration::from_secs(600);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// 去掉了元音和容易混淆的字符，避免组合出单词或者输入错误
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/oauth/device/code", post(device_code))
+        .route("/oauth/device", get(verification_page).post(verify))
+        .route("/oauth/token", post(token))
+}
+
+#[derive(Deserialize)]
+struct DeviceCodeRequest {
+    client_id: String,
+}
+
+async fn device_code(
+    State(state): State<AppState>,
+    Form(input): Form<DeviceCodeRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let mut raw = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut raw);
+    let device_code = URL_SAFE_NO_PAD.encode(raw);
+    let user_code = generate_user_code();
+    let expires_at = Utc::now() + chrono::Duration::from_std(CODE_TTL).unwrap();
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    conn.execute(
+        "INSERT INTO device_codes (device_code, user_code, client_id, expires_at)
+         VALUES ($1, $2, $3, $4)",
+        &[&device_code, &user_code, &input.client_id, &expires_at],
+    )
+    .await
+    .map_err(internal_error)?;
+
+    let verification_uri = format!("{}/oauth/device", state.config.public_url);
+    Ok(Json(json!({
+        "device_code": device_code,
+        "user_code": user_code,
+        "verification_uri_complete": format!("{}?user_code={}", verification_uri, user_code),
+        "verification_uri": verification_uri,
+        "expires_in": CODE_TTL.as_secs(),
+        "interval": POLL_INTERVAL.as_secs(),
+    })))
+}
+
+/**
+ * 生成形如 WDJB-MJHT 的 user_code
+ */
+fn generate_user_code() -> String {
+    let mut rng = rand::thread_rng();
+    let chars: String = (0..8)
+        .map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", &chars[..4], &chars[4..])
+}
+
+/**
+ * 用户输入时可能不带横线或者使用小写，这里统一成生成时的格式
+ */
+fn normalize_user_code(code: &str) -> String {
+    let chars: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if chars.len() == 8 {
+        format!("{}-{}", &chars[..4], &chars[4..])
+    } else {
+        chars
+    }
+}
+
+#[derive(Template)]
+#[template(path = "device.html")]
+struct DeviceTemplate {
+    user_code: String,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VerificationQuery {
+    user_code: Option<String>,
+}
+
+async fn verification_page(
+    Query(query): Query<VerificationQuery>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let page = DeviceTemplate {
+        user_code: query.user_code.unwrap_or_default(),
+        message: None,
+    };
+    Ok(Html(page.render().map_err(internal_error)?))
+}
+
+#[derive(Deserialize)]
+struct VerifyForm {
+    user_code: String,
+    username: String,
+    password: String,
+    action: String,
+}
+
+/**
+ * 用户在浏览器中登录并批准或拒绝设备授权
+ */
+async fn verify(
+    State(state): State<AppState>,
+    audit: Audit,
+    Form(input): Form<VerifyForm>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let user_code = normalize_user_code(&input.user_code);
+    let render = |message: &str| {
+        DeviceTemplate {
+            user_code: user_code.clone(),
+            message: Some(message.to_string()),
+        }
+        .render()
+        .map(Html)
+        .map_err(internal_error)
+    };
+
+    let (user_id, verified) =
+        verify_credentials(&state, audit.ip(), &input.username, &input.password).await?;
+    if !verified {
+        audit
+            .record(
+                &state.pool,
+                user_id,
+                &input.username,
+                "auth.login_failed",
+                json!({ "username": input.username, "flow": "device" }),
+            )
+            .await;
+        return render("Invalid username or password.");
+    }
+
+    let status = if input.action == "approve" {
+        "approved"
+    } else {
+        "denied"
+    };
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let updated = conn
+        .execute(
+            "UPDATE device_codes SET status = $1, user_id = $2
+             WHERE user_code = $3 AND status = 'pending' AND expires_at > now()",
+            &[&status, &user_id, &user_code],
+        )
+        .await
+        .map_err(internal_error)?;
+    if updated == 0 {
+        return render("This code is invalid or has expired.");
+    }
+
+    audit
+        .record(
+            &state.pool,
+            user_id,
+            &input.username,
+            "auth.device_authorize",
+            json!({ "user_code": user_code, "status": status }),
+        )
+        .await;
+    render(if status == "approved" {
+        "Device connected, you can return to your device now."
+    } else {
+        "Device authorization denied."
+    })
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    device_code: String,
+    client_id: String,
+}
+
+/**
+ * 设备轮询获取 token，错误码遵循 RFC 8628 第 3.5 节
+ */
+async fn token(
+    State(state): State<AppState>,
+    Form(input): Form<TokenRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let oauth_error = |error: &str| Ok(json_error(StatusCode::BAD_REQUEST, error));
+
+    if input.grant_type != DEVICE_CODE_GRANT {
+        return oauth_error("unsupported_grant_type");
+    }
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let Some(row) = conn
+        .query_opt(
+            "SELECT client_id, status, expires_at, last_polled_at FROM device_codes
+             WHERE device_code = $1",
+            &[&input.device_code],
+        )
+        .await
+        .map_err(internal_error)?
+    else {
+        return oauth_error("invalid_grant");
+    };
+
+    let client_id: String = row.get("client_id");
+    let status: String = row.get("status");
+    let expires_at: DateTime<Utc> = row.get("expires_at");
+    let last_polled_at: Option<DateTime<Utc>> = row.get("last_polled_at");
+
+    if client_id != input.client_id {
+        return oauth_error("invalid_grant");
+    }
+    if expires_at < Utc::now() {
+        return oauth_error("expired_token");
+    }
+
+    conn.execute(
+        "UPDATE device_codes SET last_polled_at = now() WHERE device_code = $1",
+        &[&input.device_code],
+    )
+    .await
+    .map_err(internal_error)?;
+    // 轮询过快时要求客户端放慢速度
+    if last_polled_at
+        .is_some_and(|polled| (Utc::now() - polled).to_std().unwrap_or_default() < POLL_INTERVAL)
+    {
+        return oauth_error("slow_down");
+    }
+
+    match status.as_str() {
+        "pending" => oauth_error("authorization_pending"),
+        "denied" => oauth_error("access_denied"),
+        "approved" => {
+            // 只有一个并发请求能把状态从 approved 改成 used，保证 device_code 只能换一次 token
+            let Some(row) = conn
+                .query_opt(
+                    "UPDATE device_codes d SET status = 'used' FROM users u
+                     WHERE d.device_code = $1 AND d.status = 'approved' AND u.id = d.user_id
+                       AND u.deleted_at IS NULL
+                     RETURNING u.id, u.username",
+                    &[&input.device_code],
+                )
+                .await
+                .map_err(internal_error)?
+            else {
+                return oauth_error("invalid_grant");
+            };
+            let body = token_response(&state, row.get(0), row.get(1), None).await?;
+            Ok(Json(body).into_response())
+        }
+        _ => oauth_error("invalid_grant"),
+    }
+}
@@ -0,0 +1,159 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde_json::json;
+use tokio::sync::watch;
+
+use crate::{
+    audit::Audit,
+    config::DrainConfig,
+    permissions::{Authorize, ServerManage},
+    AppState,
+};
+
+/*
+ * 滚动发布时摘掉流量再退出，不丢请求
+ * 开始排空（POST /admin/drain，或者收到 SIGTERM/Ctrl-C）之后：
+ * 1. /readyz 返回 503，负载均衡器发现之后不再把新请求发过来
+ * 2. 这期间仍然照常处理请求，响应带上 Connection: close，客户端的长连接用完就断开，重连到其他实例
+ * 3. 等待 DRAIN_WAIT_SECS 秒（要比负载均衡器的健康检查间隔 × 失败次数长），然后停止接受新连接，
+ *    等正在处理的请求完成后退出
+ * 4. 超过 DRAIN_TIMEOUT_SECS 秒还没处理完（比如 SSE 之类的长连接）就直接退出
+ * Kubernetes 会在发送 SIGTERM 的同时把 Pod 从 Service 里摘掉，terminationGracePeriodSeconds 要比两个时间加起来更长。
+ */
+
+#[derive(Clone)]
+pub struct Drain {
+    // 是否已经开始排空
+    draining: Arc<watch::Sender<bool>>,
+    // 是否已经开始停止服务
+    stopping: Arc<watch::Sender<bool>>,
+    wait: Duration,
+}
+
+impl Drain {
+    pub fn new(config: &DrainConfig) -> Self {
+        Drain {
+            draining: Arc::new(watch::channel(false).0),
+            stopping: Arc::new(watch::channel(false).0),
+            wait: config.wait,
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /**
+     * 开始排空，DRAIN_WAIT_SECS 秒之后开始停止服务；已经在排空时返回 false
+     */
+    pub fn start(&self, reason: &str) -> bool {
+        if self.draining.send_replace(true) {
+            return false;
+        }
+        tracing::warn!(
+            "draining ({}), shutting down in {} seconds",
+            reason,
+            self.wait.as_secs()
+        );
+        let drain = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(drain.wait).await;
+            tracing::warn!("stop accepting connections, waiting for in-flight requests");
+            drain.stopping.send_replace(true);
+        });
+        true
+    }
+
+    /**
+     * 开始停止服务时返回，交给 axum::serve 的 with_graceful_shutdown
+     */
+    pub async fn stopping(self) {
+        let mut stopping = self.stopping.subscribe();
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    }
+
+    /**
+     * 收到 SIGTERM 或者 Ctrl-C 时开始排空
+     */
+    pub fn listen_for_signals(&self) {
+        let drain = self.clone();
+        tokio::spawn(async move {
+            let ctrl_c = tokio::signal::ctrl_c();
+            #[cfg(unix)]
+            let terminate = async {
+                match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                    Ok(mut signal) => {
+                        signal.recv().await;
+                    }
+                    Err(err) => {
+                        tracing::warn!("listen for SIGTERM failed: {}", err);
+                        std::future::pending::<()>().await;
+                    }
+                }
+            };
+            #[cfg(not(unix))]
+            let terminate = std::future::pending::<()>();
+            let reason = tokio::select! {
+                _ = ctrl_c => "Ctrl-C",
+                _ = terminate => "SIGTERM",
+            };
+            drain.start(reason);
+        });
+    }
+}
+
+/**
+ * 排空期间的响应带上 Connection: close，处理完这个请求就断开长连接
+ */
+pub async fn close_connections(State(drain): State<Drain>, req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    if drain.is_draining() {
+        res.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    res
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/drain", post(drain))
+}
+
+async fn drain(
+    Authorize { user: admin, .. }: Authorize<ServerManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+) -> Response {
+    let started = state
+        .drain
+        .start(&format!("requested by {}", admin.username));
+    if started {
+        audit
+            .record(
+                &state.pool,
+                Some(admin.id),
+                &admin.username,
+                "admin.drain",
+                json!({}),
+            )
+            .await;
+    }
+    let config = &state.config.drain;
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "status": "draining",
+            "already_draining": !started,
+            "wait_secs": config.wait.as_secs(),
+            "timeout_secs": config.timeout.as_secs(),
+        })),
+    )
+        .into_response()
+}
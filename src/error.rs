@@ -0,0 +1,76 @@
+use std::{any::Any, backtrace::Backtrace, panic::PanicHookInfo};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::error_report;
+
+/**
+ * 统一的 JSON 错误响应，格式为 { "error": "..." }
+ */
+pub fn json_error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+/**
+ * 请求体超出限制时，RequestBodyLimitLayer 和各个解包器返回的都是纯文本的 413，
+ * 这里统一替换成 JSON 格式，方便客户端解析
+ */
+pub async fn payload_too_large_json(res: Response) -> Response {
+    if res.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        json_error(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+    } else {
+        res
+    }
+}
+
+/**
+ * 把任意错误转换为 500 响应
+ */
+pub fn internal_error<E>(err: E) -> (StatusCode, String)
+where
+    E: std::error::Error,
+{
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+/**
+ * handler 里 panic 时 CatchPanicLayer 调用这个函数生成响应，客户端只看到 500，
+ * 不返回 panic 的内容，里面可能有内部的细节；annotate_errors 会在响应体里加上请求 ID，方便对照日志
+ */
+pub fn panic_response(_err: Box<dyn Any + Send + 'static>) -> Response {
+    let mut res = json_error(StatusCode::INTERNAL_SERVER_ERROR, "internal server error");
+    // panic hook 已经上报过了，见 error_report
+    res.extensions_mut().insert(error_report::Panicked);
+    res
+}
+
+/**
+ * 代替默认的 panic hook，把 panic 的内容、位置和调用栈写进日志，而不是直接打印到 stderr
+ * hook 在 panic 的线程里执行，当前的 request span 还在，日志里带着请求 ID
+ */
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info: &PanicHookInfo| {
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        tracing::error!(
+            panic.message = message,
+            panic.location = location.as_str(),
+            "panic: {}\n{}",
+            message,
+            Backtrace::force_capture()
+        );
+    }));
+}
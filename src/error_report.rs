@@ -0,0 +1,190 @@
+#[cfg(feature = "sentry")]
+use std::sync::Arc;
+
+#[cfg(feature = "sentry")]
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::MatchedPath,
+    http::header,
+};
+use axum::{extract::Request, middleware::Next, response::Response};
+#[cfg(feature = "sentry")]
+use sentry::{Hub, Level, SentryFutureExt};
+
+use crate::config::SentryConfig;
+#[cfg(feature = "sentry")]
+use crate::request_id::X_REQUEST_ID;
+
+/*
+ * 把 5xx 错误和 panic 上报到 Sentry（或者兼容 Sentry 协议的服务，比如 GlitchTip），
+ * 不用等用户反馈或者翻日志才知道出了问题
+ * 需要打开 sentry 特性编译（cargo build --features sentry），并设置 SENTRY_DSN；
+ * 没有打开特性时这里的函数什么都不做，设置了 SENTRY_DSN 会在启动时打印一条警告。
+ * - 每个请求使用单独的 Hub（capture 中间件），事件上带着 route（匹配到的路由）、request_id 和 status 标签，
+ *   登录用户的 id 和用户名由 AuthUser 提取器设置（set_user），和日志、错误响应里的 request_id 可以对上
+ * - handler 返回 5xx 时上报一条错误事件，内容是错误响应里的消息（JSON 的 error 字段或者纯文本）
+ * - panic 由 sentry 的 panic hook 上报，带调用栈；CatchPanicLayer 生成的 500 响应带有 Panicked 标记，不会重复上报
+ * 发版本时把 release 设为 rs-practice-axum@{版本}+{git commit}（见 build.rs），可以在 Sentry 里区分是哪个版本引入的问题。
+ * 放在 CatchPanicLayer 外面，能看到 panic 之后的响应；在 request_id::annotate_errors 里面，响应体还没有加上请求 ID。
+ */
+
+// 超过这个大小的错误响应体不读取消息
+#[cfg(feature = "sentry")]
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/**
+ * 响应是 CatchPanicLayer 在 panic 之后生成的，panic hook 已经上报过了
+ */
+#[derive(Clone, Copy)]
+pub struct Panicked;
+
+/**
+ * init 返回的句柄，需要一直持有到程序退出，drop 的时候把还没发送的事件发出去
+ */
+pub struct ErrorReportGuard {
+    #[cfg(feature = "sentry")]
+    _client: Option<sentry::ClientInitGuard>,
+}
+
+/**
+ * 初始化 Sentry 客户端，需要在 error::install_panic_hook 之后调用，sentry 的 panic hook 会接在原来的 hook 前面
+ */
+#[cfg(feature = "sentry")]
+pub fn init(config: &SentryConfig) -> ErrorReportGuard {
+    let disabled = ErrorReportGuard { _client: None };
+    let Some(dsn) = &config.dsn else {
+        return disabled;
+    };
+    let dsn = match dsn.parse() {
+        Ok(dsn) => dsn,
+        Err(err) => {
+            tracing::warn!("invalid SENTRY_DSN, errors are not reported: {}", err);
+            return disabled;
+        }
+    };
+    let guard = sentry::init(sentry::ClientOptions {
+        dsn: Some(dsn),
+        release: Some(
+            format!(
+                "{}@{}+{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                env!("GIT_SHA")
+            )
+            .into(),
+        ),
+        environment: config.environment.clone().map(Into::into),
+        sample_rate: config.sample_rate,
+        attach_stacktrace: true,
+        ..Default::default()
+    });
+    tracing::info!("reporting errors to Sentry");
+    ErrorReportGuard {
+        _client: Some(guard),
+    }
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init(config: &SentryConfig) -> ErrorReportGuard {
+    if config.dsn.is_some() {
+        tracing::warn!(
+            "SENTRY_DSN is set, but this build has no sentry feature, errors are not reported"
+        );
+    }
+    ErrorReportGuard {}
+}
+
+/**
+ * 记录当前请求的登录用户，之后上报的事件都带着它
+ */
+#[cfg(feature = "sentry")]
+pub fn set_user(id: i64, username: &str) {
+    sentry::configure_scope(|scope| {
+        scope.set_user(Some(sentry::User {
+            id: Some(id.to_string()),
+            username: Some(username.to_string()),
+            ..Default::default()
+        }))
+    });
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn set_user(_id: i64, _username: &str) {}
+
+/**
+ * 错误响应里的消息，JSON 取 error 字段，太大或者不是文本的响应体不读
+ */
+#[cfg(feature = "sentry")]
+async fn error_message(res: Response) -> (Response, Option<String>) {
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("text/plain")
+        .to_string();
+    let json = content_type.starts_with("application/json");
+    let small = res
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= MAX_ERROR_BODY as u64);
+    if !small || !json && !content_type.starts_with("text/plain") {
+        return (res, None);
+    }
+    let (parts, body) = res.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_ERROR_BODY).await else {
+        return (Response::from_parts(parts, Body::empty()), None);
+    };
+    let message = if json {
+        serde_json::from_slice::<serde_json::Value>(&bytes)
+            .ok()
+            .and_then(|value| value.get("error")?.as_str().map(str::to_string))
+    } else {
+        Some(String::from_utf8_lossy(&bytes).trim().to_string())
+    };
+    (Response::from_parts(parts, Body::from(bytes)), message)
+}
+
+#[cfg(feature = "sentry")]
+pub async fn capture(req: Request, next: Next) -> Response {
+    // 打开了特性但是没有设置 SENTRY_DSN
+    if Hub::main().client().is_none() {
+        return next.run(req).await;
+    }
+    let hub = Arc::new(Hub::new_from_top(Hub::main()));
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = req.method().to_string();
+    hub.configure_scope(|scope| {
+        scope.set_tag("route", &route);
+        scope.set_tag(
+            "request_id",
+            req.headers()
+                .get(X_REQUEST_ID)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-"),
+        );
+        scope.set_transaction(Some(&format!("{} {}", method, route)));
+    });
+
+    let res = next.run(req).bind_hub(hub.clone()).await;
+    if !res.status().is_server_error() || res.extensions().get::<Panicked>().is_some() {
+        return res;
+    }
+    let status = res.status();
+    let (res, message) = error_message(res).await;
+    hub.configure_scope(|scope| scope.set_tag("status", status.as_u16()));
+    hub.capture_message(
+        &message.unwrap_or_else(|| format!("{} {} returned {}", method, route, status)),
+        Level::Error,
+    );
+    res
+}
+
+#[cfg(not(feature = "sentry"))]
+pub async fn capture(req: Request, next: Next) -> Response {
+    next.run(req).await
+}
@@ -0,0 +1,98 @@
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+
+/*
+ * ETag 和条件 GET
+ * GET/HEAD 成功返回的 JSON 和 HTML 响应：
+ * - handler 已经设置了 ETag 时保留（比如 users 按 version 生成的强 ETag，也用于 If-Match 乐观锁）
+ * - 否则按响应体的 SHA-256 生成弱 ETag，形如 W/"1a2b..."；同样的内容压缩与否 ETag 都一样，所以用弱 ETag
+ * 请求的 If-None-Match 和 ETag 匹配时（弱比较，支持逗号分隔的多个值和 *）返回 304，不带响应体，
+ * 客户端和代理继续使用自己缓存的那一份。
+ * 需要读出整个响应体才能计算，超过 MAX_BODY 或者长度未知（比如 SSE）的响应不处理。
+ * 放在 compat 和 degraded 外面，按转换之后、客户端实际收到的内容计算；放在 compression 里面，按压缩之前的内容计算。
+ */
+
+const MAX_BODY: u64 = 1024 * 1024;
+
+/**
+ * 按弱比较判断 If-None-Match 是否匹配：去掉 W/ 前缀之后比较
+ */
+pub fn matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    if_none_match
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == etag)
+}
+
+fn has_etag_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.starts_with("application/json") || value.starts_with("text/html")
+        })
+}
+
+fn not_modified(mut res: Response) -> Response {
+    *res.status_mut() = StatusCode::NOT_MODIFIED;
+    res.headers_mut().remove(header::CONTENT_LENGTH);
+    let (parts, _) = res.into_parts();
+    Response::from_parts(parts, Body::empty())
+}
+
+pub async fn conditional_get(req: Request, next: Next) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+    let if_none_match = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let res = next.run(req).await;
+    if res.status() != StatusCode::OK {
+        return res;
+    }
+
+    // handler 自己设置了 ETag，只需要处理 If-None-Match
+    if let Some(etag) = res
+        .headers()
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+    {
+        let modified = if_none_match
+            .as_deref()
+            .is_none_or(|if_none_match| !matches(if_none_match, etag));
+        return if modified { res } else { not_modified(res) };
+    }
+
+    let size = res.body().size_hint().exact();
+    if !has_etag_type(res.headers()) || size.is_none_or(|size| size > MAX_BODY) {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_BODY as usize).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let digest = Sha256::digest(&bytes);
+    let hex: String = digest[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let etag = format!("W/\"{}\"", hex);
+    parts
+        .headers
+        .insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    let res = Response::from_parts(parts, Body::from(bytes));
+    match if_none_match {
+        Some(if_none_match) if matches(&if_none_match, &etag) => not_modified(res),
+        _ => res,
+    }
+}
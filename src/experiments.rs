@@ -0,0 +1,201 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    config::ExperimentsConfig,
+    db::ConnectionPool,
+    error::internal_error,
+    pagination::{Paginated, Pagination},
+    permissions::{Authorize, ConfigRead},
+    session::Session,
+    AppState,
+};
+
+/*
+ * A/B 实验：同一个页面或者邮件准备几个版本（文案、设计），不同的用户看到不同的版本，比较哪个效果更好
+ * - EXPERIMENTS 配置进行中的实验，形如 subscribe_form=control|short_copy，多个实验用逗号分隔
+ * - 每个实验对象（登录用户 user:1，匿名访客是会话里保存的随机 id session:<uuid>）按 SHA-256(实验名:对象) 固定分到一个版本，
+ *   同一个人每次看到的都一样，不需要保存分组结果
+ * - 模板里用 experiments.is("实验名", "版本") 选择要显示的块，没有配置的实验 is 总是 false，显示 else 里的默认版本，
+ *   所以下线实验只需要改配置，不需要改代码：
+ *   {% if experiments.is("subscribe_form", "short_copy") %}...{% else %}...{% endif %}
+ * - 每次分组（assign）都算一次曝光，写进 experiment_exposures 表，同时打印一条 target 为 experiments 的日志
+ * GET /admin/experiments 按版本统计曝光的人数和次数，按实验名分页（见 pagination），需要 config:read 权限。
+ * 实验来自配置而不是数据库，所以分页是在内存里对配置的实验名做的，只查询这一页的实验的统计。
+ * 现在使用实验的地方：/form 的订阅表单（subscribe_form）和接口下线提醒邮件（deprecation_email，见 deprecation）。
+ */
+
+// 会话里保存匿名访客 id 的键
+const SESSION_KEY: &str = "experiment_subject";
+
+#[derive(Clone)]
+pub struct Experiments {
+    // 实验名 -> 版本
+    experiments: Arc<HashMap<String, Vec<String>>>,
+}
+
+/**
+ * 一个实验对象在各个实验里分到的版本，交给模板使用
+ */
+#[derive(Default)]
+pub struct Assignment(HashMap<&'static str, String>);
+
+impl Assignment {
+    pub fn is(&self, experiment: &str, variant: &str) -> bool {
+        self.0
+            .get(experiment)
+            .is_some_and(|assigned| assigned == variant)
+    }
+}
+
+/**
+ * 匿名访客的实验对象，第一次访问时生成一个随机 id 保存在会话里
+ */
+pub fn session_subject(session: &Session) -> String {
+    let id = session.get::<Uuid>(SESSION_KEY).unwrap_or_else(|| {
+        let id = Uuid::new_v4();
+        session.insert(SESSION_KEY, id);
+        id
+    });
+    format!("session:{}", id)
+}
+
+pub fn user_subject(user_id: i64) -> String {
+    format!("user:{}", user_id)
+}
+
+impl Experiments {
+    pub fn new(config: &ExperimentsConfig) -> Self {
+        // 格式在加载配置时已经检查过
+        let experiments = config
+            .experiments
+            .iter()
+            .filter_map(|entry| entry.split_once('='))
+            .map(|(name, variants)| {
+                let variants = variants
+                    .split('|')
+                    .map(|variant| variant.trim().to_string())
+                    .collect();
+                (name.trim().to_string(), variants)
+            })
+            .collect();
+        Experiments {
+            experiments: Arc::new(experiments),
+        }
+    }
+
+    fn variant(&self, experiment: &str, subject: &str) -> Option<&str> {
+        let variants = self.experiments.get(experiment)?;
+        let digest = Sha256::digest(format!("{}:{}", experiment, subject));
+        let bucket = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        variants
+            .get((bucket % variants.len() as u64) as usize)
+            .map(String::as_str)
+    }
+
+    /**
+     * 给实验对象分组并记录曝光，没有配置的实验不分组、不记录
+     * 记录失败只打印警告，不影响页面和邮件
+     */
+    pub async fn assign(
+        &self,
+        pool: &ConnectionPool,
+        subject: &str,
+        experiments: &[&'static str],
+    ) -> Assignment {
+        let mut assignment = Assignment::default();
+        for &experiment in experiments {
+            let Some(variant) = self.variant(experiment, subject) else {
+                continue;
+            };
+            tracing::info!(
+                target: "experiments",
+                experiment,
+                variant,
+                subject,
+                "experiment exposure"
+            );
+            if let Err((_, err)) = record_exposure(pool, experiment, subject, variant).await {
+                tracing::warn!("record experiment exposure failed: {}", err);
+            }
+            assignment.0.insert(experiment, variant.to_string());
+        }
+        assignment
+    }
+}
+
+async fn record_exposure(
+    pool: &ConnectionPool,
+    experiment: &str,
+    subject: &str,
+    variant: &str,
+) -> Result<(), (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    conn.execute(
+        "INSERT INTO experiment_exposures (experiment, subject, variant) VALUES ($1, $2, $3)
+         ON CONFLICT (experiment, subject) DO UPDATE
+         SET variant = EXCLUDED.variant,
+             exposures = experiment_exposures.exposures + 1,
+             last_seen_at = now()",
+        &[&experiment, &subject, &variant],
+    )
+    .await
+    .map_err(internal_error)?;
+    Ok(())
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/experiments", get(list))
+}
+
+/**
+ * 配置的实验和每个版本的曝光统计
+ */
+async fn list(
+    _auth: Authorize<ConfigRead>,
+    State(state): State<AppState>,
+    pagination: Pagination,
+) -> Result<Json<Paginated<Value>>, (StatusCode, String)> {
+    let mut names: Vec<&String> = state.experiments.experiments.keys().collect();
+    names.sort();
+    let total = names.len() as i64;
+    let names: Vec<&String> = names
+        .into_iter()
+        .skip(pagination.offset() as usize)
+        .take(pagination.limit() as usize)
+        .collect();
+
+    let conn = state.read().get().await.map_err(internal_error)?;
+    let rows = conn
+        .query(
+            "SELECT experiment, variant, count(*) AS subjects, sum(exposures)::BIGINT AS exposures
+             FROM experiment_exposures WHERE experiment = ANY($1)
+             GROUP BY experiment, variant ORDER BY experiment, variant",
+            &[&names],
+        )
+        .await
+        .map_err(internal_error)?;
+    let mut experiments: Vec<Value> = Vec::new();
+    for name in names {
+        let variants: Vec<Value> = state.experiments.experiments[name]
+            .iter()
+            .map(|variant| {
+                let row = rows.iter().find(|row| {
+                    row.get::<_, &str>("experiment") == name
+                        && row.get::<_, &str>("variant") == variant
+                });
+                json!({
+                    "variant": variant,
+                    "subjects": row.map(|row| row.get::<_, i64>("subjects")).unwrap_or(0),
+                    "exposures": row.map(|row| row.get::<_, i64>("exposures")).unwrap_or(0),
+                })
+            })
+            .collect();
+        experiments.push(json!({ "name": name, "variants": variants }));
+    }
+    Ok(Json(Paginated::new(experiments, total, pagination)))
+}
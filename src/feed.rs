@@ -0,0 +1,274 @@
+use std::io;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use quick_xml::{
+    events::{BytesDecl, BytesText, Event},
+    Writer,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{
+        repo::{self, Incident},
+        with_retry,
+    },
+    delta::HTTP_DATE,
+    error::internal_error,
+    etag, AppState,
+};
+
+/*
+ * 状态页故障的订阅源，用 RSS 阅读器或者 Slack、Teams 的 RSS 应用订阅之后，有新故障或者进展时就能收到通知
+ * 数据库里没有博客文章这类内容，公开的、按时间发布的只有状态页上的故障，所以订阅源的条目就是故障：
+ * - GET /feed.xml   RSS 2.0
+ * - GET /feed.atom  Atom 1.0
+ * 最近更新过的 FEED_ITEMS 个故障，从新到旧，每个条目的内容是它的全部处理进展，链接指向 /status 页面上对应的位置。
+ * 订阅源的更新时间（RSS 的 lastBuildDate、Atom 的 updated）取最新的一个故障的 updated_at，没有故障时是 1970-01-01。
+ * 缓存：
+ * - Last-Modified 同样取最新的 updated_at，If-Modified-Since 不早于它时返回 304
+ * - ETag 是内容的 SHA-256，If-None-Match 匹配时返回 304，两个请求头都有时以 If-None-Match 为准
+ * - Cache-Control: public, max-age=300，阅读器一般几十分钟才拉取一次，几分钟的延迟可以接受
+ * 链接用 PUBLIC_URL 拼成绝对地址，阅读器不知道订阅源是从哪个地址拉取的。
+ */
+
+const FEED_ITEMS: i64 = 20;
+const FEED_TITLE: &str = "Service status";
+const FEED_DESCRIPTION: &str = "Incidents and their updates from the status page";
+const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/feed.xml", get(rss))
+        .route("/feed.atom", get(atom))
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/**
+ * 条目的内容是 HTML，写进 XML 时会再转义一次，阅读器按 HTML 显示
+ */
+fn content(incident: &Incident) -> String {
+    let mut html = format!(
+        "<p>Impact: {}. Affected: {}.</p>",
+        html_escape(&incident.impact),
+        html_escape(&incident.components.join(", "))
+    );
+    for update in &incident.updates {
+        html.push_str(&format!(
+            "<p><strong>{}</strong> {}: {}</p>",
+            html_escape(&update.status),
+            update.created_at.format("%Y-%m-%d %H:%M UTC"),
+            html_escape(&update.message)
+        ));
+    }
+    html
+}
+
+fn title(incident: &Incident) -> String {
+    format!("{} ({})", incident.title, incident.status)
+}
+
+fn text<W: io::Write>(writer: &mut Writer<W>, name: &str, value: &str) -> io::Result<()> {
+    writer
+        .create_element(name)
+        .write_text_content(BytesText::new(value))?;
+    Ok(())
+}
+
+fn write_rss(base: &str, incidents: &[Incident], updated: DateTime<Utc>) -> io::Result<Vec<u8>> {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("rss")
+        .with_attributes([("version", "2.0"), ("xmlns:atom", ATOM_NS)])
+        .write_inner_content(|writer| {
+            writer
+                .create_element("channel")
+                .write_inner_content(|writer| {
+                    text(writer, "title", FEED_TITLE)?;
+                    text(writer, "link", &format!("{}/status", base))?;
+                    text(writer, "description", FEED_DESCRIPTION)?;
+                    text(writer, "language", "en")?;
+                    text(writer, "lastBuildDate", &updated.to_rfc2822())?;
+                    // RSS 2.0 建议带上指向自己的 atom:link
+                    writer
+                        .create_element("atom:link")
+                        .with_attributes([
+                            ("href", format!("{}/feed.xml", base).as_str()),
+                            ("rel", "self"),
+                            ("type", "application/rss+xml"),
+                        ])
+                        .write_empty()?;
+                    for incident in incidents {
+                        let link = format!("{}/status#incident-{}", base, incident.id);
+                        writer
+                            .create_element("item")
+                            .write_inner_content(|writer| {
+                                text(writer, "title", &title(incident))?;
+                                text(writer, "link", &link)?;
+                                text(writer, "description", &content(incident))?;
+                                text(writer, "category", &incident.impact)?;
+                                text(writer, "pubDate", &incident.created_at.to_rfc2822())?;
+                                writer
+                                    .create_element("guid")
+                                    .with_attribute(("isPermaLink", "true"))
+                                    .write_text_content(BytesText::new(&link))?;
+                                Ok(())
+                            })?;
+                    }
+                    Ok(())
+                })?;
+            Ok(())
+        })?;
+    Ok(writer.into_inner())
+}
+
+fn write_atom(base: &str, incidents: &[Incident], updated: DateTime<Utc>) -> io::Result<Vec<u8>> {
+    let rfc3339 = |time: DateTime<Utc>| time.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("feed")
+        .with_attribute(("xmlns", ATOM_NS))
+        .write_inner_content(|writer| {
+            text(writer, "title", FEED_TITLE)?;
+            text(writer, "subtitle", FEED_DESCRIPTION)?;
+            text(writer, "id", &format!("{}/status", base))?;
+            text(writer, "updated", &rfc3339(updated))?;
+            writer
+                .create_element("link")
+                .with_attribute(("href", format!("{}/status", base).as_str()))
+                .write_empty()?;
+            writer
+                .create_element("link")
+                .with_attributes([
+                    ("href", format!("{}/feed.atom", base).as_str()),
+                    ("rel", "self"),
+                    ("type", "application/atom+xml"),
+                ])
+                .write_empty()?;
+            // 条目没有单独的作者时必须有订阅源级别的作者
+            writer
+                .create_element("author")
+                .write_inner_content(|writer| text(writer, "name", FEED_TITLE))?;
+            for incident in incidents {
+                let link = format!("{}/status#incident-{}", base, incident.id);
+                writer
+                    .create_element("entry")
+                    .write_inner_content(|writer| {
+                        text(writer, "title", &title(incident))?;
+                        text(writer, "id", &link)?;
+                        text(writer, "published", &rfc3339(incident.created_at))?;
+                        text(writer, "updated", &rfc3339(incident.updated_at))?;
+                        writer
+                            .create_element("link")
+                            .with_attribute(("href", link.as_str()))
+                            .write_empty()?;
+                        writer
+                            .create_element("category")
+                            .with_attribute(("term", incident.impact.as_str()))
+                            .write_empty()?;
+                        writer
+                            .create_element("content")
+                            .with_attribute(("type", "html"))
+                            .write_text_content(BytesText::new(&content(incident)))?;
+                        Ok(())
+                    })?;
+            }
+            Ok(())
+        })?;
+    Ok(writer.into_inner())
+}
+
+type Render = fn(&str, &[Incident], DateTime<Utc>) -> io::Result<Vec<u8>>;
+
+/**
+ * 读出故障，生成订阅源，处理条件请求
+ */
+async fn feed(
+    state: &AppState,
+    headers: &HeaderMap,
+    content_type: &'static str,
+    render: Render,
+) -> Result<Response, (StatusCode, String)> {
+    let incidents = with_retry(state, |conn| async move {
+        repo::latest_incidents(&*conn, FEED_ITEMS).await
+    })
+    .await?;
+    let updated = incidents
+        .iter()
+        .map(|incident| incident.updated_at)
+        .max()
+        .unwrap_or(DateTime::UNIX_EPOCH)
+        .trunc_subsecs(0);
+    let base = state.config.public_url.trim_end_matches('/');
+    let body = render(base, &incidents, updated).map_err(internal_error)?;
+
+    let etag = format!("\"{:x}\"", Sha256::digest(&body));
+    let last_modified = updated.format(HTTP_DATE).to_string();
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let not_modified = match header(header::IF_NONE_MATCH) {
+        Some(if_none_match) => etag::matches(if_none_match, &etag),
+        None => header(header::IF_MODIFIED_SINCE)
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+            .is_some_and(|since| since >= updated),
+    };
+    let headers = [
+        (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=300"),
+        ),
+        (
+            header::ETAG,
+            HeaderValue::from_str(&etag).expect("hex digests are valid header values"),
+        ),
+        (
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&last_modified).expect("HTTP date is a valid header value"),
+        ),
+    ];
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+    Ok((headers, body).into_response())
+}
+
+async fn rss(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    feed(
+        &state,
+        &headers,
+        "application/rss+xml; charset=utf-8",
+        write_rss,
+    )
+    .await
+}
+
+async fn atom(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    feed(
+        &state,
+        &headers,
+        "application/atom+xml; charset=utf-8",
+        write_atom,
+    )
+    .await
+}
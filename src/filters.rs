@@ -0,0 +1,302 @@
+use std::{borrow::Borrow, convert::Infallible, fmt};
+
+use axum::{async_trait, extract::FromRequestParts, http::header, http::request::Parts};
+use chrono::{DateTime, Utc};
+
+/*
+ * askama 自定义过滤器
+ * 模板所在的模块里 use crate::filters 之后，模板中就可以使用 {{ value|number(locale) }} 这样的写法，
+ * askama 会把 `value|name(args)` 翻译成 filters::name(value, args) 的调用，value 可能是值也可能是引用。
+ * - number(locale): 按地区习惯加上千位分隔符，整数不带小数，浮点数保留两位小数
+ * - currency(code, locale): 显示金额，code 是 ISO 4217 货币代码（比如 "EUR"），决定货币符号和小数位数，
+ *   locale 只决定分隔符和符号放在前面还是后面，同样是欧元，en-US 下是 €1,234.50，de-DE 下是 1.234,50 €
+ * - relative_time: 显示为 "3 minutes ago"、"in 2 days" 这样的相对时间
+ * - pdf_text: 转义 PDF 模板标记里有特殊含义的字符，换行换成空格，见 pdf
+ * 地区格式只内置了常用的几种，没有完整的 ICU 数据，不认识的地区按 en-US 处理。
+ */
+
+/**
+ * 一个地区的数字格式
+ */
+struct LocaleFormat {
+    tag: &'static str,
+    decimal: char,
+    group: char,
+    // 货币符号放在数字前面还是后面，后面时中间加一个空格
+    symbol_first: bool,
+}
+
+const LOCALES: &[LocaleFormat] = &[
+    LocaleFormat {
+        tag: "en-US",
+        decimal: '.',
+        group: ',',
+        symbol_first: true,
+    },
+    LocaleFormat {
+        tag: "en-GB",
+        decimal: '.',
+        group: ',',
+        symbol_first: true,
+    },
+    LocaleFormat {
+        tag: "de-DE",
+        decimal: ',',
+        group: '.',
+        symbol_first: false,
+    },
+    LocaleFormat {
+        tag: "fr-FR",
+        decimal: ',',
+        // 法语用窄的不换行空格分组
+        group: '\u{202f}',
+        symbol_first: false,
+    },
+    LocaleFormat {
+        tag: "zh-CN",
+        decimal: '.',
+        group: ',',
+        symbol_first: true,
+    },
+    LocaleFormat {
+        tag: "ja-JP",
+        decimal: '.',
+        group: ',',
+        symbol_first: true,
+    },
+];
+
+/**
+ * 先按完整的地区标签匹配，再按语言匹配（比如 de-AT 使用 de-DE 的格式）
+ */
+fn lookup(locale: &str) -> Option<&'static LocaleFormat> {
+    let language = locale.split(['-', '_']).next().unwrap_or(locale);
+    LOCALES
+        .iter()
+        .find(|format| format.tag.eq_ignore_ascii_case(&locale.replace('_', "-")))
+        .or_else(|| {
+            LOCALES.iter().find(|format| {
+                format
+                    .tag
+                    .split('-')
+                    .next()
+                    .is_some_and(|lang| lang.eq_ignore_ascii_case(language))
+            })
+        })
+}
+
+fn format_of(locale: &str) -> &'static LocaleFormat {
+    lookup(locale).unwrap_or(&LOCALES[0])
+}
+
+/**
+ * 可以格式化的数字类型
+ */
+pub trait Decimal {
+    // 默认显示几位小数
+    const FRACTION_DIGITS: usize;
+
+    // 不带分组的十进制表示，小数点固定为 '.'
+    fn to_plain(&self, fraction_digits: usize) -> String;
+}
+
+macro_rules! integer_decimal {
+    ($($ty:ty),*) => {
+        $(
+            impl Decimal for $ty {
+                const FRACTION_DIGITS: usize = 0;
+
+                fn to_plain(&self, fraction_digits: usize) -> String {
+                    if fraction_digits == 0 {
+                        self.to_string()
+                    } else {
+                        format!("{}.{}", self, "0".repeat(fraction_digits))
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_decimal!(i32, i64, u32, u64, usize);
+
+impl Decimal for f64 {
+    const FRACTION_DIGITS: usize = 2;
+
+    fn to_plain(&self, fraction_digits: usize) -> String {
+        format!("{:.*}", fraction_digits, self)
+    }
+}
+
+impl<T: Decimal + ?Sized> Decimal for &T {
+    const FRACTION_DIGITS: usize = T::FRACTION_DIGITS;
+
+    fn to_plain(&self, fraction_digits: usize) -> String {
+        (**self).to_plain(fraction_digits)
+    }
+}
+
+/**
+ * 把 "-1234567.50" 转换成对应地区的写法，比如 de-DE 下为 "-1.234.567,50"
+ */
+fn localize(plain: &str, format: &LocaleFormat) -> String {
+    let (sign, digits) = match plain.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", plain),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+    let mut grouped = String::new();
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            grouped.push(format.group);
+        }
+        grouped.push(digit);
+    }
+    if !fraction.is_empty() {
+        grouped.push(format.decimal);
+        grouped.push_str(fraction);
+    }
+    format!("{}{}", sign, grouped)
+}
+
+pub fn number<T: Decimal>(value: T, locale: impl AsRef<str>) -> askama::Result<String> {
+    let format = format_of(locale.as_ref());
+    Ok(localize(&value.to_plain(T::FRACTION_DIGITS), format))
+}
+
+/**
+ * 一种货币的符号和小数位数
+ */
+struct CurrencyFormat {
+    code: &'static str,
+    symbol: &'static str,
+    // 最小单位的位数，比如日元没有小数
+    digits: usize,
+}
+
+const CURRENCIES: &[CurrencyFormat] = &[
+    CurrencyFormat {
+        code: "USD",
+        symbol: "$",
+        digits: 2,
+    },
+    CurrencyFormat {
+        code: "EUR",
+        symbol: "€",
+        digits: 2,
+    },
+    CurrencyFormat {
+        code: "GBP",
+        symbol: "£",
+        digits: 2,
+    },
+    CurrencyFormat {
+        code: "CNY",
+        symbol: "¥",
+        digits: 2,
+    },
+    CurrencyFormat {
+        code: "JPY",
+        symbol: "￥",
+        digits: 0,
+    },
+];
+
+/**
+ * 金额以元为单位（不是分），不认识的货币代码直接显示代码本身，保留两位小数
+ * 现在还没有显示金额的模板，用上之前先允许未使用
+ */
+#[allow(dead_code)]
+pub fn currency<T: Decimal>(
+    value: T,
+    code: impl AsRef<str>,
+    locale: impl AsRef<str>,
+) -> askama::Result<String> {
+    let code = code.as_ref();
+    let (symbol, digits) = CURRENCIES
+        .iter()
+        .find(|currency| currency.code.eq_ignore_ascii_case(code))
+        .map_or((code, 2), |currency| (currency.symbol, currency.digits));
+    let format = format_of(locale.as_ref());
+    let amount = localize(&value.to_plain(digits), format);
+    Ok(if format.symbol_first {
+        match amount.strip_prefix('-') {
+            Some(amount) => format!("-{}{}", symbol, amount),
+            None => format!("{}{}", symbol, amount),
+        }
+    } else {
+        format!("{}\u{a0}{}", amount, symbol)
+    })
+}
+
+const UNITS: &[(&str, i64)] = &[
+    ("year", 365 * 86400),
+    ("month", 30 * 86400),
+    ("week", 7 * 86400),
+    ("day", 86400),
+    ("hour", 3600),
+    ("minute", 60),
+];
+
+pub fn relative_time(time: impl Borrow<DateTime<Utc>>) -> askama::Result<String> {
+    let seconds = (Utc::now() - *time.borrow()).num_seconds();
+    let Some((unit, size)) = UNITS.iter().find(|(_, size)| seconds.abs() >= *size) else {
+        return Ok("just now".to_string());
+    };
+    let count = seconds.abs() / size;
+    let plural = if count == 1 { "" } else { "s" };
+    Ok(if seconds > 0 {
+        format!("{} {}{} ago", count, unit, plural)
+    } else {
+        format!("in {} {}{}", count, unit, plural)
+    })
+}
+
+/**
+ * 标记是按行解析的，内容里的换行会拆开表格的一行，所以换成空格
+ */
+pub fn pdf_text(value: impl fmt::Display) -> askama::Result<String> {
+    let mut out = String::new();
+    for c in value.to_string().chars() {
+        match c {
+            '\\' | '|' | '*' | '#' | '-' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\r' | '\n' => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/**
+ * 从 Accept-Language 请求头中选出第一个支持的地区，都不支持时使用 en-US
+ * 比如 "de-AT,de;q=0.9,en;q=0.8" 会匹配到 de-DE
+ */
+pub struct Locale(pub &'static str);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Locale
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let tag = parts
+            .headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                // 浏览器发送的列表已经按优先级从高到低排列，这里忽略 q 值
+                value
+                    .split(',')
+                    .filter_map(|item| item.split(';').next())
+                    .find_map(|tag| lookup(tag.trim()))
+            })
+            .map_or(LOCALES[0].tag, |format| format.tag);
+        Ok(Locale(tag))
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+
+use crate::{db::ConnectionPool, AppState};
+
+/*
+ * 给负载均衡或者 Kubernetes 使用的探针，不需要登录，响应带 no-store，不会被缓存
+ * - GET /healthz  存活检查（livenessProbe），进程能处理请求就返回 200，不访问数据库，
+ *                 数据库出问题时重启进程也没有用，不应该因此被判定为不存活
+ * - GET /readyz   就绪检查（readinessProbe），下面的检查都通过时返回 200，否则返回 503，不再接收流量
+ *                 - database    通过连接池执行 SELECT 1
+ *                 - migrations  嵌入的迁移都已执行，并且没有被修改过（见 db::schema）
+ *                 - schema      代码用到的表和列都存在，类型一致（见 db::schema）
+ *                 开始排空（见 drain）之后直接返回 503，响应体里 draining 为 true
+ * 响应体里列出每一项检查的结果：{ "status": "ready", "checks": { "database": { "status": "ok", ... }, ... } }
+ * migrations 和 schema 使用后台定期检查的结果（SCHEMA_CHECK_INTERVAL_SECS），不会每次探测都查询一遍系统表。
+ */
+
+// 探测请求不能像普通请求那样等满连接池的超时时间
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+pub fn routes() -> Router<AppState> {
+    STARTED.get_or_init(Instant::now);
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+fn probe_response(ok: bool, body: Value) -> Response {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        Json(body),
+    )
+        .into_response()
+}
+
+/**
+ * 进程启动（注册路由）以来的时间
+ */
+pub fn uptime() -> Duration {
+    STARTED
+        .get()
+        .map_or(Duration::ZERO, |started| started.elapsed())
+}
+
+async fn healthz() -> Response {
+    probe_response(
+        true,
+        json!({
+            "status": "alive",
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_secs": uptime().as_secs(),
+        }),
+    )
+}
+
+/**
+ * 通过连接池执行 SELECT 1，也用于状态页（见 status）
+ */
+pub async fn ping(pool: &ConnectionPool) -> Result<(), String> {
+    let check = async {
+        let conn = pool.get().await.map_err(|err| err.to_string())?;
+        conn.simple_query("SELECT 1")
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    };
+    tokio::time::timeout(DATABASE_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()))
+}
+
+/**
+ * 一项检查的结果，problems 为空时为 ok
+ */
+fn check(problems: &[String], checked: bool) -> (bool, Value) {
+    if !checked {
+        return (false, json!({ "status": "pending" }));
+    }
+    if problems.is_empty() {
+        return (true, json!({ "status": "ok" }));
+    }
+    (false, json!({ "status": "failed", "problems": problems }))
+}
+
+async fn readyz(State(state): State<AppState>) -> Response {
+    let started = Instant::now();
+    let database = ping(&state.pool).await;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    let database = match database {
+        Ok(()) => (true, json!({ "status": "ok", "elapsed_ms": elapsed_ms })),
+        Err(err) => (
+            false,
+            json!({ "status": "failed", "elapsed_ms": elapsed_ms, "error": err }),
+        ),
+    };
+
+    let report = state.schema_drift.report();
+    let checked = report.checked_at.is_some();
+    let migrations = check(&report.migrations, checked);
+    let schema = check(&report.columns, checked);
+
+    // 开始排空之后不再接收新的流量，见 drain
+    let draining = state.drain.is_draining();
+    let ready = !draining && database.0 && migrations.0 && schema.0;
+    probe_response(
+        ready,
+        json!({
+            "status": if ready { "ready" } else { "not ready" },
+            "checked_at": report.checked_at,
+            "draining": draining,
+            "checks": {
+                "database": database.1,
+                "migrations": migrations.1,
+                "schema": schema.1,
+            },
+        }),
+    )
+}
@@ -0,0 +1,243 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::{delete, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit::Audit,
+    auth::{AuthUser, SESSION_TOKEN_KEY},
+    error::internal_error,
+    permissions::{Authorize, UserImpersonate},
+    session::Session,
+    AppState,
+};
+
+/*
+ * 模拟登录
+ * 客服人员排查问题时需要「以某个用户的身份」查看系统，但不能拿到用户的密码。
+ * 管理员调用接口后拿到一个短期有效、带有 impersonator 声明的 token，用它发起的所有操作在审计日志中都会记录实际的管理员。
+ * 浏览器里也是一样：会话里的 token 换成模拟的 token（原来的 token 保存起来），
+ * 页面上的操作都以被模拟的用户身份执行，HTML 页面顶部显示醒目的提示条。
+ * DELETE /admin/impersonate 结束模拟：吊销模拟的 token（会话里的，以及请求本身用的），会话换回管理员原来的 token。
+ * 没有主动结束时，过期之后下一个请求也会把会话换回原来的 token。
+ * 被模拟的用户的角色不能有管理员自己的角色没有的权限（或者更大的作用范围），否则返回 403，
+ * 比如只有 user:impersonate 的客服不能模拟管理员。
+ */
+
+const SESSION_KEY: &str = "impersonation";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Impersonation {
+    user_id: i64,
+    username: String,
+    impersonator: String,
+    expires_at: DateTime<Utc>,
+    // 模拟前会话里的 token，结束时换回来
+    original_token: Option<String>,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/impersonate/:user_id", post(start))
+        .route("/admin/impersonate", delete(stop))
+}
+
+async fn start(
+    auth: Authorize<UserImpersonate>,
+    State(state): State<AppState>,
+    session: Session,
+    audit: Audit,
+    Path(user_id): Path<i64>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    // 不允许在模拟状态下再次模拟其他用户
+    if auth.user.claims.impersonator.is_some() {
+        return Err((StatusCode::FORBIDDEN, "already impersonating".to_string()));
+    }
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let (username, role): (String, String) = conn
+        .query_opt(
+            "SELECT username, role FROM users WHERE id = $1 AND deleted_at IS NULL",
+            &[&user_id],
+        )
+        .await
+        .map_err(internal_error)?
+        .map(|row| (row.get(0), row.get(1)))
+        .ok_or((StatusCode::NOT_FOUND, "user not found".to_string()))?;
+    drop(conn);
+    if !state
+        .policies
+        .covers(&state.pool, &auth.role, &role)
+        .await?
+    {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "cannot impersonate a user with more permissions".to_string(),
+        ));
+    }
+
+    let ttl = state.config.auth.impersonation_ttl;
+    let token = state
+        .jwt
+        .issue_with(user_id, &username, Some(auth.user.id), ttl)
+        .map_err(internal_error)?;
+    let expires_at = Utc::now() + chrono::Duration::from_std(ttl).unwrap();
+
+    session.insert(
+        SESSION_KEY,
+        Impersonation {
+            user_id,
+            username: username.clone(),
+            impersonator: auth.user.username.clone(),
+            expires_at,
+            original_token: session.get(SESSION_TOKEN_KEY),
+        },
+    );
+    session.insert(SESSION_TOKEN_KEY, &token);
+    audit
+        .record(
+            &state.pool,
+            Some(auth.user.id),
+            &auth.user.username,
+            "admin.impersonate.start",
+            json!({ "user_id": user_id, "username": username }),
+        )
+        .await;
+
+    Ok(Json(json!({
+        "access_token": token,
+        "token_type": "Bearer",
+        "expires_in": ttl.as_secs(),
+        "impersonating": { "id": user_id, "username": username },
+    })))
+}
+
+async fn stop(
+    State(state): State<AppState>,
+    session: Session,
+    audit: Audit,
+    user: Option<AuthUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tokens = Vec::new();
+    if let Some(user) = user.filter(|user| user.claims.impersonator.is_some()) {
+        tokens.push(user.claims);
+    }
+    let impersonation = session.get::<Impersonation>(SESSION_KEY);
+    let impersonator = impersonation
+        .as_ref()
+        .map(|impersonation| impersonation.impersonator.clone())
+        .unwrap_or_default();
+    if impersonation.is_some() {
+        if let Some(claims) = session
+            .get::<String>(SESSION_TOKEN_KEY)
+            .and_then(|token| state.jwt.decode(&token))
+            .filter(|claims| claims.impersonator.is_some())
+        {
+            tokens.push(claims);
+        }
+        restore(&session, impersonation);
+    }
+
+    for claims in &tokens {
+        state.revocations.revoke(&state.pool, claims).await?;
+    }
+    if let Some(claims) = tokens.first() {
+        audit
+            .record(
+                &state.pool,
+                claims.impersonator,
+                &impersonator,
+                "admin.impersonate.stop",
+                json!({ "user_id": claims.sub, "username": claims.username }),
+            )
+            .await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/**
+ * 退出登录时结束模拟，返回模拟之前会话里的 token，调用方需要把它也吊销
+ */
+pub fn clear(session: &Session) -> Option<String> {
+    let impersonation = session.get::<Impersonation>(SESSION_KEY)?;
+    session.remove(SESSION_KEY);
+    impersonation.original_token
+}
+
+/**
+ * 会话换回模拟之前的 token
+ */
+fn restore(session: &Session, impersonation: Option<Impersonation>) {
+    session.remove(SESSION_KEY);
+    match impersonation.and_then(|impersonation| impersonation.original_token) {
+        Some(token) => session.insert(SESSION_TOKEN_KEY, token),
+        None => session.remove(SESSION_TOKEN_KEY),
+    }
+}
+
+/**
+ * 模拟登录提示条中间件
+ * 会话处于模拟状态时，在所有 HTML 响应的 <body> 之后插入提示条，提醒操作人当前不是以自己的身份在浏览
+ * 需要挂在 session_layer 的内层，才能从 request extensions 里拿到 Session
+ */
+pub async fn banner(req: Request, next: Next) -> Response {
+    let session = req.extensions().get::<Session>().cloned();
+    let mut impersonation = session
+        .as_ref()
+        .and_then(|session| session.get::<Impersonation>(SESSION_KEY));
+    // 模拟已经过期，会话换回原来的 token，这个请求就以管理员自己的身份处理
+    if let (Some(session), Some(expired)) = (&session, &impersonation) {
+        if expired.expires_at <= Utc::now() {
+            restore(session, impersonation.take());
+        }
+    }
+
+    let res = next.run(req).await;
+    let Some(impersonation) = impersonation else {
+        return res;
+    };
+    let is_html = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    if !is_html {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let html = String::from_utf8_lossy(&bytes);
+    let notice = format!(
+        r#"<div style="background:#c00;color:#fff;padding:8px;text-align:center">{} is impersonating {} until {}</div>"#,
+        html_escape(&impersonation.impersonator),
+        html_escape(&impersonation.username),
+        impersonation.expires_at.format("%H:%M:%S UTC"),
+    );
+    let html = match html.find("<body>") {
+        Some(index) => format!("{}{}{}", &html[..index + 6], notice, &html[index + 6..]),
+        None => format!("{}{}", notice, html),
+    };
+
+    // body 长度变了，需要去掉原来的 Content-Length
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(html))
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
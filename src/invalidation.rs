@@ -0,0 +1,185 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{db::ConnectionPool, AppState};
+
+/*
+ * 跨实例的缓存失效
+ * 每个实例都在内存里缓存了一些数据，一个实例（或者直接在数据库里）修改之后，其他实例要等缓存过期才能看到新数据。
+ * 这里通过 Postgres 的 cache_invalidation 频道（见 notify）广播失效消息，每个实例收到后丢掉对应的缓存：
+ * - response    降级模式的响应缓存（见 degraded），key 是路径前缀，写操作成功后由处理请求的实例发出
+ * - revocation  已吊销 token 的缓存（见 auth），key 是 jti
+ * - policies    角色权限的缓存（见 permissions），key 是角色名
+ * - rules       数据库里的规则表，key 是表名，收到后立即重新加载，不用等下一次定时加载
+ * - sitemap     生成好的 sitemap.xml（见 sitemap），key 是表名，todos 或 incidents 有变化时丢掉所有租户的缓存
+ * 后面四种由数据库触发器发出（见 V17、V22 迁移），事务提交之后才会发出，用 psql 直接改表也会生效。
+ * 消息丢失（比如监听的连接正在重连）时退回到原来的机制：缓存过期或者定时重新加载。
+ * 每条消息带上发出的时间，从发出到本实例处理完的延迟记在 /metrics 的 cache_invalidation_latency_seconds{cache} 里，
+ * 各实例的时钟有偏差时这个延迟只能作为参考。
+ */
+
+// 失效消息的频道，notify 固定监听这个频道
+pub const CHANNEL: &str = "cache_invalidation";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cache {
+    Response,
+    Revocation,
+    Policies,
+    Rules,
+    Sitemap,
+}
+
+impl Cache {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cache::Response => "response",
+            Cache::Revocation => "revocation",
+            Cache::Policies => "policies",
+            Cache::Rules => "rules",
+            Cache::Sitemap => "sitemap",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    cache: Cache,
+    key: String,
+    // 发出消息的实例，数据库触发器发出的消息没有
+    origin: Option<Uuid>,
+    sent_at: DateTime<Utc>,
+}
+
+/**
+ * 发送和接收失效消息，origin 用来跳过自己发出的消息（发出之前已经在本实例生效了）
+ */
+#[derive(Clone)]
+pub struct Invalidations {
+    origin: Uuid,
+}
+
+impl Invalidations {
+    pub fn new() -> Self {
+        Invalidations {
+            origin: Uuid::new_v4(),
+        }
+    }
+
+    /**
+     * 在后台发出失效消息，不阻塞当前请求，失败只打印日志
+     */
+    pub fn publish(&self, pool: &ConnectionPool, cache: Cache, key: &str) {
+        let payload = json!({
+            "cache": cache,
+            "key": key,
+            "origin": self.origin,
+            "sent_at": Utc::now(),
+        })
+        .to_string();
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let sent = match pool.get().await {
+                Ok(conn) => conn
+                    .execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+                    .await
+                    .map(|_| ())
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            };
+            if let Err(err) = sent {
+                tracing::warn!("publish cache invalidation failed: {}", err);
+            }
+        });
+    }
+
+    /**
+     * 启动后台任务，处理收到的失效消息
+     */
+    pub fn start(&self, state: AppState) {
+        let origin = self.origin;
+        let mut receiver = state.notifier.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let notification = match receiver.recv().await {
+                    Ok(notification) => notification,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("missed {} notifications, cache may be stale", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if notification.channel != CHANNEL {
+                    continue;
+                }
+                let message: Message = match serde_json::from_str(&notification.payload) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        tracing::warn!(
+                            "invalid cache invalidation {:?}: {}",
+                            notification.payload,
+                            err
+                        );
+                        continue;
+                    }
+                };
+                if message.origin == Some(origin) {
+                    continue;
+                }
+                apply(&state, &message).await;
+                let latency = (Utc::now() - message.sent_at).to_std().unwrap_or_default();
+                state
+                    .metrics
+                    .observe_invalidation(message.cache.as_str(), latency);
+            }
+        });
+    }
+}
+
+async fn apply(state: &AppState, message: &Message) {
+    tracing::debug!(
+        "invalidate {} cache: {}",
+        message.cache.as_str(),
+        message.key
+    );
+    let reloaded = match message.cache {
+        Cache::Response => {
+            state.response_cache.invalidate(&message.key);
+            Ok(())
+        }
+        Cache::Revocation => {
+            state.revocations.forget(&message.key);
+            Ok(())
+        }
+        Cache::Policies => {
+            state.policies.invalidate(&message.key);
+            Ok(())
+        }
+        Cache::Sitemap => {
+            state.sitemaps.invalidate();
+            Ok(())
+        }
+        Cache::Rules => match message.key.as_str() {
+            // 兼容规则按客户端类别生效，和客户端策略一起加载
+            "client_policies" | "compat_rules" => {
+                match state.client_policies.reload(&state.pool).await {
+                    Ok(()) => state.compat_rules.reload(&state.pool).await,
+                    Err(err) => Err(err),
+                }
+            }
+            "redirect_rules" => state.rules.reload(&state.pool).await,
+            "deprecations" => state.deprecations.reload(&state.pool).await,
+            other => {
+                tracing::warn!("unknown rules table {:?}", other);
+                Ok(())
+            }
+        },
+    };
+    if let Err((_, err)) = reloaded {
+        tracing::warn!("reload {} failed: {}", message.key, err);
+    }
+}
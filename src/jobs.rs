@@ -0,0 +1,252 @@
+use std::{future::Future, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthUser,
+    db::{repo, ConnectionPool},
+    error::internal_error,
+    AppState,
+};
+
+/*
+ * 异步任务（请求/确认模式）
+ * 大批量导入、生成报表这类可能超过请求超时时间的操作，不在请求里直接执行，而是：
+ * 1. handler 调用 jobs::accept 登记一个任务并在后台执行，立即返回 202 Accepted，
+ *    Location 响应头指向 /api/jobs/:id
+ * 2. 客户端轮询该地址，任务未完成时返回 status 为 pending/running，并带上 Retry-After 提示下次轮询的间隔
+ * 3. 任务完成后 status 变为 succeeded（result 为结果）或 failed（error 为错误信息）
+ * 任务只有创建者本人可以查看。
+ * 结果是有类型的（实现 JobResult），和类型名一起保存在 job_results 表里，JOB_RESULT_TTL_SECS 秒之后过期删除，
+ * 过期之后任务本身还在，result 为空、result_expired 为 true。GET /api/jobs/:id/result 只返回结果本身。
+ * 任务结束时在 jobs 频道上发出 NOTIFY（id、kind、owner_id、status 和 result_url，不包含结果本身），
+ * 把 jobs 加到 NOTIFY_CHANNELS 里之后页面可以通过 GET /events?channel=jobs 得到通知，不用一直轮询。
+ */
+
+// 建议客户端的轮询间隔（秒）
+const RETRY_AFTER_SECS: u64 = 2;
+// 任务结束时发出 NOTIFY 的频道
+const CHANNEL: &str = "jobs";
+// 多久清理一次过期的结果
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/**
+ * 任务的结果类型，TYPE 和序列化后的结果一起保存，客户端按 result_type 解析 result
+ */
+pub trait JobResult: Serialize + Send + 'static {
+    const TYPE: &'static str;
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/jobs/:id", get(show))
+        .route("/api/jobs/:id/result", get(result))
+}
+
+/**
+ * 登记一个后台任务并返回 202 响应
+ * work 在单独的 tokio 任务中执行，返回的结果保存到 job_results，JOB_RESULT_TTL_SECS 秒之后过期
+ */
+pub async fn accept<T, F, Fut>(
+    state: &AppState,
+    owner_id: i64,
+    kind: &str,
+    work: F,
+) -> Result<Response, (StatusCode, String)>
+where
+    T: JobResult,
+    F: FnOnce(AppState) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T, (StatusCode, String)>> + Send + 'static,
+{
+    let id = Uuid::new_v4();
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    repo::insert_job(&*conn, id, kind, owner_id)
+        .await
+        .map_err(internal_error)?;
+    drop(conn);
+
+    let state = state.clone();
+    let kind = kind.to_string();
+    tokio::spawn(async move {
+        let pool = state.pool.clone();
+        let ttl = state.config.jobs.result_ttl;
+        if let Err((_, err)) = set_status(&pool, id, "running", None).await {
+            tracing::warn!("job {} ({}) could not start: {}", id, kind, err);
+            return;
+        }
+        let result = work(state).await.and_then(|result| {
+            serde_json::to_value(result).map_err(|err| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("serialize result: {}", err),
+                )
+            })
+        });
+        let (status, outcome) = match result {
+            Ok(result) => ("succeeded", succeed(&pool, id, T::TYPE, result, ttl).await),
+            Err((_, err)) => {
+                tracing::warn!("job {} ({}) failed: {}", id, kind, err);
+                ("failed", set_status(&pool, id, "failed", Some(err)).await)
+            }
+        };
+        if let Err((_, err)) = outcome {
+            tracing::warn!("job {} ({}) could not record outcome: {}", id, kind, err);
+            return;
+        }
+        notify(&pool, id, &kind, owner_id, status).await;
+    });
+
+    let location = format!("/api/jobs/{}", id);
+    Ok((
+        StatusCode::ACCEPTED,
+        [
+            (header::LOCATION, location.clone()),
+            (header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()),
+        ],
+        Json(json!({ "id": id, "status": "pending", "location": location })),
+    )
+        .into_response())
+}
+
+async fn set_status(
+    pool: &ConnectionPool,
+    id: Uuid,
+    status: &str,
+    error: Option<String>,
+) -> Result<(), (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    repo::set_job_status(&*conn, id, status, error)
+        .await
+        .map_err(internal_error)
+}
+
+/**
+ * 保存结果并把任务标记为成功，在同一个事务里，不会出现成功了却没有结果的任务
+ */
+async fn succeed(
+    pool: &ConnectionPool,
+    id: Uuid,
+    result_type: &str,
+    result: serde_json::Value,
+    ttl: Duration,
+) -> Result<(), (StatusCode, String)> {
+    let mut conn = pool.get().await.map_err(internal_error)?;
+    let tx = conn.transaction().await.map_err(internal_error)?;
+    repo::insert_job_result(&tx, id, result_type, &result, ttl)
+        .await
+        .map_err(internal_error)?;
+    repo::set_job_status(&tx, id, "succeeded", None)
+        .await
+        .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)
+}
+
+/**
+ * 任务结束时发出通知，失败只打印日志，客户端仍然可以轮询
+ */
+async fn notify(pool: &ConnectionPool, id: Uuid, kind: &str, owner_id: i64, status: &str) {
+    let payload = json!({
+        "id": id,
+        "kind": kind,
+        "owner_id": owner_id,
+        "status": status,
+        "location": format!("/api/jobs/{}", id),
+        "result_url": (status == "succeeded").then(|| format!("/api/jobs/{}/result", id)),
+    });
+    let sent = match pool.get().await {
+        Ok(conn) => repo::notify(&*conn, CHANNEL, &payload.to_string()).await,
+        Err(err) => {
+            tracing::warn!("job {} could not send notification: {}", id, err);
+            return;
+        }
+    };
+    if let Err(err) = sent {
+        tracing::warn!("job {} could not send notification: {}", id, err);
+    }
+}
+
+/**
+ * 删除过期的任务结果，由后台任务每隔 PRUNE_INTERVAL 执行一次
+ */
+pub async fn prune_results(pool: &ConnectionPool) -> Result<u64, (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    repo::prune_job_results(&*conn)
+        .await
+        .map_err(internal_error)
+}
+
+/**
+ * 服务重启后，之前还没执行完的任务已经不可能再完成了，启动时把它们标记为失败（只考虑单实例部署）
+ */
+pub async fn fail_interrupted(pool: &ConnectionPool) -> Result<u64, (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    repo::fail_unfinished_jobs(&*conn, "interrupted by server restart")
+        .await
+        .map_err(internal_error)
+}
+
+async fn show(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let job = repo::find_job(&*conn, id, user.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, "job not found".to_string()))?;
+
+    if job.finished_at.is_none() {
+        return Ok((
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            Json(job),
+        )
+            .into_response());
+    }
+    Ok(Json(job).into_response())
+}
+
+/**
+ * 只返回任务的结果，任务还没完成时返回 409，结果已经过期时返回 410
+ */
+async fn result(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let job = repo::find_job(&*conn, id, user.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, "job not found".to_string()))?;
+
+    match (job.status.as_str(), job.result) {
+        (_, Some(result)) => Ok(Json(json!({
+            "id": job.id,
+            "result_type": job.result_type,
+            "result": result,
+            "expires_at": job.result_expires_at,
+        }))
+        .into_response()),
+        ("succeeded", None) => Err((StatusCode::GONE, "job result has expired".to_string())),
+        ("failed", None) => Err((
+            StatusCode::CONFLICT,
+            format!("job failed: {}", job.error.unwrap_or_default()),
+        )),
+        _ => Ok((
+            StatusCode::CONFLICT,
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            Json(json!({ "error": "job has not finished", "status": job.status })),
+        )
+            .into_response()),
+    }
+}
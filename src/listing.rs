@@ -0,0 +1,164 @@
+use std::marker::PhantomData;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{request::Parts, StatusCode},
+};
+use tokio_postgres::types::ToSql;
+
+/*
+ * 列表接口的过滤和排序参数
+ * - ?filter[role]=admin&filter[username]=bob  按字段过滤，多个条件之间是 AND，值按字段类型解析
+ * - ?sort=-created_at,username                 按字段排序，前面加 - 表示倒序
+ * 每个列表接口通过 ListSpec 声明允许过滤和排序的字段（白名单），字段名和 SQL 列名是分开的，
+ * 客户端传入的字段名只用来查表，拼进 SQL 的只有代码里写死的列名，值全部通过参数传递，不存在 SQL 注入。
+ * 不在白名单里的字段返回 400。排序最后总是加上主键，保证分页时顺序稳定。
+ */
+
+#[derive(Debug, Clone, Copy)]
+pub enum FieldType {
+    Text,
+    Integer,
+    Boolean,
+}
+
+pub struct Field {
+    // 查询参数里使用的名字
+    pub name: &'static str,
+    // 对应的 SQL 列
+    pub column: &'static str,
+    pub ty: FieldType,
+    pub filter: bool,
+    pub sort: bool,
+}
+
+/**
+ * 一个列表接口允许的过滤和排序字段
+ */
+pub trait ListSpec {
+    const FIELDS: &'static [Field];
+    // 没有传 sort 时的排序，同时也是最后的兜底排序，需要能唯一确定一行（一般是主键）
+    const DEFAULT_SORT: &'static str;
+}
+
+type Param = Box<dyn ToSql + Sync + Send>;
+
+pub struct Listing<S> {
+    filters: Vec<(&'static str, Param)>,
+    sort: Vec<(&'static str, bool)>,
+    _spec: PhantomData<S>,
+}
+
+/**
+ * 不过滤、按默认顺序排序
+ */
+impl<S> Default for Listing<S> {
+    fn default() -> Self {
+        Listing {
+            filters: Vec::new(),
+            sort: Vec::new(),
+            _spec: PhantomData,
+        }
+    }
+}
+
+fn bad_request(message: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message)
+}
+
+impl<S: ListSpec> Listing<S> {
+    fn field(name: &str) -> Option<&'static Field> {
+        S::FIELDS.iter().find(|field| field.name == name)
+    }
+
+    fn parse(params: &[(String, String)]) -> Result<Self, (StatusCode, String)> {
+        let mut filters = Vec::new();
+        let mut sort = Vec::new();
+        for (key, value) in params {
+            if let Some(name) = key
+                .strip_prefix("filter[")
+                .and_then(|rest| rest.strip_suffix(']'))
+            {
+                let field = Self::field(name)
+                    .filter(|field| field.filter)
+                    .ok_or_else(|| bad_request(format!("cannot filter by {}", name)))?;
+                let invalid = || bad_request(format!("invalid value for filter[{}]", name));
+                let param: Param = match field.ty {
+                    FieldType::Text => Box::new(value.clone()),
+                    FieldType::Integer => Box::new(value.parse::<i64>().map_err(|_| invalid())?),
+                    FieldType::Boolean => Box::new(value.parse::<bool>().map_err(|_| invalid())?),
+                };
+                filters.push((field.column, param));
+            } else if key == "sort" {
+                for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                    let (name, desc) = match item.strip_prefix('-') {
+                        Some(name) => (name, true),
+                        None => (item, false),
+                    };
+                    let field = Self::field(name)
+                        .filter(|field| field.sort)
+                        .ok_or_else(|| bad_request(format!("cannot sort by {}", name)))?;
+                    sort.push((field.column, desc));
+                }
+            }
+        }
+        Ok(Listing {
+            filters,
+            sort,
+            ..Default::default()
+        })
+    }
+
+    /**
+     * 过滤条件，形如 " AND role = $2 AND username = $3"，没有过滤条件时为空字符串
+     * first 是第一个条件使用的参数编号，前面的编号留给调用方自己的参数
+     */
+    pub fn conditions(&self, first: usize) -> String {
+        self.filters
+            .iter()
+            .enumerate()
+            .map(|(index, (column, _))| format!(" AND {} = ${}", column, first + index))
+            .collect()
+    }
+
+    /**
+     * 过滤条件的参数，按 conditions 中的顺序排列
+     */
+    pub fn params(&self) -> impl Iterator<Item = &(dyn ToSql + Sync)> {
+        self.filters
+            .iter()
+            .map(|(_, param)| &**param as &(dyn ToSql + Sync))
+    }
+
+    pub fn param_count(&self) -> usize {
+        self.filters.len()
+    }
+
+    /**
+     * ORDER BY 后面的部分
+     */
+    pub fn order_by(&self) -> String {
+        self.sort
+            .iter()
+            .map(|(column, desc)| format!("{}{}, ", column, if *desc { " DESC" } else { "" }))
+            .chain(std::iter::once(S::DEFAULT_SORT.to_string()))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<S, St> FromRequestParts<St> for Listing<S>
+where
+    S: ListSpec,
+    St: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &St) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<Vec<(String, String)>>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| bad_request(err.body_text()))?;
+        Self::parse(&params)
+    }
+}
@@ -0,0 +1,68 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Semaphore;
+
+use crate::{config::LoadShedConfig, metrics::Metrics};
+
+/*
+ * 并发上限和过载保护
+ * 同时处理的请求超过 MAX_CONCURRENT_REQUESTS 时，新的请求最多等待 LOAD_SHED_WAIT_MS 毫秒，
+ * 还是没有空位就直接返回 503（经过 quota::retry_hints 带上 Retry-After 和重试提示），
+ * 而不是在内存里无限排队，排到的时候客户端早就超时了，只会让情况更糟。
+ * 被拒绝的请求计入 /metrics 的 http_requests_shed_total。
+ * /healthz 和 /metrics 不受限制：过载时存活检查失败会让进程被重启，正好丢掉所有正在处理的请求。
+ */
+
+const EXEMPT_PATHS: [&str; 2] = ["/healthz", "/metrics"];
+
+#[derive(Clone)]
+pub struct LoadShed {
+    // 不限制时为 None
+    permits: Option<Arc<Semaphore>>,
+    wait: Duration,
+    metrics: Metrics,
+}
+
+impl LoadShed {
+    pub fn new(config: &LoadShedConfig, metrics: Metrics) -> Self {
+        LoadShed {
+            permits: (config.max_concurrent > 0)
+                .then(|| Arc::new(Semaphore::new(config.max_concurrent))),
+            wait: config.wait,
+            metrics,
+        }
+    }
+}
+
+pub async fn apply(State(shed): State<LoadShed>, req: Request, next: Next) -> Response {
+    let Some(permits) = &shed.permits else {
+        return next.run(req).await;
+    };
+    if EXEMPT_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    let permit = match permits.clone().try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(_) if shed.wait.is_zero() => None,
+        Err(_) => tokio::time::timeout(shed.wait, permits.clone().acquire_owned())
+            .await
+            .ok()
+            .and_then(Result::ok),
+    };
+    let Some(_permit) = permit else {
+        shed.metrics.count_shed();
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, HeaderValue::from_static("1"))],
+            "server is overloaded",
+        )
+            .into_response();
+    };
+    next.run(req).await
+}
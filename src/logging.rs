@@ -0,0 +1,411 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{SecondsFormat, Utc};
+use opentelemetry_sdk::trace::TracerProvider;
+use serde_json::{json, Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{
+        format::{DefaultFields, JsonFields, Writer},
+        FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer,
+};
+
+use crate::{
+    config::{LogConfig, TelemetryConfig},
+    telemetry,
+};
+
+/*
+ * 日志输出格式，通过 LOG_FORMAT 切换
+ * - text：默认，适合在终端里看的格式，本地开发使用
+ * - json：每行一个 JSON 对象，方便 Loki、ELK 之类的系统采集，生产环境使用
+ *   { "timestamp": "...", "level": "INFO", "target": "...", "request_id": "...", "span": "request", "fields": { "message": "...", ... } }
+ *   request_id 取自所在的 request span（见 request_id 模块），请求之外的日志没有这个字段
+ * 日志级别仍然通过 RUST_LOG 设置，比如 RUST_LOG=debug 或 RUST_LOG=info,tokio_postgres=warn，默认 info。
+ *
+ * 设置 LOG_DIR 后，除了标准输出之外还会写到 {LOG_DIR}/{LOG_FILE_NAME} 里，格式和标准输出相同，不带颜色。
+ * LOG_ROTATION 决定什么时候换一个新文件，LOG_MAX_FILES 是最多保留几个旧文件，更早的自动删除：
+ * - daily / hourly：按天或按小时，文件名后面加上日期，比如 app.log.2024-01-01
+ * - size：当前文件超过 LOG_MAX_SIZE_MB 时改名为 app.log.1，原来的 .1 改名为 .2，以此类推
+ * - never：一直写同一个文件
+ * 写文件在单独的线程里进行（tracing-appender 的 non_blocking），磁盘慢的时候不会卡住处理请求的线程；
+ * 日志太多来不及写时会丢弃新的日志，而不是让请求等待。
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{:?} is not one of text, json", value)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogRotation {
+    Daily,
+    Hourly,
+    Size,
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "daily" => Ok(LogRotation::Daily),
+            "hourly" => Ok(LogRotation::Hourly),
+            "size" => Ok(LogRotation::Size),
+            "never" => Ok(LogRotation::Never),
+            _ => Err(format!(
+                "{:?} is not one of daily, hourly, size, never",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogRotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogRotation::Daily => "daily",
+            LogRotation::Hourly => "hourly",
+            LogRotation::Size => "size",
+            LogRotation::Never => "never",
+        })
+    }
+}
+
+/**
+ * init 返回的句柄，需要一直持有到程序退出
+ * - 写文件时，drop 的时候才会把缓冲区里剩下的日志写完
+ * - 导出 OTLP 时，drop 的时候把还没发送的 span 发出去
+ */
+pub struct LogGuard {
+    _file: Option<WorkerGuard>,
+    tracer: Option<TracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(tracer) = &self.tracer {
+            if let Err(err) = tracer.shutdown() {
+                eprintln!("failed to flush traces: {}", err);
+            }
+        }
+    }
+}
+
+/**
+ * 按配置注册全局的日志 Collector
+ */
+pub fn init(config: &LogConfig, telemetry: &TelemetryConfig) -> Result<LogGuard, String> {
+    let targets = match std::env::var("RUST_LOG") {
+        Ok(value) => value.parse().unwrap_or_else(|err| {
+            eprintln!("ignoring RUST_LOG={:?}: {}", value, err);
+            Targets::new().with_default(Level::INFO)
+        }),
+        Err(_) => Targets::new().with_default(Level::INFO),
+    };
+    let mut layers = vec![layer(config.format, io::stdout, DefaultFields::new(), true)];
+    let mut guard = LogGuard {
+        _file: None,
+        tracer: None,
+    };
+    if let Some(dir) = &config.dir {
+        let (writer, worker) = tracing_appender::non_blocking(file_writer(config, dir)?);
+        layers.push(layer(config.format, writer, PlainFields::default(), false));
+        guard._file = Some(worker);
+    }
+    if let Some((layer, tracer)) = telemetry::layer(telemetry)? {
+        layers.push(layer);
+        guard.tracer = Some(tracer);
+    }
+    // RUST_LOG 只过滤上面的这些输出，不影响 tokio-console，它需要 tokio 内部 TRACE 级别的 span（见 runtime）
+    let layers = layers.with_filter(targets);
+    #[cfg(feature = "console")]
+    let layers = vec![layers.boxed(), console_subscriber::spawn().boxed()];
+    tracing_subscriber::registry().with(layers).init();
+    Ok(guard)
+}
+
+fn layer<S, W, F>(
+    format: LogFormat,
+    writer: W,
+    fields: F,
+    ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    F: for<'a> FormatFields<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.fmt_fields(fields).boxed(),
+        LogFormat::Json => layer
+            .event_format(JsonLines)
+            .fmt_fields(JsonFields::new())
+            .boxed(),
+    }
+}
+
+fn file_writer(config: &LogConfig, dir: &str) -> Result<Box<dyn Write + Send>, String> {
+    let error = |err: &dyn fmt::Display| format!("LOG_DIR: {}: {}", dir, err);
+    let rotation = match config.rotation {
+        LogRotation::Size => {
+            let writer = SizeRolling::open(
+                Path::new(dir).join(&config.file_name),
+                config.max_size_mb * 1024 * 1024,
+                config.max_files,
+            )
+            .map_err(|err| error(&err))?;
+            return Ok(Box::new(writer));
+        }
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&config.file_name)
+        .max_log_files(config.max_files + 1)
+        .build(dir)
+        .map_err(|err| error(&err))?;
+    Ok(Box::new(appender))
+}
+
+/**
+ * 写文件用的字段格式化器，和 DefaultFields 完全一样，只是类型不同
+ * span 的字段格式化一次之后按格式化器的类型缓存在 span 里，两个输出用同一个类型时，
+ * 文件里会出现标准输出那边带颜色的字段
+ */
+#[derive(Default)]
+struct PlainFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for PlainFields {
+    fn format_fields<R: tracing_subscriber::field::RecordFields>(
+        &self,
+        writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+/**
+ * 按大小切分的日志文件，tracing-appender 只支持按时间切分
+ * non_blocking 每次写入的是完整的一条日志，所以切分只会发生在两条日志之间
+ */
+struct SizeRolling {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl SizeRolling {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(SizeRolling {
+            path,
+            max_size,
+            max_files,
+            file,
+            written,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    /**
+     * app.log.{n-1} -> app.log.{n}，……，app.log -> app.log.1，超过 max_files 的最旧的文件被覆盖
+     */
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRolling {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/**
+ * 一条日志输出成一行 JSON
+ * span 的字段用 JsonFields 格式化，保存在 span 的 extensions 里，这里从中取出 request_id
+ */
+struct JsonLines;
+
+impl<S, N> FormatEvent<S, N> for JsonLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_string(),
+            json!(Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)),
+        );
+        line.insert("level".to_string(), json!(metadata.level().as_str()));
+        // 通过 log crate 打印的日志（比如 refinery），真正的 target 在 log.target 字段里
+        let target = visitor.log_target.as_deref().unwrap_or(metadata.target());
+        line.insert("target".to_string(), json!(target));
+        if let Some(request_id) = request_id(ctx) {
+            line.insert("request_id".to_string(), json!(request_id));
+        }
+        if let Some(span) = ctx.lookup_current() {
+            line.insert("span".to_string(), json!(span.name()));
+        }
+        line.insert("fields".to_string(), Value::Object(visitor.fields));
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+/**
+ * 从当前 span 开始往外找 request_id 字段
+ */
+fn request_id<S, N>(ctx: &FmtContext<'_, S, N>) -> Option<String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    for span in ctx.event_scope()? {
+        let extensions = span.extensions();
+        let Some(fields) = extensions.get::<FormattedFields<N>>() else {
+            continue;
+        };
+        if let Ok(Value::Object(mut fields)) = serde_json::from_str::<Value>(fields) {
+            if let Some(Value::String(id)) = fields.remove("request_id") {
+                return Some(id);
+            }
+        }
+    }
+    None
+}
+
+#[derive(Default)]
+struct JsonVisitor {
+    fields: Map<String, Value>,
+    log_target: Option<String>,
+}
+
+impl JsonVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // log.module_path、log.file 这些是 tracing-log 附带的，不输出
+        if !field.name().starts_with("log.") {
+            self.fields.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "log.target" {
+            self.log_target = Some(value.to_string());
+        } else {
+            self.insert(field, json!(value));
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, json!(format!("{:?}", value)));
+    }
+}
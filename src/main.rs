@@ -1,75 +1,742 @@
+mod access_log;
+mod admin;
+mod archive;
+mod assets;
+mod audit;
+mod auth;
+mod body_log;
+mod cbor;
+mod client_policy;
+mod compat;
+mod compression;
+mod config;
+mod consistency;
+mod console;
+mod csv;
+mod dashboard;
+mod db;
+mod degraded;
+mod delta;
+mod deprecation;
+mod device;
+mod drain;
+mod error;
+mod error_report;
+mod etag;
+mod experiments;
+mod feed;
+mod filters;
+mod health;
+mod impersonate;
+mod import;
+mod invalidation;
+mod jobs;
+mod listing;
+mod load_shed;
+mod logging;
+mod mail;
+mod markdown;
+mod metrics;
+mod msgpack;
+mod ndjson;
+mod negotiate;
+mod notify;
+mod pagination;
+mod pdf;
+mod permissions;
+mod protobuf;
+mod push;
+mod quota;
+mod refresh;
+mod request_id;
+mod rules;
+mod runtime;
+mod scheduler;
+mod search;
+mod seed;
+mod session;
+mod signed_url;
+mod sitemap;
+mod status;
+mod storage;
+mod sync;
+mod telemetry;
+mod tenant;
+mod throttle;
+mod timeout;
+mod todos;
+mod users;
+mod widgets;
+mod xlsx;
+mod xml;
+mod yaml;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
 use askama::Template;
 use axum::{
-    extract::{rejection::JsonRejection, Form, Json, Path, Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
+    body::Body,
+    extract::{
+        rejection::JsonRejection, DefaultBodyLimit, Form, FromRef, Json, Multipart, Path, Query,
+        Request, State,
+    },
+    http::{HeaderMap, StatusCode, Uri},
+    middleware,
+    response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
-    Router,
+    Router, ServiceExt,
 };
-use bb8::Pool;
-use bb8_postgres::PostgresConnectionManager;
 // serde 是 Rust 生态中用得最广泛的序列化和反序列化框架
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio_postgres::NoTls;
+use tower::{util::MapResponse, Layer};
 use tower_http::{
-    services::{ServeDir, ServeFile},
+    catch_panic::CatchPanicLayer,
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    services::ServeDir,
     trace::TraceLayer,
 };
 
+use admin::AdminTables;
+use assets::Overlay;
+use auth::{JwtKeys, RevocationList};
+use client_policy::ClientPolicies;
+use compat::CompatRules;
+use config::Config;
+use db::{schema::SchemaDrift, CircuitBreaker, ConnectionPool, Replicas};
+use degraded::ResponseCache;
+use deprecation::Deprecations;
+use drain::Drain;
+use experiments::{Assignment, Experiments};
+use invalidation::Invalidations;
+use load_shed::LoadShed;
+use mail::Mailer;
+use markdown::Markdown;
+use metrics::Metrics;
+use notify::Notifier;
+use pdf::PdfRenderer;
+use permissions::{Authorize, DownloadSign, PolicyCache};
+use push::WebPush;
+use quota::ApiQuota;
+use rules::Rules;
+use session::{Session, SessionKeys};
+use signed_url::{SignedUrl, UrlSigner};
+use sitemap::Sitemaps;
+use status::StatusPage;
+use storage::ObjectStore;
+use tenant::TenantResolver;
+use throttle::LoginThrottle;
+use timeout::Timeouts;
+use yaml::Yaml;
+
 /**
  * 全局应用状态，统一管理全局共享信息
  */
 #[derive(Clone)]
 struct AppState {
-    pool: Pool<PostgresConnectionManager<NoTls>>,
+    config: Arc<Config>,
+    // 主库，所有写操作都走这里
+    pool: ConnectionPool,
+    replicas: Replicas,
+    url_signer: UrlSigner,
+    jwt: JwtKeys,
+    revocations: RevocationList,
+    policies: PolicyCache,
+    login_throttle: LoginThrottle,
+    admin_tables: AdminTables,
+    notifier: Notifier,
+    assets: Overlay,
+    breaker: CircuitBreaker,
+    response_cache: ResponseCache,
+    push: WebPush,
+    client_policies: ClientPolicies,
+    compat_rules: CompatRules,
+    schema_drift: SchemaDrift,
+    rules: Rules,
+    deprecations: Deprecations,
+    invalidations: Invalidations,
+    experiments: Experiments,
+    status: StatusPage,
+    mailer: Mailer,
+    pdf: PdfRenderer,
+    markdown: Markdown,
+    sitemaps: Sitemaps,
+    drain: Drain,
+    metrics: Metrics,
+}
+
+impl AppState {
+    /**
+     * 只读查询使用的连接池：有只读副本时轮流返回副本，否则返回主库
+     * 请求带着一致性令牌时只用已经追上的副本，都没追上时返回主库，见 consistency
+     */
+    fn read(&self) -> &ConnectionPool {
+        let replica = match consistency::required_lsn() {
+            Some(lsn) => self.replicas.pick_caught_up(lsn),
+            None => self.replicas.pick(),
+        };
+        replica.unwrap_or(&self.pool)
+    }
+
+    /**
+     * 写操作使用的连接池，始终是主库
+     */
+    fn write(&self) -> &ConnectionPool {
+        &self.pool
+    }
+}
+
+/**
+ * 实现 FromRef 之后，提取器可以只从全局状态中取出自己需要的那一部分
+ */
+impl FromRef<AppState> for UrlSigner {
+    fn from_ref(state: &AppState) -> Self {
+        state.url_signer.clone()
+    }
+}
+
+impl FromRef<AppState> for JwtKeys {
+    fn from_ref(state: &AppState) -> Self {
+        state.jwt.clone()
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    // 配置有问题时列出所有问题后退出
+    let config = Config::from_env().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
     /*
      * 这是一个 Collector，可以将记录的日志收集后，再输出到控制台中。
      * 收集的过程是通过通知的方式实现的：当 Event 发生或者 Span 开始/结束时，会调用 Collect 特征的相应方法通知 Collector。
+     * 输出格式由 LOG_FORMAT 决定，见 logging，所以要在读取配置之后注册。
+     * 设置了 OTEL_EXPORTER_OTLP_ENDPOINT 时，span 还会导出到链路追踪系统，见 telemetry。
      */
-    tracing_subscriber::fmt::init();
+    let _log_guard = logging::init(&config.log, &config.telemetry).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    // panic 的内容和调用栈写进日志，需要在日志初始化之后
+    error::install_panic_hook();
+    // 设置了 SENTRY_DSN 时把错误和 panic 上报到 Sentry，需要在 panic hook 之后初始化
+    let _error_report_guard = error_report::init(&config.sentry);
+    db::repo::set_slow_query_threshold(config.database.slow_query);
 
+    /*
+     * 命令行子命令，不传时默认启动服务
+     * - serve: 启动 HTTP 服务
+     * - migrate: 执行数据库迁移后退出
+     * - seed [目录]: 执行迁移并导入测试数据后退出，目录默认为 fixtures
+     */
+    match std::env::args().nth(1).as_deref() {
+        None | Some("serve") => serve(config).await,
+        Some("migrate") => {
+            let pool = connect_or_exit(&config).await;
+            migrate_or_exit(&pool).await;
+        }
+        Some("seed") => {
+            let dir = std::env::args()
+                .nth(2)
+                .unwrap_or_else(|| "fixtures".to_string());
+            let pool = connect_or_exit(&config).await;
+            migrate_or_exit(&pool).await;
+            if let Err(err) = seed::run(&pool, std::path::Path::new(&dir)).await {
+                tracing::error!("seed failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(other) => {
+            eprintln!(
+                "unknown command: {}\nusage: rs-practice-axum [serve|migrate|seed [dir]]",
+                other
+            );
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn connect_or_exit(config: &Config) -> ConnectionPool {
+    match db::connect(&config.database.url, &config.database).await {
+        Ok(pool) => pool,
+        Err(err) => {
+            tracing::error!("failed to connect to database: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn migrate_or_exit(pool: &ConnectionPool) {
+    if let Err(err) = db::migrate(pool).await {
+        tracing::error!("database migration failed: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/**
+ * 连接所有只读副本，任意一个连不上都直接退出，避免带着错误的配置启动
+ */
+async fn connect_replicas_or_exit(config: &Config) -> Replicas {
+    let mut pools = Vec::new();
+    for (index, url) in config.database.replica_urls.iter().enumerate() {
+        match db::connect(url, &config.database).await {
+            Ok(pool) => pools.push(pool),
+            Err(err) => {
+                tracing::error!("failed to connect to read replica #{}: {}", index, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    Replicas::new(pools)
+}
+
+async fn serve(config: Config) {
     // 数据库
-    let manager = PostgresConnectionManager::new_from_stringlike(
-        "host=localhost user=postgres dbname=postgres password=123456",
-        NoTls,
-    )
-    .unwrap();
+    let pool = connect_or_exit(&config).await;
+    let replicas = connect_replicas_or_exit(&config).await;
+    if config.database.migrate_on_startup {
+        migrate_or_exit(&pool).await;
+    }
+    if let Err((_, err)) = jobs::fail_interrupted(&pool).await {
+        tracing::error!("failed to mark interrupted jobs as failed: {}", err);
+        std::process::exit(1);
+    }
+    let admin_tables = match AdminTables::load(&pool, &config.admin.tables).await {
+        Ok(tables) => tables,
+        Err((_, err)) => {
+            tracing::error!("failed to load ADMIN_TABLES: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let app_state = AppState {
+        config: Arc::new(config.clone()),
+        pool,
+        replicas,
+        url_signer: UrlSigner::new(&config.url_signing),
+        jwt: JwtKeys::new(&config.auth),
+        revocations: RevocationList::new(&config.auth),
+        policies: PolicyCache::new(config.auth.policy_cache_ttl),
+        login_throttle: LoginThrottle::new(&config.auth),
+        admin_tables,
+        notifier: Notifier::start(&config.database),
+        assets: Overlay::new(&config.assets),
+        breaker: CircuitBreaker::new(config.database.retry.breaker_cooldown),
+        response_cache: ResponseCache::new(&config.response_cache),
+        push: WebPush::new(&config.push),
+        client_policies: ClientPolicies::default(),
+        compat_rules: CompatRules::default(),
+        schema_drift: SchemaDrift::default(),
+        rules: Rules::new(&config.redirects),
+        deprecations: Deprecations::default(),
+        invalidations: Invalidations::new(),
+        experiments: Experiments::new(&config.experiments),
+        status: StatusPage::new(&config.status),
+        mailer: Mailer::new(&config.mail),
+        pdf: PdfRenderer::new(&config.pdf),
+        markdown: Markdown::new(&config.markdown),
+        sitemaps: Sitemaps::default(),
+        drain: Drain::new(&config.drain),
+        metrics: Metrics::default(),
+    };
+    // 处理其他实例和数据库触发器发出的缓存失效消息
+    app_state.invalidations.start(app_state.clone());
+    // 把 PUSH_CHANNELS 的通知推送给订阅了 Web Push 的浏览器
+    app_state
+        .push
+        .start(app_state.pool.clone(), &app_state.notifier);
+
+    // 定期删除过期的任务结果
+    let jobs_pool = app_state.pool.clone();
+    scheduler::spawn_every("prune_job_results", jobs::PRUNE_INTERVAL, move || {
+        let pool = jobs_pool.clone();
+        async move {
+            match jobs::prune_results(&pool).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("pruned {} expired job results", count),
+                Err((_, err)) => tracing::warn!("prune job results failed: {}", err),
+            }
+        }
+    });
+
+    // 定期查询只读副本回放到的位置，带着一致性令牌的读请求据此选择副本
+    if !app_state.replicas.all().is_empty() {
+        app_state.replicas.refresh_replay_lsn().await;
+        let replicas = app_state.replicas.clone();
+        scheduler::spawn_every(
+            "refresh_replica_lsn",
+            config.database.replica_lsn_poll,
+            move || {
+                let replicas = replicas.clone();
+                async move { replicas.refresh_replay_lsn().await }
+            },
+        );
+    }
 
-    // 连接池对象
-    let pool = Pool::builder().build(manager).await.unwrap();
+    // 检查数据库结构是否和迁移一致，有问题时只打印警告，结果显示在 /readyz 和管理后台
+    if let Err((_, err)) = app_state.schema_drift.refresh(&app_state.pool).await {
+        tracing::warn!("check schema drift failed: {}", err);
+    }
+    let drift_pool = app_state.pool.clone();
+    let schema_drift = app_state.schema_drift.clone();
+    scheduler::spawn_every(
+        "check_schema_drift",
+        config.database.schema_check,
+        move || {
+            let pool = drift_pool.clone();
+            let drift = schema_drift.clone();
+            async move {
+                if let Err((_, err)) = drift.refresh(&pool).await {
+                    tracing::warn!("check schema drift failed: {}", err);
+                }
+            }
+        },
+    );
+
+    // 按 User-Agent 区分的客户端策略，启动时加载一次，之后定期重新加载
+    // 老版本客户端的兼容规则按客户端类别生效，跟着一起加载
+    if let Err((_, err)) = app_state.client_policies.reload(&app_state.pool).await {
+        tracing::warn!("load client policies failed: {}", err);
+    }
+    if let Err((_, err)) = app_state.compat_rules.reload(&app_state.pool).await {
+        tracing::warn!("load compat rules failed: {}", err);
+    }
+    let policy_pool = app_state.pool.clone();
+    let client_policies = app_state.client_policies.clone();
+    let compat_rules = app_state.compat_rules.clone();
+    scheduler::spawn_every(
+        "reload_client_policies",
+        config.rate_limit.client_policy_reload,
+        move || {
+            let pool = policy_pool.clone();
+            let policies = client_policies.clone();
+            let compat_rules = compat_rules.clone();
+            async move {
+                if let Err((_, err)) = policies.reload(&pool).await {
+                    tracing::warn!("reload client policies failed: {}", err);
+                }
+                if let Err((_, err)) = compat_rules.reload(&pool).await {
+                    tracing::warn!("reload compat rules failed: {}", err);
+                }
+            }
+        },
+    );
+
+    // 重定向和改写规则，启动时加载一次，之后定期重新加载
+    if let Err((_, err)) = app_state.rules.reload(&app_state.pool).await {
+        tracing::warn!("load redirect rules failed: {}", err);
+    }
+    let rules_pool = app_state.pool.clone();
+    let rules = app_state.rules.clone();
+    scheduler::spawn_every(
+        "reload_redirect_rules",
+        config.redirects.reload,
+        move || {
+            let pool = rules_pool.clone();
+            let rules = rules.clone();
+            async move {
+                if let Err((_, err)) = rules.reload(&pool).await {
+                    tracing::warn!("reload redirect rules failed: {}", err);
+                }
+            }
+        },
+    );
+
+    // 废弃的接口，启动时加载一次，之后定期重新加载，同时把调用次数写进数据库
+    if let Err((_, err)) = app_state.deprecations.reload(&app_state.pool).await {
+        tracing::warn!("load deprecations failed: {}", err);
+    }
+    let deprecation_pool = app_state.pool.clone();
+    let deprecations = app_state.deprecations.clone();
+    scheduler::spawn_every(
+        "reload_deprecations",
+        config.deprecation.reload,
+        move || {
+            let pool = deprecation_pool.clone();
+            let deprecations = deprecations.clone();
+            async move {
+                if let Err((_, err)) = deprecations.reload(&pool).await {
+                    tracing::warn!("reload deprecations failed: {}", err);
+                }
+            }
+        },
+    );
+
+    // 把过期的审计日志归档到对象存储，没有配置对象存储时不归档
+    if let Some(store) = ObjectStore::new(&config.storage) {
+        let archive_pool = app_state.pool.clone();
+        let archive_config = config.archive.clone();
+        scheduler::spawn_every("archive_audit_log", config.archive.interval, move || {
+            let pool = archive_pool.clone();
+            let store = store.clone();
+            let config = archive_config.clone();
+            async move {
+                if let Err((_, err)) = archive::run(&pool, &store, &config).await {
+                    tracing::warn!("archive audit log failed: {}", err);
+                }
+            }
+        });
+    }
 
-    let app_state = AppState { pool };
+    // 提前创建分区表之后几个月的分区，卸下过期的分区
+    if let Err((_, err)) = db::partitions::maintain(&app_state.pool, &config.partitions).await {
+        tracing::warn!("maintain partitions failed: {}", err);
+    }
+    let partition_pool = app_state.pool.clone();
+    let partition_config = config.partitions.clone();
+    scheduler::spawn_every(
+        "maintain_partitions",
+        db::partitions::MAINTAIN_INTERVAL,
+        move || {
+            let pool = partition_pool.clone();
+            let config = partition_config.clone();
+            async move {
+                if let Err((_, err)) = db::partitions::maintain(&pool, &config).await {
+                    tracing::warn!("maintain partitions failed: {}", err);
+                }
+            }
+        },
+    );
+
+    // 状态页：启动时检查一次各组件，之后定期检查并记录可用率
+    if let Err((_, err)) = app_state.status.refresh(&app_state, true).await {
+        tracing::warn!("refresh status page failed: {}", err);
+    }
+    let status_state = app_state.clone();
+    scheduler::spawn_every("sample_status", config.status.sample_interval, move || {
+        let state = status_state.clone();
+        async move {
+            if let Err((_, err)) = state.status.refresh(&state, true).await {
+                tracing::warn!("refresh status page failed: {}", err);
+            }
+        }
+    });
+
+    // 给还在调用快要下线的接口的用户发提醒邮件
+    let notice_pool = app_state.pool.clone();
+    let mailer = app_state.mailer.clone();
+    let notice_experiments = app_state.experiments.clone();
+    let notice_before = config.deprecation.notice_before;
+    scheduler::spawn_every(
+        "notify_deprecations",
+        Duration::from_secs(3600),
+        move || {
+            let pool = notice_pool.clone();
+            let mailer = mailer.clone();
+            let experiments = notice_experiments.clone();
+            async move {
+                if let Err((_, err)) =
+                    deprecation::notify(&pool, &mailer, &experiments, notice_before).await
+                {
+                    tracing::warn!("send deprecation notices failed: {}", err);
+                }
+            }
+        },
+    );
+
+    // 定期清理已过期的 token 吊销记录和 refresh token
+    let prune_pool = app_state.pool.clone();
+    scheduler::spawn_every(
+        "prune_revoked_tokens",
+        Duration::from_secs(3600),
+        move || {
+            let pool = prune_pool.clone();
+            async move {
+                if let Err((_, err)) = RevocationList::prune(&pool).await {
+                    tracing::warn!("prune revoked tokens failed: {}", err);
+                }
+                if let Err((_, err)) = refresh::prune(&pool).await {
+                    tracing::warn!("prune refresh tokens failed: {}", err);
+                }
+            }
+        },
+    );
+
+    // 会话密钥环，按时间轮换，调度器定期检查
+    let session_keys = SessionKeys::new(&config.session, config.public_url.starts_with("https://"));
+    let rotating_keys = session_keys.clone();
+    scheduler::spawn_every(
+        "rotate_session_keys",
+        config.session.rotation_interval,
+        move || {
+            let keys = rotating_keys.clone();
+            async move { keys.rotate() }
+        },
+    );
+
+    // 静态文件，按 ASSET_ROOTS 的顺序在多个目录中查找；访问不存在的 url 时返回 index.html
+    let assets = app_state.assets.clone().not_found_page("index.html");
 
-    // 配置当访问不存在 url 时的默认返回
-    let serve_dir =
-        ServeDir::new("assets2").not_found_service(ServeFile::new("assets2/index.html")); // not_found_service 传入的是默认获取的文件
+    /*
+     * 不同的路由可以有不同的请求体大小限制，防止超大的请求体耗尽内存
+     * 把需要同一限制的路由放到一个子 Router 里，再用 layer 挂上 RequestBodyLimitLayer，最后 merge 回主路由
+     */
+    let json_routes = Router::new()
+        .route("/form", get(show_form).post(accept_form))
+        .route("/json", post(accept_json))
+        .route("/yaml", post(accept_yaml))
+        .route("/handleParsingError", post(handle_parsing_error))
+        .route("/handlerReturn", post(handler_return))
+        .merge(xml::routes())
+        .merge(msgpack::routes())
+        .merge(cbor::routes())
+        .merge(protobuf::routes())
+        .merge(markdown::routes())
+        .layer(RequestBodyLimitLayer::new(config.body_limit.json));
+
+    // 上传接口需要先关闭 axum 解包器默认的 2MB 限制，再使用更大的上限
+    let upload_routes = Router::new()
+        .route("/upload", post(accept_upload))
+        .merge(import::routes())
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(config.body_limit.upload));
 
     // 使用路由构建应用程序
     let app = Router::new()
         .route("/", get(handler))
         .route("/query", get(query))
-        .route("/form", get(show_form).post(accept_form))
-        .route("/json", post(accept_json))
-        .route("/handleParsingError", post(handle_parsing_error))
-        .route("/handlerReturn", post(handler_return))
+        .merge(json_routes)
+        .merge(upload_routes)
+        .merge(auth::routes())
+        .merge(refresh::routes())
+        .merge(audit::routes())
+        .merge(archive::routes())
+        .merge(device::routes())
+        .merge(permissions::routes())
+        .merge(impersonate::routes())
+        .merge(throttle::routes())
+        .merge(users::routes())
+        .merge(todos::routes())
+        .merge(search::routes())
+        .merge(sync::routes())
+        .merge(jobs::routes())
+        .merge(admin::routes())
+        .merge(console::routes())
+        .merge(config::routes())
+        .merge(client_policy::routes())
+        .merge(compat::routes())
+        .merge(degraded::routes())
+        .merge(deprecation::routes())
+        .merge(drain::routes())
+        .merge(experiments::routes())
+        .merge(notify::routes())
+        .merge(push::routes())
+        .merge(status::routes())
+        .merge(feed::routes())
+        .merge(sitemap::routes())
+        .merge(widgets::routes())
+        .merge(health::routes())
+        .merge(db::routes())
+        .merge(dashboard::routes())
+        .merge(rules::routes())
+        .merge(runtime::routes())
+        .merge(metrics::routes())
         .route("/returnTemplate/:name", get(return_template)) // 通过 path 传参的路由
-        .route("/query_from_db", get(query_from_db))
-        .nest_service("/assets", ServeDir::new("assets")) // 把 /assets/* 的 URL 映射到 assets 目录下
-        .nest_service("/assets2", serve_dir.clone())
-        .fallback_service(serve_dir) // 注意需要挂载
-        .layer(TraceLayer::new_for_http()) // 日志中间件服务
+        .route("/session", get(session_counter))
+        .route("/sign_download/*path", get(sign_download))
+        .route("/downloads/*path", get(download)) // 需要签名才能访问的 DOWNLOADS_DIR 下的文件
+        .nest_service("/assets", assets.clone()) // 把 /assets/* 的 URL 映射到静态文件目录下
+        .nest_service("/assets2", assets.clone()) // 旧地址，和 /assets 是同一组目录
+        .fallback_service(assets) // 注意需要挂载
+        .layer(middleware::from_fn(db::transaction_layer)) // 请求级事务，配合 db::Tx 提取器使用
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            consistency::apply,
+        )) // 写请求返回一致性令牌，带着令牌的读请求只用已经追上的只读副本
+        .layer(middleware::from_fn_with_state(
+            TenantResolver::new(&config.tenant),
+            tenant::resolve,
+        )) // 根据 X-Tenant-Id 或子域名确定租户，配合 tenant::Tenant 提取器使用
+        .layer(middleware::from_fn(impersonate::banner)) // 模拟登录时在 HTML 页面顶部显示提示条
+        .layer(middleware::from_fn_with_state(
+            session_keys,
+            session::session_layer,
+        )) // 加密 cookie 会话
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            deprecation::apply,
+        )) // 废弃的接口带上 Deprecation/Sunset 响应头，下线之后返回 410
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            compat::apply,
+        )) // 按客户端类别转换老版本客户端的请求和响应
+        .layer(middleware::map_response(error::payload_too_large_json)) // 413 统一返回 JSON
+        .layer(middleware::from_fn_with_state(
+            ApiQuota::new(&config.rate_limit),
+            quota::quota_layer,
+        )) // 接口配额，响应带上 RateLimit-* 响应头
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            client_policy::apply,
+        )) // 按 User-Agent 区分客户端，决定配额、缓存时间和可以访问的接口
+        .layer(middleware::from_fn_with_state(
+            LoadShed::new(&config.load_shed, app_state.metrics.clone()),
+            load_shed::apply,
+        )) // 同时处理的请求太多时直接返回 503，不无限排队
+        .layer(middleware::map_response(quota::retry_hints)) // 429/503 统一返回带重试提示的 JSON
+        .layer(middleware::from_fn_with_state(
+            Timeouts::new(&config.timeout),
+            timeout::apply,
+        )) // 请求超时，按路由设置不同的时间，超时返回 408/504
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            degraded::degraded,
+        )) // 数据库不可用时返回缓存的数据或者静态提示页面
+        .layer(middleware::from_fn(etag::conditional_get)) // JSON/HTML 响应带上 ETag，If-None-Match 匹配时返回 304
         .fallback(handler_404) // 没有匹配到任何一个 url pattern 的情况
-        .with_state(app_state); // 这样就可以把这个全局状态传递到每一个 handler 和中间件里了
+        // 下面几层放在 fallback 之后，没有匹配到路由的请求也会经过
+        .layer(CatchPanicLayer::custom(error::panic_response)) // handler panic 时返回 500，连接和进程不受影响
+        .layer(middleware::from_fn(error_report::capture)) // 5xx 和 panic 带上请求信息上报到 Sentry
+        .layer(middleware::from_fn_with_state(
+            app_state.metrics.clone(),
+            metrics::track,
+        )) // 按路由和状态码统计请求数和耗时，见 /metrics
+        .layer(middleware::from_fn_with_state(
+            app_state.drain.clone(),
+            drain::close_connections,
+        )) // 排空期间处理完请求就断开长连接
+        .layer(middleware::from_fn(request_id::annotate_errors)) // 错误响应体里带上请求 ID
+        .layer(middleware::from_fn_with_state(
+            Arc::new(config.compression.clone()),
+            compression::vary,
+        )) // 可能压缩的响应带上 Vary: Accept-Encoding
+        .layer(middleware::from_fn_with_state(
+            Arc::new(config.body_log.clone()),
+            body_log::log_bodies,
+        )) // BODY_LOG=true 时打印脱敏之后的请求体和响应体
+        .layer(compression::layer(&config.compression)) // 按 Accept-Encoding 压缩较大的 HTML/JSON 响应
+        .layer(middleware::from_fn(telemetry::trace_context)) // 解析 traceparent，调用其他服务时接着传下去
+        .layer(middleware::from_fn(access_log::annotate)) // 把请求的信息交给 TraceLayer 打印访问日志
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_id::make_span)
+                .on_response(access_log::on_response)
+                .on_failure(access_log::on_failure),
+        ) // 日志中间件服务，span 里记录请求 ID，每个请求打印一行访问日志，开启 OTLP 导出时同时发送给链路追踪系统
+        .layer(PropagateRequestIdLayer::new(request_id::X_REQUEST_ID)) // 响应带上 x-request-id
+        .layer(SetRequestIdLayer::new(
+            request_id::X_REQUEST_ID,
+            MakeRequestUuid,
+        )) // 没有 x-request-id 的请求生成一个，放在最外层，后面的中间件都能拿到
+        .with_state(app_state.clone()); // 这样就可以把这个全局状态传递到每一个 handler 和中间件里了
+
+    /*
+     * Router 的 layer 是在匹配路由之后才执行的，在里面修改请求的路径已经来不及影响路由了，
+     * 所以重定向和改写规则包在整个 Router 外面，先改写路径再交给 Router 匹配
+     */
+    let app = middleware::from_fn_with_state(app_state.rules.clone(), rules::apply).layer(app);
 
     // 启动端口监听
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .unwrap();
+    let listener = match tokio::net::TcpListener::bind(config.listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("failed to listen on {}: {}", config.listen, err);
+            std::process::exit(1);
+        }
+    };
 
     /*
      * Rust 标准的 log 协议: https://docs.rs/log/latest/log/
@@ -81,7 +748,33 @@ async fn main() {
      */
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app).await.unwrap();
+    // 使用 into_make_service_with_connect_info 启动，handler 中才能通过 ConnectInfo 拿到客户端地址
+    // 每个连接的 service 包上一层计数，统计当前打开的连接数
+    let metrics = app_state.metrics.clone();
+    let make_service = MapResponse::new(
+        ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app),
+        move |service| metrics.count_connection(service),
+    );
+
+    /*
+     * 收到 SIGTERM 或者 POST /admin/drain 之后先让 /readyz 失败，等负载均衡器摘掉流量，
+     * 再停止接受新连接，等正在处理的请求完成后退出，见 drain
+     */
+    let drain = app_state.drain.clone();
+    drain.listen_for_signals();
+    let server =
+        axum::serve(listener, make_service).with_graceful_shutdown(drain.clone().stopping());
+    let drain_timeout = config.drain.timeout;
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            drain.stopping().await;
+            tokio::time::sleep(drain_timeout).await;
+        } => tracing::warn!(
+            "in-flight requests did not finish within {} seconds, exiting",
+            drain_timeout.as_secs()
+        ),
+    }
 }
 
 async fn handler() -> Html<&'static str> {
@@ -111,33 +804,31 @@ async fn query(Query(params): Query<Params>) -> Html<&'static str> {
     Html("<h3>Test query</h3>")
 }
 
-async fn show_form() -> Html<&'static str> {
-    Html(
-        r#"
-        <!doctype html>
-        <html>
-            <head></head>
-            <body>
-                <form action="/form" method="post">
-                    <label for="name">
-                        Enter your name:
-                        <input type="text" name="name">
-                    </label>
-
-                    <label>
-                        Enter your email:
-                        <input type="text" name="email">
-                    </label>
-
-                    <input type="submit" value="Subscribe!">
-                </form>
-            </body>
-        </html>
-        "#,
-    )
+#[derive(Template)]
+#[template(path = "subscribe.html")]
+struct SubscribeTemplate {
+    experiments: Assignment,
 }
 
-#[derive(Deserialize, Debug)]
+/**
+ * 订阅表单，文案由 subscribe_form 实验决定（见 experiments）
+ */
+async fn show_form(
+    State(state): State<AppState>,
+    session: Session,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let subject = experiments::session_subject(&session);
+    let experiments = state
+        .experiments
+        .assign(&state.pool, &subject, &["subscribe_form"])
+        .await;
+    let page = SubscribeTemplate { experiments }
+        .render()
+        .map_err(error::internal_error)?;
+    Ok(Html(page))
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(dead_code)]
 struct Input {
     name: String,
@@ -161,6 +852,14 @@ async fn accept_json(Json(input): Json<Input>) -> Html<&'static str> {
     Html("<h3>Json posted</h3>")
 }
 
+/**
+ * POST YAML 请求，和 accept_json 用的是同一个结构体，见 yaml
+ */
+async fn accept_yaml(Yaml(input): Yaml<Input>) -> Html<&'static str> {
+    tracing::debug!("yaml params {:?}", input);
+    Html("<h3>Yaml posted</h3>")
+}
+
 /**
  * 解析错误处理请求
  * 想要处理请求的解析错误，可以使用 Axum 的 Rejection
@@ -226,6 +925,26 @@ async fn handler_return(Json(input): Json<Input>) -> impl IntoResponse {
     }
 }
 
+/**
+ * POST multipart 上传请求
+ * Multipart 解包器会逐个读取表单字段，这里只统计每个字段的大小并返回
+ * 超过上传限制时 field.bytes() 会返回错误，它的 IntoResponse 实现会给出 413 状态码
+ */
+async fn accept_upload(mut multipart: Multipart) -> Result<impl IntoResponse, Response> {
+    let mut files = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(IntoResponse::into_response)?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        let file_name = field.file_name().map(str::to_string);
+        let bytes = field.bytes().await.map_err(IntoResponse::into_response)?;
+        files.push(json!({ "name": name, "file_name": file_name, "size": bytes.len() }));
+    }
+    Ok(Json(json!({ "files": files })))
+}
+
 #[derive(Template)]
 #[template(path = "hello.html")]
 struct HelloTemplate {
@@ -239,31 +958,48 @@ async fn return_template(Path(name): Path<String>) -> impl IntoResponse {
     HelloTemplate { name }.to_string()
 }
 
-async fn query_from_db(
-    State(AppState { pool }): State<AppState>, // 解包全局状态，拿到其中管理的 pool
-) -> Result<String, (StatusCode, String)> {
-    tracing::debug!("get db conn {:?}", pool);
-    let conn = pool.get().await.map_err(internal_error)?;
-
-    tracing::debug!("query_from_db: 1");
-    let row = conn
-        .query_one("select 1 + 1", &[])
-        .await
-        .map_err(internal_error)?;
-    tracing::debug!("query_from_db: 2");
-
-    let two: i32 = row.try_get(0).map_err(internal_error)?;
-    tracing::debug!("query_from_db: 3");
-    tracing::debug!("calc_result {:?}", two);
+/**
+ * 会话示例：每访问一次计数加一，计数保存在加密的 cookie 中
+ */
+async fn session_counter(session: Session) -> String {
+    let count = session.get::<u64>("count").unwrap_or(0) + 1;
+    session.insert("count", count);
+    format!("visited {} times", count)
+}
 
-    Ok(two.to_string())
+/**
+ * 签名 URL 示例：为 DOWNLOADS_DIR 下的文件生成一个临时下载链接，需要 download:sign 权限
+ * DOWNLOADS_DIR 不是公开的静态文件目录，里面的文件只能通过签名链接下载
+ */
+async fn sign_download(
+    _auth: Authorize<DownloadSign>,
+    State(signer): State<UrlSigner>,
+    Path(path): Path<String>,
+) -> String {
+    signer.sign(&format!("/downloads/{}", path), signer.default_ttl)
 }
 
-fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+/**
+ * 校验签名通过后，把请求转交给 DOWNLOADS_DIR 的静态文件服务读取文件
+ * 保留原请求的 header，这样 Range、If-Modified-Since 等条件请求依然有效
+ */
+async fn download(
+    _: SignedUrl,
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    // Path 提取到的是解码后的路径，重新转义之后才能放进 URI
+    let Ok(uri) = signed_url::encode_path(&format!("/{}", path)).parse::<Uri>() else {
+        return (StatusCode::BAD_REQUEST, "invalid path").into_response();
+    };
+    let mut req = Request::new(Body::empty());
+    *req.uri_mut() = uri;
+    *req.headers_mut() = headers;
+    let downloads = ServeDir::new(&state.config.url_signing.downloads_dir);
+    tower::ServiceExt::oneshot(downloads, req)
+        .await
+        .into_response()
 }
 
 async fn handler_404() -> impl IntoResponse {
@@ -0,0 +1,214 @@
+use std::{io, path::Path, sync::Arc};
+
+use askama::Template;
+use axum::{
+    extract::{Path as UrlPath, Query, State},
+    http::StatusCode,
+    response::Html,
+    routing::{get, post},
+    Router,
+};
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use serde::Deserialize;
+use syntect::{
+    highlighting::ThemeSet,
+    html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+use crate::{config::MarkdownConfig, error::internal_error, AppState};
+
+/*
+ * 把 Markdown 渲染成 HTML，放进 templates/layout.html 的页面框架里
+ * - GET /pages/:slug   内容页面，读取 CONTENT_DIR 目录下的 {slug}.md，页面标题是第一个一级标题，没有时用 slug
+ * - POST /preview      请求体是 Markdown 原文，返回渲染好的页面，编辑器的预览区域用 ?fragment=true 只取正文的 HTML
+ * 渲染分三步：
+ * - pulldown-cmark 解析 CommonMark，另外支持表格和删除线
+ * - 带语言的代码块（```rust）交给 syntect 高亮，输出带 hl- 前缀 class 的 pre 和 span，
+ *   配色是按 MARKDOWN_THEME 生成的 CSS，放在页面的 <style> 里；不认识的语言按纯文本显示
+ * - 最后用 ammonia 按白名单清理整个 HTML：Markdown 里可以直接写 HTML，预览的内容又来自用户，
+ *   script、事件属性、javascript: 链接之类的都会被去掉，只保留高亮用到的 class
+ * 内容页面的文件是部署时放进去的，同样会清理，和预览的显示效果保持一致。
+ * 语法定义和主题在启动时加载一次，渲染是同步的 CPU 计算，在 spawn_blocking 里执行。
+ * 预览的请求体大小受 JSON_BODY_LIMIT 限制。
+ */
+
+// 高亮的 class 加上前缀，不和页面自己的样式冲突
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+const DEFAULT_THEME: &str = "InspiredGitHub";
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/pages/:slug", get(page))
+        .route("/preview", post(preview))
+}
+
+#[derive(Template)]
+#[template(path = "page.html")]
+struct PageTemplate<'a> {
+    title: &'a str,
+    css: &'a str,
+    body: &'a str,
+}
+
+struct Renderer {
+    syntaxes: SyntaxSet,
+    css: String,
+    sanitizer: ammonia::Builder<'static>,
+}
+
+#[derive(Clone)]
+pub struct Markdown(Arc<Renderer>);
+
+impl Markdown {
+    pub fn new(config: &MarkdownConfig) -> Self {
+        let mut themes = ThemeSet::load_defaults().themes;
+        let theme = themes.remove(&config.theme).unwrap_or_else(|| {
+            tracing::warn!(
+                "unknown MARKDOWN_THEME {}, using {}",
+                config.theme,
+                DEFAULT_THEME
+            );
+            themes.remove(DEFAULT_THEME).unwrap_or_default()
+        });
+        let css = css_for_theme_with_class_style(&theme, CLASS_STYLE)
+            .inspect_err(|err| tracing::warn!("failed to generate highlight CSS: {}", err))
+            .unwrap_or_default();
+        let mut sanitizer = ammonia::Builder::default();
+        sanitizer
+            .add_tag_attributes("pre", &["class"])
+            .add_tag_attributes("span", &["class"]);
+        Markdown(Arc::new(Renderer {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            css,
+            sanitizer,
+        }))
+    }
+
+    /**
+     * Markdown 转成清理过的 HTML 片段
+     */
+    pub fn to_html(&self, source: &str) -> String {
+        let mut events = Vec::new();
+        let mut code: Option<(String, String)> = None;
+        for event in Parser::new_ext(
+            source,
+            Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+        ) {
+            match (event, &mut code) {
+                (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), None) => {
+                    // 信息字符串可能是 "rust,ignore" 或者 "rust title"，只取语言
+                    let lang = info.split([' ', ',']).next().unwrap_or_default();
+                    code = Some((lang.to_string(), String::new()));
+                }
+                (Event::Text(text), Some((_, buf))) => buf.push_str(&text),
+                (Event::End(TagEnd::CodeBlock), Some(_)) => {
+                    let (lang, buf) = code.take().unwrap_or_default();
+                    events.push(Event::Html(self.highlight(&lang, &buf).into()));
+                }
+                (event, _) => events.push(event),
+            }
+        }
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, events.into_iter());
+        self.0.sanitizer.clean(&html).to_string()
+    }
+
+    fn highlight(&self, lang: &str, source: &str) -> String {
+        let syntaxes = &self.0.syntaxes;
+        let syntax = syntaxes
+            .find_syntax_by_token(lang)
+            .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, CLASS_STYLE);
+        for line in LinesWithEndings::from(source) {
+            if let Err(err) = generator.parse_html_for_line_which_includes_newline(line) {
+                tracing::warn!("failed to highlight {} code block: {}", lang, err);
+                break;
+            }
+        }
+        format!(
+            "<pre class=\"hl-code\"><code>{}</code></pre>\n",
+            generator.finalize()
+        )
+    }
+
+    /**
+     * 渲染 Markdown，fragment 为 true 时只返回正文，否则放进页面框架里
+     */
+    async fn render(
+        &self,
+        title: String,
+        source: String,
+        fragment: bool,
+    ) -> Result<Html<String>, (StatusCode, String)> {
+        let markdown = self.clone();
+        let body = tokio::task::spawn_blocking(move || markdown.to_html(&source))
+            .await
+            .map_err(internal_error)?;
+        if fragment {
+            return Ok(Html(body));
+        }
+        let page = PageTemplate {
+            title: &title,
+            css: &self.0.css,
+            body: &body,
+        };
+        Ok(Html(page.render().map_err(internal_error)?))
+    }
+}
+
+/**
+ * 第一个一级标题的文字
+ */
+fn title(source: &str) -> Option<String> {
+    source
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|title| title.trim().to_string())
+        .filter(|title| !title.is_empty())
+}
+
+/**
+ * slug 只能由字母、数字、- 和 _ 组成，不会读到 CONTENT_DIR 以外的文件
+ */
+fn valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+async fn page(
+    State(state): State<AppState>,
+    UrlPath(slug): UrlPath<String>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, "page not found".to_string());
+    if !valid_slug(&slug) {
+        return Err(not_found());
+    }
+    let path = Path::new(&state.config.markdown.content_dir).join(format!("{}.md", slug));
+    let source = match tokio::fs::read_to_string(&path).await {
+        Ok(source) => source,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(not_found()),
+        Err(err) => return Err(internal_error(err)),
+    };
+    let title = title(&source).unwrap_or_else(|| slug.clone());
+    state.markdown.render(title, source, false).await
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    #[serde(default)]
+    fragment: bool,
+}
+
+async fn preview(
+    State(state): State<AppState>,
+    Query(query): Query<PreviewQuery>,
+    source: String,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let title = title(&source).unwrap_or_else(|| "Preview".to_string());
+    state.markdown.render(title, source, query.fragment).await
+}
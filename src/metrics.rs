@@ -0,0 +1,516 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use tower::Service;
+
+use crate::{request_id::X_REQUEST_ID, AppState};
+
+/*
+ * Prometheus 指标，GET /metrics 返回文本格式，供 Prometheus 定期抓取
+ * - http_requests_total{method,route,status}               请求数
+ * - http_request_duration_seconds{method,route,status}     请求耗时的直方图
+ * - http_requests_in_flight                                正在处理的请求数
+ * - http_connections_active                                当前打开的 TCP 连接数
+ * - http_requests_shed_total                               过载时被拒绝的请求数，见 load_shed
+ * - http_deprecated_requests_total{route}                   调用废弃接口的次数，见 deprecation
+ * - cache_invalidation_latency_seconds{cache}              失效消息从发出到本实例处理完的延迟，见 invalidation
+ * - db_pool_connections{pool,state}                        连接池里使用中（in_use）和空闲（idle）的连接数
+ * - db_pool_max_connections{pool}                          连接池的上限 DB_POOL_MAX_SIZE
+ * - db_pool_wait_seconds{pool}                             从连接池获取连接的等待时间的直方图
+ * - db_pool_timeouts_total{pool}                           等不到连接而超时的次数
+ * route 是匹配到的路由（比如 /todos/:id）而不是实际的路径，没有匹配到路由的请求记为 unmatched，避免标签的取值无限增长。
+ * 设置 METRICS_TOKEN 后需要带上 Authorization: Bearer {METRICS_TOKEN} 才能访问，对应 Prometheus 的 authorization 配置。
+ * 另外在内存里保留最近的 5xx 请求，显示在 /admin/status 上（见 dashboard），不导出给 Prometheus。
+ */
+
+// 保留最近多少个 5xx 请求
+const RECENT_ERRORS: usize = 20;
+
+// 直方图的桶（秒），和 Prometheus 客户端库的默认值相同
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/**
+ * 累计直方图，每个桶记录耗时小于等于它的次数
+ */
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /**
+     * 输出 {name}_bucket、{name}_sum 和 {name}_count 三组样本，labels 形如 method="GET",route="/"
+     */
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name,
+                labels,
+                separator,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count();
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, count
+        );
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct RequestKey {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+/**
+ * 一个返回 5xx 的请求
+ */
+#[derive(Clone)]
+pub struct RecentError {
+    pub at: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub request_id: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: Mutex<HashMap<RequestKey, Arc<Histogram>>>,
+    errors: Mutex<VecDeque<RecentError>>,
+    in_flight: AtomicI64,
+    connections: AtomicI64,
+    shed: AtomicU64,
+    deprecated: Mutex<HashMap<String, u64>>,
+    invalidations: Mutex<HashMap<&'static str, Arc<Histogram>>>,
+}
+
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+impl Metrics {
+    fn observe_request(&self, key: RequestKey, elapsed: Duration) {
+        let histogram = self
+            .inner
+            .requests
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .clone();
+        histogram.observe(elapsed);
+    }
+
+    /**
+     * 按状态码的第一位统计的请求数，下标 0 是 1xx，4 是 5xx
+     */
+    pub fn requests_by_class(&self) -> [u64; 5] {
+        let mut classes = [0; 5];
+        for (key, histogram) in self.inner.requests.lock().unwrap().iter() {
+            if let Some(class) = classes.get_mut((key.status / 100) as usize - 1) {
+                *class += histogram.count();
+            }
+        }
+        classes
+    }
+
+    pub fn in_flight(&self) -> i64 {
+        self.inner.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> i64 {
+        self.inner.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn shed(&self) -> u64 {
+        self.inner.shed.load(Ordering::Relaxed)
+    }
+
+    /**
+     * 最近的 5xx 请求，新的在前
+     */
+    pub fn recent_errors(&self) -> Vec<RecentError> {
+        self.inner
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    fn record_error(&self, error: RecentError) {
+        let mut errors = self.inner.errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(error);
+    }
+
+    pub fn count_shed(&self) {
+        self.inner.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_deprecated(&self, route: &str) {
+        *self
+            .inner
+            .deprecated
+            .lock()
+            .unwrap()
+            .entry(route.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn observe_invalidation(&self, cache: &'static str, latency: Duration) {
+        let histogram = self
+            .inner
+            .invalidations
+            .lock()
+            .unwrap()
+            .entry(cache)
+            .or_default()
+            .clone();
+        histogram.observe(latency);
+    }
+
+    /**
+     * 给每个连接的 service 加上计数，见 Counted
+     */
+    pub fn count_connection<S>(&self, inner: S) -> Counted<S> {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+        Counted {
+            inner,
+            _guard: Arc::new(ConnectionGuard(self.inner.clone())),
+        }
+    }
+}
+
+struct ConnectionGuard(Arc<Inner>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * 每个 TCP 连接对应一个 service，处理请求时会被复制，连接关闭并且所有副本都 drop 之后计数减一
+ */
+#[derive(Clone)]
+pub struct Counted<S> {
+    inner: S,
+    _guard: Arc<ConnectionGuard>,
+}
+
+impl<S, R> Service<R> for Counted<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+/**
+ * 正在处理的请求数，请求结束（包括被取消）时减一
+ */
+struct InFlight(Arc<Inner>);
+
+impl InFlight {
+    fn start(inner: &Arc<Inner>) -> Self {
+        inner.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(inner.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * 统计请求数和耗时的中间件，需要放在 fallback 后面，没有匹配到路由的请求也要统计
+ */
+pub async fn track(State(metrics): State<Metrics>, req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let path = req.uri().path().to_string();
+    let request_id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    let _in_flight = InFlight::start(&metrics.inner);
+    let started = Instant::now();
+    let res = next.run(req).await;
+    if res.status().is_server_error() {
+        metrics.record_error(RecentError {
+            at: Utc::now(),
+            method: method.clone(),
+            path,
+            status: res.status().as_u16(),
+            request_id,
+        });
+    }
+    metrics.observe_request(
+        RequestKey {
+            method,
+            route,
+            status: res.status().as_u16(),
+        },
+        started.elapsed(),
+    );
+    res
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/metrics", get(scrape))
+}
+
+/**
+ * 标签值里的反斜杠、双引号和换行需要转义
+ */
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn authorized(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(token) = &state.config.metrics.token else {
+        return true;
+    };
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| value == token)
+}
+
+async fn scrape(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !authorized(&state, &headers) {
+        return (StatusCode::UNAUTHORIZED, "invalid metrics token").into_response();
+    }
+    let inner = &state.metrics.inner;
+    let mut out = String::new();
+
+    let mut requests: Vec<(RequestKey, Arc<Histogram>)> = inner
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, histogram)| (key.clone(), histogram.clone()))
+        .collect();
+    requests.sort_by(|(a, _), (b, _)| {
+        (&a.route, &a.method, a.status).cmp(&(&b.route, &b.method, b.status))
+    });
+    let labels = |key: &RequestKey| {
+        format!(
+            "method=\"{}\",route=\"{}\",status=\"{}\"",
+            escape(&key.method),
+            escape(&key.route),
+            key.status
+        )
+    };
+    out.push_str("# HELP http_requests_total Number of HTTP requests.\n");
+    out.push_str("# TYPE http_requests_total counter\n");
+    for (key, histogram) in &requests {
+        let _ = writeln!(
+            out,
+            "http_requests_total{{{}}} {}",
+            labels(key),
+            histogram.count()
+        );
+    }
+    out.push_str("# HELP http_request_duration_seconds HTTP request latency.\n");
+    out.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for (key, histogram) in &requests {
+        histogram.write(&mut out, "http_request_duration_seconds", &labels(key));
+    }
+
+    out.push_str("# HELP http_requests_in_flight Number of HTTP requests being handled.\n");
+    out.push_str("# TYPE http_requests_in_flight gauge\n");
+    let _ = writeln!(
+        out,
+        "http_requests_in_flight {}",
+        inner.in_flight.load(Ordering::Relaxed)
+    );
+    out.push_str("# HELP http_connections_active Number of open client connections.\n");
+    out.push_str("# TYPE http_connections_active gauge\n");
+    let _ = writeln!(
+        out,
+        "http_connections_active {}",
+        inner.connections.load(Ordering::Relaxed)
+    );
+
+    out.push_str("# HELP http_requests_shed_total Number of requests rejected because the server was overloaded.\n");
+    out.push_str("# TYPE http_requests_shed_total counter\n");
+    let _ = writeln!(
+        out,
+        "http_requests_shed_total {}",
+        inner.shed.load(Ordering::Relaxed)
+    );
+    let mut deprecated: Vec<(String, u64)> = inner
+        .deprecated
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(route, count)| (route.clone(), *count))
+        .collect();
+    deprecated.sort();
+    out.push_str(
+        "# HELP http_deprecated_requests_total Number of requests to deprecated routes.\n",
+    );
+    out.push_str("# TYPE http_deprecated_requests_total counter\n");
+    for (route, count) in &deprecated {
+        let _ = writeln!(
+            out,
+            "http_deprecated_requests_total{{route=\"{}\"}} {}",
+            escape(route),
+            count
+        );
+    }
+
+    let mut invalidations: Vec<(&str, Arc<Histogram>)> = inner
+        .invalidations
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(cache, histogram)| (*cache, histogram.clone()))
+        .collect();
+    invalidations.sort_by_key(|(cache, _)| *cache);
+    out.push_str("# HELP cache_invalidation_latency_seconds Time from publishing a cache invalidation to applying it.\n");
+    out.push_str("# TYPE cache_invalidation_latency_seconds histogram\n");
+    for (cache, histogram) in &invalidations {
+        histogram.write(
+            &mut out,
+            "cache_invalidation_latency_seconds",
+            &format!("cache=\"{}\"", cache),
+        );
+    }
+
+    let max_size = state.config.database.pool.max_size;
+    let mut pools = vec![("primary".to_string(), &state.pool)];
+    for (index, pool) in state.replicas.all().iter().enumerate() {
+        pools.push((format!("replica{}", index), pool));
+    }
+    out.push_str("# HELP db_pool_connections Number of database connections by state.\n");
+    out.push_str("# TYPE db_pool_connections gauge\n");
+    for (name, pool) in &pools {
+        let pool_state = pool.state();
+        let in_use = pool_state.connections - pool_state.idle_connections;
+        let _ = writeln!(
+            out,
+            "db_pool_connections{{pool=\"{}\",state=\"in_use\"}} {}",
+            name, in_use
+        );
+        let _ = writeln!(
+            out,
+            "db_pool_connections{{pool=\"{}\",state=\"idle\"}} {}",
+            name, pool_state.idle_connections
+        );
+    }
+    out.push_str("# HELP db_pool_max_connections Maximum size of the connection pool.\n");
+    out.push_str("# TYPE db_pool_max_connections gauge\n");
+    for (name, _) in &pools {
+        let _ = writeln!(
+            out,
+            "db_pool_max_connections{{pool=\"{}\"}} {}",
+            name, max_size
+        );
+    }
+    out.push_str("# HELP db_pool_wait_seconds Time spent waiting for a pooled connection.\n");
+    out.push_str("# TYPE db_pool_wait_seconds histogram\n");
+    for (name, pool) in &pools {
+        pool.stats().wait.write(
+            &mut out,
+            "db_pool_wait_seconds",
+            &format!("pool=\"{}\"", name),
+        );
+    }
+    out.push_str("# HELP db_pool_timeouts_total Number of timed out connection checkouts.\n");
+    out.push_str("# TYPE db_pool_timeouts_total counter\n");
+    for (name, pool) in &pools {
+        let _ = writeln!(
+            out,
+            "db_pool_timeouts_total{{pool=\"{}\"}} {}",
+            name,
+            pool.stats().timeouts.load(Ordering::Relaxed)
+        );
+    }
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        )],
+        out,
+    )
+        .into_response()
+}
@@ -0,0 +1,199 @@
+use std::{fmt, time::Instant};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::BytesRejection, FromRequest, Query, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{internal_error, json_error},
+    AppState, Input,
+};
+
+/*
+ * MessagePack 格式的请求和响应，Content-Type: application/msgpack，用法和 Json 一样：
+ *   async fn handler(MsgPack(input): MsgPack<Input>) -> MsgPack<Output>
+ * MessagePack 是二进制的 JSON，数据模型相同，但是数字、布尔值不用转成文本，字符串前面直接写长度，
+ * 所以体积更小、编解码更快，适合移动端或者服务之间大量传输数据。
+ * 序列化时把字段名写进去（rmp_serde::to_vec_named），结构和 JSON 一一对应，调试工具解出来就能看懂。
+ * - POST /msgpack            示例接口，把收到的 Input 原样返回
+ * - GET  /msgpack/benchmark  用同样的 Input 列表比较 JSON 和 MessagePack 的体积和编解码耗时，?items= 指定条数
+ * 解析失败时返回 MsgPackRejection：
+ * - MissingMsgPackContentType  没有 Content-Type: application/msgpack（或者 application/x-msgpack），415
+ * - MsgPackError               不是合法的 MessagePack，或者和目标类型对不上，400
+ * - BytesRejection             读取请求体失败，比如超出大小限制
+ */
+
+const MSGPACK: &str = "application/msgpack";
+
+// benchmark 最多生成多少条数据
+const MAX_BENCHMARK_ITEMS: usize = 100_000;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/msgpack", post(echo))
+        .route("/msgpack/benchmark", get(benchmark))
+}
+
+pub struct MsgPack<T>(pub T);
+
+#[derive(Debug)]
+pub enum MsgPackRejection {
+    MissingMsgPackContentType,
+    MsgPackError(rmp_serde::decode::Error),
+    BytesRejection(BytesRejection),
+}
+
+impl fmt::Display for MsgPackRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsgPackRejection::MissingMsgPackContentType => {
+                f.write_str("expected request with `Content-Type: application/msgpack`")
+            }
+            MsgPackRejection::MsgPackError(err) => {
+                write!(f, "failed to parse the MessagePack body: {}", err)
+            }
+            MsgPackRejection::BytesRejection(err) => err.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for MsgPackRejection {
+    fn into_response(self) -> Response {
+        let status = match self {
+            MsgPackRejection::MissingMsgPackContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            MsgPackRejection::MsgPackError(_) => StatusCode::BAD_REQUEST,
+            MsgPackRejection::BytesRejection(err) => return err.into_response(),
+        };
+        json_error(status, &self.to_string())
+    }
+}
+
+/**
+ * application/msgpack 是 IANA 注册的类型，application/x-msgpack 是以前常用的写法
+ */
+fn is_msgpack_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == MSGPACK || mime == "application/x-msgpack"
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for MsgPack<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = MsgPackRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_msgpack_content_type(req.headers()) {
+            return Err(MsgPackRejection::MissingMsgPackContentType);
+        }
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(MsgPackRejection::BytesRejection)?;
+        rmp_serde::from_slice(&bytes)
+            .map(MsgPack)
+            .map_err(MsgPackRejection::MsgPackError)
+    }
+}
+
+impl<T> IntoResponse for MsgPack<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        match rmp_serde::to_vec_named(&self.0) {
+            Ok(body) => (
+                [(header::CONTENT_TYPE, HeaderValue::from_static(MSGPACK))],
+                body,
+            )
+                .into_response(),
+            Err(err) => internal_error(err).into_response(),
+        }
+    }
+}
+
+async fn echo(MsgPack(input): MsgPack<Input>) -> MsgPack<Input> {
+    tracing::debug!("msgpack params {:?}", input);
+    MsgPack(input)
+}
+
+#[derive(Deserialize)]
+struct BenchmarkQuery {
+    #[serde(default = "default_items")]
+    items: usize,
+}
+
+fn default_items() -> usize {
+    1000
+}
+
+/**
+ * 编码再解码一次，返回体积和两步的耗时（微秒）
+ */
+fn measure<E>(
+    items: &[Input],
+    encode: impl Fn(&[Input]) -> Result<Vec<u8>, E>,
+    decode: impl Fn(&[u8]) -> Result<Vec<Input>, E>,
+) -> Result<Value, E> {
+    let started = Instant::now();
+    let bytes = encode(items)?;
+    let encode_us = started.elapsed().as_micros() as u64;
+    let started = Instant::now();
+    decode(&bytes)?;
+    let decode_us = started.elapsed().as_micros() as u64;
+    Ok(json!({
+        "bytes": bytes.len(),
+        "encode_us": encode_us,
+        "decode_us": decode_us,
+    }))
+}
+
+async fn benchmark(
+    Query(query): Query<BenchmarkQuery>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let count = query.items.clamp(1, MAX_BENCHMARK_ITEMS);
+    let items: Vec<Input> = (0..count)
+        .map(|i| Input {
+            name: format!("user{}", i),
+            email: format!("user{}@example.com", i),
+        })
+        .collect();
+    // 编解码是同步的 CPU 计算，数据量大时会占住工作线程，放到阻塞线程池里
+    let report = tokio::task::spawn_blocking(move || -> Result<Value, String> {
+        let json = measure(
+            &items,
+            |items| serde_json::to_vec(items).map_err(|err| err.to_string()),
+            |bytes| serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+        )?;
+        let msgpack = measure(
+            &items,
+            |items| rmp_serde::to_vec_named(items).map_err(|err| err.to_string()),
+            |bytes| rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+        )?;
+        Ok(json!({ "items": items.len(), "json": json, "msgpack": msgpack }))
+    })
+    .await
+    .map_err(internal_error)?
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    Ok(Json(report))
+}
@@ -0,0 +1,67 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use futures_util::StreamExt;
+use serde::Serialize;
+use tokio_postgres::{Row, RowStream};
+
+use crate::db::{repo::FromRow, Connection};
+
+/*
+ * 把查询结果流式导出为 NDJSON（每行一个 JSON 对象，以换行分隔），比如 GET /api/users.ndjson、GET /todos.ndjson
+ * 和 csv 一样：数据库那边用 query_raw 逐行读取，每攒够一批行就序列化成 JSON 发给客户端，
+ * 内存里最多只有一批数据，客户端读得慢时数据库那边也会跟着等，导出几百万行也不会把结果集读进内存。
+ * 和一个大的 JSON 数组相比，客户端也可以逐行解析，不需要等整个响应结束，比如
+ *   curl -s localhost:3000/todos.ndjson | jq -c 'select(.done)'
+ * 每一行的字段和列表接口里的对象相同（就是行的结构体序列化的结果），没有表头。
+ * 响应头：
+ * - Content-Type: application/x-ndjson
+ * - Content-Disposition: attachment; filename="users.ndjson"，浏览器里直接下载成文件
+ * 响应头发出之后才出错时只能中断响应，客户端收到的最后一行可能不完整，错误记在日志里。
+ * 导出新的表不需要额外实现 trait，行的结构体能从 Row 读出并且可以序列化就行，在 handler 里调用 stream。
+ */
+
+// 每批序列化多少行
+const ROWS_PER_CHUNK: usize = 500;
+
+fn encode<T: FromRow + Serialize>(
+    rows: Vec<Result<Row, tokio_postgres::Error>>,
+) -> Result<Bytes, BoxError> {
+    let mut out = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut out, &T::from_row(&row?)?)?;
+        out.push(b'\n');
+    }
+    Ok(Bytes::from(out))
+}
+
+/**
+ * NDJSON 下载响应，conn 是执行查询的连接，rows 读完或者客户端断开之后才还回连接池
+ */
+pub fn stream<T>(conn: Connection, rows: RowStream, filename: &str) -> Response
+where
+    T: FromRow + Serialize + 'static,
+{
+    let body = rows.ready_chunks(ROWS_PER_CHUNK).map(move |batch| {
+        let _conn = &conn;
+        encode::<T>(batch).inspect_err(|err| tracing::error!("NDJSON export failed: {}", err))
+    });
+
+    let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename))
+        .expect("NDJSON file names are valid header values");
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/x-ndjson"),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
@@ -0,0 +1,168 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde_json::Value;
+
+use crate::error::{internal_error, json_error};
+
+/*
+ * 内容协商：同一个 handler 按 Accept 请求头返回 JSON、HTML 或者纯文本
+ * - 每种格式的质量取最具体的匹配范围的 q 值（完整的 text/html 优先于 text 通配，再优先于全部通配），q=0 表示不接受
+ * - 质量最高的格式胜出，相同时按 handler 给的默认格式、JSON、HTML、纯文本的顺序
+ * - 没有 Accept 请求头或者是全部通配时返回默认格式：/todos 页面默认 HTML，/api/users 默认 JSON，
+ *   所以浏览器和原来的 API 客户端看到的都和以前一样
+ * - 三种格式都不接受时返回 406，错误信息里列出支持的类型
+ * 响应带 Vary: Accept，缓存（浏览器、CDN、degraded 的响应缓存）按 Accept 区分。
+ * 资源实现 Negotiate trait 提供三种表示，handler 里用 Accept 提取器调用 respond：
+ *   accept.respond(Format::Html, &DetailTemplate { todo })
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Html,
+    Text,
+}
+
+impl Format {
+    const ALL: [Format; 3] = [Format::Json, Format::Html, Format::Text];
+
+    fn media_type(self) -> (&'static str, &'static str) {
+        match self {
+            Format::Json => ("application", "json"),
+            Format::Html => ("text", "html"),
+            Format::Text => ("text", "plain"),
+        }
+    }
+}
+
+/**
+ * 一个资源的三种表示，只有选中的那一种会被生成
+ */
+pub trait Negotiate {
+    fn json(&self) -> Value;
+    fn html(&self) -> Result<String, askama::Error>;
+    fn text(&self) -> String;
+}
+
+/**
+ * Accept 里的一项，比如 text/html;q=0.9
+ */
+struct MediaRange {
+    ty: String,
+    subtype: String,
+    q: f32,
+}
+
+impl MediaRange {
+    fn parse(item: &str) -> Option<Self> {
+        let mut parts = item.split(';');
+        let (ty, subtype) = parts.next()?.trim().split_once('/')?;
+        let mut q = 1.0;
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    // q 值格式不对时忽略这一项
+                    q = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+        }
+        Some(MediaRange {
+            ty: ty.trim().to_ascii_lowercase(),
+            subtype: subtype.trim().to_ascii_lowercase(),
+            q,
+        })
+    }
+
+    /**
+     * 匹配时的具体程度：完全相同是 2，只通配子类型是 1，全部通配是 0，不匹配是 None
+     */
+    fn specificity(&self, (ty, subtype): (&str, &str)) -> Option<u8> {
+        match (self.ty.as_str(), self.subtype.as_str()) {
+            ("*", "*") => Some(0),
+            (range_ty, "*") if range_ty == ty => Some(1),
+            (range_ty, range_subtype) if range_ty == ty && range_subtype == subtype => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * 请求的 Accept 头，不会拒绝请求，没有协商出格式时由 respond 返回 406
+ */
+pub struct Accept(Option<Vec<MediaRange>>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Accept
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ranges = parts
+            .headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter(|item| !item.trim().is_empty())
+            .filter_map(MediaRange::parse)
+            .collect::<Vec<_>>();
+        Ok(Accept((!ranges.is_empty()).then_some(ranges)))
+    }
+}
+
+impl Accept {
+    fn quality(ranges: &[MediaRange], format: Format) -> f32 {
+        ranges
+            .iter()
+            .filter_map(|range| Some((range.specificity(format.media_type())?, range.q)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, q)| q)
+    }
+
+    /**
+     * 协商出的格式，default 是客户端没有偏好时使用的格式
+     */
+    pub fn format(&self, default: Format) -> Option<Format> {
+        let Some(ranges) = &self.0 else {
+            return Some(default);
+        };
+        let mut best: Option<(Format, f32)> = None;
+        for format in std::iter::once(default).chain(Format::ALL) {
+            let q = Self::quality(ranges, format);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((format, q));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+
+    pub fn respond(&self, default: Format, resource: &impl Negotiate) -> Response {
+        let vary = [(header::VARY, HeaderValue::from_static("accept"))];
+        let res = match self.format(default) {
+            Some(Format::Json) => Json(resource.json()).into_response(),
+            Some(Format::Html) => match resource.html() {
+                Ok(page) => Html(page).into_response(),
+                Err(err) => internal_error(err).into_response(),
+            },
+            Some(Format::Text) => resource.text().into_response(),
+            None => json_error(
+                StatusCode::NOT_ACCEPTABLE,
+                "supported types are application/json, text/html and text/plain",
+            ),
+        };
+        (vary, res).into_response()
+    }
+}
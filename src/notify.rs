@@ -0,0 +1,187 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use futures_util::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc};
+use tokio_postgres::AsyncMessage;
+
+use crate::{
+    config::DatabaseConfig, config::TlsConfig, db::tls, invalidation, tenant::Tenant, AppState,
+};
+
+/*
+ * Postgres LISTEN/NOTIFY 转发为 Server-Sent Events
+ * 后台任务用一个单独的数据库连接 LISTEN 配置的频道（NOTIFY_CHANNELS），收到的通知通过 broadcast 通道
+ * 分发给所有连接在 GET /events 上的浏览器。其它进程（或者触发器）执行 NOTIFY 写入的变化，页面上可以实时看到。
+ * - GET /events                所有频道的通知
+ * - GET /events?channel=todos  只接收某个频道的通知
+ * SSE 的事件名就是频道名，浏览器里用 EventSource.addEventListener("todos", ...) 接收。
+ * 客户端只收到自己租户的通知：payload 需要是带 tenant 字段的 JSON 对象（比如 todos 触发器发的
+ * {"op": "UPDATE", "id": 1, "tenant": "acme"}），tenant 和请求的租户不同、或者没有 tenant 字段的通知都不转发，
+ * 转发之前去掉 tenant 字段。能看到的内容和同一个租户下公开的 todos 列表一样，所以和列表一样不需要登录。
+ * LISTEN 需要一直占用同一个连接，所以不从连接池里取连接；连接断开后按指数退避重连，重连期间的通知会丢失。
+ * 除了 NOTIFY_CHANNELS，还固定监听 cache_invalidation 频道（见 invalidation），这个频道只在服务内部使用，不通过 /events 转发。
+ */
+
+// 每个客户端最多积压多少条还没发出去的通知，超出后最旧的通知被丢弃
+const CAPACITY: usize = 256;
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub channel: String,
+    pub payload: String,
+}
+
+#[derive(Clone)]
+pub struct Notifier {
+    sender: broadcast::Sender<Notification>,
+    // 可以通过 /events 转发给浏览器的频道，也就是 NOTIFY_CHANNELS
+    public: Arc<Vec<String>>,
+}
+
+impl Notifier {
+    /**
+     * 创建通知分发器并启动后台 LISTEN 任务
+     */
+    pub fn start(config: &DatabaseConfig) -> Self {
+        let sender = broadcast::channel(CAPACITY).0;
+        let url = config.url.clone();
+        let tls = config.tls.clone();
+        let mut channels = config.notify_channels.clone();
+        channels.push(invalidation::CHANNEL.to_string());
+        let listener = sender.clone();
+        tokio::spawn(async move {
+            let mut delay = Duration::from_secs(1);
+            loop {
+                match listen(&url, &tls, &channels, &listener).await {
+                    Ok(()) => {
+                        tracing::warn!("notification connection closed");
+                        delay = Duration::from_secs(1);
+                    }
+                    Err(err) => tracing::warn!("notification connection failed: {}", err),
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        });
+        Notifier {
+            sender,
+            public: Arc::new(config.notify_channels.clone()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+}
+
+/**
+ * 建立连接并 LISTEN 所有频道，之后一直转发通知，直到连接断开
+ */
+async fn listen(
+    url: &str,
+    tls: &TlsConfig,
+    channels: &[String],
+    sender: &broadcast::Sender<Notification>,
+) -> Result<(), tokio_postgres::Error> {
+    let connector = tls::connector(tls).expect("invalid TLS settings");
+    let (client, mut connection) = tls::pg_config(url, tls)?.connect(connector).await?;
+
+    // Connection 需要一直被 poll，client 上的语句才会被执行，所以放到单独的任务里，把收到的消息转发回来
+    let (messages_tx, mut messages) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut stream = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = stream.next().await {
+            if messages_tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    for channel in channels {
+        // 频道名加上双引号，避免被转成小写或者注入 SQL
+        let quoted = format!("\"{}\"", channel.replace('"', "\"\""));
+        client.batch_execute(&format!("LISTEN {}", quoted)).await?;
+    }
+    tracing::info!("listening for notifications on {}", channels.join(", "));
+
+    while let Some(message) = messages.recv().await {
+        if let AsyncMessage::Notification(notification) = message? {
+            // 没有客户端连接时 send 会返回错误，忽略即可
+            let _ = sender.send(Notification {
+                channel: notification.channel().to_string(),
+                payload: notification.payload().to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/events", get(events))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    channel: Option<String>,
+}
+
+/**
+ * 通知属于 tenant 时返回去掉 tenant 字段之后的 payload，否则返回 None
+ */
+fn tenant_payload(payload: &str, tenant: &Tenant) -> Option<String> {
+    let mut value: serde_json::Value = serde_json::from_str(payload).ok()?;
+    let object = value.as_object_mut()?;
+    if object.remove("tenant")?.as_str() != Some(tenant.id()) {
+        return None;
+    }
+    Some(value.to_string())
+}
+
+async fn events(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.notifier.subscribe();
+    let stream = stream::unfold(receiver, move |mut receiver| {
+        let channel = query.channel.clone();
+        let public = state.notifier.public.clone();
+        let tenant = tenant.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(notification) => {
+                        if !public.contains(&notification.channel)
+                            || channel
+                                .as_ref()
+                                .is_some_and(|channel| *channel != notification.channel)
+                        {
+                            continue;
+                        }
+                        let Some(payload) = tenant_payload(&notification.payload, &tenant) else {
+                            continue;
+                        };
+                        let event = Event::default().event(notification.channel).data(payload);
+                        return Some((Ok(event), receiver));
+                    }
+                    // 客户端处理得太慢，中间有通知被丢弃了，告诉客户端需要重新加载完整数据
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        let event = Event::default().event("lagged").data(skipped.to_string());
+                        return Some((Ok(event), receiver));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    // 定期发送注释行，避免空闲连接被代理断开
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,152 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{request::Parts, StatusCode},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+
+/*
+ * 列表接口统一的分页参数和返回格式
+ * - 请求参数：?page=2&per_page=20，page 从 1 开始，默认 1；per_page 默认 50，最大 200，超出范围时自动修正，
+ *   page 大到 OFFSET 溢出时返回 400
+ * - 返回格式：{ "items": [...], "total": 123, "page": 2, "per_page": 20, "total_pages": 7 }
+ * 分页参数和其它查询参数可以同时使用，各自的 Query 提取器只读取自己需要的字段。
+ *
+ * 数据量很大的表用 OFFSET 翻到后面几页时，数据库需要先扫描并丢弃前面所有的行，count(*) 也要扫描整张表。
+ * 这类接口另外支持游标分页（keyset pagination）：
+ * - 请求参数：?cursor=&per_page=20，第一页 cursor 为空，之后传上一页返回的 next_cursor
+ * - 返回格式：{ "items": [...], "next_cursor": "..." }，没有下一页时 next_cursor 为 null
+ * 游标对客户端来说是不透明的字符串，内部是上一页最后一条记录的排序键，查询时用 WHERE id < 游标 代替 OFFSET。
+ */
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 200;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pagination {
+    pub page: i64,
+    pub per_page: i64,
+}
+
+impl Pagination {
+    pub fn limit(&self) -> i64 {
+        self.per_page
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page - 1) * self.per_page
+    }
+}
+
+#[derive(Deserialize)]
+struct PaginationQuery {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Pagination
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<PaginationQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.body_text()))?;
+        let pagination = Pagination {
+            page: query.page.unwrap_or(1).max(1),
+            per_page: query
+                .per_page
+                .unwrap_or(DEFAULT_PER_PAGE)
+                .clamp(1, MAX_PER_PAGE),
+        };
+        // page 太大时 offset 会溢出，这样的页不可能有数据，直接拒绝
+        if (pagination.page - 1)
+            .checked_mul(pagination.per_page)
+            .is_none()
+        {
+            return Err((StatusCode::BAD_REQUEST, "page is too large".to_string()));
+        }
+        Ok(pagination)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub total_pages: i64,
+}
+
+impl<T> Paginated<T> {
+    pub fn new(items: Vec<T>, total: i64, pagination: Pagination) -> Self {
+        Paginated {
+            items,
+            total,
+            page: pagination.page,
+            per_page: pagination.per_page,
+            total_pages: (total + pagination.per_page - 1) / pagination.per_page,
+        }
+    }
+}
+
+/**
+ * 游标里保存的内容，加上版本号方便以后修改格式
+ */
+#[derive(Serialize, Deserialize)]
+struct CursorData {
+    v: u8,
+    after: i64,
+}
+
+fn invalid_cursor() -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, "invalid cursor".to_string())
+}
+
+/**
+ * 解析客户端传入的游标，空字符串表示第一页，返回 None
+ */
+pub fn decode_cursor(cursor: &str) -> Result<Option<i64>, (StatusCode, String)> {
+    if cursor.is_empty() {
+        return Ok(None);
+    }
+    let bytes = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| invalid_cursor())?;
+    let data: CursorData = serde_json::from_slice(&bytes).map_err(|_| invalid_cursor())?;
+    if data.v != 1 {
+        return Err(invalid_cursor());
+    }
+    Ok(Some(data.after))
+}
+
+fn encode_cursor(after: i64) -> String {
+    let data = serde_json::to_vec(&CursorData { v: 1, after }).unwrap_or_default();
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+#[derive(Serialize)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> CursorPage<T> {
+    /**
+     * items 需要多查一条（LIMIT per_page + 1），多出来的那条存在说明还有下一页
+     * key 取出每条记录的排序键，用来生成下一页的游标
+     */
+    pub fn new(mut items: Vec<T>, pagination: Pagination, key: impl Fn(&T) -> i64) -> Self {
+        let has_more = items.len() as i64 > pagination.per_page;
+        items.truncate(pagination.per_page as usize);
+        let next_cursor = has_more
+            .then(|| items.last().map(|item| encode_cursor(key(item))))
+            .flatten();
+        CursorPage { items, next_cursor }
+    }
+}
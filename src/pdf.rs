@@ -0,0 +1,296 @@
+use std::{path::Path, sync::Arc};
+
+use askama::Template;
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use genpdf::{
+    elements::{Break, FrameCellDecorator, Image, Paragraph, TableLayout, UnorderedList},
+    fonts::{FontData, FontFamily},
+    style::Style,
+    Alignment, Document, Element, Scale, SimplePageDecorator,
+};
+use printpdf::BuiltinFont;
+
+use crate::{config::PdfConfig, error::internal_error};
+
+/*
+ * 在服务端把 askama 模板渲染成 PDF，比如 GET /todos/:id/report.pdf
+ * askama 只负责内容，模板（比如 templates/todos/report.txt）输出的是一种很简单的标记，由这里负责排版：
+ * - "# 标题"、"## 小标题"
+ * - 以 "- " 开头的行是列表项
+ * - 以 "|" 开头的行是表格的一行，单元格用 "|" 分隔，连续的几行组成一个表格，第一行是加粗的表头
+ * - 空行分隔段落，同一段里的多行连在一起，自动换行
+ * - 段落、列表项和单元格里可以用 **加粗**
+ * - 反斜杠让后面的一个字符按原样显示，模板里的动态内容要经过 pdf_text 过滤器转义，
+ *   否则标题里的 "|" 或者 "**" 会被当成标记
+ * 字体和 logo：
+ * - PDF 里的文字要用嵌入的 TrueType 字体，从 PDF_FONT_DIR 目录读取 {PDF_FONT}.ttf 和 {PDF_FONT}-Bold.ttf，
+ *   默认是系统自带的 DejaVu Sans。只有字体里有的字符才能显示，中文需要换成 Noto Sans CJK 这类字体
+ * - PDF_LOGO 是第一页左上角的图片，路径相对于工作目录，默认是 assets/naive_logo.jpg
+ * 字体和 logo 在启动时读取一次，字体读取失败时服务照常启动，只是生成 PDF 的接口返回 503，logo 读取失败时不显示 logo。
+ * 排版是同步的 CPU 计算，在 spawn_blocking 里执行。生成的 PDF 包含完整的字体文件，release 构建时会压缩，
+ * 每个文件一两 MB；debug 构建不压缩，会有好几 MB。
+ */
+
+// logo 按 300 dpi 显示，大图缩小到这个比例
+const LOGO_SCALE: f64 = 0.5;
+
+#[derive(Clone)]
+pub struct PdfRenderer {
+    fonts: Option<Arc<FontFamily<FontData>>>,
+    logo: Option<Arc<Image>>,
+}
+
+impl PdfRenderer {
+    pub fn new(config: &PdfConfig) -> Self {
+        let fonts = load_fonts(config)
+            .inspect_err(|err| {
+                tracing::warn!(
+                    "failed to load PDF fonts from {}, PDF endpoints are disabled: {}",
+                    config.font_dir,
+                    err
+                )
+            })
+            .ok();
+        let logo = config.logo.as_ref().and_then(|path| {
+            Image::from_path(path)
+                .inspect_err(|err| tracing::warn!("failed to load PDF logo {}: {}", path, err))
+                .ok()
+        });
+        PdfRenderer {
+            fonts: fonts.map(Arc::new),
+            logo: logo.map(Arc::new),
+        }
+    }
+
+    /**
+     * 渲染模板再排版成 PDF，title 是文件属性里的标题，同时显示在每一页的页眉里
+     */
+    pub async fn render(
+        &self,
+        title: String,
+        template: &impl Template,
+    ) -> Result<Vec<u8>, (StatusCode, String)> {
+        let Some(fonts) = self.fonts.clone() else {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "PDF fonts are not available, check PDF_FONT_DIR and PDF_FONT".to_string(),
+            ));
+        };
+        let source = template.render().map_err(internal_error)?;
+        let logo = self.logo.clone();
+        tokio::task::spawn_blocking(move || build(&fonts, logo.as_deref(), title, &source))
+            .await
+            .map_err(internal_error)?
+            .map_err(internal_error)
+    }
+}
+
+/**
+ * genpdf 会把字体族里的四个字体都嵌入到 PDF 里，斜体没有用到，
+ * 标记成 PDF 阅读器自带的 Helvetica 斜体，不占文件大小，也只需要两个字体文件
+ */
+fn load_fonts(config: &PdfConfig) -> Result<FontFamily<FontData>, genpdf::error::Error> {
+    let dir = Path::new(&config.font_dir);
+    let regular = dir.join(format!("{}.ttf", config.font));
+    let bold = dir.join(format!("{}-Bold.ttf", config.font));
+    Ok(FontFamily {
+        italic: FontData::load(&regular, Some(BuiltinFont::HelveticaOblique))?,
+        bold_italic: FontData::load(&bold, Some(BuiltinFont::HelveticaBoldOblique))?,
+        regular: FontData::load(&regular, None)?,
+        bold: FontData::load(&bold, None)?,
+    })
+}
+
+/**
+ * PDF 下载响应，download 为 false 时浏览器直接打开预览
+ */
+pub fn response(body: Vec<u8>, filename: &str, download: bool) -> Response {
+    let disposition = if download { "attachment" } else { "inline" };
+    let disposition = HeaderValue::from_str(&format!("{}; filename=\"{}\"", disposition, filename))
+        .expect("PDF file names are valid header values");
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/pdf"),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+enum Block<'a> {
+    Title(&'a str),
+    Heading(&'a str),
+    Text(Vec<&'a str>),
+    List(Vec<&'a str>),
+    Table(Vec<Vec<&'a str>>),
+    Gap,
+}
+
+/**
+ * 把模板输出的标记按行分成块，行首的标记要在转义之前判断，转义过的 "\#" 不算标题
+ */
+fn parse(source: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    for line in source.lines().map(str::trim) {
+        if let Some(text) = line.strip_prefix("# ") {
+            blocks.push(Block::Title(text));
+        } else if let Some(text) = line.strip_prefix("## ") {
+            blocks.push(Block::Heading(text));
+        } else if let Some(item) = line.strip_prefix("- ") {
+            match blocks.last_mut() {
+                Some(Block::List(items)) => items.push(item),
+                _ => blocks.push(Block::List(vec![item])),
+            }
+        } else if line.starts_with('|') {
+            let row = cells(line);
+            match blocks.last_mut() {
+                Some(Block::Table(rows)) => rows.push(row),
+                _ => blocks.push(Block::Table(vec![row])),
+            }
+        } else if line.is_empty() {
+            if !matches!(blocks.last(), None | Some(Block::Gap)) {
+                blocks.push(Block::Gap);
+            }
+        } else {
+            match blocks.last_mut() {
+                Some(Block::Text(lines)) => lines.push(line),
+                _ => blocks.push(Block::Text(vec![line])),
+            }
+        }
+    }
+    blocks
+}
+
+/**
+ * "| a | b |" -> ["a", "b"]，转义过的 "\|" 不分隔单元格
+ */
+fn cells(line: &str) -> Vec<&str> {
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let mut cells = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '|' => {
+                cells.push(line[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    let rest = line[start..].trim();
+    if !rest.is_empty() {
+        cells.push(rest);
+    }
+    cells
+}
+
+/**
+ * 处理段落里的 **加粗** 和转义
+ */
+fn paragraph(text: &str, style: Style) -> Paragraph {
+    let mut paragraph = Paragraph::default();
+    let mut span = String::new();
+    let mut bold = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => span.extend(chars.next()),
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if !span.is_empty() {
+                    let span_style = if bold { style.bold() } else { style };
+                    paragraph.push_styled(std::mem::take(&mut span), span_style);
+                }
+                bold = !bold;
+            }
+            _ => span.push(c),
+        }
+    }
+    if !span.is_empty() {
+        paragraph.push_styled(span, if bold { style.bold() } else { style });
+    }
+    paragraph
+}
+
+fn table(rows: Vec<Vec<&str>>) -> Result<TableLayout, genpdf::error::Error> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(1).max(1);
+    let mut table = TableLayout::new(vec![1; columns]);
+    table.set_cell_decorator(FrameCellDecorator::new(true, true, false));
+    for (index, cells) in rows.into_iter().enumerate() {
+        let style = if index == 0 {
+            Style::new().bold()
+        } else {
+            Style::new()
+        };
+        let mut row = table.row();
+        for column in 0..columns {
+            let text = cells.get(column).copied().unwrap_or_default();
+            row.push_element(paragraph(text, style).padded((1, 2)));
+        }
+        row.push()?;
+    }
+    Ok(table)
+}
+
+fn build(
+    fonts: &FontFamily<FontData>,
+    logo: Option<&Image>,
+    title: String,
+    source: &str,
+) -> Result<Vec<u8>, genpdf::error::Error> {
+    let mut doc = Document::new(fonts.clone());
+    doc.set_title(title.clone());
+    doc.set_font_size(10);
+    doc.set_line_spacing(1.25);
+    let mut decorator = SimplePageDecorator::new();
+    decorator.set_margins(15);
+    decorator.set_header(move |page| {
+        Paragraph::new(format!("{} - page {}", title, page))
+            .aligned(Alignment::Right)
+            .styled(Style::new().with_font_size(8))
+            .padded((0, 0, 4, 0))
+    });
+    doc.set_page_decorator(decorator);
+
+    if let Some(logo) = logo {
+        doc.push(logo.clone().with_scale(Scale::new(LOGO_SCALE, LOGO_SCALE)));
+        doc.push(Break::new(1));
+    }
+    for block in parse(source) {
+        match block {
+            Block::Title(text) => {
+                doc.push(paragraph(text, Style::new().bold().with_font_size(18)));
+                doc.push(Break::new(0.5));
+            }
+            Block::Heading(text) => {
+                doc.push(paragraph(text, Style::new().bold().with_font_size(13)));
+                doc.push(Break::new(0.25));
+            }
+            Block::Text(lines) => doc.push(paragraph(&lines.join(" "), Style::new())),
+            Block::List(items) => {
+                let mut list = UnorderedList::new();
+                for item in items {
+                    list.push(paragraph(item, Style::new()));
+                }
+                doc.push(list);
+            }
+            Block::Table(rows) => doc.push(table(rows)?),
+            Block::Gap => doc.push(Break::new(1)),
+        }
+    }
+
+    let mut out = Vec::new();
+    doc.render(&mut out)?;
+    Ok(out)
+}
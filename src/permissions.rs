@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::{request::Parts, StatusCode},
+    routing::{get, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit::Audit,
+    auth::AuthUser,
+    db::{with_retry, ConnectionPool},
+    error::internal_error,
+    pagination::{Paginated, Pagination},
+    AppState,
+};
+
+/**
+ * 权限的作用范围
+ * any: 对所有资源都有该权限；own: 只能操作自己的资源；org: 还可以操作同组织成员的资源
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Any,
+    Org,
+    Own,
+}
+
+impl Scope {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "any" => Some(Scope::Any),
+            "org" => Some(Scope::Org),
+            "own" => Some(Scope::Own),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Any => "any",
+            Scope::Org => "org",
+            Scope::Own => "own",
+        }
+    }
+
+    /**
+     * self 的范围是否包含 other，any 包含 org，org 包含 own
+     */
+    fn covers(self, other: Scope) -> bool {
+        matches!(
+            (self, other),
+            (Scope::Any, _) | (Scope::Org, Scope::Org | Scope::Own) | (Scope::Own, Scope::Own)
+        )
+    }
+}
+
+/**
+ * 每个权限用一个实现了 Permission 的空类型表示
+ * 稳定版 Rust 还不支持 &str 作为 const 泛型参数，没法写成 Authorize::<"post:edit">，
+ * 所以用 Authorize<PostEdit> 这种标记类型的方式，效果是一样的：权限名在编译期就确定了
+ */
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+macro_rules! permission {
+    ($name:ident, $value:literal) => {
+        pub struct $name;
+
+        impl Permission for $name {
+            const NAME: &'static str = $value;
+        }
+    };
+}
+
+permission!(AuditRead, "audit:read");
+permission!(ConfigRead, "config:read");
+permission!(ConsoleUse, "console:use");
+permission!(DownloadSign, "download:sign");
+permission!(RoleManage, "role:manage");
+permission!(ServerManage, "server:manage");
+permission!(TableManage, "table:manage");
+permission!(TodoEdit, "todo:edit");
+permission!(UserImpersonate, "user:impersonate");
+permission!(UserManage, "user:manage");
+
+// 权限名 -> 作用范围
+type Grants = HashMap<String, Scope>;
+
+/**
+ * 角色权限缓存
+ * 每次鉴权都查库代价太高，这里按角色缓存 role_permissions 的内容，缓存过期或者 role_permissions 有修改后重新加载
+ * 其他实例（或者直接在数据库里）的修改通过 invalidation 的消息通知到这里
+ */
+#[derive(Clone)]
+pub struct PolicyCache {
+    roles: Arc<Mutex<HashMap<String, (Grants, Instant)>>>,
+    ttl: Duration,
+}
+
+impl PolicyCache {
+    pub fn new(ttl: Duration) -> Self {
+        PolicyCache {
+            roles: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    async fn grants(
+        &self,
+        pool: &ConnectionPool,
+        role: &str,
+    ) -> Result<Grants, (StatusCode, String)> {
+        if let Some((grants, loaded_at)) = self.roles.lock().unwrap().get(role) {
+            if loaded_at.elapsed() < self.ttl {
+                return Ok(grants.clone());
+            }
+        }
+
+        let conn = pool.get().await.map_err(internal_error)?;
+        let rows = conn
+            .query(
+                "SELECT permission, scope FROM role_permissions WHERE role = $1",
+                &[&role],
+            )
+            .await
+            .map_err(internal_error)?;
+        let grants: Grants = rows
+            .iter()
+            .filter_map(|row| Some((row.get(0), Scope::parse(row.get(1))?)))
+            .collect();
+
+        self.roles
+            .lock()
+            .unwrap()
+            .insert(role.to_string(), (grants.clone(), Instant::now()));
+        Ok(grants)
+    }
+
+    pub fn invalidate(&self, role: &str) {
+        self.roles.lock().unwrap().remove(role);
+    }
+
+    /**
+     * 查询角色对某个权限的作用范围，没有该权限时返回 None
+     * 授予了 "*" 的角色拥有所有权限
+     */
+    pub async fn scope_of(
+        &self,
+        pool: &ConnectionPool,
+        role: &str,
+        permission: &str,
+    ) -> Result<Option<Scope>, (StatusCode, String)> {
+        let grants = self.grants(pool, role).await?;
+        Ok(grants.get(permission).or_else(|| grants.get("*")).copied())
+    }
+
+    /**
+     * role 是否拥有 other 的全部权限，并且每个权限的作用范围都不比 other 小
+     * 用在模拟登录上：不能借模拟别人拿到自己没有的权限
+     */
+    pub async fn covers(
+        &self,
+        pool: &ConnectionPool,
+        role: &str,
+        other: &str,
+    ) -> Result<bool, (StatusCode, String)> {
+        let grants = self.grants(pool, role).await?;
+        let others = self.grants(pool, other).await?;
+        let all = grants.get("*").copied();
+        Ok(others
+            .iter()
+            .all(|(permission, scope)| match (all, permission.as_str()) {
+                (Some(all), _) => all.covers(*scope),
+                (None, "*") => false,
+                (None, permission) => grants
+                    .get(permission)
+                    .is_some_and(|mine| mine.covers(*scope)),
+            }))
+    }
+}
+
+/**
+ * 鉴权提取器
+ * Authorize<P> 要求当前用户的角色拥有权限 P，否则直接返回 403。
+ * 对于 own / org 作用范围的权限，提取器只能确认「有权限」，具体能否操作某条资源，
+ * 需要 handler 查出资源之后再调用 check 判断归属。
+ */
+pub struct Authorize<P> {
+    pub user: AuthUser,
+    pub role: String,
+    pub scope: Scope,
+    _permission: PhantomData<fn() -> P>,
+}
+
+#[async_trait]
+impl<P> FromRequestParts<AppState> for Authorize<P>
+where
+    P: Permission,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+
+        let user_id = user.id;
+        let role: String = with_retry(state, |conn| async move {
+            conn.query_opt(
+                "SELECT role FROM users WHERE id = $1 AND deleted_at IS NULL",
+                &[&user_id],
+            )
+            .await
+        })
+        .await?
+        .map(|row| row.get(0))
+        .ok_or((StatusCode::UNAUTHORIZED, "user not found".to_string()))?;
+
+        let scope = state
+            .policies
+            .scope_of(&state.pool, &role, P::NAME)
+            .await?
+            .ok_or_else(|| forbidden(P::NAME))?;
+        Ok(Authorize {
+            user,
+            role,
+            scope,
+            _permission: PhantomData,
+        })
+    }
+}
+
+impl<P: Permission> Authorize<P> {
+    /**
+     * 判断能否操作某条资源，owner_id 是资源的所有者（todo 的创建者、用户自己），
+     * 没有所有者的资源（比如新建用户、批量操作，或者加上 owner_id 之前创建的 todo）只有 any 范围才能操作
+     */
+    pub async fn check(
+        &self,
+        pool: &ConnectionPool,
+        owner_id: Option<i64>,
+    ) -> Result<(), (StatusCode, String)> {
+        let allowed = match (self.scope, owner_id) {
+            (Scope::Any, _) => true,
+            (_, None) => false,
+            (_, Some(owner_id)) if owner_id == self.user.id => true,
+            (Scope::Own, _) => false,
+            // 所有者和当前用户至少同属一个组织
+            (Scope::Org, Some(owner_id)) => {
+                let conn = pool.get().await.map_err(internal_error)?;
+                conn.query_opt(
+                    "SELECT 1 FROM org_members mine
+                     JOIN org_members theirs ON theirs.org_id = mine.org_id
+                     WHERE mine.user_id = $1 AND theirs.user_id = $2",
+                    &[&self.user.id, &owner_id],
+                )
+                .await
+                .map_err(internal_error)?
+                .is_some()
+            }
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(forbidden(P::NAME))
+        }
+    }
+}
+
+fn forbidden(permission: &str) -> (StatusCode, String) {
+    (
+        StatusCode::FORBIDDEN,
+        format!("missing permission {}", permission),
+    )
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/roles/:role/permissions", get(list_role_permissions))
+        .route(
+            "/admin/roles/:role/permissions/:permission",
+            put(grant_permission).delete(revoke_permission),
+        )
+}
+
+async fn list_role_permissions(
+    _auth: Authorize<RoleManage>,
+    State(state): State<AppState>,
+    Path(role): Path<String>,
+    pagination: Pagination,
+) -> Result<Json<Paginated<Value>>, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let total: i64 = conn
+        .query_one(
+            "SELECT count(*) FROM role_permissions WHERE role = $1",
+            &[&role],
+        )
+        .await
+        .map_err(internal_error)?
+        .get(0);
+    let rows = conn
+        .query(
+            "SELECT permission, scope FROM role_permissions WHERE role = $1 ORDER BY permission
+             LIMIT $2 OFFSET $3",
+            &[&role, &pagination.limit(), &pagination.offset()],
+        )
+        .await
+        .map_err(internal_error)?;
+    let permissions: Vec<Value> = rows
+        .iter()
+        .map(|row| {
+            json!({
+                "permission": row.get::<_, String>(0),
+                "scope": row.get::<_, String>(1),
+            })
+        })
+        .collect();
+    Ok(Json(Paginated::new(permissions, total, pagination)))
+}
+
+#[derive(Deserialize)]
+struct GrantInput {
+    scope: Scope,
+}
+
+/**
+ * 给角色授予权限，已经存在时更新作用范围
+ */
+async fn grant_permission(
+    auth: Authorize<RoleManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path((role, permission)): Path<(String, String)>,
+    Json(input): Json<GrantInput>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    conn.execute(
+        "INSERT INTO role_permissions (role, permission, scope) VALUES ($1, $2, $3)
+         ON CONFLICT (role, permission) DO UPDATE SET scope = EXCLUDED.scope",
+        &[&role, &permission, &input.scope.as_str()],
+    )
+    .await
+    .map_err(internal_error)?;
+    state.policies.invalidate(&role);
+
+    audit
+        .record(
+            &state.pool,
+            Some(auth.user.id),
+            &auth.user.username,
+            "admin.permission.grant",
+            json!({ "role": role, "permission": permission, "scope": input.scope }),
+        )
+        .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn revoke_permission(
+    auth: Authorize<RoleManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path((role, permission)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let deleted = conn
+        .execute(
+            "DELETE FROM role_permissions WHERE role = $1 AND permission = $2",
+            &[&role, &permission],
+        )
+        .await
+        .map_err(internal_error)?;
+    if deleted == 0 {
+        return Err((StatusCode::NOT_FOUND, "permission not granted".to_string()));
+    }
+    state.policies.invalidate(&role);
+
+    audit
+        .record(
+            &state.pool,
+            Some(auth.user.id),
+            &auth.user.username,
+            "admin.permission.revoke",
+            json!({ "role": role, "permission": permission }),
+        )
+        .await;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::BytesRejection, FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use prost::Message;
+
+use crate::{error::json_error, AppState};
+
+/*
+ * Protocol Buffers 格式的请求和响应，Content-Type: application/x-protobuf，给吞吐量大的客户端使用
+ * 和 Json、MsgPack 这些不同，protobuf 没有字段名，只有字段编号，需要先在 proto 目录下的 .proto 文件里定义消息，
+ * 编译时由 build.rs 用 prost 生成 Rust 类型（在 pb 模块里），客户端用同一份 .proto 生成自己语言的代码。
+ * 增加字段时用新的编号，不要修改或者复用已有的编号，旧的客户端会忽略不认识的字段。
+ *   async fn handler(Protobuf(input): Protobuf<pb::Input>) -> Protobuf<pb::Posted>
+ * POST /protobuf 是 POST /json 的 protobuf 版本，消息见 proto/demo.proto。
+ * 解析失败时返回 ProtobufRejection：
+ * - MissingProtobufContentType  没有 Content-Type: application/x-protobuf（或者 application/protobuf），415
+ * - ProtobufError               不是合法的 protobuf 数据，400
+ * - BytesRejection              读取请求体失败，比如超出大小限制
+ */
+
+pub mod pb {
+    include!(concat!(env!("OUT_DIR"), "/demo.rs"));
+}
+
+const PROTOBUF: &str = "application/x-protobuf";
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/protobuf", post(accept_protobuf))
+}
+
+pub struct Protobuf<T>(pub T);
+
+#[derive(Debug)]
+pub enum ProtobufRejection {
+    MissingProtobufContentType,
+    ProtobufError(prost::DecodeError),
+    BytesRejection(BytesRejection),
+}
+
+impl fmt::Display for ProtobufRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtobufRejection::MissingProtobufContentType => {
+                f.write_str("expected request with `Content-Type: application/x-protobuf`")
+            }
+            ProtobufRejection::ProtobufError(err) => {
+                write!(f, "failed to decode the protobuf body: {}", err)
+            }
+            ProtobufRejection::BytesRejection(err) => err.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for ProtobufRejection {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ProtobufRejection::MissingProtobufContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ProtobufRejection::ProtobufError(_) => StatusCode::BAD_REQUEST,
+            ProtobufRejection::BytesRejection(err) => return err.into_response(),
+        };
+        json_error(status, &self.to_string())
+    }
+}
+
+/**
+ * protobuf 没有正式注册的 MIME 类型，application/x-protobuf 最常用，也接受 application/protobuf
+ */
+fn is_protobuf_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == PROTOBUF || mime == "application/protobuf"
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Protobuf<T>
+where
+    T: Message + Default,
+    S: Send + Sync,
+{
+    type Rejection = ProtobufRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_protobuf_content_type(req.headers()) {
+            return Err(ProtobufRejection::MissingProtobufContentType);
+        }
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(ProtobufRejection::BytesRejection)?;
+        T::decode(bytes)
+            .map(Protobuf)
+            .map_err(ProtobufRejection::ProtobufError)
+    }
+}
+
+impl<T> IntoResponse for Protobuf<T>
+where
+    T: Message,
+{
+    fn into_response(self) -> Response {
+        (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(PROTOBUF))],
+            self.0.encode_to_vec(),
+        )
+            .into_response()
+    }
+}
+
+/**
+ * POST protobuf 请求，和 accept_json 一样只是打印收到的参数
+ */
+async fn accept_protobuf(Protobuf(input): Protobuf<pb::Input>) -> Protobuf<pb::Posted> {
+    tracing::debug!("protobuf params {:?}", input);
+    Protobuf(pb::Posted {
+        message: "Protobuf posted".to_string(),
+    })
+}
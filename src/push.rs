@@ -0,0 +1,348 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use futures_util::StreamExt;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+use crate::{
+    auth::AuthUser,
+    config::PushConfig,
+    db::{repo, ConnectionPool},
+    error::internal_error,
+    notify::{Notification, Notifier},
+    telemetry, AppState,
+};
+
+/*
+ * Web Push：浏览器关闭页面之后也能收到通知
+ * 1. 页面注册 /assets/sw.js 这个 service worker（推送不受 service worker 作用域的限制）
+ * 2. 通过 GET /api/push/public-key 拿到 VAPID 公钥，调用 pushManager.subscribe 得到订阅
+ * 3. 把 subscription.toJSON() 提交到 POST /api/push/subscribe 保存，DELETE 同一个地址取消订阅
+ * PUSH_CHANNELS 里的频道收到 NOTIFY 时（见 notify 模块），后台任务给所有订阅发送一条推送，
+ * service worker 收到之后显示通知，点击打开对应的页面。
+ * 推送请求用 VAPID（RFC 8292）签名：ES256 签名的 JWT 放在 Authorization 请求头里，推送服务据此确认是我们发的。
+ * 推送不带内容，不需要按 RFC 8291 加密，同一个频道的推送用 Topic 请求头合并，浏览器离线期间只保留最新的一条。
+ * 推送服务返回 404/410 说明订阅已经失效（用户取消了授权、浏览器清除了数据），直接删除。
+ * 生成密钥：npx web-push generate-vapid-keys，把输出的公钥和私钥分别设置到 VAPID_PUBLIC_KEY 和 VAPID_PRIVATE_KEY。
+ */
+
+// 推送服务最多保存多久还没送达的推送
+const TTL_SECS: u64 = 24 * 60 * 60;
+// VAPID JWT 的有效期，规范要求不超过 24 小时
+const TOKEN_TTL_SECS: i64 = 12 * 60 * 60;
+// 同时发送的推送请求数
+const CONCURRENCY: usize = 16;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * P-256 私钥的 PKCS#8 编码：固定的前缀 + 32 字节私钥 + 固定的中间部分 + 65 字节公钥
+ * jsonwebtoken（ring）只接受 PKCS#8，web-push 工具生成的却是原始字节，这里按固定格式拼出来
+ */
+const PKCS8_PREFIX: &[u8] = &[
+    0x30, 0x81, 0x87, 0x02, 0x01, 0x00, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02,
+    0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x04, 0x6d, 0x30, 0x6b, 0x02,
+    0x01, 0x01, 0x04, 0x20,
+];
+const PKCS8_MIDDLE: &[u8] = &[0xa1, 0x44, 0x03, 0x42, 0x00];
+
+#[derive(Serialize)]
+struct VapidClaims<'a> {
+    aud: &'a str,
+    exp: i64,
+    sub: &'a str,
+}
+
+pub struct VapidKeys {
+    encoding: EncodingKey,
+    public_key: String,
+    subject: String,
+}
+
+impl VapidKeys {
+    /**
+     * 解析 base64url 编码的密钥，并试签一次，确认私钥和公钥是同一对
+     */
+    pub fn new(public_key: &str, private_key: &str, subject: &str) -> Result<Self, String> {
+        let public = URL_SAFE_NO_PAD
+            .decode(public_key.trim_end_matches('='))
+            .ok()
+            .filter(|bytes| bytes.len() == 65 && bytes[0] == 0x04)
+            .ok_or("VAPID_PUBLIC_KEY must be an uncompressed P-256 key encoded as base64url")?;
+        let private = URL_SAFE_NO_PAD
+            .decode(private_key.trim_end_matches('='))
+            .ok()
+            .filter(|bytes| bytes.len() == 32)
+            .ok_or("must be a 32 byte P-256 key encoded as base64url")?;
+        let der = [PKCS8_PREFIX, &private, PKCS8_MIDDLE, &public].concat();
+        let keys = VapidKeys {
+            encoding: EncodingKey::from_ec_der(&der),
+            public_key: URL_SAFE_NO_PAD.encode(&public),
+            subject: subject.to_string(),
+        };
+        keys.authorization("https://push.example.com")
+            .map_err(|_| "does not match VAPID_PUBLIC_KEY".to_string())?;
+        Ok(keys)
+    }
+
+    /**
+     * 推送请求的 Authorization 请求头，aud 是推送服务的 origin
+     */
+    fn authorization(&self, audience: &str) -> Result<String, jsonwebtoken::errors::Error> {
+        let claims = VapidClaims {
+            aud: audience,
+            exp: Utc::now().timestamp() + TOKEN_TTL_SECS,
+            sub: &self.subject,
+        };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::ES256), &claims, &self.encoding)?;
+        Ok(format!("vapid t={}, k={}", token, self.public_key))
+    }
+}
+
+#[derive(Clone)]
+pub struct WebPush {
+    // 没有配置 VAPID 密钥时为 None，订阅接口返回 404，也不会启动推送任务
+    keys: Option<Arc<VapidKeys>>,
+    channels: Arc<Vec<String>>,
+    client: reqwest::Client,
+}
+
+impl WebPush {
+    pub fn new(config: &PushConfig) -> Self {
+        // 密钥在加载配置时已经检查过
+        let keys = config
+            .vapid_public_key
+            .as_ref()
+            .zip(config.vapid_private_key.as_ref())
+            .and_then(|(public_key, private_key)| {
+                VapidKeys::new(public_key, private_key, &config.vapid_subject).ok()
+            });
+        WebPush {
+            keys: keys.map(Arc::new),
+            channels: Arc::new(config.channels.clone()),
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /**
+     * 启动后台任务，把 PUSH_CHANNELS 里的通知推送给所有订阅
+     */
+    pub fn start(&self, pool: ConnectionPool, notifier: &Notifier) {
+        if self.keys.is_none() || self.channels.is_empty() {
+            return;
+        }
+        let push = self.clone();
+        let mut receiver = notifier.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(notification) => {
+                        if push.channels.contains(&notification.channel) {
+                            push.fan_out(&pool, &notification).await;
+                        }
+                    }
+                    // 推送慢于通知的速度，被丢弃的通知不再补发，下一条通知照样会推送
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("web push skipped {} notifications", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn fan_out(&self, pool: &ConnectionPool, notification: &Notification) {
+        let subscriptions = match pool.get().await {
+            Ok(conn) => repo::list_push_subscriptions(&*conn).await,
+            Err(err) => {
+                tracing::warn!("web push: {}", err);
+                return;
+            }
+        };
+        let subscriptions = match subscriptions {
+            Ok(subscriptions) => subscriptions,
+            Err(err) => {
+                tracing::warn!("web push: {}", err);
+                return;
+            }
+        };
+        futures_util::stream::iter(subscriptions)
+            .for_each_concurrent(CONCURRENCY, |subscription| async move {
+                match self
+                    .send(&subscription.endpoint, &notification.channel)
+                    .await
+                {
+                    Ok(status) if status == StatusCode::NOT_FOUND || status == StatusCode::GONE => {
+                        tracing::info!("web push subscription {} expired", subscription.id);
+                        if let Ok(conn) = pool.get().await {
+                            if let Err(err) =
+                                repo::delete_expired_push_subscription(&*conn, subscription.id)
+                                    .await
+                            {
+                                tracing::warn!("web push: {}", err);
+                            }
+                        }
+                    }
+                    Ok(status) if !status.is_success() => {
+                        tracing::warn!(
+                            "web push to subscription {} failed with {}",
+                            subscription.id,
+                            status
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!(
+                            "web push to subscription {} failed: {}",
+                            subscription.id,
+                            err
+                        )
+                    }
+                }
+            })
+            .await;
+    }
+
+    /**
+     * 发送一条不带内容的推送，返回推送服务的状态码
+     */
+    async fn send(&self, endpoint: &str, topic: &str) -> Result<StatusCode, String> {
+        let keys = self.keys.as_ref().ok_or("web push is not configured")?;
+        let url = Url::parse(endpoint).map_err(|err| err.to_string())?;
+        let audience = url.origin().ascii_serialization();
+        let authorization = keys
+            .authorization(&audience)
+            .map_err(|err| err.to_string())?;
+        let res = self
+            .client
+            .post(url)
+            .headers(telemetry::trace_headers())
+            .header("authorization", authorization)
+            .header("ttl", TTL_SECS)
+            .header("topic", topic_header(topic))
+            .header("content-length", 0)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY))
+    }
+}
+
+/**
+ * Topic 只能包含 base64url 字符，最长 32 个字符，频道名里的其它字符换成 -
+ */
+fn topic_header(channel: &str) -> String {
+    channel
+        .chars()
+        .take(32)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/push/public-key", get(public_key))
+        .route("/api/push/subscribe", post(subscribe).delete(unsubscribe))
+}
+
+fn keys(state: &AppState) -> Result<&VapidKeys, (StatusCode, String)> {
+    state.push.keys.as_deref().ok_or((
+        StatusCode::NOT_FOUND,
+        "web push is not configured".to_string(),
+    ))
+}
+
+/**
+ * 浏览器调用 pushManager.subscribe 时需要的 applicationServerKey
+ */
+async fn public_key(State(state): State<AppState>) -> Result<Json<Value>, (StatusCode, String)> {
+    Ok(Json(json!({ "public_key": keys(&state)?.public_key })))
+}
+
+/**
+ * 和浏览器里 PushSubscription.toJSON() 的格式相同
+ */
+#[derive(Deserialize)]
+struct SubscriptionInput {
+    endpoint: String,
+    keys: SubscriptionKeys,
+}
+
+#[derive(Deserialize)]
+struct SubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+/**
+ * 推送服务的地址都是 https 的，其它地址不保存，避免服务端被用来访问内网地址
+ */
+fn check_endpoint(endpoint: &str) -> Result<(), (StatusCode, String)> {
+    match Url::parse(endpoint) {
+        Ok(url) if url.scheme() == "https" && url.host_str().is_some() => Ok(()),
+        _ => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "endpoint must be an https URL".to_string(),
+        )),
+    }
+}
+
+async fn subscribe(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Json(input): Json<SubscriptionInput>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    keys(&state)?;
+    check_endpoint(&input.endpoint)?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    repo::save_push_subscription(
+        &*conn,
+        user.id,
+        &input.endpoint,
+        &input.keys.p256dh,
+        &input.keys.auth,
+    )
+    .await
+    .map_err(internal_error)?;
+    Ok(StatusCode::CREATED)
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeInput {
+    endpoint: String,
+}
+
+async fn unsubscribe(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Json(input): Json<UnsubscribeInput>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let deleted = repo::delete_push_subscription(&*conn, user.id, &input.endpoint)
+        .await
+        .map_err(internal_error)?;
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "subscription not found".to_string()))
+    }
+}
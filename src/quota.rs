@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::to_bytes,
+    extract::{ConnectInfo, Request, State},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{client_policy::ClientPolicy, config::RateLimitConfig};
+
+/*
+ * 接口配额和重试提示
+ * - 每个客户端 IP 在一个固定时间窗口内有固定的请求配额（按 User-Agent 分类的客户端可以有自己的配额，见 client_policy），
+ *   接口响应都带上 IETF 草案定义的
+ *   RateLimit-Limit（配额）、RateLimit-Remaining（剩余次数）、RateLimit-Reset（距离配额重置的秒数）响应头，
+ *   配额用完后返回 429
+ * - 所有 429 和 503 响应都统一成 { "error", "retry_after", "backoff_hint" } 的 JSON 格式，并带上 Retry-After 响应头，
+ *   客户端不需要针对每个接口单独猜测应该等多久再重试
+ */
+
+// 计入配额的路径前缀，静态文件和示例页面不限制
+const API_PREFIXES: &[&str] = &["/api/", "/auth/", "/admin/"];
+
+// 没有更具体的信息时，建议客户端等待的秒数
+const DEFAULT_RETRY_AFTER_SECS: u64 = 1;
+// 指数退避时建议的最长等待时间
+const MAX_BACKOFF_MS: u64 = 60_000;
+
+// 改写响应体时最多读取的字节数，429/503 的响应体都只是一条错误信息
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/**
+ * 告诉客户端之后应该怎样重试
+ * - fixed: 等待 retry_after 秒后重试即可，例如配额到期重置
+ * - exponential: 从 initial_delay_ms 开始每次失败等待时间翻倍，最多 max_delay_ms，并加上随机抖动，
+ *   例如数据库临时不可用时，什么时候恢复是不确定的
+ */
+#[derive(Serialize)]
+struct BackoffHint {
+    strategy: &'static str,
+    initial_delay_ms: u64,
+    max_delay_ms: u64,
+    jitter: bool,
+}
+
+impl BackoffHint {
+    fn fixed(retry_after: u64) -> Self {
+        BackoffHint {
+            strategy: "fixed",
+            initial_delay_ms: retry_after * 1000,
+            max_delay_ms: retry_after * 1000,
+            jitter: false,
+        }
+    }
+
+    fn exponential(retry_after: u64) -> Self {
+        BackoffHint {
+            strategy: "exponential",
+            initial_delay_ms: retry_after * 1000,
+            max_delay_ms: MAX_BACKOFF_MS.max(retry_after * 1000),
+            jitter: true,
+        }
+    }
+}
+
+/**
+ * 按 IP 统计的固定窗口计数器，只保存在内存中（只考虑单实例部署）
+ */
+#[derive(Clone)]
+pub struct ApiQuota {
+    windows: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+    limit: u32,
+    window: Duration,
+}
+
+/**
+ * 一次请求之后的配额状态
+ */
+struct Usage {
+    allowed: bool,
+    limit: u32,
+    remaining: u32,
+    reset: u64,
+}
+
+impl Usage {
+    fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(self.reset));
+    }
+}
+
+impl ApiQuota {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        ApiQuota {
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            limit: config.requests,
+            window: config.window,
+        }
+    }
+
+    fn take(&self, ip: IpAddr, limit: u32) -> Usage {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if !windows.contains_key(&ip) {
+            // 新客户端进来时顺便清理已经过期的窗口，避免内存无限增长
+            windows.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+        let (start, count) = windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        let allowed = *count < limit;
+        if allowed {
+            *count += 1;
+        }
+        let left = self.window.saturating_sub(now.duration_since(*start));
+        Usage {
+            allowed,
+            limit,
+            remaining: limit.saturating_sub(*count),
+            // 向上取整，避免客户端在窗口重置之前就重试
+            reset: left.as_secs() + u64::from(left.subsec_nanos() > 0),
+        }
+    }
+}
+
+/**
+ * 配额中间件
+ */
+pub async fn quota_layer(State(quota): State<ApiQuota>, req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    if !API_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+        return next.run(req).await;
+    }
+    let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+        return next.run(req).await;
+    };
+
+    let limit = req
+        .extensions()
+        .get::<Arc<ClientPolicy>>()
+        .and_then(|policy| policy.rate_limit)
+        .unwrap_or(quota.limit);
+    let usage = quota.take(addr.ip(), limit);
+    let mut res = if usage.allowed {
+        next.run(req).await
+    } else {
+        let retry_after = usage.reset.max(1);
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after)],
+            Json(json!({
+                "error": "rate limit exceeded",
+                "retry_after": retry_after,
+                "backoff_hint": BackoffHint::fixed(retry_after),
+            })),
+        )
+            .into_response()
+    };
+    usage.apply(res.headers_mut());
+    res
+}
+
+/**
+ * 把各处返回的 429 和 503（登录限流、数据库不可用等）统一改写为带重试提示的 JSON
+ * 原来的错误信息保留在 error 字段里；已经带有 retry_after 的响应（配额用完）不再改写
+ */
+pub async fn retry_hints(res: Response) -> Response {
+    if !matches!(
+        res.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let bytes = to_bytes(body, MAX_ERROR_BODY).await.unwrap_or_default();
+    let mut body = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .filter(Value::is_object)
+        .unwrap_or_else(|| json!({ "error": String::from_utf8_lossy(&bytes).trim() }));
+
+    if body.get("retry_after").is_none() {
+        let retry_after = parts
+            .headers
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_RETRY_AFTER_SECS);
+        body["retry_after"] = json!(retry_after);
+        body["backoff_hint"] = json!(BackoffHint::exponential(retry_after));
+        parts
+            .headers
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+
+    // 响应体换成了 JSON，原来的长度和类型都不再适用
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::CONTENT_TYPE);
+    (parts, Json(body)).into_response()
+}
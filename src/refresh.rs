@@ -0,0 +1,209 @@
+use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    audit::Audit,
+    auth::{token_response, AuthUser},
+    db::ConnectionPool,
+    error::internal_error,
+    AppState,
+};
+
+/*
+ * Refresh token
+ * access token 有效期很短，客户端用长期有效的 refresh token 换取新的 access token。
+ * 每个 refresh token 只能使用一次，使用后立即作废并签发一个新的（轮换），同一次登录产生的所有 refresh token 属于同一个 family。
+ * 如果一个已经作废的 refresh token 被再次使用，说明它很可能已经泄露，此时吊销整个 family，强制重新登录。
+ * 数据库里只保存 token 的 SHA-256 摘要，即使数据库泄露也无法直接使用其中的 token。
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/refresh", post(refresh))
+        .route("/auth/logout", post(logout))
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/**
+ * 签发一个新的 refresh token，family 为 None 时表示一次新的登录
+ */
+pub async fn issue(
+    state: &AppState,
+    user_id: i64,
+    family: Option<Uuid>,
+) -> Result<String, (StatusCode, String)> {
+    let mut raw = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut raw);
+    let token = URL_SAFE_NO_PAD.encode(raw);
+    let family = family.unwrap_or_else(Uuid::new_v4);
+    let expires_at =
+        Utc::now() + chrono::Duration::from_std(state.config.auth.refresh_token_ttl).unwrap();
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    conn.execute(
+        "INSERT INTO refresh_tokens (token_hash, family_id, user_id, expires_at)
+         VALUES ($1, $2, $3, $4)",
+        &[&hash_token(&token), &family, &user_id, &expires_at],
+    )
+    .await
+    .map_err(internal_error)?;
+    Ok(token)
+}
+
+async fn revoke_family(pool: &ConnectionPool, family: Uuid) -> Result<(), (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    conn.execute(
+        "UPDATE refresh_tokens SET revoked_at = now() WHERE family_id = $1 AND revoked_at IS NULL",
+        &[&family],
+    )
+    .await
+    .map_err(internal_error)?;
+    Ok(())
+}
+
+/**
+ * 吊销 refresh token 所在的整个 family，返回 family 和用户，token 不存在时返回 None
+ * 退出登录和 /oauth/revoke 共用
+ */
+pub async fn revoke_token(
+    pool: &ConnectionPool,
+    token: &str,
+) -> Result<Option<(Uuid, i64, String)>, (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    let row = conn
+        .query_opt(
+            "SELECT r.family_id, u.id, u.username FROM refresh_tokens r
+             JOIN users u ON u.id = r.user_id
+             WHERE r.token_hash = $1",
+            &[&hash_token(token)],
+        )
+        .await
+        .map_err(internal_error)?;
+    drop(conn);
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let family: Uuid = row.get(0);
+    revoke_family(pool, family).await?;
+    Ok(Some((family, row.get(1), row.get(2))))
+}
+
+/**
+ * 清理已经过期的 refresh token，由调度器定期调用
+ */
+pub async fn prune(pool: &ConnectionPool) -> Result<u64, (StatusCode, String)> {
+    let conn = pool.get().await.map_err(internal_error)?;
+    conn.execute("DELETE FROM refresh_tokens WHERE expires_at < now()", &[])
+        .await
+        .map_err(internal_error)
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+async fn refresh(
+    State(state): State<AppState>,
+    audit: Audit,
+    Json(input): Json<RefreshRequest>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let invalid = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            "invalid refresh token".to_string(),
+        )
+    };
+    let token_hash = hash_token(&input.refresh_token);
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    // 作废当前 token 和检查它是否有效放在同一条 UPDATE 里，并发使用同一个 token 时只有一个请求能成功
+    let rotated = conn
+        .query_opt(
+            "UPDATE refresh_tokens r SET revoked_at = now() FROM users u
+             WHERE r.token_hash = $1 AND r.revoked_at IS NULL AND r.expires_at > now()
+               AND u.id = r.user_id AND u.deleted_at IS NULL
+             RETURNING r.family_id, u.id, u.username",
+            &[&token_hash],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let Some(row) = rotated else {
+        // token 存在但已经作废，说明被重复使用了，吊销整个 family
+        let reused = conn
+            .query_opt(
+                "SELECT r.family_id, u.id, u.username FROM refresh_tokens r
+                 JOIN users u ON u.id = r.user_id
+                 WHERE r.token_hash = $1 AND r.revoked_at IS NOT NULL",
+                &[&token_hash],
+            )
+            .await
+            .map_err(internal_error)?;
+        drop(conn);
+        if let Some(row) = reused {
+            let family: Uuid = row.get(0);
+            revoke_family(&state.pool, family).await?;
+            audit
+                .record(
+                    &state.pool,
+                    row.get(1),
+                    row.get(2),
+                    "auth.refresh_reuse",
+                    json!({ "family_id": family }),
+                )
+                .await;
+        }
+        return Err(invalid());
+    };
+    drop(conn);
+
+    let family: Uuid = row.get(0);
+    let user_id: i64 = row.get(1);
+    let username: String = row.get(2);
+    token_response(&state, user_id, &username, Some(family))
+        .await
+        .map(Json)
+}
+
+/**
+ * 退出登录：吊销 refresh token 所在的整个 family
+ * 如果同时携带了 access token，也一并吊销，让它立即失效而不是等到过期
+ */
+async fn logout(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    audit: Audit,
+    Json(input): Json<RefreshRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if let Some((family, user_id, username)) =
+        revoke_token(&state.pool, &input.refresh_token).await?
+    {
+        audit
+            .record(
+                &state.pool,
+                Some(user_id),
+                &username,
+                "auth.logout",
+                json!({ "family_id": family }),
+            )
+            .await;
+    }
+    if let Some(user) = user {
+        state.revocations.revoke(&state.pool, &user.claims).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
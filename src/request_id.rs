@@ -0,0 +1,107 @@
+use axum::{
+    body::{to_bytes, HttpBody},
+    extract::Request,
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::Value;
+use tracing::Span;
+
+use crate::telemetry;
+
+/*
+ * 请求 ID：每个请求都有一个 x-request-id
+ * - 请求里已经带了 x-request-id（比如前面的网关生成的）时沿用，否则生成一个 UUID（SetRequestIdLayer）
+ * - 响应带上同样的 x-request-id 响应头（PropagateRequestIdLayer）
+ * - TraceLayer 的 span 里记录 request_id，这个请求期间打印的日志都能按它查出来
+ * - 4xx/5xx 的错误响应体里也带上 request_id：JSON 对象加一个 request_id 字段，纯文本在末尾加一行，
+ *   用户反馈问题时把它发过来，就能在日志里找到对应的请求
+ */
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// 超过这个大小的错误响应体不再改写
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+fn request_id(headers: &axum::http::HeaderMap) -> &str {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+}
+
+/**
+ * TraceLayer 使用的 span，在默认的 method 和 uri 之外加上 request_id
+ * 开启了 OTLP 导出时（见 telemetry）再加上链路追踪系统使用的 span 名字和类型，并接上上游的链路
+ */
+pub fn make_span(req: &Request) -> Span {
+    // trace_id 在请求带有 traceparent 时由 telemetry::trace_context 填上
+    if !telemetry::enabled() {
+        return tracing::info_span!(
+            "request",
+            method = %req.method(),
+            uri = %req.uri(),
+            request_id = %request_id(req.headers()),
+            trace_id = tracing::field::Empty,
+        );
+    }
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        request_id = %request_id(req.headers()),
+        trace_id = tracing::field::Empty,
+        otel.name = %telemetry::span_name(req),
+        otel.kind = "server",
+    );
+    telemetry::link_parent(&span, req.headers());
+    span
+}
+
+/**
+ * 在错误响应体里加上请求 ID
+ */
+pub async fn annotate_errors(req: Request, next: Next) -> Response {
+    let id = request_id(req.headers()).to_string();
+    let res = next.run(req).await;
+    if id.is_empty() || !(res.status().is_client_error() || res.status().is_server_error()) {
+        return res;
+    }
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("text/plain");
+    let json = content_type.starts_with("application/json");
+    let small = res
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= MAX_ERROR_BODY as u64);
+    if !small || !json && !content_type.starts_with("text/plain") {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let bytes = to_bytes(body, MAX_ERROR_BODY).await.unwrap_or_default();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if json {
+        if let Ok(Value::Object(mut body)) = serde_json::from_slice::<Value>(&bytes) {
+            body.insert("request_id".to_string(), Value::String(id));
+            return (parts, Json(body)).into_response();
+        }
+        return (parts, bytes).into_response();
+    }
+    let mut text = String::from_utf8_lossy(&bytes).trim_end().to_string();
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(&format!("request id: {}", id));
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    (parts, text).into_response()
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderValue, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    config::RedirectConfig,
+    db::ConnectionPool,
+    error::internal_error,
+    permissions::{Authorize, ConfigRead},
+    AppState,
+};
+
+/*
+ * 重定向和改写规则：市场活动换了地址、旧的链接需要继续可用时，改规则就行，不需要改代码重新发布
+ * 规则有两个来源，先匹配文件里的，再按 priority 匹配 redirect_rules 表里的（见 V13 迁移），第一条匹配的规则生效：
+ * - REDIRECT_RULES_FILE 指定的文件，每行一条：pattern target [status]，# 开头的是注释，例子见下面
+ * - redirect_rules 表，列的含义和文件里相同
+ * pattern 里 :name 匹配一段路径，结尾的 * 匹配剩下的所有部分；target 里用 :name 和 :splat 引用匹配到的内容。
+ * status 是 301/302/303/307/308 时返回重定向，默认 301；200 表示改写，在服务内部换成 target 再路由，客户端看不到变化。
+ * target 没有查询参数时保留原请求的查询参数。
+ * 规则在路由之前执行（见 main 里的 serve），改写之后的路径同样经过鉴权、配额等所有中间件。
+ * 后台任务每隔 REDIRECT_RULES_RELOAD_SECS 秒重新读取文件和表，加载失败时继续使用上一次的规则。
+ * GET /admin/redirects?path= 查看当前的规则和某个路径会被如何处理，需要 config:read 权限。
+ */
+
+// 规则文件的例子：
+//   /blog/:slug   /todos/:slug      301
+//   /old/*        /new/:splat
+//   /promo        /todos?tag=promo  200
+
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Splat,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rule {
+    pub pattern: String,
+    pub target: String,
+    pub status: u16,
+    // file 或者 database
+    pub source: &'static str,
+    #[serde(skip)]
+    segments: Vec<Segment>,
+}
+
+/**
+ * 路径按 / 分段，忽略结尾的 /
+ */
+fn split(path: &str) -> Vec<&str> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let path = path.strip_suffix('/').unwrap_or(path);
+    if path.is_empty() {
+        Vec::new()
+    } else {
+        path.split('/').collect()
+    }
+}
+
+impl Rule {
+    fn new(pattern: &str, target: &str, status: u16, source: &'static str) -> Result<Self, String> {
+        if !pattern.starts_with('/') {
+            return Err(format!("pattern {:?} must start with /", pattern));
+        }
+        if target.is_empty() {
+            return Err(format!("pattern {:?}: target must not be empty", pattern));
+        }
+        if status == 200 && !target.starts_with('/') {
+            return Err(format!("rewrite target {:?} must start with /", target));
+        }
+        if status != 200 && !REDIRECT_STATUSES.contains(&status) {
+            return Err(format!(
+                "status {} is not one of 200, 301, 302, 303, 307, 308",
+                status
+            ));
+        }
+        let parts = split(pattern);
+        let mut segments = Vec::new();
+        for (index, part) in parts.iter().enumerate() {
+            let segment = match *part {
+                "*" if index == parts.len() - 1 => Segment::Splat,
+                "*" => return Err(format!("pattern {:?}: * must be at the end", pattern)),
+                part => match part.strip_prefix(':') {
+                    Some("") => return Err(format!("pattern {:?}: empty parameter name", pattern)),
+                    Some(name) => Segment::Param(name.to_string()),
+                    None => Segment::Literal(part.to_string()),
+                },
+            };
+            segments.push(segment);
+        }
+        Ok(Rule {
+            pattern: pattern.to_string(),
+            target: target.to_string(),
+            status,
+            source,
+            segments,
+        })
+    }
+
+    /**
+     * 匹配时返回 :name 和 :splat 对应的值
+     */
+    fn captures(&self, path: &str) -> Option<HashMap<&str, String>> {
+        let parts = split(path);
+        let mut captures = HashMap::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Splat => {
+                    captures.insert("splat", parts[index.min(parts.len())..].join("/"));
+                    return Some(captures);
+                }
+                Segment::Param(name) => {
+                    captures.insert(name.as_str(), parts.get(index)?.to_string());
+                }
+                Segment::Literal(literal) => {
+                    if parts.get(index) != Some(&literal.as_str()) {
+                        return None;
+                    }
+                }
+            }
+        }
+        (parts.len() == self.segments.len()).then_some(captures)
+    }
+
+    /**
+     * 把 target 里的 :name 换成匹配到的值，没有对应参数的（比如端口号 :8080）原样保留
+     */
+    fn expand(&self, captures: &HashMap<&str, String>) -> String {
+        let mut expanded = String::with_capacity(self.target.len());
+        let mut rest = self.target.as_str();
+        while let Some(start) = rest.find(':') {
+            expanded.push_str(&rest[..start]);
+            let name_len = rest[start + 1..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len() - start - 1);
+            let name = &rest[start + 1..start + 1 + name_len];
+            match captures.get(name) {
+                Some(value) => expanded.push_str(value),
+                None => expanded.push_str(&rest[start..start + 1 + name_len]),
+            }
+            rest = &rest[start + 1 + name_len..];
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+/**
+ * 规则文件，每行 pattern target [status]
+ */
+fn parse_file(content: &str) -> Result<Vec<Rule>, String> {
+    let mut rules = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let rule = match fields[..] {
+            [pattern, target] => Rule::new(pattern, target, 301, "file"),
+            [pattern, target, status] => status
+                .parse()
+                .map_err(|_| format!("status {:?} is not a number", status))
+                .and_then(|status| Rule::new(pattern, target, status, "file")),
+            _ => Err("expected: pattern target [status]".to_string()),
+        };
+        rules.push(rule.map_err(|err| format!("line {}: {}", number + 1, err))?);
+    }
+    Ok(rules)
+}
+
+/**
+ * 规则匹配的结果
+ */
+#[derive(Debug, Serialize)]
+pub struct Resolved {
+    pub pattern: String,
+    pub target: String,
+    pub status: u16,
+}
+
+/**
+ * 当前生效的规则，文件里的在前
+ */
+#[derive(Clone)]
+pub struct Rules {
+    rules: Arc<RwLock<Arc<Vec<Rule>>>>,
+    file: Option<PathBuf>,
+}
+
+impl Rules {
+    pub fn new(config: &RedirectConfig) -> Self {
+        Rules {
+            rules: Arc::default(),
+            file: config.file.as_ref().map(PathBuf::from),
+        }
+    }
+
+    /**
+     * 重新读取文件和表，任意一个失败时保留原来的规则
+     */
+    pub async fn reload(&self, pool: &ConnectionPool) -> Result<(), (StatusCode, String)> {
+        let mut rules = match &self.file {
+            Some(file) => {
+                let content = tokio::fs::read_to_string(file).await.map_err(|err| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("{}: {}", file.display(), err),
+                    )
+                })?;
+                parse_file(&content).map_err(|err| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("{}: {}", file.display(), err),
+                    )
+                })?
+            }
+            None => Vec::new(),
+        };
+
+        let conn = pool.get().await.map_err(internal_error)?;
+        let rows = conn
+            .query(
+                "SELECT pattern, target, status FROM redirect_rules ORDER BY priority, pattern",
+                &[],
+            )
+            .await
+            .map_err(internal_error)?;
+        for row in rows {
+            let pattern: String = row.get(0);
+            let target: String = row.get(1);
+            let status: i32 = row.get(2);
+            // 表上的约束已经保证了格式，这里只会因为 :name 之类的写法不对而失败，跳过这一条
+            match Rule::new(&pattern, &target, status as u16, "database") {
+                Ok(rule) => rules.push(rule),
+                Err(err) => tracing::warn!("skipped redirect rule: {}", err),
+            }
+        }
+        *self.rules.write().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    fn all(&self) -> Arc<Vec<Rule>> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /**
+     * 找到第一条匹配的规则，返回目标地址
+     */
+    pub fn resolve(&self, uri: &Uri) -> Option<Resolved> {
+        let rules = self.all();
+        let (rule, captures) = rules
+            .iter()
+            .find_map(|rule| Some((rule, rule.captures(uri.path())?)))?;
+        let mut target = rule.expand(&captures);
+        if let (Some(query), false) = (uri.query(), target.contains('?')) {
+            target.push('?');
+            target.push_str(query);
+        }
+        Some(Resolved {
+            pattern: rule.pattern.clone(),
+            target,
+            status: rule.status,
+        })
+    }
+}
+
+/**
+ * 规则中间件，需要包在 Router 外面，改写才能影响路由
+ */
+pub async fn apply(State(rules): State<Rules>, mut req: Request, next: Next) -> Response {
+    let Some(resolved) = rules.resolve(req.uri()) else {
+        return next.run(req).await;
+    };
+    if resolved.status != 200 {
+        let (Ok(status), Ok(location)) = (
+            StatusCode::from_u16(resolved.status),
+            HeaderValue::from_str(&resolved.target),
+        ) else {
+            return next.run(req).await;
+        };
+        return (status, [(header::LOCATION, location)]).into_response();
+    }
+    match resolved.target.parse::<Uri>() {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(err) => tracing::warn!(
+            "redirect rule {} produced an invalid path {:?}: {}",
+            resolved.pattern,
+            resolved.target,
+            err
+        ),
+    }
+    next.run(req).await
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/redirects", get(list))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    path: Option<String>,
+}
+
+async fn list(
+    _auth: Authorize<ConfigRead>,
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let rules = state.rules.all();
+    let mut body = json!({ "rules": *rules });
+    if let Some(path) = query.path {
+        let uri = path
+            .parse::<Uri>()
+            .map_err(|err| (StatusCode::BAD_REQUEST, format!("path: {}", err)))?;
+        body["path"] = json!(path);
+        body["resolved"] = json!(state.rules.resolve(&uri));
+    }
+    Ok(Json(body))
+}
@@ -0,0 +1,77 @@
+use axum::{routing::get, Json, Router};
+use serde_json::{json, Value};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::{
+    permissions::{Authorize, ServerManage},
+    AppState,
+};
+
+/*
+ * tokio 运行时的状态，排查请求变慢、任务堆积时使用
+ * GET /admin/runtime 需要 server:manage 权限，返回：
+ * - workers              工作线程数
+ * - alive_tasks          还没结束的任务数，一直增长说明有任务泄漏（比如忘记退出的后台循环）
+ * - global_queue_depth   全局队列里等待调度的任务数，持续不为 0 说明工作线程忙不过来
+ * - worker_stats         每个工作线程累计的忙碌时间和休眠次数
+ * 用 RUSTFLAGS="--cfg tokio_unstable" 编译时还会返回 tokio 的不稳定指标：
+ * 每个工作线程本地队列的长度、poll 次数、窃取次数，以及阻塞线程池的状态（spawned_tasks、blocking_threads 等）。
+ *
+ * 需要看到每个任务在做什么时用 tokio-console：
+ *   RUSTFLAGS="--cfg tokio_unstable" cargo run --features console
+ *   tokio-console   # 另开一个终端，默认连接 127.0.0.1:6669
+ * console 特性会在启动时注册 console-subscriber，它会记录每个任务的调度信息，有一定开销，只在开发环境使用。
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/runtime", get(runtime))
+}
+
+fn flavor(flavor: RuntimeFlavor) -> &'static str {
+    match flavor {
+        RuntimeFlavor::CurrentThread => "current_thread",
+        RuntimeFlavor::MultiThread => "multi_thread",
+        _ => "unknown",
+    }
+}
+
+async fn runtime(_auth: Authorize<ServerManage>) -> Json<Value> {
+    let handle = Handle::current();
+    let metrics = handle.metrics();
+    let worker_stats: Vec<Value> = (0..metrics.num_workers())
+        .map(|worker| {
+            #[allow(unused_mut)]
+            let mut stats = json!({
+                "worker": worker,
+                "busy_ms": metrics.worker_total_busy_duration(worker).as_millis() as u64,
+                "park_count": metrics.worker_park_count(worker),
+            });
+            #[cfg(tokio_unstable)]
+            {
+                stats["local_queue_depth"] = json!(metrics.worker_local_queue_depth(worker));
+                stats["poll_count"] = json!(metrics.worker_poll_count(worker));
+                stats["steal_count"] = json!(metrics.worker_steal_count(worker));
+            }
+            stats
+        })
+        .collect();
+
+    #[allow(unused_mut)]
+    let mut report = json!({
+        "flavor": flavor(handle.runtime_flavor()),
+        "workers": metrics.num_workers(),
+        "alive_tasks": metrics.num_alive_tasks(),
+        "global_queue_depth": metrics.global_queue_depth(),
+        "worker_stats": worker_stats,
+        "tokio_unstable": cfg!(tokio_unstable),
+        "console": cfg!(feature = "console"),
+    });
+    #[cfg(tokio_unstable)]
+    {
+        report["spawned_tasks"] = json!(metrics.spawned_tasks_count());
+        report["blocking_threads"] = json!(metrics.num_blocking_threads());
+        report["idle_blocking_threads"] = json!(metrics.num_idle_blocking_threads());
+        report["blocking_queue_depth"] = json!(metrics.blocking_queue_depth());
+    }
+    Json(report)
+}
@@ -0,0 +1,22 @@
+use std::{future::Future, time::Duration};
+
+/**
+ * 简单的定时任务调度器
+ * 每个任务都是一个独立的 tokio task，按固定间隔执行一次传入的闭包。
+ * tokio::time::interval 的第一次 tick 会立即完成，所以这里先跳过它，避免服务刚启动就执行一次任务。
+ */
+pub fn spawn_every<F, Fut>(name: &'static str, period: Duration, mut task: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            tracing::debug!("scheduler: run task {}", name);
+            task().await;
+        }
+    });
+}
@@ -0,0 +1,93 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{
+        repo::{self, SearchHit},
+        with_retry,
+    },
+    pagination::{Paginated, Pagination},
+    tenant::Tenant,
+    AppState,
+};
+
+/*
+ * 全文检索：GET /api/search?q=buy milk
+ * 基于 Postgres 的 tsvector：todos.search 是标题分词之后的生成列，上面建了 GIN 索引（见 V7 迁移），
+ * 查询用 websearch_to_tsquery 解析，支持 "短语"、or 和 -排除 这样的写法，结果按 ts_rank 相关度排序。
+ * 每条结果带一个 snippet，匹配的词用 <mark></mark> 包起来，其它内容已经转义过，可以直接插入页面。
+ * 和 /todos 页面一样不需要登录，只搜索当前租户的数据，已删除的记录不会出现在结果里。支持 ?page 和 ?per_page 分页。
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/api/search", get(search))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    kind: &'static str,
+    id: i64,
+    title: String,
+    done: bool,
+    rank: f32,
+    snippet: String,
+}
+
+/**
+ * 转义 HTML 之后把 ts_headline 的标记替换成 <mark> 标签
+ */
+fn highlight(snippet: &str) -> String {
+    snippet
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\u{2}', "<mark>")
+        .replace('\u{3}', "</mark>")
+}
+
+impl From<SearchHit> for SearchResult {
+    fn from(hit: SearchHit) -> Self {
+        SearchResult {
+            kind: "todo",
+            id: hit.id,
+            snippet: highlight(&hit.snippet),
+            title: hit.title,
+            done: hit.done,
+            rank: hit.rank,
+        }
+    }
+}
+
+async fn search(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<SearchQuery>,
+    pagination: Pagination,
+) -> Result<Json<Paginated<SearchResult>>, (StatusCode, String)> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "q must not be empty".to_string()));
+    }
+    let tenant = tenant.id();
+    let (hits, total) = with_retry(&state, |conn| async move {
+        let hits =
+            repo::search_todos(&*conn, tenant, q, pagination.limit(), pagination.offset()).await?;
+        let total = repo::count_search_todos(&*conn, tenant, q).await?;
+        Ok((hits, total))
+    })
+    .await?;
+    let items = hits.into_iter().map(SearchResult::from).collect();
+    Ok(Json(Paginated::new(items, total, pagination)))
+}
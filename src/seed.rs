@@ -0,0 +1,111 @@
+use std::{error::Error, fs, path::Path};
+
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{auth::hash_password, db::ConnectionPool, tenant::DEFAULT_TENANT};
+
+/*
+ * 本地开发和演示用的测试数据，通过 seed 子命令导入：rs-practice-axum seed [目录]，目录默认为 fixtures
+ * - users.json: 用户，用户名已存在时跳过，不会覆盖已有用户的密码
+ * - todos.json: 待办事项，导入到默认租户，已有相同标题时跳过
+ * - *.sql: 按文件名顺序执行的 SQL，需要自己写成可以重复执行的形式（比如 ON CONFLICT DO NOTHING）
+ * 全部数据在同一个事务里导入，任何一步出错都不会留下一半的数据；重复执行不会产生重复数据。
+ */
+
+type SeedError = Box<dyn Error + Send + Sync>;
+
+#[derive(Deserialize)]
+struct UserFixture {
+    username: String,
+    password: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default = "default_role")]
+    role: String,
+}
+
+fn default_role() -> String {
+    "user".to_string()
+}
+
+#[derive(Deserialize)]
+struct TodoFixture {
+    title: String,
+    #[serde(default)]
+    done: bool,
+}
+
+/**
+ * 读取 JSON 数组，文件不存在时返回 None
+ */
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<Vec<T>>, SeedError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)?;
+    let items =
+        serde_json::from_str(&content).map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(Some(items))
+}
+
+pub async fn run(pool: &ConnectionPool, dir: &Path) -> Result<(), SeedError> {
+    if !dir.is_dir() {
+        return Err(format!("fixture directory {} not found", dir.display()).into());
+    }
+
+    let mut conn = pool.get().await?;
+    let tx = conn.transaction().await?;
+
+    if let Some(users) = read_json::<UserFixture>(&dir.join("users.json"))? {
+        let mut inserted = 0;
+        for user in &users {
+            let password_hash = hash_password(&user.password).map_err(|(_, err)| err)?;
+            inserted += tx
+                .execute(
+                    "INSERT INTO users (username, email, role, password_hash) VALUES ($1, $2, $3, $4)
+                     ON CONFLICT (username) DO NOTHING",
+                    &[&user.username, &user.email, &user.role, &password_hash],
+                )
+                .await?;
+        }
+        tracing::info!(
+            "users: {} inserted, {} already present",
+            inserted,
+            users.len() as u64 - inserted
+        );
+    }
+
+    if let Some(todos) = read_json::<TodoFixture>(&dir.join("todos.json"))? {
+        let mut inserted = 0;
+        for todo in &todos {
+            inserted += tx
+                .execute(
+                    "INSERT INTO todos (tenant_id, title, done) SELECT $3, $1, $2
+                     WHERE NOT EXISTS (SELECT 1 FROM todos WHERE title = $1 AND tenant_id = $3)",
+                    &[&todo.title, &todo.done, &DEFAULT_TENANT],
+                )
+                .await?;
+        }
+        tracing::info!(
+            "todos: {} inserted, {} already present",
+            inserted,
+            todos.len() as u64 - inserted
+        );
+    }
+
+    let mut scripts: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sql"))
+        .collect();
+    scripts.sort();
+    for script in scripts {
+        let sql = fs::read_to_string(&script)?;
+        tx.batch_execute(&sql)
+            .await
+            .map_err(|err| format!("{}: {}", script.display(), err))?;
+        tracing::info!("executed {}", script.display());
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
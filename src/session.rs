@@ -0,0 +1,288 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+
+use crate::config::SessionConfig;
+
+pub const COOKIE_NAME: &str = "session";
+
+// cookie 的格式为 base64(key_id(4 字节) + nonce(12 字节) + 密文)
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+struct SessionKey {
+    id: u32,
+    cipher: Aes256Gcm,
+}
+
+struct KeyRing {
+    // 当前的轮换周期，等于 unix 时间 / rotation_interval
+    epoch: u64,
+    // 当前周期的密钥，新写入的 cookie 总是用它加密
+    current: u32,
+    keys: Vec<SessionKey>,
+}
+
+struct Inner {
+    master: Key<Aes256Gcm>,
+    interval: u64,
+    max_keys: u64,
+    ring: RwLock<Arc<KeyRing>>,
+    // PUBLIC_URL 是 https 时 cookie 加上 Secure，不会通过明文的 HTTP 发送
+    secure: bool,
+}
+
+/**
+ * 会话加密密钥环
+ * 同时持有多个密钥：加密时只用最新的密钥，解密时根据 cookie 里记录的 key_id 找到对应的密钥，
+ * 这样轮换密钥之后，用旧密钥加密的 cookie 依然可以读取，不会让所有用户被迫重新登录。
+ * 每个周期的密钥不是随机生成的，而是由 SESSION_KEY 和周期编号用 HMAC-SHA256 派生出来，key_id 就是周期编号：
+ * 所有实例只要 SESSION_KEY 和 SESSION_KEY_ROTATION_SECS 相同，在同一时刻用的就是同一组密钥，
+ * 重启之后也能算出之前的密钥，一个实例写的 cookie 在其他实例上照样能解密。
+ * 周期按时间计算，不依赖调度器什么时候触发，各个实例的时钟有几秒的误差时，
+ * 下一个周期的密钥也提前放在密钥环里，时钟快的实例写的 cookie 时钟慢的实例也能读。
+ * 没有设置 SESSION_KEY 时启动时随机生成，这时只适合单个实例，重启后所有会话失效。
+ */
+#[derive(Clone)]
+pub struct SessionKeys(Arc<Inner>);
+
+impl SessionKeys {
+    pub fn new(config: &SessionConfig, secure: bool) -> Self {
+        let master = match &config.key {
+            Some(encoded) => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(encoded)
+                    .expect("SESSION_KEY must be base64 encoded");
+                assert_eq!(bytes.len(), 32, "SESSION_KEY must be 32 bytes");
+                *Key::<Aes256Gcm>::from_slice(&bytes)
+            }
+            None => Aes256Gcm::generate_key(OsRng),
+        };
+        let interval = config.rotation_interval.as_secs().max(1);
+        let max_keys = config.max_keys.max(1) as u64;
+        let epoch = current_epoch(interval);
+        SessionKeys(Arc::new(Inner {
+            ring: RwLock::new(Arc::new(build_ring(&master, epoch, max_keys))),
+            master,
+            interval,
+            max_keys,
+            secure,
+        }))
+    }
+
+    /**
+     * 到了新的周期时换成新的密钥环，超出保留个数的旧密钥会被淘汰
+     * 由调度器定期调用，加解密时也会检查，泄露的密钥最多在 rotation_interval * max_keys 之后就彻底失效
+     */
+    pub fn rotate(&self) {
+        self.ring();
+    }
+
+    fn ring(&self) -> Arc<KeyRing> {
+        let epoch = current_epoch(self.0.interval);
+        let ring = self.0.ring.read().unwrap().clone();
+        if ring.epoch == epoch {
+            return ring;
+        }
+        let mut guard = self.0.ring.write().unwrap();
+        if guard.epoch != epoch {
+            *guard = Arc::new(build_ring(&self.0.master, epoch, self.0.max_keys));
+            tracing::info!("session key rotated, current key {}", guard.current);
+        }
+        guard.clone()
+    }
+
+    fn encrypt(&self, values: &Map<String, Value>) -> String {
+        let ring = self.ring();
+        let key = ring
+            .keys
+            .iter()
+            .find(|key| key.id == ring.current)
+            .expect("key ring contains the current key");
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(values).unwrap();
+        let ciphertext = key.cipher.encrypt(&nonce, plaintext.as_ref()).unwrap();
+
+        let mut raw = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        raw.extend_from_slice(&key.id.to_be_bytes());
+        raw.extend_from_slice(&nonce);
+        raw.extend_from_slice(&ciphertext);
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    /**
+     * 解密 cookie，返回会话数据以及它是否由旧密钥加密（需要重新加密）
+     * 密钥已被淘汰、密文被篡改等情况都视作没有会话
+     */
+    fn decrypt(&self, cookie: &str) -> Option<(Map<String, Value>, bool)> {
+        let raw = URL_SAFE_NO_PAD.decode(cookie).ok()?;
+        if raw.len() < KEY_ID_LEN + NONCE_LEN {
+            return None;
+        }
+        let (id, rest) = raw.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let id = u32::from_be_bytes(id.try_into().ok()?);
+
+        let ring = self.ring();
+        let key = ring.keys.iter().find(|key| key.id == id)?;
+        let plaintext = key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        let stale = ring.current != id;
+        Some((serde_json::from_slice(&plaintext).ok()?, stale))
+    }
+}
+
+fn current_epoch(interval: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now / interval
+}
+
+/**
+ * 派生某个周期的密钥，key_id 取周期编号的低 32 位
+ */
+fn derive_key(master: &Key<Aes256Gcm>, epoch: u64) -> SessionKey {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(master).expect("HMAC accepts any key length");
+    mac.update(b"session key ");
+    mac.update(&epoch.to_be_bytes());
+    let derived = mac.finalize().into_bytes();
+    SessionKey {
+        id: epoch as u32,
+        cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&derived)),
+    }
+}
+
+/**
+ * 当前周期和之前的 max_keys - 1 个周期，再加上下一个周期
+ */
+fn build_ring(master: &Key<Aes256Gcm>, epoch: u64, max_keys: u64) -> KeyRing {
+    let first = epoch.saturating_sub(max_keys - 1);
+    KeyRing {
+        epoch,
+        current: epoch as u32,
+        keys: (first..=epoch + 1)
+            .map(|epoch| derive_key(master, epoch))
+            .collect(),
+    }
+}
+
+struct SessionData {
+    values: Map<String, Value>,
+    changed: bool,
+}
+
+/**
+ * 会话提取器，handler 里直接在参数中声明 session: Session 就能读写会话数据
+ * 需要挂载 session_layer 中间件之后才能使用
+ */
+#[derive(Clone)]
+pub struct Session(Arc<Mutex<SessionData>>);
+
+impl Session {
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let data = self.0.lock().unwrap();
+        data.values
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) {
+        let mut data = self.0.lock().unwrap();
+        data.values
+            .insert(key.to_string(), serde_json::to_value(value).unwrap());
+        data.changed = true;
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut data = self.0.lock().unwrap();
+        if data.values.remove(key).is_some() {
+            data.changed = true;
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Session
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Session>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "session layer is not installed",
+        ))
+    }
+}
+
+/**
+ * 会话中间件
+ * 请求进来时解密 cookie 放到 request extensions 里，响应返回前如果会话被修改，
+ * 或者 cookie 是用旧密钥加密的，就用当前密钥重新加密并写回 Set-Cookie。
+ */
+pub async fn session_layer(
+    State(keys): State<SessionKeys>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let (values, stale) = cookie_value(req.headers(), COOKIE_NAME)
+        .and_then(|cookie| keys.decrypt(cookie))
+        .unwrap_or_default();
+
+    let session = Session(Arc::new(Mutex::new(SessionData {
+        values,
+        changed: false,
+    })));
+    req.extensions_mut().insert(session.clone());
+
+    let mut res = next.run(req).await;
+
+    let data = session.0.lock().unwrap();
+    if data.changed || stale {
+        let cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax{}",
+            COOKIE_NAME,
+            keys.encrypt(&data.values),
+            if keys.0.secure { "; Secure" } else { "" }
+        );
+        if let Ok(value) = HeaderValue::from_str(&cookie) {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    res
+}
+
+pub fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Query},
+    http::{request::Parts, StatusCode},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::config::UrlSigningConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// URL 路径里需要转义的字符，/ 保留，非 ASCII 字符总是转义
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/**
+ * 把解码后的路径转义成可以放进 URL 的形式
+ */
+pub fn encode_path(path: &str) -> String {
+    utf8_percent_encode(path, PATH).to_string()
+}
+
+/**
+ * 带过期时间的签名 URL
+ * 签名内容为 "path:expires"，使用 HMAC-SHA256 计算，拿到链接的人在过期前无需登录也能访问，
+ * 但无法修改 path 或延长 expires，否则签名校验不通过。
+ * 签名的 path 是解码之后的路径：同一个路径在 URL 里可以有不同的转义写法（比如 %7E 和 ~），
+ * 签名时和校验时都先解码，两边比较的是同一种形式，包含空格、中文的文件名也能通过校验。
+ */
+#[derive(Clone)]
+pub struct UrlSigner {
+    secret: Arc<Vec<u8>>,
+    pub default_ttl: Duration,
+}
+
+impl UrlSigner {
+    pub fn new(config: &UrlSigningConfig) -> Self {
+        let secret = match &config.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        UrlSigner {
+            secret: Arc::new(secret),
+            default_ttl: config.default_ttl,
+        }
+    }
+
+    /**
+     * 为 path（解码后的路径）生成一个在 ttl 之后过期的签名 URL，返回的 URL 里路径已经转义
+     */
+    pub fn sign(&self, path: &str, ttl: Duration) -> String {
+        let expires = now() + ttl.as_secs();
+        format!(
+            "{}?expires={}&signature={}",
+            encode_path(path),
+            expires,
+            self.signature(path, expires)
+        )
+    }
+
+    fn signature(&self, path: &str, expires: u64) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(path, expires).finalize().into_bytes())
+    }
+
+    fn mac(&self, path: &str, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        mac.update(format!("{}:{}", path, expires).as_bytes());
+        mac
+    }
+
+    fn verify(&self, path: &str, expires: u64, signature: &str) -> bool {
+        match URL_SAFE_NO_PAD.decode(signature) {
+            // verify_slice 是常量时间比较，避免通过响应时间猜出签名
+            Ok(signature) => self.mac(path, expires).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/**
+ * 签名 URL 提取器
+ * 放在 handler 参数里即可保证请求携带了有效且未过期的签名，否则直接返回 403
+ */
+pub struct SignedUrl;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SignedUrl
+where
+    UrlSigner: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let signer = UrlSigner::from_ref(state);
+        let Query(params) = Query::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| (StatusCode::FORBIDDEN, "invalid signed url"))?;
+
+        let expires = params
+            .get("expires")
+            .and_then(|expires| expires.parse::<u64>().ok())
+            .ok_or((StatusCode::FORBIDDEN, "missing expires"))?;
+        let signature = params
+            .get("signature")
+            .ok_or((StatusCode::FORBIDDEN, "missing signature"))?;
+
+        let path = percent_decode_str(parts.uri.path())
+            .decode_utf8()
+            .map_err(|_| (StatusCode::FORBIDDEN, "invalid signed url"))?;
+        if !signer.verify(&path, expires, signature) {
+            return Err((StatusCode::FORBIDDEN, "invalid signature"));
+        }
+        if expires < now() {
+            return Err((StatusCode::FORBIDDEN, "signed url expired"));
+        }
+        Ok(SignedUrl)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use quick_xml::{
+    events::{BytesDecl, BytesText, Event},
+    Writer,
+};
+
+use crate::{
+    config::Config,
+    db::{repo, with_retry},
+    delta::HTTP_DATE,
+    error::internal_error,
+    tenant::{Tenant, DEFAULT_TENANT},
+    AppState,
+};
+
+/*
+ * GET /sitemap.xml，告诉搜索引擎有哪些公开的页面，以及每个页面最后修改的时间（lastmod）
+ * - /                 首页，lastmod 是 index.html 文件的修改时间
+ * - /status           状态页，lastmod 是最近一次修改故障的时间
+ * - /todos            待办事项列表，lastmod 是最近修改的待办事项的时间
+ * - /todos/:id        每个没有删除的待办事项，最近修改的排在前面
+ * 需要登录的接口和管理页面不会出现在这里。
+ * 每个租户一份，链接用 PUBLIC_URL 拼成绝对地址；配置了 TENANT_BASE_DOMAIN 时其他租户用自己的子域名，
+ * 只通过 X-Tenant-Id 区分的租户没有自己的地址，搜索引擎只能看到默认租户。
+ * 租户没有单独的表，没有待办事项的租户（包括随便编出来的租户名）当作不存在，返回 404，不生成也不缓存。
+ * 一个 sitemap 最多 50000 个链接，待办事项超出的部分不列出，需要更多时应该拆成多个文件再用 sitemap 索引文件列出。
+ * 生成好的 sitemap 缓存在内存里：
+ * - todos、incidents 表有变化时数据库触发器发出失效消息（见 invalidation 和 V22 迁移），每个实例都丢掉缓存，下次请求时重新生成
+ * - 万一失效消息丢了，缓存最多保留 SITEMAP_TTL
+ * - 最多缓存 MAX_CACHED 个租户，满了之后先丢掉过期的，还不够再丢掉生成得最早的
+ * 响应带有 Last-Modified（所有 lastmod 里最新的一个），If-Modified-Since 不早于它时返回 304。
+ */
+
+const MAX_URLS: usize = 50_000;
+// 首页、状态页和待办事项列表
+const STATIC_URLS: usize = 3;
+const SITEMAP_TTL: Duration = Duration::from_secs(3600);
+const MAX_CACHED: usize = 1000;
+const SITEMAP_NS: &str = "http://www.sitemaps.org/schemas/sitemap/0.9";
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/sitemap.xml", get(sitemap))
+}
+
+struct Sitemap {
+    body: Bytes,
+    last_modified: DateTime<Utc>,
+    generated_at: Instant,
+}
+
+/**
+ * 按租户缓存的 sitemap
+ * epoch 每次失效时加一，生成期间缓存被清空过的话，这次生成的结果可能已经过时了，不放进缓存
+ */
+#[derive(Clone, Default)]
+pub struct Sitemaps {
+    cache: Arc<RwLock<HashMap<String, Arc<Sitemap>>>>,
+    epoch: Arc<AtomicU64>,
+}
+
+impl Sitemaps {
+    pub fn invalidate(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.cache.write().unwrap().clear();
+    }
+
+    fn get(&self, tenant: &str) -> Option<Arc<Sitemap>> {
+        self.cache
+            .read()
+            .unwrap()
+            .get(tenant)
+            .filter(|sitemap| sitemap.generated_at.elapsed() < SITEMAP_TTL)
+            .cloned()
+    }
+
+    fn insert(&self, tenant: &str, epoch: u64, sitemap: Arc<Sitemap>) {
+        let mut cache = self.cache.write().unwrap();
+        if self.epoch.load(Ordering::SeqCst) != epoch {
+            return;
+        }
+        if cache.len() >= MAX_CACHED && !cache.contains_key(tenant) {
+            cache.retain(|_, sitemap| sitemap.generated_at.elapsed() < SITEMAP_TTL);
+            if cache.len() >= MAX_CACHED {
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, sitemap)| sitemap.generated_at)
+                    .map(|(tenant, _)| tenant.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+        cache.insert(tenant.to_string(), sitemap);
+    }
+}
+
+/**
+ * 租户对外的地址，不以 / 结尾
+ */
+fn base_url(config: &Config, tenant: &Tenant) -> String {
+    let public_url = config.public_url.trim_end_matches('/');
+    match &config.tenant.base_domain {
+        Some(domain) if tenant.id() != DEFAULT_TENANT => {
+            let scheme = public_url.split("://").next().unwrap_or("https");
+            format!("{}://{}.{}", scheme, tenant.id(), domain)
+        }
+        _ => public_url.to_string(),
+    }
+}
+
+/**
+ * 按 ASSET_ROOTS 的顺序找到的第一个 index.html 的修改时间
+ */
+async fn index_modified(roots: &[String]) -> Option<DateTime<Utc>> {
+    for root in roots {
+        if let Ok(metadata) = tokio::fs::metadata(Path::new(root).join("index.html")).await {
+            return metadata.modified().ok().map(DateTime::<Utc>::from);
+        }
+    }
+    None
+}
+
+fn write_urlset(urls: &[(String, Option<DateTime<Utc>>)]) -> io::Result<Vec<u8>> {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("urlset")
+        .with_attribute(("xmlns", SITEMAP_NS))
+        .write_inner_content(|writer| {
+            for (loc, lastmod) in urls {
+                writer.create_element("url").write_inner_content(|writer| {
+                    writer
+                        .create_element("loc")
+                        .write_text_content(BytesText::new(loc))?;
+                    if let Some(lastmod) = lastmod {
+                        writer
+                            .create_element("lastmod")
+                            .write_text_content(BytesText::new(
+                                &lastmod.to_rfc3339_opts(SecondsFormat::Secs, true),
+                            ))?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+    Ok(writer.into_inner())
+}
+
+async fn generate(state: &AppState, tenant: &Tenant) -> Result<Sitemap, (StatusCode, String)> {
+    let (todos, incidents_updated) = with_retry(state, |conn| async move {
+        let todos =
+            repo::sitemap_todos(&*conn, tenant.id(), (MAX_URLS - STATIC_URLS) as i64).await?;
+        let incidents_updated = repo::incidents_updated_at(&*conn).await?;
+        Ok((todos, incidents_updated))
+    })
+    .await?;
+    if todos.is_empty() && tenant.id() != DEFAULT_TENANT {
+        return Err((StatusCode::NOT_FOUND, "unknown tenant".to_string()));
+    }
+
+    let base = base_url(&state.config, tenant);
+    let todos_updated = todos.first().map(|(_, updated_at)| *updated_at);
+    let mut urls = vec![
+        (
+            format!("{}/", base),
+            index_modified(&state.config.assets.roots).await,
+        ),
+        (format!("{}/status", base), incidents_updated),
+        (format!("{}/todos", base), todos_updated),
+    ];
+    urls.extend(
+        todos
+            .into_iter()
+            .map(|(id, updated_at)| (format!("{}/todos/{}", base, id), Some(updated_at))),
+    );
+    let last_modified = urls
+        .iter()
+        .filter_map(|(_, lastmod)| *lastmod)
+        .max()
+        .unwrap_or(DateTime::UNIX_EPOCH)
+        .trunc_subsecs(0);
+    let body = write_urlset(&urls).map_err(internal_error)?;
+    Ok(Sitemap {
+        body: body.into(),
+        last_modified,
+        generated_at: Instant::now(),
+    })
+}
+
+async fn sitemap(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let sitemap = match state.sitemaps.get(tenant.id()) {
+        Some(sitemap) => sitemap,
+        None => {
+            let epoch = state.sitemaps.epoch.load(Ordering::SeqCst);
+            let sitemap = Arc::new(generate(&state, &tenant).await?);
+            state.sitemaps.insert(tenant.id(), epoch, sitemap.clone());
+            sitemap
+        }
+    };
+
+    let not_modified = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .is_some_and(|since| since >= sitemap.last_modified);
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/xml; charset=utf-8"),
+        ),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=300"),
+        ),
+        // 不同租户的 sitemap 不一样，共享缓存要按租户分开
+        (header::VARY, HeaderValue::from_static("x-tenant-id")),
+        (
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&sitemap.last_modified.format(HTTP_DATE).to_string())
+                .expect("HTTP date is a valid header value"),
+        ),
+    ];
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+    Ok((headers, sitemap.body.clone()).into_response())
+}
@@ -0,0 +1,524 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    audit::Audit,
+    config::StatusConfig,
+    db::{
+        repo::{self, Incident, StatusSample},
+        ConnectionPool,
+    },
+    error::internal_error,
+    health,
+    permissions::{Authorize, ServerManage},
+    AppState,
+};
+
+/*
+ * 公开的状态页，不需要登录，用户不用再来问服务是不是挂了
+ * - GET /status       HTML 页面：整体状态、各组件的状态、进行中的故障、最近 14 天的故障记录、
+ *                     每个组件最近 STATUS_HISTORY_DAYS 天的可用率条
+ * - GET /api/status   同样的内容，JSON 格式
+ * 组件的状态来自和 /readyz 相同的检查（见 health）：
+ * - api        能返回这个页面就是正常的
+ * - database   主库能执行 SELECT 1
+ * - replicas   只读副本都能连上（配置了副本时才显示），有副本连不上时读请求会退回主库，所以只算性能下降
+ * 进行中的故障会把它影响的组件标记为 degraded（minor、major）或者 outage（critical）。
+ * 后台任务每隔 STATUS_SAMPLE_SECS 秒检查一次，结果按天累计到 status_samples 表里，
+ * 同时刷新内存里的快照；页面只读快照，数据库挂掉的时候状态页照样能打开，故障列表是挂掉之前的那一份。
+ * 故障由管理员维护，需要 server:manage 权限：
+ * - POST /admin/incidents              { "title", "impact", "components": [], "message" } 创建
+ * - POST /admin/incidents/:id/updates  { "status", "message" } 更新进展，status 为 resolved 时结束
+ */
+
+const IMPACTS: &[&str] = &["minor", "major", "critical"];
+const INCIDENT_STATUSES: &[&str] = &["investigating", "identified", "monitoring", "resolved"];
+// 故障记录显示多少天
+const INCIDENT_HISTORY_DAYS: u64 = 14;
+// 一天里超过这个比例的检查失败，可用率条显示为故障
+const OUTAGE_RATIO: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Health {
+    Operational,
+    Degraded,
+    Outage,
+}
+
+impl Health {
+    fn as_str(self) -> &'static str {
+        match self {
+            Health::Operational => "operational",
+            Health::Degraded => "degraded",
+            Health::Outage => "outage",
+        }
+    }
+
+    fn worst(self, other: Health) -> Health {
+        if other > self {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/**
+ * 可用率条的一天，没有检查记录时 uptime 为 None
+ */
+#[derive(Debug, Clone, Serialize)]
+struct Day {
+    day: NaiveDate,
+    uptime: Option<f64>,
+    status: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Component {
+    name: &'static str,
+    description: &'static str,
+    status: Health,
+    // 整个时间段的可用率（百分比）
+    uptime: Option<f64>,
+    days: Vec<Day>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Snapshot {
+    status: Health,
+    checked_at: Option<DateTime<Utc>>,
+    components: Vec<Component>,
+    incidents: Vec<Incident>,
+    past_incidents: Vec<Incident>,
+}
+
+/**
+ * 状态页的快照，由后台任务定期刷新
+ */
+#[derive(Clone)]
+pub struct StatusPage {
+    snapshot: Arc<RwLock<Arc<Snapshot>>>,
+    history_days: u32,
+}
+
+/**
+ * 一次检查的结果
+ */
+struct Check {
+    name: &'static str,
+    description: &'static str,
+    health: Health,
+}
+
+async fn check_components(state: &AppState) -> Vec<Check> {
+    let mut checks = vec![Check {
+        name: "api",
+        description: "Web pages and the REST API",
+        health: Health::Operational,
+    }];
+    let database = match health::ping(&state.pool).await {
+        Ok(()) => Health::Operational,
+        Err(err) => {
+            tracing::warn!("status check: database is down: {}", err);
+            Health::Outage
+        }
+    };
+    checks.push(Check {
+        name: "database",
+        description: "Saving and loading data",
+        health: database,
+    });
+    if !state.replicas.all().is_empty() {
+        let mut health = Health::Operational;
+        for replica in state.replicas.all() {
+            if health::ping(replica).await.is_err() {
+                health = Health::Degraded;
+            }
+        }
+        checks.push(Check {
+            name: "replicas",
+            description: "Read replicas, reads fall back to the primary when they are down",
+            health,
+        });
+    }
+    checks
+}
+
+fn day_status(sample: &StatusSample) -> (f64, &'static str) {
+    let uptime = (sample.checks - sample.outages) as f64 / sample.checks.max(1) as f64;
+    let status = if sample.outages as f64 > sample.checks as f64 * OUTAGE_RATIO {
+        "outage"
+    } else if sample.outages > 0 || sample.degraded > 0 {
+        "degraded"
+    } else {
+        "operational"
+    };
+    (uptime * 100.0, status)
+}
+
+impl StatusPage {
+    pub fn new(config: &StatusConfig) -> Self {
+        StatusPage {
+            snapshot: Arc::new(RwLock::new(Arc::new(Snapshot {
+                status: Health::Operational,
+                checked_at: None,
+                components: Vec::new(),
+                incidents: Vec::new(),
+                past_incidents: Vec::new(),
+            }))),
+            history_days: config.history_days,
+        }
+    }
+
+    fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /**
+     * 检查各组件，然后重新生成快照；数据库访问不了时沿用上一次的故障列表和可用率
+     * record 为 true 时把检查结果计入可用率，只有定期检查才记录，管理员修改故障时的刷新不算，免得拉高次数
+     */
+    pub async fn refresh(
+        &self,
+        state: &AppState,
+        record: bool,
+    ) -> Result<(), (StatusCode, String)> {
+        let checks = check_components(state).await;
+        let database_up = checks
+            .iter()
+            .any(|check| check.name == "database" && check.health == Health::Operational);
+        let previous = self.snapshot();
+        let today = Utc::now().date_naive();
+        let first_day = today
+            .checked_sub_days(Days::new(u64::from(self.history_days) - 1))
+            .unwrap_or(today);
+
+        let (incidents, samples) = if database_up {
+            self.load(&state.pool, record.then_some(&checks[..]), first_day)
+                .await?
+        } else {
+            let incidents = previous
+                .incidents
+                .iter()
+                .chain(previous.past_incidents.iter())
+                .cloned()
+                .collect();
+            (incidents, Vec::new())
+        };
+
+        let (active, past): (Vec<Incident>, Vec<Incident>) = incidents
+            .into_iter()
+            .partition(|incident| incident.resolved_at.is_none());
+        let mut samples_by_component: HashMap<&str, Vec<&StatusSample>> = HashMap::new();
+        for sample in &samples {
+            samples_by_component
+                .entry(sample.component.as_str())
+                .or_default()
+                .push(sample);
+        }
+
+        let mut components = Vec::new();
+        for check in &checks {
+            // 进行中的故障影响到的组件
+            let health = active
+                .iter()
+                .filter(|incident| incident.components.iter().any(|name| name == check.name))
+                .fold(check.health, |health, incident| {
+                    health.worst(if incident.impact == "critical" {
+                        Health::Outage
+                    } else {
+                        Health::Degraded
+                    })
+                });
+            let (uptime, days) = match samples_by_component.get(check.name) {
+                Some(samples) => {
+                    let days = first_day
+                        .iter_days()
+                        .take_while(|day| *day <= today)
+                        .map(
+                            |day| match samples.iter().find(|sample| sample.day == day) {
+                                Some(sample) => {
+                                    let (uptime, status) = day_status(sample);
+                                    Day {
+                                        day,
+                                        uptime: Some(uptime),
+                                        status,
+                                    }
+                                }
+                                None => Day {
+                                    day,
+                                    uptime: None,
+                                    status: "unknown",
+                                },
+                            },
+                        )
+                        .collect();
+                    let checks: i64 = samples.iter().map(|sample| sample.checks).sum();
+                    let outages: i64 = samples.iter().map(|sample| sample.outages).sum();
+                    let uptime =
+                        (checks > 0).then(|| (checks - outages) as f64 / checks as f64 * 100.0);
+                    (uptime, days)
+                }
+                // 数据库访问不了的时候沿用上一次的可用率
+                None => previous
+                    .components
+                    .iter()
+                    .find(|component| component.name == check.name)
+                    .map(|component| (component.uptime, component.days.clone()))
+                    .unwrap_or_default(),
+            };
+            components.push(Component {
+                name: check.name,
+                description: check.description,
+                status: health,
+                uptime,
+                days,
+            });
+        }
+
+        let status = components
+            .iter()
+            .fold(Health::Operational, |status, component| {
+                status.worst(component.status)
+            });
+        *self.snapshot.write().unwrap() = Arc::new(Snapshot {
+            status,
+            checked_at: Some(Utc::now()),
+            components,
+            incidents: active,
+            past_incidents: past,
+        });
+        Ok(())
+    }
+
+    /**
+     * 记录这次检查的结果（checks 不为 None 时），读出故障列表和可用率，顺便删除超出时间范围的记录
+     */
+    async fn load(
+        &self,
+        pool: &ConnectionPool,
+        checks: Option<&[Check]>,
+        first_day: NaiveDate,
+    ) -> Result<(Vec<Incident>, Vec<StatusSample>), (StatusCode, String)> {
+        let conn = pool.get().await.map_err(internal_error)?;
+        for check in checks.unwrap_or_default() {
+            repo::record_status_sample(
+                &*conn,
+                check.name,
+                check.health == Health::Degraded,
+                check.health == Health::Outage,
+            )
+            .await
+            .map_err(internal_error)?;
+        }
+        repo::prune_status_samples(&*conn, first_day)
+            .await
+            .map_err(internal_error)?;
+        let since = Utc::now() - Days::new(INCIDENT_HISTORY_DAYS);
+        let incidents = repo::list_incidents(&*conn, since)
+            .await
+            .map_err(internal_error)?;
+        let samples = repo::list_status_samples(&*conn, first_day)
+            .await
+            .map_err(internal_error)?;
+        Ok((incidents, samples))
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/status", get(page))
+        .route("/api/status", get(api))
+        .route("/admin/incidents", post(create_incident))
+        .route("/admin/incidents/:id/updates", post(update_incident))
+}
+
+#[derive(Template)]
+#[template(path = "status.html")]
+struct StatusTemplate {
+    snapshot: Arc<Snapshot>,
+}
+
+impl StatusTemplate {
+    fn headline(&self) -> &'static str {
+        match self.snapshot.status {
+            Health::Operational => "All systems operational",
+            Health::Degraded => "Some systems are degraded",
+            Health::Outage => "Major outage",
+        }
+    }
+}
+
+/**
+ * 快照每隔一段时间才刷新，允许浏览器和 CDN 缓存一小会儿
+ */
+fn cacheable(res: impl IntoResponse) -> Response {
+    (
+        [(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=30"),
+        )],
+        res,
+    )
+        .into_response()
+}
+
+async fn page(State(state): State<AppState>) -> Result<Response, (StatusCode, String)> {
+    let page = StatusTemplate {
+        snapshot: state.status.snapshot(),
+    }
+    .render()
+    .map_err(internal_error)?;
+    Ok(cacheable(Html(page)))
+}
+
+async fn api(State(state): State<AppState>) -> Response {
+    let snapshot = state.status.snapshot();
+    cacheable(Json(&*snapshot))
+}
+
+#[derive(Deserialize)]
+struct NewIncident {
+    title: String,
+    impact: String,
+    #[serde(default)]
+    components: Vec<String>,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct NewUpdate {
+    status: String,
+    message: String,
+}
+
+fn bad_request(message: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message)
+}
+
+/**
+ * 修改故障之后马上刷新本实例的快照，其他实例等到下一次定期刷新
+ */
+async fn respond_with_incident(
+    state: &AppState,
+    status: StatusCode,
+    id: i64,
+) -> Result<Response, (StatusCode, String)> {
+    if let Err((_, err)) = state.status.refresh(state, false).await {
+        tracing::warn!("refresh status page failed: {}", err);
+    }
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let incident = repo::find_incident(&*conn, id)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, "incident not found".to_string()))?;
+    Ok((status, Json(incident)).into_response())
+}
+
+async fn create_incident(
+    Authorize { user: admin, .. }: Authorize<ServerManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Json(input): Json<NewIncident>,
+) -> Result<Response, (StatusCode, String)> {
+    if input.title.trim().is_empty() || input.message.trim().is_empty() {
+        return Err(bad_request("title and message are required".to_string()));
+    }
+    if !IMPACTS.contains(&input.impact.as_str()) {
+        return Err(bad_request(format!(
+            "impact must be one of {}",
+            IMPACTS.join(", ")
+        )));
+    }
+    let known: Vec<&str> = state
+        .status
+        .snapshot()
+        .components
+        .iter()
+        .map(|component| component.name)
+        .collect();
+    if let Some(unknown) = input
+        .components
+        .iter()
+        .find(|name| !known.contains(&name.as_str()))
+    {
+        return Err(bad_request(format!(
+            "unknown component {:?}, expected one of {}",
+            unknown,
+            known.join(", ")
+        )));
+    }
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let id = repo::create_incident(
+        &*conn,
+        input.title.trim(),
+        &input.impact,
+        &input.components,
+        input.message.trim(),
+    )
+    .await
+    .map_err(internal_error)?;
+    drop(conn);
+    audit
+        .record(
+            &state.pool,
+            Some(admin.id),
+            &admin.username,
+            "admin.incident.create",
+            json!({ "id": id, "title": input.title, "impact": input.impact, "components": input.components }),
+        )
+        .await;
+    respond_with_incident(&state, StatusCode::CREATED, id).await
+}
+
+async fn update_incident(
+    Authorize { user: admin, .. }: Authorize<ServerManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path(id): Path<i64>,
+    Json(input): Json<NewUpdate>,
+) -> Result<Response, (StatusCode, String)> {
+    if input.message.trim().is_empty() {
+        return Err(bad_request("message is required".to_string()));
+    }
+    if !INCIDENT_STATUSES.contains(&input.status.as_str()) {
+        return Err(bad_request(format!(
+            "status must be one of {}",
+            INCIDENT_STATUSES.join(", ")
+        )));
+    }
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let found = repo::add_incident_update(&*conn, id, &input.status, input.message.trim())
+        .await
+        .map_err(internal_error)?;
+    drop(conn);
+    if !found {
+        return Err((StatusCode::NOT_FOUND, "incident not found".to_string()));
+    }
+    audit
+        .record(
+            &state.pool,
+            Some(admin.id),
+            &admin.username,
+            "admin.incident.update",
+            json!({ "id": id, "status": input.status }),
+        )
+        .await;
+    respond_with_incident(&state, StatusCode::OK, id).await
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+
+use crate::{config::StorageConfig, telemetry};
+
+/*
+ * S3 兼容的对象存储（AWS S3、MinIO、Cloudflare R2 等），现在只用来保存归档的审计日志（见 archive）
+ * - S3_ENDPOINT           服务地址，按 path-style 访问：{S3_ENDPOINT}/{S3_BUCKET}/{key}
+ * - S3_REGION             签名用的区域，MinIO 一般是 us-east-1
+ * - S3_BUCKET             存储桶，不设置时关闭
+ * - S3_ACCESS_KEY_ID / S3_SECRET_ACCESS_KEY  访问密钥
+ * 只实现了用到的 PUT 和 GET，请求按 AWS Signature Version 4 签名。
+ * 上传时 x-amz-content-sha256 是请求体的 SHA-256，服务端收到的内容和它对不上时会拒绝这次上传。
+ */
+
+type HmacSha256 = Hmac<Sha256>;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct ObjectStore {
+    client: reqwest::Client,
+    endpoint: Url,
+    region: String,
+    bucket: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+/**
+ * 十六进制的 SHA-256
+ */
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/**
+ * 按 SigV4 的规则编码路径：除了 A-Z a-z 0-9 - _ . ~ 和分隔用的 / 都转成 %XX
+ */
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl ObjectStore {
+    /**
+     * 没有设置 S3_BUCKET 时返回 None
+     */
+    pub fn new(config: &StorageConfig) -> Option<Self> {
+        // 格式和密钥在加载配置时已经检查过
+        Some(ObjectStore {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            endpoint: config.endpoint.parse().ok()?,
+            region: config.region.clone(),
+            bucket: config.bucket.clone()?,
+            access_key_id: config.access_key_id.clone()?,
+            secret_access_key: config.secret_access_key.clone()?,
+        })
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /**
+     * 生成带签名的请求，payload_hash 是请求体的 SHA-256
+     */
+    fn request(&self, method: Method, key: &str, payload_hash: &str) -> reqwest::RequestBuilder {
+        let path = encode_path(&format!(
+            "{}/{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            self.bucket,
+            key
+        ));
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        // reqwest 发出的 Host 不带默认端口，签名时要保持一致
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method.as_str(),
+            path,
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let key = [&date, &self.region, "s3", "aws4_request"].iter().fold(
+            format!("AWS4{}", self.secret_access_key).into_bytes(),
+            |key, part| hmac(&key, part),
+        );
+        let signature = hex(&hmac(&key, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+
+        self.client
+            .request(method, url)
+            .headers(telemetry::trace_headers())
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+    }
+
+    /**
+     * 上传一个对象，已经存在时覆盖
+     */
+    pub async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), String> {
+        let res = self
+            .request(Method::PUT, key, &sha256_hex(&body))
+            .header("content-type", content_type)
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| format!("PUT {}: {}", key, err))?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(format!("PUT {}: {} {}", key, status, body));
+        }
+        Ok(())
+    }
+
+    /**
+     * 下载一个对象
+     */
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        let res = self
+            .request(Method::GET, key, &sha256_hex(b""))
+            .send()
+            .await
+            .map_err(|err| format!("GET {}: {}", key, err))?;
+        if !res.status().is_success() {
+            return Err(format!("GET {}: {}", key, res.status()));
+        }
+        res.bytes()
+            .await
+            .map(|bytes| bytes.to_vec())
+            .map_err(|err| format!("GET {}: {}", key, err))
+    }
+}
@@ -0,0 +1,357 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit::Audit,
+    auth::AuthUser,
+    db::{
+        repo::{self, Todo},
+        with_retry,
+    },
+    error::internal_error,
+    pagination::Pagination,
+    permissions::{Authorize, TodoEdit},
+    tenant::Tenant,
+    AppState,
+};
+
+/*
+ * 离线同步：移动端离线时在本地修改数据，联网后批量提交，再拉取服务端的变化
+ * - GET  /api/sync/todos?since=0&per_page=100  变化列表（change feed）
+ *   返回 seq 大于 since 的记录（包括已删除的，deleted_at 不为空），按 seq 排序，
+ *   客户端保存返回的 next_since，下次从这里继续；has_more 为 true 时说明还没拉完
+ * - POST /api/sync/todos                       批量提交离线期间的修改
+ *   { "conflict": "lww", "mutations": [{ "op": "upsert", "id": 1, "base_version": 3, "title": "...", "updated_at": "..." }] }
+ *   - op 为 upsert 且没有 id 时是新建，需要带上客户端生成的 client_id，重复提交不会创建两条
+ *   - base_version 是客户端修改时看到的版本号，和服务端一致时直接写入，否则交给冲突处理
+ *   - updated_at 是客户端修改的时间，写入时不会晚于服务端的当前时间，避免时钟快的设备永远获胜
+ *   整批修改在一个事务里执行，每条修改返回 applied / conflict / not_found / invalid / forbidden，以及服务端的最新数据
+ *   和 todos 的修改接口一样需要 todo:edit 权限，修改已有的记录时按作用范围检查创建者，
+ *   超出范围的那条修改返回 forbidden，其它修改照常执行；新建的记录以当前用户为创建者
+ * 冲突处理（conflict 参数）：
+ * - lww：默认，updated_at 更新的一方获胜（last-write-wins）
+ * - server_wins：保留服务端的数据，客户端用返回的记录覆盖本地
+ * - merge：todos 自定义的合并规则，见 Merge
+ * 拉取变化只需要登录，只同步当前租户的数据，已删除的记录不能再修改。
+ * seq 在语句执行时分配，并发的事务提交顺序和 seq 顺序可能不同，刚提交的变化偶尔会在下一轮同步才拉到。
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/api/sync/todos", get(changes).post(push))
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    #[serde(default)]
+    since: i64,
+}
+
+async fn changes(
+    _user: AuthUser,
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<ChangesQuery>,
+    pagination: Pagination,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let tenant = tenant.id();
+    let limit = pagination.limit();
+    let mut changes = with_retry(&state, |conn| async move {
+        repo::todo_changes(&*conn, tenant, query.since, limit + 1).await
+    })
+    .await?;
+    let has_more = changes.len() as i64 > limit;
+    changes.truncate(limit as usize);
+    let next_since = changes.last().map_or(query.since, |todo| todo.seq);
+    Ok(Json(json!({
+        "changes": changes,
+        "next_since": next_since,
+        "has_more": has_more,
+    })))
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    Upsert,
+    Delete,
+}
+
+#[derive(Deserialize)]
+struct Mutation {
+    // 客户端用来对应返回结果的 id，新建时同时用于去重
+    client_id: Option<String>,
+    op: Op,
+    id: Option<i64>,
+    base_version: Option<i32>,
+    title: Option<String>,
+    done: Option<bool>,
+    updated_at: DateTime<Utc>,
+}
+
+/**
+ * 客户端提交的修改，没有提交的字段保留服务端的值
+ */
+pub struct Change<'a> {
+    pub title: Option<&'a str>,
+    pub done: Option<bool>,
+    pub deleted: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+/**
+ * 最终写入的值
+ */
+pub struct Resolved {
+    pub title: String,
+    pub done: bool,
+    pub deleted: bool,
+}
+
+impl Change<'_> {
+    /**
+     * 没有冲突时，把客户端的修改应用到服务端的记录上
+     */
+    fn apply(&self, server: &Todo) -> Resolved {
+        Resolved {
+            title: self.title.unwrap_or(&server.title).to_string(),
+            done: self.done.unwrap_or(server.done),
+            deleted: self.deleted,
+        }
+    }
+}
+
+/**
+ * 冲突处理：客户端基于旧版本做的修改遇到服务端已经变化的记录时，决定写入什么
+ * 返回 None 表示保留服务端的数据，这条修改的结果是 conflict
+ */
+pub trait ConflictHandler: Sync {
+    fn resolve(&self, server: &Todo, change: &Change) -> Option<Resolved>;
+}
+
+struct LastWriteWins;
+
+impl ConflictHandler for LastWriteWins {
+    fn resolve(&self, server: &Todo, change: &Change) -> Option<Resolved> {
+        (change.updated_at > server.updated_at).then(|| change.apply(server))
+    }
+}
+
+struct ServerWins;
+
+impl ConflictHandler for ServerWins {
+    fn resolve(&self, _server: &Todo, _change: &Change) -> Option<Resolved> {
+        None
+    }
+}
+
+/**
+ * todos 的合并规则：任意一方标记为完成就算完成，标题取后修改的一方，删除只有比服务端的修改更晚时才生效
+ */
+struct Merge;
+
+impl ConflictHandler for Merge {
+    fn resolve(&self, server: &Todo, change: &Change) -> Option<Resolved> {
+        let newer = change.updated_at > server.updated_at;
+        if change.deleted {
+            return newer.then(|| change.apply(server));
+        }
+        Some(Resolved {
+            title: match change.title {
+                Some(title) if newer => title.to_string(),
+                _ => server.title.clone(),
+            },
+            done: server.done || change.done.unwrap_or(false),
+            deleted: false,
+        })
+    }
+}
+
+fn handler(name: &str) -> Option<&'static dyn ConflictHandler> {
+    match name {
+        "lww" => Some(&LastWriteWins),
+        "server_wins" => Some(&ServerWins),
+        "merge" => Some(&Merge),
+        _ => None,
+    }
+}
+
+fn default_conflict() -> String {
+    "lww".to_string()
+}
+
+#[derive(Deserialize)]
+struct PushRequest {
+    #[serde(default = "default_conflict")]
+    conflict: String,
+    mutations: Vec<Mutation>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Applied,
+    Conflict,
+    NotFound,
+    Invalid,
+    Forbidden,
+}
+
+#[derive(Serialize)]
+struct MutationResult {
+    client_id: Option<String>,
+    status: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<Todo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+async fn push(
+    auth: Authorize<TodoEdit>,
+    State(state): State<AppState>,
+    tenant: Tenant,
+    audit: Audit,
+    Json(input): Json<PushRequest>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let handler = handler(&input.conflict).ok_or((
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "conflict must be one of lww, server_wins, merge".to_string(),
+    ))?;
+    let limit = state.config.body_limit.bulk_operations;
+    if input.mutations.len() > limit {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("at most {} mutations per request", limit),
+        ));
+    }
+
+    let mut conn = state.pool.get().await.map_err(internal_error)?;
+    let tx = conn.transaction().await.map_err(internal_error)?;
+    let now = Utc::now();
+    let mut results = Vec::with_capacity(input.mutations.len());
+    for mutation in input.mutations {
+        let (status, record, error) =
+            apply(&state, &auth, &tx, tenant.id(), handler, &mutation, now).await?;
+        results.push(MutationResult {
+            client_id: mutation.client_id,
+            status,
+            record,
+            error,
+        });
+    }
+    tx.commit().await.map_err(internal_error)?;
+    drop(conn);
+
+    let user = &auth.user;
+    let applied = results
+        .iter()
+        .filter(|result| matches!(result.status, Outcome::Applied))
+        .count();
+    audit
+        .record(
+            &state.pool,
+            Some(user.id),
+            &user.username,
+            "todo.sync",
+            json!({
+                "tenant": tenant.id(),
+                "mutations": results.len(),
+                "applied": applied,
+                "conflict": input.conflict,
+            }),
+        )
+        .await;
+    Ok(Json(json!({ "results": results })))
+}
+
+type Applied = (Outcome, Option<Todo>, Option<&'static str>);
+
+async fn apply(
+    state: &AppState,
+    auth: &Authorize<TodoEdit>,
+    tx: &tokio_postgres::Transaction<'_>,
+    tenant: &str,
+    handler: &dyn ConflictHandler,
+    mutation: &Mutation,
+    now: DateTime<Utc>,
+) -> Result<Applied, (StatusCode, String)> {
+    let invalid = |error| Ok((Outcome::Invalid, None, Some(error)));
+    let title = mutation.title.as_deref().map(str::trim);
+    if title.is_some_and(str::is_empty) {
+        return invalid("title must not be empty");
+    }
+    let updated_at = mutation.updated_at.min(now);
+
+    let Some(id) = mutation.id else {
+        if mutation.op == Op::Delete {
+            return invalid("id is required");
+        }
+        let (Some(client_id), Some(title)) = (mutation.client_id.as_deref(), title) else {
+            return invalid("client_id and title are required to create a todo");
+        };
+        let done = mutation.done.unwrap_or(false);
+        let todo =
+            repo::insert_synced_todo(tx, tenant, client_id, title, done, updated_at, auth.user.id)
+                .await
+                .map_err(internal_error)?;
+        return Ok((Outcome::Applied, Some(todo), None));
+    };
+
+    let Some(server) = repo::lock_todo(tx, tenant, id)
+        .await
+        .map_err(internal_error)?
+    else {
+        return Ok((Outcome::NotFound, None, None));
+    };
+    if server.deleted_at.is_some() {
+        return Ok((Outcome::Conflict, Some(server), None));
+    }
+    let owner = repo::todo_owner(tx, tenant, id)
+        .await
+        .map_err(internal_error)?
+        .flatten();
+    match auth.check(&state.pool, owner).await {
+        Ok(()) => {}
+        Err((StatusCode::FORBIDDEN, _)) => {
+            return Ok((
+                Outcome::Forbidden,
+                None,
+                Some("missing permission todo:edit"),
+            ))
+        }
+        Err(err) => return Err(err),
+    }
+    let change = Change {
+        title,
+        done: mutation.done,
+        deleted: mutation.op == Op::Delete,
+        updated_at,
+    };
+    let resolved = if mutation.base_version == Some(server.version) {
+        Some(change.apply(&server))
+    } else {
+        handler.resolve(&server, &change)
+    };
+    let Some(resolved) = resolved else {
+        return Ok((Outcome::Conflict, Some(server), None));
+    };
+    let todo = repo::write_synced_todo(
+        tx,
+        tenant,
+        id,
+        &resolved.title,
+        resolved.done,
+        resolved.deleted,
+        updated_at.max(server.updated_at),
+    )
+    .await
+    .map_err(internal_error)?;
+    Ok((Outcome::Applied, Some(todo), None))
+}
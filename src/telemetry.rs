@@ -0,0 +1,235 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{Status, TraceContextExt, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, TracerProvider},
+    Resource,
+};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::config::TelemetryConfig;
+
+/*
+ * 把 tracing 的 span 通过 OTLP/HTTP 导出到 Jaeger、Tempo 之类的链路追踪系统，默认关闭
+ * - OTEL_EXPORTER_OTLP_ENDPOINT   接收端地址，比如 http://localhost:4318，设置后开启导出，span 发送到 {地址}/v1/traces
+ * - OTEL_SERVICE_NAME             服务名，默认 rs-practice-axum
+ * - OTEL_RESOURCE_ATTRIBUTES      附加的资源属性，比如 deployment.environment=prod,service.version=1.2.0
+ * - OTEL_TRACES_SAMPLER_ARG       采样比例，默认 1 全部采样，0.1 表示按 trace id 采样十分之一
+ * 导出的是每个请求的 request span（见 request_id::make_span）和其中每条语句的 db.query span（见 db::repo），
+ * 请求带有 W3C traceparent 请求头时接在上游的链路后面，并且沿用上游的采样决定。
+ * span 在后台批量发送，接收端不可用时丢弃，不影响处理请求；采样只影响导出，不影响日志输出。
+ *
+ * W3C trace context 的传递（trace_context 中间件和 trace_headers），不管有没有开启导出都生效：
+ * - 请求带有格式正确的 traceparent 时，request span 上记录 trace_id，日志可以和上游服务的日志按 trace id 对上
+ * - 请求处理期间调用其他 HTTP 服务（对象存储、Web Push）时带上 traceparent 和 tracestate，下游服务接着同一条链路：
+ *   开启导出时 parent 是本服务的 span，没有开启时原样转发收到的请求头，下游的 span 直接接在上游的后面
+ * - 格式不对的 traceparent（版本 ff、trace id 或 parent id 全是 0、长度不对）忽略，相当于没有带
+ */
+
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+pub const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+
+tokio::task_local! {
+    // 当前请求收到的 traceparent 和 tracestate，没有开启导出时原样转发
+    static INCOMING: Option<(HeaderValue, Option<HeaderValue>)>;
+}
+
+// 是否开启了导出，没有开启时 request span 不带 otel.* 字段，文本日志里不会多出这些内容
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync>;
+
+/**
+ * 按配置创建导出 span 的 Layer，没有设置 OTEL_EXPORTER_OTLP_ENDPOINT 时返回 None
+ * 返回的 TracerProvider 需要在退出前 shutdown，把还没发送的 span 发出去
+ */
+pub fn layer<S>(config: &TelemetryConfig) -> Result<Option<(BoxedLayer<S>, TracerProvider)>, String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .with_timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|err| format!("OTEL_EXPORTER_OTLP_ENDPOINT: {}", err))?;
+
+    let mut attributes = vec![KeyValue::new("service.name", config.service_name.clone())];
+    for attribute in &config.resource_attributes {
+        if let Some((key, value)) = attribute.split_once('=') {
+            attributes.push(KeyValue::new(
+                key.trim().to_string(),
+                value.trim().to_string(),
+            ));
+        }
+    }
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(Resource::new_with_defaults(attributes))
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    ENABLED.store(true, Ordering::Relaxed);
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer).boxed();
+    Ok(Some((layer, provider)))
+}
+
+/**
+ * request span 在链路追踪系统里显示的名字：方法加上匹配到的路由，比如 GET /todos/:id
+ * 用路由而不是实际的路径，同一个接口的请求归到一起
+ */
+pub fn span_name(req: &Request) -> String {
+    match req.extensions().get::<MatchedPath>() {
+        Some(path) => format!("{} {}", req.method(), path.as_str()),
+        None => req.method().to_string(),
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/**
+ * 检查 traceparent 的格式（{version}-{trace id}-{parent id}-{flags}），返回其中的 trace id
+ * 版本 00 必须正好是四段，更高的版本后面可能还有字段，只看前四段
+ */
+fn parse_traceparent(value: &str) -> Option<&str> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    let hex = |part: &str, len: usize| {
+        part.len() == len
+            && part
+                .bytes()
+                .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+    };
+    let valid = hex(version, 2)
+        && version != "ff"
+        && (version != "00" || parts.next().is_none())
+        && hex(trace_id, 32)
+        && trace_id.bytes().any(|byte| byte != b'0')
+        && hex(parent_id, 16)
+        && parent_id.bytes().any(|byte| byte != b'0')
+        && hex(flags, 2);
+    valid.then_some(trace_id)
+}
+
+/**
+ * 解析请求的 traceparent 和 tracestate，在 request span 上记录 trace_id，并保存下来给 trace_headers 使用
+ * 放在 TraceLayer 里面，当前的 span 就是 request span
+ */
+pub async fn trace_context(req: Request, next: Next) -> Response {
+    let traceparent = req
+        .headers()
+        .get(TRACEPARENT)
+        .filter(|value| value.to_str().ok().and_then(parse_traceparent).is_some())
+        .cloned();
+    let incoming = traceparent.map(|traceparent| {
+        if let Some(trace_id) = traceparent.to_str().ok().and_then(parse_traceparent) {
+            Span::current().record("trace_id", tracing::field::display(trace_id));
+        }
+        (traceparent, req.headers().get(TRACESTATE).cloned())
+    });
+    INCOMING.scope(incoming, next.run(req)).await
+}
+
+/**
+ * 调用其他 HTTP 服务时要带上的 trace context 请求头，不在请求里（比如后台任务）并且没有开启导出时为空
+ */
+pub fn trace_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if enabled() {
+        let context = Span::current().context();
+        if context.span().span_context().is_valid() {
+            global::get_text_map_propagator(|propagator| {
+                propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+            });
+            return headers;
+        }
+    }
+    if let Ok(Some((traceparent, tracestate))) = INCOMING.try_with(Clone::clone) {
+        headers.insert(TRACEPARENT, traceparent);
+        if let Some(tracestate) = tracestate {
+            headers.insert(TRACESTATE, tracestate);
+        }
+    }
+    headers
+}
+
+/**
+ * 请求带有 traceparent 时，把 request span 接到上游的链路后面
+ */
+pub fn link_parent(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/**
+ * 开启导出时在 span 上记录状态码，5xx 标记为失败，由 access_log::on_response 调用
+ */
+pub fn record_response(res: &Response, span: &Span) {
+    if !enabled() {
+        return;
+    }
+    span.set_attribute(
+        "http.response.status_code",
+        i64::from(res.status().as_u16()),
+    );
+    if res.status().is_server_error() {
+        span.set_status(Status::error(res.status().to_string()));
+    }
+}
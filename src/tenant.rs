@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::config::TenantConfig;
+
+/*
+ * 多租户
+ * 每个请求属于一个租户，由 resolve 中间件确定之后作为请求扩展保存，handler 里声明 tenant: Tenant 参数就能拿到：
+ * 1. X-Tenant-Id 请求头，比如 X-Tenant-Id: acme
+ * 2. 配置了 TENANT_BASE_DOMAIN=example.com 时，acme.example.com 的子域名 acme
+ * 3. 都没有时使用默认租户 default，只部署一个租户时不需要任何配置
+ * 租户 id 只允许小写字母、数字和 -，最长 63 个字符（和子域名的规则一样），X-Tenant-Id 不合法时返回 400。
+ * 数据隔离在数据访问层（db::repo）实现：todos 表有 tenant_id 列，所有读写 todos 的函数都需要传入租户，
+ * SQL 里总是带上 tenant_id 条件，其它租户的记录和不存在一样。
+ * 用户、权限和审计日志是全局的，不区分租户：同一个账号可以访问所有租户，管理员的操作也不受租户限制。
+ */
+
+// 和迁移里 tenant_id 列的默认值一致，迁移之前已有的数据都属于这个租户
+pub const DEFAULT_TENANT: &str = "default";
+
+const TENANT_HEADER: &str = "x-tenant-id";
+
+#[derive(Debug, Clone)]
+pub struct Tenant(pub Arc<str>);
+
+impl Tenant {
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 63
+        && !id.starts_with('-')
+        && !id.ends_with('-')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+#[derive(Clone)]
+pub struct TenantResolver {
+    // 子域名的上一级域名，前面加上 "." 方便比较
+    suffix: Option<Arc<str>>,
+}
+
+impl TenantResolver {
+    pub fn new(config: &TenantConfig) -> Self {
+        TenantResolver {
+            suffix: config
+                .base_domain
+                .as_ref()
+                .map(|domain| format!(".{}", domain.to_ascii_lowercase()).into()),
+        }
+    }
+
+    /**
+     * 子域名只取紧挨着 base domain 的那一级，需要和 X-Tenant-Id 一样是合法的租户 id，否则不算
+     */
+    fn subdomain(&self, headers: &HeaderMap) -> Option<String> {
+        let suffix = self.suffix.as_deref()?;
+        let host = headers.get(header::HOST)?.to_str().ok()?;
+        let host = host.split(':').next()?.to_ascii_lowercase();
+        let subdomain = host.strip_suffix(suffix)?;
+        let label = subdomain.rsplit('.').next()?;
+        is_valid(label).then(|| label.to_string())
+    }
+
+    fn resolve(&self, headers: &HeaderMap) -> Result<String, (StatusCode, String)> {
+        if let Some(value) = headers.get(TENANT_HEADER) {
+            return value
+                .to_str()
+                .ok()
+                .map(str::trim)
+                .filter(|id| is_valid(id))
+                .map(str::to_string)
+                .ok_or((StatusCode::BAD_REQUEST, "invalid X-Tenant-Id".to_string()));
+        }
+        Ok(self
+            .subdomain(headers)
+            .unwrap_or_else(|| DEFAULT_TENANT.to_string()))
+    }
+}
+
+pub async fn resolve(
+    State(resolver): State<TenantResolver>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    match resolver.resolve(req.headers()) {
+        Ok(id) => {
+            req.extensions_mut().insert(Tenant(id.into()));
+            next.run(req).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Tenant
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // 没有经过 resolve 中间件说明路由配置有问题，不能悄悄地落到默认租户上
+        parts.extensions.get::<Tenant>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "tenant not resolved".to_string(),
+        ))
+    }
+}
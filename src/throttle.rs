@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::post,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::{
+    audit::Audit,
+    config::AuthConfig,
+    error::internal_error,
+    permissions::{Authorize, UserManage},
+    AppState,
+};
+
+// 前几次失败不做限制，之后每多失败一次，需要等待的时间翻倍
+const FREE_ATTEMPTS: u32 = 3;
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/**
+ * 失败次数对应的退避时间：1s, 2s, 4s ... 最多 MAX_BACKOFF
+ */
+fn backoff(failures: u32) -> Duration {
+    if failures < FREE_ATTEMPTS {
+        return Duration::ZERO;
+    }
+    let exp = (failures - FREE_ATTEMPTS).min(16);
+    Duration::from_secs(1 << exp).min(MAX_BACKOFF)
+}
+
+fn too_many_attempts(wait: Duration) -> (StatusCode, String) {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        format!(
+            "too many failed login attempts, retry in {} seconds",
+            wait.as_secs().max(1)
+        ),
+    )
+}
+
+/**
+ * 登录限流
+ * 同时按 IP 和按账号统计失败次数：
+ * - 按 IP 的统计只保存在内存中，用来拖慢对大量账号的撞库
+ * - 按账号的统计保存在 users 表中，失败次数达到上限后锁定账号一段时间，到期自动解锁，也可以由管理员手动解锁
+ */
+#[derive(Clone)]
+pub struct LoginThrottle {
+    by_ip: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+    pub max_failures: i32,
+    pub lockout: Duration,
+}
+
+/**
+ * users 表中和登录失败相关的字段
+ */
+pub struct AccountFailures {
+    pub failed_logins: i32,
+    pub last_failed_login_at: Option<DateTime<Utc>>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &AuthConfig) -> Self {
+        LoginThrottle {
+            by_ip: Arc::new(Mutex::new(HashMap::new())),
+            max_failures: config.max_failed_logins,
+            lockout: config.lockout_duration,
+        }
+    }
+
+    pub fn check_ip(&self, ip: Option<&str>) -> Result<(), (StatusCode, String)> {
+        let Some(ip) = ip else {
+            return Ok(());
+        };
+        if let Some((failures, last)) = self.by_ip.lock().unwrap().get(ip) {
+            let wait = backoff(*failures).saturating_sub(last.elapsed());
+            if !wait.is_zero() {
+                return Err(too_many_attempts(wait));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_account(&self, account: &AccountFailures) -> Result<(), (StatusCode, String)> {
+        let now = Utc::now();
+        if let Some(locked_until) = account.locked_until.filter(|until| *until > now) {
+            return Err(too_many_attempts(
+                (locked_until - now).to_std().unwrap_or_default(),
+            ));
+        }
+        if let Some(last) = account.last_failed_login_at {
+            let elapsed = (now - last).to_std().unwrap_or_default();
+            let wait = backoff(account.failed_logins.max(0) as u32).saturating_sub(elapsed);
+            if !wait.is_zero() {
+                return Err(too_many_attempts(wait));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_ip_failure(&self, ip: Option<&str>) {
+        let Some(ip) = ip else {
+            return;
+        };
+        let mut by_ip = self.by_ip.lock().unwrap();
+        // 超过最长退避时间没有再失败的 IP 就不用再记录了，避免内存无限增长
+        by_ip.retain(|_, (_, last)| last.elapsed() < MAX_BACKOFF * 2);
+        let entry = by_ip.entry(ip.to_string()).or_insert((0, Instant::now()));
+        entry.0 += 1;
+        entry.1 = Instant::now();
+    }
+
+    pub fn reset_ip(&self, ip: Option<&str>) {
+        if let Some(ip) = ip {
+            self.by_ip.lock().unwrap().remove(ip);
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/users/:id/unlock", post(unlock))
+}
+
+/**
+ * 管理员手动解锁账号，同时清空失败次数
+ */
+async fn unlock(
+    auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let updated = conn
+        .execute(
+            "UPDATE users SET failed_logins = 0, last_failed_login_at = NULL, locked_until = NULL
+             WHERE id = $1",
+            &[&id],
+        )
+        .await
+        .map_err(internal_error)?;
+    if updated == 0 {
+        return Err((StatusCode::NOT_FOUND, "user not found".to_string()));
+    }
+
+    audit
+        .record(
+            &state.pool,
+            Some(auth.user.id),
+            &auth.user.username,
+            "admin.user.unlock",
+            json!({ "user_id": id }),
+        )
+        .await;
+    Ok(StatusCode::NO_CONTENT)
+}
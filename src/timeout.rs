@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::StreamExt;
+use serde_json::json;
+
+use crate::config::TimeoutConfig;
+
+/*
+ * 请求超时：handler 卡住（比如等锁、等外部服务）时不会一直占着连接，客户端也能尽快拿到明确的错误
+ * - REQUEST_TIMEOUT_SECS  默认的超时时间
+ * - ROUTE_TIMEOUTS        单独设置的路由，按匹配到的路由（比如 /todos/:id）查找，
+ *                         上传接口需要更长的时间，健康检查要尽快失败，0 表示不限制
+ * 超时之后返回 JSON 错误：请求体还没有收完时是 408（客户端发得太慢），否则是 504（服务端处理得太慢）。
+ * 超时只限制生成响应头之前的部分，SSE 之类的流式响应体不受影响；超时的 handler 会被取消，请求级事务随之回滚。
+ */
+
+#[derive(Clone)]
+pub struct Timeouts {
+    default: Duration,
+    // 为 None 时不限制
+    routes: Arc<HashMap<String, Option<Duration>>>,
+}
+
+impl Timeouts {
+    pub fn new(config: &TimeoutConfig) -> Self {
+        // 格式在加载配置时已经检查过
+        let routes = config
+            .routes
+            .iter()
+            .filter_map(|route| {
+                let (path, secs) = route.split_once('=')?;
+                let secs: u64 = secs.trim().parse().ok()?;
+                let timeout = (secs > 0).then(|| Duration::from_secs(secs));
+                Some((path.trim().to_string(), timeout))
+            })
+            .collect();
+        Timeouts {
+            default: config.default,
+            routes: Arc::new(routes),
+        }
+    }
+
+    fn for_route(&self, route: Option<&str>) -> Option<Duration> {
+        route
+            .and_then(|route| self.routes.get(route).copied())
+            .unwrap_or(Some(self.default))
+    }
+}
+
+/**
+ * 记录请求体是否已经读完，用来区分 408 和 504
+ */
+fn track_body(body: Body) -> (Body, Arc<AtomicBool>) {
+    let received = Arc::new(AtomicBool::new(body.is_end_stream()));
+    if received.load(Ordering::Relaxed) {
+        return (body, received);
+    }
+    let done = received.clone();
+    let end = futures_util::stream::once(async move {
+        done.store(true, Ordering::Relaxed);
+    })
+    .filter_map(|()| async { None::<Result<Bytes, axum::Error>> });
+    let body = Body::from_stream(body.into_data_stream().chain(end));
+    (body, received)
+}
+
+/**
+ * 超时中间件，放在 Router 的 layer 里，按 MatchedPath 选择超时时间
+ */
+pub async fn apply(State(timeouts): State<Timeouts>, req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let Some(timeout) = timeouts.for_route(route.as_deref()) else {
+        return next.run(req).await;
+    };
+
+    let (parts, body) = req.into_parts();
+    let (body, received) = track_body(body);
+    match tokio::time::timeout(timeout, next.run(Request::from_parts(parts, body))).await {
+        Ok(res) => res,
+        Err(_) => {
+            let (status, message) = if received.load(Ordering::Relaxed) {
+                (StatusCode::GATEWAY_TIMEOUT, "request timed out")
+            } else {
+                (
+                    StatusCode::REQUEST_TIMEOUT,
+                    "timed out waiting for the request body",
+                )
+            };
+            tracing::warn!(
+                "{} after {} seconds: {}",
+                message,
+                timeout.as_secs(),
+                route.as_deref().unwrap_or("unmatched")
+            );
+            (
+                status,
+                Json(json!({ "error": message, "timeout_secs": timeout.as_secs() })),
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,512 @@
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_postgres::RowStream;
+
+use crate::{
+    audit::Audit,
+    db::{
+        repo::{self, Todo, TodoList, Versioned},
+        with_retry, Connection,
+    },
+    delta::ModifiedSince,
+    error::internal_error,
+    filters::{self, Locale},
+    listing::Listing,
+    ndjson,
+    negotiate::{Accept, Format, Negotiate},
+    pagination::{Paginated, Pagination},
+    pdf,
+    permissions::{Authorize, TableManage, TodoEdit},
+    tenant::Tenant,
+    xlsx::{self, Cell, XlsxRecord},
+    AppState,
+};
+
+/*
+ * 服务端渲染的 todos 页面，演示 HTML 表单 -> handler -> Postgres -> askama 模板的完整流程
+ * 浏览器里的表单只支持 GET 和 POST，所以修改、切换状态、删除都用 POST 提交到不同的 URL，
+ * 处理完之后重定向回页面（Post/Redirect/Get），避免刷新页面时重复提交表单。
+ * 删除是软删除，列表页加上 ?include_deleted=true 时也显示已删除的待办事项，
+ * 加上 ?modified_since= 或 If-Modified-Since 时只显示之后变化过的（包括已删除的），见 delta，
+ * 恢复通过管理接口 POST /api/todos/:id/restore，需要 table:manage 权限。
+ * 新建、修改、切换状态和删除需要登录并且有 todo:edit 权限（浏览器里先在 /admin/login 登录），
+ * 普通用户的 todo:edit 是 own 范围，只能修改自己创建的待办事项，见 permissions。
+ * 编辑页面的表单里带有读取时的版本号，保存时版本号已经变了（别人在这期间修改过）会返回 412，
+ * 并显示最新的内容，不会悄悄覆盖别人的修改。
+ * 列表和详情页还可以按 Accept 返回 JSON 或者纯文本（见 negotiate），默认仍然是 HTML。
+ * 列表是分页的（?page=2&per_page=20，见 pagination），JSON 返回统一的分页格式。
+ * GET /todos.xlsx 把列表导出为 Excel 文件，条件和列表页相同，但是不分页，见 xlsx；GET /todos.ndjson 同样条件导出为 NDJSON，见 ndjson。
+ * GET /todos/:id/report.pdf 把一条待办事项生成 PDF 报告（模板是 todos/report.txt，见 pdf），
+ * 默认在浏览器里预览，加上 ?download=true 时下载。
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/todos", get(list).post(create))
+        .route("/todos.xlsx", get(export))
+        .route("/todos.ndjson", get(export_ndjson))
+        .route("/todos/:id", get(show).post(update))
+        .route("/todos/:id/edit", get(edit))
+        .route("/todos/:id/report.pdf", get(report))
+        .route("/todos/:id/toggle", post(toggle))
+        .route("/todos/:id/delete", post(destroy))
+        .route("/api/todos/:id/restore", post(restore))
+}
+
+#[derive(Template)]
+#[template(path = "todos/list.html")]
+struct ListTemplate {
+    page: Paginated<Todo>,
+    message: Option<String>,
+    locale: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "todos/detail.html")]
+struct DetailTemplate {
+    todo: Todo,
+}
+
+#[derive(Template)]
+#[template(path = "todos/report.txt")]
+struct ReportTemplate<'a> {
+    todo: Todo,
+    tenant: &'a str,
+    generated_at: DateTime<Utc>,
+}
+
+#[derive(Template)]
+#[template(path = "todos/edit.html")]
+struct EditTemplate {
+    todo: Todo,
+    message: Option<String>,
+}
+
+/**
+ * 纯文本表示里的一行，比如 [x] 3 Buy milk
+ */
+fn text_line(todo: &Todo) -> String {
+    let status = match (todo.deleted_at.is_some(), todo.done) {
+        (true, _) => "-",
+        (false, true) => "x",
+        (false, false) => " ",
+    };
+    format!("[{}] {} {}", status, todo.id, todo.title)
+}
+
+impl XlsxRecord for Todo {
+    const COLUMNS: &'static [(&'static str, f64)] = &[
+        ("id", 8.0),
+        ("title", 40.0),
+        ("done", 8.0),
+        ("created_at", 20.0),
+        ("updated_at", 20.0),
+        ("deleted_at", 20.0),
+        ("version", 8.0),
+    ];
+
+    fn cells(self) -> Vec<Cell> {
+        vec![
+            Cell::Integer(self.id),
+            Cell::Text(self.title),
+            Cell::Boolean(self.done),
+            Cell::DateTime(self.created_at),
+            Cell::DateTime(self.updated_at),
+            self.deleted_at.map_or(Cell::Empty, Cell::DateTime),
+            Cell::Integer(self.version.into()),
+        ]
+    }
+}
+
+impl Negotiate for ListTemplate {
+    fn json(&self) -> serde_json::Value {
+        json!(self.page)
+    }
+
+    fn html(&self) -> Result<String, askama::Error> {
+        self.render()
+    }
+
+    fn text(&self) -> String {
+        self.page
+            .items
+            .iter()
+            .map(|todo| text_line(todo) + "\n")
+            .collect()
+    }
+}
+
+impl Negotiate for DetailTemplate {
+    fn json(&self) -> serde_json::Value {
+        json!(self.todo)
+    }
+
+    fn html(&self) -> Result<String, askama::Error> {
+        self.render()
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "{}\ncreated {}\n",
+            text_line(&self.todo),
+            self.todo.created_at.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+fn render(template: impl Template) -> Result<Html<String>, (StatusCode, String)> {
+    Ok(Html(template.render().map_err(internal_error)?))
+}
+
+fn not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "todo not found".to_string())
+}
+
+async fn all(
+    state: &AppState,
+    tenant: &Tenant,
+    include_deleted: bool,
+    modified_since: Option<DateTime<Utc>>,
+    listing: &Listing<TodoList>,
+    pagination: Pagination,
+) -> Result<Paginated<Todo>, (StatusCode, String)> {
+    let (todos, total) = with_retry(state, |conn| async move {
+        let todos = repo::list_todos(
+            &*conn,
+            tenant.id(),
+            include_deleted,
+            modified_since,
+            listing,
+            pagination.limit(),
+            pagination.offset(),
+        )
+        .await?;
+        let total = repo::count_todos(
+            &*conn,
+            tenant.id(),
+            include_deleted,
+            modified_since,
+            listing,
+        )
+        .await?;
+        Ok((todos, total))
+    })
+    .await?;
+    Ok(Paginated::new(todos, total, pagination))
+}
+
+async fn find(state: &AppState, tenant: &Tenant, id: i64) -> Result<Todo, (StatusCode, String)> {
+    with_retry(state, |conn| async move {
+        repo::find_todo(&*conn, tenant.id(), id).await
+    })
+    .await?
+    .ok_or_else(not_found)
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+// 每个提取器一个参数，拆开反而不好读
+#[allow(clippy::too_many_arguments)]
+async fn list(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Locale(locale): Locale,
+    Query(query): Query<ListQuery>,
+    pagination: Pagination,
+    delta: ModifiedSince,
+    listing: Listing<TodoList>,
+    accept: Accept,
+) -> Result<Response, (StatusCode, String)> {
+    let include_deleted = delta.include_deleted(query.include_deleted);
+    let page = all(
+        &state,
+        &tenant,
+        include_deleted,
+        delta.since(),
+        &listing,
+        pagination,
+    )
+    .await?;
+    let unchanged = page.total == 0;
+    let page = accept.respond(
+        Format::Html,
+        &ListTemplate {
+            page,
+            message: None,
+            locale,
+        },
+    );
+    Ok(delta.respond(unchanged, page))
+}
+
+/**
+ * 导出用的查询，xlsx 和 NDJSON 共用
+ */
+async fn export_rows(
+    state: AppState,
+    tenant: Tenant,
+    query: ListQuery,
+    delta: ModifiedSince,
+    listing: Listing<TodoList>,
+) -> Result<(Connection, RowStream), (StatusCode, String)> {
+    let include_deleted = delta.include_deleted(query.include_deleted);
+    let since = delta.since();
+    let (tenant, listing) = (&tenant, &listing);
+    with_retry(&state, |conn| async move {
+        let rows = repo::stream_todos(&*conn, tenant.id(), include_deleted, since, listing).await?;
+        Ok((conn, rows))
+    })
+    .await
+}
+
+async fn export(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<ListQuery>,
+    delta: ModifiedSince,
+    listing: Listing<TodoList>,
+) -> Result<Response, (StatusCode, String)> {
+    let (conn, rows) = export_rows(state, tenant, query, delta, listing).await?;
+    Ok(xlsx::stream::<Todo>(conn, rows, "todos", "todos.xlsx"))
+}
+
+async fn export_ndjson(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Query(query): Query<ListQuery>,
+    delta: ModifiedSince,
+    listing: Listing<TodoList>,
+) -> Result<Response, (StatusCode, String)> {
+    let (conn, rows) = export_rows(state, tenant, query, delta, listing).await?;
+    Ok(ndjson::stream::<Todo>(conn, rows, "todos.ndjson"))
+}
+
+async fn show(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    accept: Accept,
+) -> Result<Response, (StatusCode, String)> {
+    let todo = find(&state, &tenant, id).await?;
+    Ok(accept.respond(Format::Html, &DetailTemplate { todo }))
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    #[serde(default)]
+    download: bool,
+}
+
+async fn report(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let todo = find(&state, &tenant, id).await?;
+    let title = format!("Todo #{}", todo.id);
+    let template = ReportTemplate {
+        todo,
+        tenant: tenant.id(),
+        generated_at: Utc::now(),
+    };
+    let body = state.pdf.render(title, &template).await?;
+    Ok(pdf::response(
+        body,
+        &format!("todo-{}.pdf", id),
+        query.download,
+    ))
+}
+
+async fn edit(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    render(EditTemplate {
+        todo: find(&state, &tenant, id).await?,
+        message: None,
+    })
+}
+
+#[derive(Deserialize)]
+struct TodoForm {
+    title: String,
+    // 没有勾选的 checkbox 不会被提交，所以需要默认值
+    #[serde(default)]
+    done: bool,
+    // 编辑页面读取时的版本号，新建时没有
+    version: Option<i32>,
+}
+
+/**
+ * 标题为空时重新渲染页面并显示提示，而不是返回一个纯文本的错误
+ */
+async fn create(
+    Authorize { user, .. }: Authorize<TodoEdit>,
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Locale(locale): Locale,
+    pagination: Pagination,
+    Form(input): Form<TodoForm>,
+) -> Result<Response, (StatusCode, String)> {
+    let title = input.title.trim();
+    if title.is_empty() {
+        let page = render(ListTemplate {
+            page: all(
+                &state,
+                &tenant,
+                false,
+                None,
+                &Listing::default(),
+                pagination,
+            )
+            .await?,
+            message: Some("title must not be empty".to_string()),
+            locale,
+        })?;
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+    }
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    repo::insert_todo(&*conn, tenant.id(), title, user.id)
+        .await
+        .map_err(internal_error)?;
+    Ok(Redirect::to("/todos").into_response())
+}
+
+async fn update(
+    auth: Authorize<TodoEdit>,
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    Form(input): Form<TodoForm>,
+) -> Result<Response, (StatusCode, String)> {
+    check_owner(&state, &auth, &tenant, id).await?;
+    let version = input.version.ok_or((
+        StatusCode::PRECONDITION_REQUIRED,
+        "version field is required".to_string(),
+    ))?;
+    let title = input.title.trim();
+    if title.is_empty() {
+        // 重新显示表单时保留原来的版本号，否则期间别人的修改会被覆盖
+        let mut todo = find(&state, &tenant, id).await?;
+        todo.version = version;
+        let page = render(EditTemplate {
+            todo,
+            message: Some("title must not be empty".to_string()),
+        })?;
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+    }
+
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let updated = repo::update_todo(&*conn, tenant.id(), id, version, title, input.done)
+        .await
+        .map_err(internal_error)?;
+    drop(conn);
+    match updated {
+        Versioned::Updated(_) => Ok(Redirect::to(&format!("/todos/{}", id)).into_response()),
+        Versioned::Conflict(_) => {
+            let page = render(EditTemplate {
+                todo: find(&state, &tenant, id).await?,
+                message: Some(
+                    "this todo was changed by someone else, review the current values and save again"
+                        .to_string(),
+                ),
+            })?;
+            Ok((StatusCode::PRECONDITION_FAILED, page).into_response())
+        }
+        Versioned::NotFound => Err(not_found()),
+    }
+}
+
+/**
+ * 按 todo:edit 的作用范围判断能否修改这条待办事项，不存在时返回 404
+ */
+async fn check_owner(
+    state: &AppState,
+    auth: &Authorize<TodoEdit>,
+    tenant: &Tenant,
+    id: i64,
+) -> Result<(), (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let owner = repo::todo_owner(&*conn, tenant.id(), id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    drop(conn);
+    auth.check(&state.pool, owner).await
+}
+
+async fn toggle(
+    auth: Authorize<TodoEdit>,
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+) -> Result<Redirect, (StatusCode, String)> {
+    check_owner(&state, &auth, &tenant, id).await?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let updated = repo::toggle_todo(&*conn, tenant.id(), id)
+        .await
+        .map_err(internal_error)?;
+    if !updated {
+        return Err(not_found());
+    }
+    Ok(Redirect::to("/todos"))
+}
+
+async fn destroy(
+    auth: Authorize<TodoEdit>,
+    State(state): State<AppState>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+) -> Result<Redirect, (StatusCode, String)> {
+    check_owner(&state, &auth, &tenant, id).await?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let deleted = repo::delete_todo(&*conn, tenant.id(), id)
+        .await
+        .map_err(internal_error)?;
+    if !deleted {
+        return Err(not_found());
+    }
+    Ok(Redirect::to("/todos"))
+}
+
+async fn restore(
+    Authorize { user, .. }: Authorize<TableManage>,
+    State(state): State<AppState>,
+    tenant: Tenant,
+    audit: Audit,
+    Path(id): Path<i64>,
+) -> Result<Json<Todo>, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let todo = repo::restore_todo(&*conn, tenant.id(), id)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, "deleted todo not found".to_string()))?;
+    drop(conn);
+
+    audit
+        .record(
+            &state.pool,
+            Some(user.id),
+            &user.username,
+            "todo.restore",
+            json!({ "id": todo.id, "title": todo.title, "tenant": tenant.id() }),
+        )
+        .await;
+    Ok(Json(todo))
+}
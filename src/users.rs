@@ -0,0 +1,666 @@
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_postgres::{error::SqlState, GenericClient, RowStream};
+
+use crate::{
+    audit::Audit,
+    auth::{hash_password, AuthUser},
+    csv::{self, CsvRecord},
+    db::{
+        repo::{self, User, UserFields, UserList, Versioned},
+        with_retry, Connection, Tx,
+    },
+    delta::ModifiedSince,
+    error::internal_error,
+    listing::Listing,
+    ndjson,
+    negotiate::{Accept, Format, Negotiate},
+    pagination::{Paginated, Pagination},
+    permissions::{Authorize, Scope, UserManage},
+    xlsx::{self, Cell, XlsxRecord},
+    AppState,
+};
+
+/*
+ * users 资源的增删改查，数据库操作的完整示例
+ * - GET    /api/users      分页列表，见 pagination
+ * - GET    /api/users.csv  导出为 CSV，条件和列表相同（include_deleted、modified_since、过滤和排序），但是不分页，见 csv
+ * - GET    /api/users.xlsx 导出为 Excel 文件，条件同上，见 xlsx
+ * - GET    /api/users.ndjson 导出为 NDJSON，每行一个用户，条件同上，见 ndjson
+ * - POST   /api/users      创建，用户名重复时返回 409
+ * - GET    /api/users/:id  详情，不存在时返回 404
+ * - PUT    /api/users/:id  整体更新，password 不传时保留原密码
+ *                          需要通过 If-Match 请求头或者 version 字段带上读取时的版本号，版本号不一致时返回 412
+ * - DELETE /api/users/:id  删除（软删除），同时作废该用户的 refresh token，删除后不能再登录
+ * - POST   /api/users/:id/restore  恢复已删除的用户
+ * - POST   /api/users/bulk 批量创建/更新/删除
+ * 列表和详情默认不包含已删除的用户，加上 ?include_deleted=true 时包含
+ * 列表支持 ?modified_since= 和 If-Modified-Since，只返回之后变化过的用户（包括已删除的），见 delta
+ * 返回单个用户的接口都带有 ETag 响应头，值就是版本号，修改时原样放到 If-Match 里即可
+ * 这些都是管理接口，需要 user:manage 权限；own / org 范围的 user:manage 只能修改、删除、恢复自己（或者同组织的成员），
+ * 不能修改角色，也不能新建用户和批量操作，见 permissions
+ * 列表和详情默认返回 JSON，Accept 里要 text/html 或者 text/plain 时返回 HTML 页面或者纯文本，见 negotiate
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/users", get(list).post(create))
+        .route("/api/users.csv", get(export))
+        .route("/api/users.xlsx", get(export_xlsx))
+        .route("/api/users.ndjson", get(export_ndjson))
+        .route("/api/users/bulk", post(bulk))
+        .route("/api/users/:id", get(show).put(update).delete(destroy))
+        .route("/api/users/:id/restore", post(restore))
+}
+
+#[derive(Deserialize)]
+struct UserInput {
+    username: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default = "default_role")]
+    role: String,
+    password: Option<String>,
+    // 读取时的版本号，也可以通过 If-Match 请求头传递
+    version: Option<i32>,
+}
+
+impl UserInput {
+    fn fields<'a>(&'a self, password_hash: Option<&'a str>) -> UserFields<'a> {
+        UserFields {
+            username: &self.username,
+            email: &self.email,
+            role: &self.role,
+            password_hash,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeletedQuery {
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+fn default_role() -> String {
+    "user".to_string()
+}
+
+fn not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "user not found".to_string())
+}
+
+/**
+ * 唯一约束冲突说明用户名已经被占用，返回 409，其它错误仍然是 500
+ */
+fn conflict_or_internal(err: tokio_postgres::Error) -> (StatusCode, String) {
+    if err.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+        (StatusCode::CONFLICT, "username already exists".to_string())
+    } else {
+        internal_error(err)
+    }
+}
+
+/*
+ * 下面几个函数在 repo 的基础上处理密码哈希和错误转换，单个接口和批量接口共用。
+ * 参数是 GenericClient，既可以传普通连接，也可以传事务。
+ */
+
+async fn insert_user(
+    client: &impl GenericClient,
+    input: &UserInput,
+) -> Result<User, (StatusCode, String)> {
+    let password = input.password.as_deref().ok_or((
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "password is required".to_string(),
+    ))?;
+    let password_hash = hash_password(password)?;
+
+    repo::insert_user(client, &input.fields(Some(&password_hash)))
+        .await
+        .map_err(conflict_or_internal)
+}
+
+/**
+ * 没有带版本号时返回 428，要求客户端先读取再修改，而不是直接覆盖
+ */
+async fn update_user(
+    client: &impl GenericClient,
+    id: i64,
+    version: Option<i32>,
+    input: &UserInput,
+) -> Result<User, (StatusCode, String)> {
+    let version = version.or(input.version).ok_or((
+        StatusCode::PRECONDITION_REQUIRED,
+        "If-Match header or version field is required".to_string(),
+    ))?;
+    let password_hash = input.password.as_deref().map(hash_password).transpose()?;
+
+    match repo::update_user(client, id, version, &input.fields(password_hash.as_deref()))
+        .await
+        .map_err(conflict_or_internal)?
+    {
+        Versioned::Updated(user) => Ok(user),
+        Versioned::Conflict(current) => Err((
+            StatusCode::PRECONDITION_FAILED,
+            format!("version mismatch, current version is {}", current),
+        )),
+        Versioned::NotFound => Err(not_found()),
+    }
+}
+
+/**
+ * 从 If-Match 请求头中读取版本号，支持 "3"、W/"3" 和 3 几种写法
+ */
+fn if_match(headers: &HeaderMap) -> Result<Option<i32>, (StatusCode, String)> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .map(|value| value.trim().trim_start_matches("W/").trim_matches('"'))
+        .and_then(|value| value.parse().ok())
+        .map(Some)
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "If-Match must be the version returned in ETag".to_string(),
+        ))
+}
+
+#[derive(Template)]
+#[template(path = "users/list.html")]
+struct ListTemplate {
+    page: Paginated<User>,
+}
+
+#[derive(Template)]
+#[template(path = "users/detail.html")]
+struct DetailTemplate {
+    user: User,
+}
+
+/**
+ * 纯文本表示里的一行，已删除的用户后面加上 (deleted)
+ */
+fn text_line(user: &User) -> String {
+    format!(
+        "{} {} <{}> {}{}",
+        user.id,
+        user.username,
+        user.email,
+        user.role,
+        if user.deleted_at.is_some() {
+            " (deleted)"
+        } else {
+            ""
+        }
+    )
+}
+
+impl Negotiate for ListTemplate {
+    fn json(&self) -> Value {
+        json!(self.page)
+    }
+
+    fn html(&self) -> Result<String, askama::Error> {
+        self.render()
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!(
+            "{} users, page {} of {}\n",
+            self.page.total, self.page.page, self.page.total_pages
+        );
+        for user in &self.page.items {
+            text += &text_line(user);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+impl Negotiate for DetailTemplate {
+    fn json(&self) -> Value {
+        json!(self.user)
+    }
+
+    fn html(&self) -> Result<String, askama::Error> {
+        self.render()
+    }
+
+    fn text(&self) -> String {
+        text_line(&self.user) + "\n"
+    }
+}
+
+impl CsvRecord for User {
+    const HEADER: &'static [&'static str] = &[
+        "id",
+        "username",
+        "email",
+        "role",
+        "created_at",
+        "updated_at",
+        "deleted_at",
+        "version",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.username.clone(),
+            self.email.clone(),
+            self.role.clone(),
+            self.created_at.to_rfc3339(),
+            self.updated_at.to_rfc3339(),
+            self.deleted_at
+                .map(|deleted_at| deleted_at.to_rfc3339())
+                .unwrap_or_default(),
+            self.version.to_string(),
+        ]
+    }
+}
+
+impl XlsxRecord for User {
+    const COLUMNS: &'static [(&'static str, f64)] = &[
+        ("id", 8.0),
+        ("username", 20.0),
+        ("email", 30.0),
+        ("role", 12.0),
+        ("created_at", 20.0),
+        ("updated_at", 20.0),
+        ("deleted_at", 20.0),
+        ("version", 8.0),
+    ];
+
+    fn cells(self) -> Vec<Cell> {
+        vec![
+            Cell::Integer(self.id),
+            Cell::Text(self.username),
+            Cell::Text(self.email),
+            Cell::Text(self.role),
+            Cell::DateTime(self.created_at),
+            Cell::DateTime(self.updated_at),
+            self.deleted_at.map_or(Cell::Empty, Cell::DateTime),
+            Cell::Integer(self.version.into()),
+        ]
+    }
+}
+
+fn etag(user: &User) -> [(header::HeaderName, String); 1] {
+    [(header::ETAG, format!("\"{}\"", user.version))]
+}
+
+/**
+ * 涉及多条语句，调用方需要传入事务（或者 Tx）
+ */
+async fn delete_user(client: &impl GenericClient, id: i64) -> Result<String, (StatusCode, String)> {
+    repo::delete_user(client, id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)
+}
+
+async fn list(
+    _auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    Query(query): Query<DeletedQuery>,
+    pagination: Pagination,
+    delta: ModifiedSince,
+    listing: Listing<UserList>,
+    accept: Accept,
+) -> Result<Response, (StatusCode, String)> {
+    let include_deleted = delta.include_deleted(query.include_deleted);
+    let since = delta.since();
+    let listing = &listing;
+    let (users, total) = with_retry(&state, |conn| async move {
+        let users = repo::list_users(
+            &*conn,
+            include_deleted,
+            since,
+            listing,
+            pagination.limit(),
+            pagination.offset(),
+        )
+        .await?;
+        let total = repo::count_users(&*conn, include_deleted, since, listing).await?;
+        Ok((users, total))
+    })
+    .await?;
+    let page = ListTemplate {
+        page: Paginated::new(users, total, pagination),
+    };
+    Ok(delta.respond(total == 0, accept.respond(Format::Json, &page)))
+}
+
+/**
+ * 导出用的查询，CSV、xlsx 和 NDJSON 共用
+ */
+async fn export_rows(
+    state: AppState,
+    query: DeletedQuery,
+    delta: ModifiedSince,
+    listing: Listing<UserList>,
+) -> Result<(Connection, RowStream), (StatusCode, String)> {
+    let include_deleted = delta.include_deleted(query.include_deleted);
+    let since = delta.since();
+    let listing = &listing;
+    with_retry(&state, |conn| async move {
+        let rows = repo::stream_users(&*conn, include_deleted, since, listing).await?;
+        Ok((conn, rows))
+    })
+    .await
+}
+
+async fn export(
+    _auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    Query(query): Query<DeletedQuery>,
+    delta: ModifiedSince,
+    listing: Listing<UserList>,
+) -> Result<Response, (StatusCode, String)> {
+    let (conn, rows) = export_rows(state, query, delta, listing).await?;
+    Ok(csv::stream::<User>(conn, rows, "users.csv"))
+}
+
+async fn export_xlsx(
+    _auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    Query(query): Query<DeletedQuery>,
+    delta: ModifiedSince,
+    listing: Listing<UserList>,
+) -> Result<Response, (StatusCode, String)> {
+    let (conn, rows) = export_rows(state, query, delta, listing).await?;
+    Ok(xlsx::stream::<User>(conn, rows, "users", "users.xlsx"))
+}
+
+async fn export_ndjson(
+    _auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    Query(query): Query<DeletedQuery>,
+    delta: ModifiedSince,
+    listing: Listing<UserList>,
+) -> Result<Response, (StatusCode, String)> {
+    let (conn, rows) = export_rows(state, query, delta, listing).await?;
+    Ok(ndjson::stream::<User>(conn, rows, "users.ndjson"))
+}
+
+async fn show(
+    _auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(query): Query<DeletedQuery>,
+    accept: Accept,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let user = with_retry(&state, |conn| async move {
+        repo::find_user(&*conn, id, query.include_deleted).await
+    })
+    .await?
+    .ok_or_else(not_found)?;
+    Ok((
+        etag(&user),
+        accept.respond(Format::Json, &DetailTemplate { user }),
+    ))
+}
+
+async fn create(
+    auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Json(input): Json<UserInput>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    // 新建的用户还不属于任何人，只有 any 范围才能创建
+    auth.check(&state.pool, None).await?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let user = insert_user(&*conn, &input).await?;
+    drop(conn);
+
+    record(&state, &audit, &auth.user, "user.create", created(&user)).await;
+    Ok((StatusCode::CREATED, etag(&user), Json(user)))
+}
+
+async fn update(
+    auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    Json(input): Json<UserInput>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let version = if_match(&headers)?;
+    auth.check(&state.pool, Some(id)).await?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    // own / org 范围只能改资料，不能改角色，否则可以把自己提升为管理员
+    if auth.scope != Scope::Any {
+        let current = repo::find_user(&*conn, id, false)
+            .await
+            .map_err(internal_error)?
+            .ok_or_else(not_found)?;
+        if current.role != input.role {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "changing roles requires user:manage on any user".to_string(),
+            ));
+        }
+    }
+    let user = update_user(&*conn, id, version, &input).await?;
+    drop(conn);
+
+    // Authorize 每次都从 users 表读取角色，修改角色后立即生效
+    record(
+        &state,
+        &audit,
+        &auth.user,
+        "user.update",
+        updated(&user, &input),
+    )
+    .await;
+    Ok((etag(&user), Json(user)))
+}
+
+async fn destroy(
+    auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path(id): Path<i64>,
+    tx: Tx,
+) -> Result<StatusCode, (StatusCode, String)> {
+    auth.check(&state.pool, Some(id)).await?;
+    let username = delete_user(&*tx, id).await?;
+
+    record(
+        &state,
+        &audit,
+        &auth.user,
+        "user.delete",
+        deleted(id, &username),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn restore(
+    auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    auth.check(&state.pool, Some(id)).await?;
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let user = repo::restore_user(&*conn, id)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, "deleted user not found".to_string()))?;
+    drop(conn);
+
+    record(&state, &audit, &auth.user, "user.restore", created(&user)).await;
+    Ok((etag(&user), Json(user)))
+}
+
+fn created(user: &User) -> Value {
+    json!({ "id": user.id, "username": user.username, "role": user.role })
+}
+
+fn updated(user: &User, input: &UserInput) -> Value {
+    json!({
+        "id": user.id,
+        "username": user.username,
+        "role": user.role,
+        "password_changed": input.password.is_some(),
+    })
+}
+
+fn deleted(id: i64, username: &str) -> Value {
+    json!({ "id": id, "username": username })
+}
+
+async fn record(state: &AppState, audit: &Audit, actor: &AuthUser, action: &str, payload: Value) {
+    audit
+        .record(
+            &state.pool,
+            Some(actor.id),
+            &actor.username,
+            action,
+            payload,
+        )
+        .await;
+}
+
+/*
+ * 批量接口
+ * 请求体形如 {"atomic": false, "operations": [{"op": "create", ...}, {"op": "update", "id": 1, "version": 3, ...}, {"op": "delete", "id": 2}]}
+ * update 操作同样需要带上 version
+ * - 默认每个操作单独执行（各自一个事务），互不影响，返回 207 和每个操作各自的状态码与结果
+ * - atomic 为 true 时所有操作放在同一个事务里，任何一个失败都整体回滚，返回失败操作的状态码和下标
+ * 一次最多 BULK_MAX_OPERATIONS 个操作，超过时返回 422
+ */
+
+#[derive(Deserialize)]
+struct BulkRequest {
+    #[serde(default)]
+    atomic: bool,
+    operations: Vec<Operation>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Create(UserInput),
+    Update(UpdateOperation),
+    Delete { id: i64 },
+}
+
+#[derive(Deserialize)]
+struct UpdateOperation {
+    id: i64,
+    #[serde(flatten)]
+    user: UserInput,
+}
+
+/**
+ * 执行单个操作，成功时返回状态码、响应内容和需要记录的审计日志
+ */
+async fn apply(
+    client: &impl GenericClient,
+    operation: &Operation,
+) -> Result<(StatusCode, Value, &'static str, Value), (StatusCode, String)> {
+    match operation {
+        Operation::Create(input) => {
+            let user = insert_user(client, input).await?;
+            let payload = created(&user);
+            Ok((StatusCode::CREATED, json!(user), "user.create", payload))
+        }
+        Operation::Update(UpdateOperation { id, user: input }) => {
+            let user = update_user(client, *id, None, input).await?;
+            let payload = updated(&user, input);
+            Ok((StatusCode::OK, json!(user), "user.update", payload))
+        }
+        Operation::Delete { id } => {
+            let username = delete_user(client, *id).await?;
+            Ok((
+                StatusCode::NO_CONTENT,
+                Value::Null,
+                "user.delete",
+                deleted(*id, &username),
+            ))
+        }
+    }
+}
+
+async fn bulk(
+    auth: Authorize<UserManage>,
+    State(state): State<AppState>,
+    audit: Audit,
+    Json(input): Json<BulkRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    // 批量操作涉及多个用户，只有 any 范围才能使用
+    auth.check(&state.pool, None).await?;
+    let limit = state.config.body_limit.bulk_operations;
+    if input.operations.len() > limit {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("at most {} operations per request", limit),
+        ));
+    }
+
+    let mut conn = state.pool.get().await.map_err(internal_error)?;
+    let mut results = Vec::with_capacity(input.operations.len());
+    let mut entries = Vec::new();
+
+    if input.atomic {
+        let tx = conn.transaction().await.map_err(internal_error)?;
+        for (index, operation) in input.operations.iter().enumerate() {
+            // 出错时 tx 被 drop，事务自动回滚
+            let (status, body, action, payload) = match apply(&tx, operation).await {
+                Ok(applied) => applied,
+                Err((status, error)) => {
+                    return Ok(
+                        (status, Json(json!({ "error": error, "index": index }))).into_response()
+                    );
+                }
+            };
+            results.push(json!({ "index": index, "status": status.as_u16(), "body": body }));
+            entries.push((action, payload));
+        }
+        tx.commit().await.map_err(internal_error)?;
+    } else {
+        for (index, operation) in input.operations.iter().enumerate() {
+            let tx = conn.transaction().await.map_err(internal_error)?;
+            let result = match apply(&tx, operation).await {
+                Ok(applied) => tx.commit().await.map(|_| applied).map_err(internal_error),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok((status, body, action, payload)) => {
+                    results
+                        .push(json!({ "index": index, "status": status.as_u16(), "body": body }));
+                    entries.push((action, payload));
+                }
+                Err((status, error)) => {
+                    results
+                        .push(json!({ "index": index, "status": status.as_u16(), "error": error }));
+                }
+            }
+        }
+    }
+    drop(conn);
+
+    for (action, payload) in entries {
+        record(&state, &audit, &auth.user, action, payload).await;
+    }
+    let status = if input.atomic {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Ok((status, Json(json!({ "results": results }))).into_response())
+}
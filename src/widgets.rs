@@ -0,0 +1,231 @@
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{
+        repo::{self, Todo},
+        with_retry,
+    },
+    error::internal_error,
+    tenant::Tenant,
+    AppState,
+};
+
+/*
+ * 可以嵌入到其它网站的小部件：最近的待办事项
+ * - GET /widgets/latest-todos?limit=5  HTML 片段，放进 <iframe src="..."> 使用
+ * - GET /widgets/latest-todos.js       JS 嵌入，<script src=".../widgets/latest-todos.js" data-limit="5" async></script>
+ *                                      在 script 标签的位置插入上面的 iframe，也可以用 data-height 指定高度
+ * JS 嵌入只负责创建 iframe，内容仍然在我们自己的源下渲染，不会把数据或者脚本注入到对方的页面里，
+ * 所以没有提供 JSONP 这种直接在对方页面里执行回调的方式。
+ * 安全方面：
+ * - WIDGET_ALLOWED_ORIGINS 里的网站才能嵌入，iframe 通过 CSP 的 frame-ancestors 由浏览器强制检查；
+ *   请求带有 Origin 或 Referer 时也会先检查一遍，不允许的网站直接返回 403
+ * - HTML 的 CSP 是 default-src 'none'，只允许和内容哈希一致的内联样式，不执行任何脚本，也不能提交表单
+ * - JS 创建的 iframe 带有 sandbox 属性，只允许在新窗口中打开链接
+ * 和 /todos 页面一样不需要登录，按请求的子域名确定租户，不显示已删除的记录。
+ */
+
+const DEFAULT_LIMIT: i64 = 5;
+const MAX_LIMIT: i64 = 20;
+
+// 这段样式的哈希写在 CSP 里，修改后哈希会跟着重新计算
+const STYLE: &str = "body{margin:0;font:14px/1.5 sans-serif;color:#222}\
+ul{list-style:none;margin:0;padding:8px}\
+li{padding:4px 0;border-bottom:1px solid #eee}\
+a{color:inherit;text-decoration:none}\
+.done{text-decoration:line-through;color:#888}";
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/widgets/latest-todos", get(latest_todos))
+        .route("/widgets/latest-todos.js", get(embed_script))
+}
+
+#[derive(Template)]
+#[template(path = "widgets/latest_todos.html")]
+struct LatestTodosTemplate<'a> {
+    todos: Vec<Todo>,
+    style: &'static str,
+    // 链接指向本站的绝对地址，在新窗口中打开
+    base_url: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "widgets/embed.js", escape = "none")]
+struct EmbedTemplate;
+
+#[derive(Deserialize)]
+struct WidgetQuery {
+    limit: Option<i64>,
+}
+
+/**
+ * 形如 https://example.com 或 http://localhost:8080，没有路径和结尾的 /
+ */
+pub fn is_origin(value: &str) -> bool {
+    let Some((scheme, host)) = value.split_once("://") else {
+        return false;
+    };
+    matches!(scheme, "http" | "https") && !host.is_empty() && !host.contains(['/', '?', '#'])
+}
+
+/**
+ * URL 的 scheme://host[:port] 部分
+ */
+fn origin_of(url: &str) -> &str {
+    let start = url.find("://").map_or(0, |index| index + 3);
+    match url[start..].find(['/', '?', '#']) {
+        Some(end) => &url[..start + end],
+        None => url,
+    }
+}
+
+/**
+ * 允许嵌入的网站，本站总是允许
+ */
+struct Origins<'a> {
+    public_origin: &'a str,
+    allowed: &'a [String],
+}
+
+impl<'a> Origins<'a> {
+    fn new(state: &'a AppState) -> Self {
+        Origins {
+            public_origin: origin_of(&state.config.public_url),
+            allowed: &state.config.widget.allowed_origins,
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        origin == self.public_origin
+            || self
+                .allowed
+                .iter()
+                .any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    /**
+     * 检查请求来自哪个网站：优先使用 Origin，没有时取 Referer 的源
+     * 两个都没有（对方设置了 no-referrer）时放行，iframe 仍然受 frame-ancestors 限制
+     */
+    fn check(&self, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+        let origin = headers
+            .get(header::ORIGIN)
+            .or_else(|| headers.get(header::REFERER))
+            .and_then(|value| value.to_str().ok())
+            .map(origin_of);
+        match origin {
+            Some(origin) if !self.allows(origin) => Err((
+                StatusCode::FORBIDDEN,
+                format!("{} is not allowed to embed this widget", origin),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn frame_ancestors(&self) -> String {
+        if self.allowed.iter().any(|allowed| allowed == "*") {
+            return "*".to_string();
+        }
+        std::iter::once("'self'")
+            .chain(self.allowed.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn style_hash() -> String {
+    STANDARD.encode(Sha256::digest(STYLE))
+}
+
+async fn latest_todos(
+    State(state): State<AppState>,
+    tenant: Tenant,
+    headers: HeaderMap,
+    Query(query): Query<WidgetQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let origins = Origins::new(&state);
+    origins.check(&headers)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let tenant = tenant.id();
+    let todos = with_retry(&state, |conn| async move {
+        repo::latest_todos(&*conn, tenant, limit).await
+    })
+    .await?;
+    let html = LatestTodosTemplate {
+        todos,
+        style: STYLE,
+        base_url: state.config.public_url.trim_end_matches('/'),
+    }
+    .render()
+    .map_err(internal_error)?;
+
+    let csp = format!(
+        "default-src 'none'; style-src 'sha256-{}'; base-uri 'none'; form-action 'none'; frame-ancestors {}",
+        style_hash(),
+        origins.frame_ancestors()
+    );
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            ),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_str(&csp).map_err(internal_error)?,
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+            (
+                header::REFERRER_POLICY,
+                HeaderValue::from_static("no-referrer"),
+            ),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("private, max-age=60"),
+            ),
+        ],
+        html,
+    )
+        .into_response())
+}
+
+async fn embed_script(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    Origins::new(&state).check(&headers)?;
+    let script = EmbedTemplate.render().map_err(internal_error)?;
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/javascript; charset=utf-8"),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=300"),
+            ),
+        ],
+        script,
+    )
+        .into_response())
+}
@@ -0,0 +1,169 @@
+use std::io::{self, BufWriter, Write};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use rust_xlsxwriter::{ColNum, Format, RowNum, Workbook, XlsxError};
+use tokio::sync::mpsc;
+use tokio_postgres::RowStream;
+
+use crate::db::{repo::FromRow, Connection};
+
+/*
+ * 把查询结果导出为 Excel 文件（.xlsx），比如 GET /api/users.xlsx、GET /todos.xlsx，条件和对应的列表相同
+ * 和 csv 一样逐行读取查询结果，区别是单元格有类型：数字、布尔值和时间在 Excel 里可以直接排序、筛选和计算，
+ * 不会像 CSV 那样被当成文本或者按本地格式猜错日期。第一行是加粗的表头，冻结在顶部并且带有筛选按钮。
+ * 数据流向：
+ *   数据库 -> 行（ROWS_BUFFERED 行的缓冲）-> 阻塞线程池里的 rust_xlsxwriter -> 响应体
+ * - rust_xlsxwriter 是同步的，放在 spawn_blocking 里运行，不占用 tokio 的工作线程
+ * - 工作表使用 constant memory 模式，写完的行马上落到临时文件里，内存里只有当前这一行
+ * - xlsx 是 zip 文件，要等所有行写完才能生成，之后边压缩边发送给客户端，不会先在内存里拼出整个文件
+ * 所以响应头马上就能返回，但是要等数据库读完之后才开始收到响应体。
+ * 客户端中途断开时写入失败，导出随之停止，连接还回连接池；出错时中断响应，错误记在日志里。
+ * 一个工作表最多 1048576 行（包括表头），超出时导出失败，这么大的数据应该用 CSV 导出。
+ * 导出新的表只需要给行的结构体实现 XlsxRecord，再在 handler 里调用 stream。
+ */
+
+// 数据库和 rust_xlsxwriter 之间最多缓冲多少行
+const ROWS_BUFFERED: usize = 1000;
+
+// 发送给客户端的每一块的大小
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const XLSX: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/**
+ * 单元格的值
+ */
+pub enum Cell {
+    Integer(i64),
+    Text(String),
+    Boolean(bool),
+    // 按 UTC 写入，Excel 里的时间没有时区
+    DateTime(DateTime<Utc>),
+    Empty,
+}
+
+/**
+ * 可以导出为 xlsx 的行，COLUMNS 是表头和列宽（字符数），和 cells 的顺序一致
+ */
+pub trait XlsxRecord: FromRow + Send + 'static {
+    const COLUMNS: &'static [(&'static str, f64)];
+
+    fn cells(self) -> Vec<Cell>;
+}
+
+/**
+ * 把 rust_xlsxwriter 写出的字节发到响应体里，客户端断开时返回 BrokenPipe
+ */
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_sheet<T: XlsxRecord>(
+    sheet_name: &str,
+    mut records: mpsc::Receiver<Result<T, tokio_postgres::Error>>,
+    out: mpsc::Sender<io::Result<Bytes>>,
+) -> Result<(), XlsxError> {
+    let bold = Format::new().set_bold();
+    let datetime = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet_with_constant_memory();
+    sheet.set_name(sheet_name)?;
+    for (col, (name, width)) in T::COLUMNS.iter().enumerate() {
+        let col = col as ColNum;
+        sheet.set_column_width(col, *width)?;
+        sheet.write_string_with_format(0, col, *name, &bold)?;
+    }
+
+    let mut row: RowNum = 0;
+    while let Some(record) = records.blocking_recv() {
+        let record = record.map_err(|err| XlsxError::CustomError(err.to_string()))?;
+        row += 1;
+        for (col, cell) in record.cells().into_iter().enumerate() {
+            let col = col as ColNum;
+            match cell {
+                Cell::Integer(value) => {
+                    sheet.write_number(row, col, value as f64)?;
+                }
+                Cell::Text(value) => {
+                    sheet.write_string(row, col, value)?;
+                }
+                Cell::Boolean(value) => {
+                    sheet.write_boolean(row, col, value)?;
+                }
+                Cell::DateTime(value) => {
+                    sheet.write_datetime_with_format(row, col, value.naive_utc(), &datetime)?;
+                }
+                Cell::Empty => {}
+            }
+        }
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    sheet.autofilter(0, 0, row, T::COLUMNS.len().saturating_sub(1) as ColNum)?;
+    workbook.save_to_writer(BufWriter::with_capacity(CHUNK_SIZE, ChannelWriter(out)))
+}
+
+/**
+ * xlsx 下载响应，conn 是执行查询的连接，rows 读完或者导出停止之后才还回连接池
+ */
+pub fn stream<T: XlsxRecord>(
+    conn: Connection,
+    rows: RowStream,
+    sheet_name: &'static str,
+    filename: &str,
+) -> Response {
+    let (records_tx, records_rx) = mpsc::channel(ROWS_BUFFERED);
+    let (body_tx, body_rx) = mpsc::channel(4);
+
+    tokio::spawn(async move {
+        let _conn = conn;
+        let mut rows = std::pin::pin!(rows);
+        while let Some(row) = rows.next().await {
+            let record = row.and_then(|row| T::from_row(&row));
+            let failed = record.is_err();
+            // 发送失败说明导出已经停止了
+            if records_tx.send(record).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let errors = body_tx.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = write_sheet(sheet_name, records_rx, body_tx) {
+            tracing::error!("XLSX export failed: {}", err);
+            let _ = errors.blocking_send(Err(io::Error::other(err.to_string())));
+        }
+    });
+
+    let body = stream::unfold(body_rx, |mut body_rx| async move {
+        let chunk = body_rx.recv().await?;
+        Some((chunk, body_rx))
+    });
+    let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename))
+        .expect("XLSX file names are valid header values");
+    (
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(XLSX)),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
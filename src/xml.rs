@@ -0,0 +1,170 @@
+use std::fmt;
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::BytesRejection, FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use quick_xml::DeError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    error::{internal_error, json_error},
+    AppState,
+};
+
+/*
+ * XML 的请求和响应，给还只会用 XML 的老客户端使用，用法和 axum 的 Json 一样：
+ *   async fn handler(Xml(input): Xml<Input>) -> Xml<Output>
+ * 序列化和反序列化用的是 quick-xml 的 serde 支持，所以同一个结构体可以同时用于 Json 和 Xml。
+ * 根元素的名字是结构体的名字（可以用 #[serde(rename = "...")] 修改），请求里的根元素名字不检查。
+ * 解析失败时返回 XmlRejection，和 JsonRejection 一样区分几种情况，handler 可以用 Result<Xml<T>, XmlRejection> 自己处理：
+ * - MissingXmlContentType  没有 Content-Type: application/xml（或者 text/xml、带 +xml 后缀的类型），415
+ * - XmlSyntaxError         不是格式正确的 XML，400
+ * - XmlDataError           XML 没问题，但是和目标类型对不上（缺少字段、类型不对），422
+ * - BytesRejection         读取请求体失败，比如超出大小限制
+ * POST /xml 是一个示例接口，和 POST /json 共用请求体大小限制。
+ */
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/xml", post(accept_xml))
+}
+
+pub struct Xml<T>(pub T);
+
+#[derive(Debug)]
+pub enum XmlRejection {
+    MissingXmlContentType,
+    XmlSyntaxError(DeError),
+    XmlDataError(DeError),
+    BytesRejection(BytesRejection),
+}
+
+impl fmt::Display for XmlRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XmlRejection::MissingXmlContentType => {
+                f.write_str("expected request with `Content-Type: application/xml`")
+            }
+            XmlRejection::XmlSyntaxError(err) => {
+                write!(f, "failed to parse the request body as XML: {}", err)
+            }
+            XmlRejection::XmlDataError(err) => write!(
+                f,
+                "failed to deserialize the XML body into the target type: {}",
+                err
+            ),
+            XmlRejection::BytesRejection(err) => err.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for XmlRejection {
+    fn into_response(self) -> Response {
+        let status = match self {
+            XmlRejection::MissingXmlContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            XmlRejection::XmlSyntaxError(_) => StatusCode::BAD_REQUEST,
+            XmlRejection::XmlDataError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            // 保留原来的状态码，比如超出大小限制时的 413
+            XmlRejection::BytesRejection(err) => return err.into_response(),
+        };
+        json_error(status, &self.to_string())
+    }
+}
+
+/**
+ * application/xml、text/xml，以及 application/atom+xml 这种带 +xml 后缀的类型
+ */
+fn is_xml_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/xml" || mime == "text/xml" || mime.ends_with("+xml")
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Xml<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = XmlRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_xml_content_type(req.headers()) {
+            return Err(XmlRejection::MissingXmlContentType);
+        }
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(XmlRejection::BytesRejection)?;
+        quick_xml::de::from_reader(&*bytes)
+            .map(Xml)
+            .map_err(|err| match err {
+                DeError::InvalidXml(_) | DeError::UnexpectedEof => {
+                    XmlRejection::XmlSyntaxError(err)
+                }
+                _ => XmlRejection::XmlDataError(err),
+            })
+    }
+}
+
+impl<T> IntoResponse for Xml<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let mut body = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        if let Err(err) = quick_xml::se::to_writer(&mut body, &self.0) {
+            return internal_error(err).into_response();
+        }
+        (
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/xml"),
+            )],
+            body,
+        )
+            .into_response()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename = "signup")]
+struct Signup {
+    name: String,
+    email: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "welcome")]
+struct Welcome {
+    name: String,
+    email: String,
+    message: String,
+}
+
+/**
+ * POST XML 请求，比如
+ *   <signup><name>alice</name><email>alice@example.com</email></signup>
+ */
+async fn accept_xml(Xml(input): Xml<Signup>) -> Xml<Welcome> {
+    tracing::debug!("xml params {:?}", input);
+    Xml(Welcome {
+        message: format!("Welcome, {}", input.name),
+        name: input.name,
+        email: input.email,
+    })
+}
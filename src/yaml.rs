@@ -0,0 +1,98 @@
+use std::fmt;
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::BytesRejection, FromRequest, Request},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::json_error;
+
+/*
+ * YAML 格式的请求体，运维工具里的配置文件可以直接 POST 上来，不用先转成 JSON
+ * 反序列化用的是 serde_yaml，所以 JSON 接口的结构体不用改就能用：
+ *   async fn handler(Yaml(input): Yaml<Input>)
+ * 只有提取器，响应还是用 Json。POST /yaml 是一个示例接口，参数和 POST /json 一样。
+ * 解析失败时返回 YamlRejection：
+ * - MissingYamlContentType  没有 Content-Type: application/yaml（或者 application/x-yaml、text/yaml、带 +yaml 后缀的类型），415
+ * - YamlError               不是合法的 YAML，或者和目标类型对不上，400，消息里带有出错的行列号
+ * - BytesRejection          读取请求体失败，比如超出大小限制
+ */
+
+pub struct Yaml<T>(pub T);
+
+#[derive(Debug)]
+pub enum YamlRejection {
+    MissingYamlContentType,
+    YamlError(serde_yaml::Error),
+    BytesRejection(BytesRejection),
+}
+
+impl fmt::Display for YamlRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YamlRejection::MissingYamlContentType => {
+                f.write_str("expected request with `Content-Type: application/yaml`")
+            }
+            YamlRejection::YamlError(err) => write!(f, "failed to parse the YAML body: {}", err),
+            YamlRejection::BytesRejection(err) => err.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for YamlRejection {
+    fn into_response(self) -> Response {
+        let status = match self {
+            YamlRejection::MissingYamlContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            YamlRejection::YamlError(_) => StatusCode::BAD_REQUEST,
+            YamlRejection::BytesRejection(err) => return err.into_response(),
+        };
+        json_error(status, &self.to_string())
+    }
+}
+
+/**
+ * YAML 没有一个统一的 MIME 类型，常见的几种都接受
+ */
+fn is_yaml_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    matches!(
+        mime.as_str(),
+        "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml"
+    ) || mime.ends_with("+yaml")
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Yaml<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = YamlRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_yaml_content_type(req.headers()) {
+            return Err(YamlRejection::MissingYamlContentType);
+        }
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(YamlRejection::BytesRejection)?;
+        serde_yaml::from_slice(&bytes)
+            .map(Yaml)
+            .map_err(YamlRejection::YamlError)
+    }
+}
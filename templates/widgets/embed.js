@@ -0,0 +1,20 @@
+// 在当前 script 标签后面插入 /widgets/latest-todos 的 iframe，见 src/widgets.rs
+(function () {
+    var script = document.currentScript;
+    if (!script) {
+        return;
+    }
+    var url = new URL("/widgets/latest-todos", script.src);
+    if (script.dataset.limit) {
+        url.searchParams.set("limit", script.dataset.limit);
+    }
+    var frame = document.createElement("iframe");
+    frame.src = url.href;
+    frame.title = "Latest todos";
+    frame.loading = "lazy";
+    frame.setAttribute("sandbox", "allow-popups allow-popups-to-escape-sandbox");
+    frame.style.border = "0";
+    frame.style.width = "100%";
+    frame.style.height = script.dataset.height || "240px";
+    script.parentNode.insertBefore(frame, script.nextSibling);
+})();